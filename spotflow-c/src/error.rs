@@ -1,5 +1,11 @@
 use anyhow::Error;
-use std::{any::Any, cell::RefCell, cmp::min, slice};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    cmp::min,
+    fmt::{self, Display},
+    slice,
+};
 
 use libc::{c_char, c_int, size_t};
 
@@ -22,8 +28,70 @@ pub enum CResult {
     // This can be extended to accomodate more specific errors
 }
 
+/// The category of the most recent error, see @ref spotflow_get_last_error_code.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No error has been produced yet.
+    SpotflowErrorCodeNone = 0,
+    /// The error doesn't belong to any of the other categories.
+    SpotflowErrorCodeOther,
+    /// The communication with the Platform failed, for example, because the Device is offline.
+    SpotflowErrorCodeNetwork,
+    /// An argument provided to the function is invalid.
+    SpotflowErrorCodeInvalidArgument,
+    /// The Workspace the Device belongs to is disabled.
+    SpotflowErrorCodeWorkspaceDisabled,
+    /// Reading from or writing to the local database file failed.
+    SpotflowErrorCodePersistence,
+    /// The local database file can't grow because the storage is full.
+    SpotflowErrorCodeStorageFull,
+    /// The Device is offline and has no stored credentials, so it can't be provisioned.
+    SpotflowErrorCodeNoNetworkNoCredentials,
+    /// No Cloud-to-Device Message was received before the given timeout elapsed.
+    SpotflowErrorCodeTimeout,
+}
+
+impl ErrorCode {
+    fn of(err: &Error) -> ErrorCode {
+        let invalid_argument = err
+            .chain()
+            .any(|cause| cause.is::<InvalidArgument>() || cause.is::<std::str::Utf8Error>());
+        if invalid_argument {
+            return ErrorCode::SpotflowErrorCodeInvalidArgument;
+        }
+
+        match spotflow::ErrorKind::of(err) {
+            spotflow::ErrorKind::Network => ErrorCode::SpotflowErrorCodeNetwork,
+            spotflow::ErrorKind::InvalidArgument => ErrorCode::SpotflowErrorCodeInvalidArgument,
+            spotflow::ErrorKind::WorkspaceDisabled => ErrorCode::SpotflowErrorCodeWorkspaceDisabled,
+            spotflow::ErrorKind::Persistence => ErrorCode::SpotflowErrorCodePersistence,
+            spotflow::ErrorKind::StorageFull => ErrorCode::SpotflowErrorCodeStorageFull,
+            spotflow::ErrorKind::NoNetworkNoCredentials => {
+                ErrorCode::SpotflowErrorCodeNoNetworkNoCredentials
+            }
+            spotflow::ErrorKind::Timeout => ErrorCode::SpotflowErrorCodeTimeout,
+            spotflow::ErrorKind::Other => ErrorCode::SpotflowErrorCodeOther,
+        }
+    }
+}
+
+// Marks the errors caused by invalid pointers or buffers passed from C
+#[derive(Debug)]
+pub(crate) struct InvalidArgument(pub(crate) &'static str);
+
+impl Display for InvalidArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for InvalidArgument {}
+
 thread_local! {
     static LAST_ERROR: RefCell<Option<Error>> = const { RefCell::new(None) };
+    // Kept separately so that the code is still available after the error message is read
+    static LAST_ERROR_CODE: Cell<ErrorCode> = const { Cell::new(ErrorCode::SpotflowErrorCodeNone) };
 }
 
 /// Update the most recent error, clearing whatever may have been there before.
@@ -46,6 +114,8 @@ pub(crate) fn update_last_error(err: Error) {
         }
     }
 
+    LAST_ERROR_CODE.with(|prev| prev.set(ErrorCode::of(&err)));
+
     LAST_ERROR.with(|prev| {
         *prev.borrow_mut() = Some(err);
     });
@@ -126,3 +196,14 @@ pub unsafe extern "C" fn spotflow_read_last_error_message(
 
     (copy_length + 1) as c_int
 }
+
+/// Get the category of the most recent error. Unlike @ref spotflow_read_last_error_message, the function doesn't
+/// consume the error, so it can be called both before and after reading the error message.
+///
+/// @return The category of the most recent error, or @ref SPOTFLOW_ERROR_CODE_NONE if no error has been produced yet.
+#[no_mangle]
+pub extern "C" fn spotflow_get_last_error_code() -> ErrorCode {
+    ensure_logging();
+
+    LAST_ERROR_CODE.with(Cell::get)
+}
@@ -1,12 +1,12 @@
-use std::panic::AssertUnwindSafe;
+use std::{panic::AssertUnwindSafe, ptr, time::Duration};
 
 use libc::{c_char, c_void, size_t};
 use spotflow::{CloudToDeviceMessage, DeviceClient};
 
 use crate::{
-    call_safe_with_result, drop_str_ptr, ensure_logging,
+    call_safe_with_result, call_safe_with_unit_result, drop_str_ptr, ensure_logging,
     error::{update_last_error, CResult},
-    ptr_to_ref, string_to_ptr,
+    obj_to_ptr, ptr_to_mut, ptr_to_ref, string_to_ptr,
 };
 
 /// The callback to process an incoming Cloud-to-Device Message. The callback is called only if you have configured it
@@ -23,8 +23,10 @@ use crate::{
 pub type C2dCallback = extern "C" fn(msg: *const C2dMessage, context: *mut c_void);
 
 /// A Cloud-to-Device Message. This object is managed by the Device SDK and its contents must not be modified.
-/// It's referenced from @ref spotflow_c2d_callback_t and the lifetime of @ref spotflow_c2d_message_t is the same as the
+/// When it's referenced from @ref spotflow_c2d_callback_t, the lifetime of @ref spotflow_c2d_message_t is the same as the
 /// lifetime of the callback. If you need to keep the message for longer, copy it to your own memory.
+/// When it's obtained from @ref spotflow_client_read_c2d_message, it's valid until you destroy it using
+/// @ref spotflow_c2d_message_destroy.
 #[repr(C)]
 pub struct C2dMessage {
     /// (Don't modify) The length of the Cloud-to-Device Message content.
@@ -124,3 +126,89 @@ unsafe extern "C" fn spotflow_client_register_c2d_callback(
         }
     }
 }
+
+/// Read a Cloud-to-Device Message that was sent to this device.
+///
+/// There may be some messages persisted in the local database file from previous or current runs, otherwise the function
+/// waits until a message is received or until the timeout elapses. The message is acknowledged when it is read by this
+/// function and will not be delivered again.
+///
+/// Don't combine this function with @ref spotflow_client_register_c2d_callback, each message is delivered only once.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param timeout_ms The maximum time to wait for a message in milliseconds.
+/// @param message (Output) The read @ref spotflow_c2d_message_t. Destroy it using @ref spotflow_c2d_message_destroy
+///                when you no longer need it.
+/// @return @ref SPOTFLOW_OK if a message was read, @ref SPOTFLOW_ERROR if any argument is invalid, no message was
+///         received before the timeout elapsed, or there is an error in accessing the local database file.
+#[no_mangle]
+#[allow(deprecated)] // We'll use the current interface until it's stabilized
+pub unsafe extern "C" fn spotflow_client_read_c2d_message(
+    client: *mut DeviceClient,
+    timeout_ms: u64,
+    message: *mut *mut C2dMessage,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+    let message = AssertUnwindSafe(message);
+
+    call_safe_with_unit_result(|| {
+        ensure_logging();
+
+        let client = ptr_to_ref(*client)?;
+        // Check the output pointer before the message is read because reading acknowledges it
+        let message = ptr_to_mut(*message)?;
+        let msg = client.get_c2d(Duration::from_millis(timeout_ms))?;
+        *message = obj_to_ptr(owned_message(&msg));
+        Ok(())
+    })
+}
+
+/// Destroy a @ref spotflow_c2d_message_t obtained from @ref spotflow_client_read_c2d_message, including its content
+/// and properties.
+///
+/// @param message The @ref spotflow_c2d_message_t object to destroy.
+#[no_mangle]
+pub unsafe extern "C" fn spotflow_c2d_message_destroy(message: *mut C2dMessage) {
+    if message.is_null() {
+        return;
+    }
+
+    let message = Box::from_raw(message);
+    drop(boxed_slice_from_raw(
+        message.content.cast_mut(),
+        message.content_length,
+    ));
+    for property in
+        boxed_slice_from_raw(message.properties.cast_mut(), message.properties_count).into_vec()
+    {
+        drop_str_ptr(property.name);
+        drop_str_ptr(property.value);
+    }
+}
+
+fn owned_message(msg: &CloudToDeviceMessage) -> C2dMessage {
+    let properties = msg
+        .properties
+        .iter()
+        .map(|(key, value)| C2dProperty {
+            name: string_to_ptr(key.clone()),
+            value: string_to_ptr(value.clone()),
+        })
+        .collect::<Box<[_]>>();
+    let content = msg.content.clone().into_boxed_slice();
+
+    C2dMessage {
+        content_length: content.len(),
+        content: Box::into_raw(content).cast::<u8>(),
+        properties_count: properties.len(),
+        properties: Box::into_raw(properties).cast::<C2dProperty>(),
+    }
+}
+
+unsafe fn boxed_slice_from_raw<T>(ptr: *mut T, len: size_t) -> Box<[T]> {
+    if ptr.is_null() {
+        return Box::new([]);
+    }
+
+    Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len))
+}
@@ -1,6 +1,8 @@
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::panic::AssertUnwindSafe;
 use std::ptr::null_mut;
+use std::time::Duration;
 
 use anyhow::{anyhow, bail, Result};
 use libc::{c_char, c_void, size_t};
@@ -9,7 +11,7 @@ use spotflow::{
 };
 
 use crate::dps::{DisplayProvisioningOperationCallback, ProvisioningOperation};
-use crate::error::{update_last_error, CResult};
+use crate::error::{update_last_error, CResult, InvalidArgument};
 use crate::marshall::Marshall;
 use crate::{
     buffer_to_slice, call_safe_with_result, call_safe_with_unit_result, drop_ptr, ensure_logging,
@@ -54,6 +56,16 @@ pub struct MessageContext {
     inner: spotflow::MessageContext,
 }
 
+/// A user property attached to outgoing [Messages](https://docs.spotflow.io/send-data/#message).
+/// Pass an array of these objects to @ref spotflow_message_context_set_properties.
+#[repr(C)]
+pub struct MessageProperty {
+    /// The name of the property.
+    pub name: *const c_char,
+    /// The value of the property.
+    pub value: *const c_char,
+}
+
 /// A set of options that specify how to connect to the Platform. This object is managed by the Device SDK.
 /// Create its instance using @ref spotflow_client_options_create and delete it using @ref spotflow_client_options_destroy.
 /// After you configure all the options, pass the address of @ref spotflow_client_options_t to
@@ -443,6 +455,8 @@ pub extern "C" fn spotflow_client_start(
 /// @see spotflow_message_context_set_stream_group
 ///      spotflow_message_context_set_stream
 ///      spotflow_message_context_set_compression
+///      spotflow_message_context_set_properties
+///      spotflow_message_context_set_source_device_id
 ///
 /// @param message_context (Output) The pointer to the @ref spotflow_message_context_t object that will be created by this function.
 /// @param stream_group (Optional) The [Stream Group](https://docs.spotflow.io/send-data/#stream-group)
@@ -530,6 +544,58 @@ pub unsafe extern "C" fn spotflow_message_context_set_stream(
     })
 }
 
+/// Set the ID of the [Device](https://docs.spotflow.io/connect-devices/#device) that originally produced the data of
+/// [Messages](https://docs.spotflow.io/send-data/#message).
+///
+/// Use it when this Device acts as a gateway relaying the data of other devices. The connection to the Platform
+/// still uses the identity of the gateway, but the Platform attributes the data to the given Device.
+///
+/// @param message_context The @ref spotflow_message_context_t object.
+/// @param source_device_id The ID of the Device that produced the data. If `NULL`, the data is attributed to this Device.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn spotflow_message_context_set_source_device_id(
+    message_context: *mut MessageContext,
+    source_device_id: *const c_char,
+) -> CResult {
+    call_safe_with_unit_result(|| {
+        ensure_logging();
+
+        let message_context = unsafe { ptr_to_mut(message_context) }?;
+        let source_device_id =
+            unsafe { ptr_to_str_option(source_device_id) }?.map(|s| s.to_string());
+
+        message_context.inner.set_source_device_id(source_device_id);
+
+        Ok(())
+    })
+}
+
+/// Set the content type of [Messages](https://docs.spotflow.io/send-data/#message), for example, `application/json`
+/// or `application/octet-stream`.
+///
+/// The content type is sent as the standard content type property of the Messages, so the Platform can route them by it.
+///
+/// @param message_context The @ref spotflow_message_context_t object.
+/// @param content_type The content type of the Messages. If `NULL`, no content type is sent.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn spotflow_message_context_set_content_type(
+    message_context: *mut MessageContext,
+    content_type: *const c_char,
+) -> CResult {
+    call_safe_with_unit_result(|| {
+        ensure_logging();
+
+        let message_context = unsafe { ptr_to_mut(message_context) }?;
+        let content_type = unsafe { ptr_to_str_option(content_type) }?.map(|s| s.to_string());
+
+        message_context.inner.set_content_type(content_type);
+
+        Ok(())
+    })
+}
+
 /// Set the compression to use for sending [Messages](https://docs.spotflow.io/send-data/#message).
 ///
 /// @param message_context The @ref spotflow_message_context_t object.
@@ -552,6 +618,47 @@ pub unsafe extern "C" fn spotflow_message_context_set_compression(
     })
 }
 
+/// Set the user properties attached to [Messages](https://docs.spotflow.io/send-data/#message).
+///
+/// The properties are key-value pairs of arbitrary metadata that the Platform can use for routing.
+/// The function copies the names and values, so you can free the array after it returns.
+/// Calling the function again replaces the previously set properties.
+///
+/// @param message_context The @ref spotflow_message_context_t object.
+/// @param properties The array of @ref spotflow_message_property_t objects. Can be `NULL` if `properties_count` is 0.
+/// @param properties_count The number of items in `properties`.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn spotflow_message_context_set_properties(
+    message_context: *mut MessageContext,
+    properties: *const MessageProperty,
+    properties_count: size_t,
+) -> CResult {
+    call_safe_with_unit_result(|| {
+        ensure_logging();
+
+        let message_context = unsafe { ptr_to_mut(message_context) }?;
+        let properties: &[MessageProperty] = if properties_count == 0 {
+            &[]
+        } else {
+            unsafe { buffer_to_slice(properties, properties_count) }?
+        };
+
+        let properties = properties
+            .iter()
+            .map(|property| {
+                let name = unsafe { ptr_to_str(property.name) }?.to_string();
+                let value = unsafe { ptr_to_str(property.value) }?.to_string();
+                Ok((name, value))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        message_context.inner.set_properties(properties);
+
+        Ok(())
+    })
+}
+
 /// Destroy the @ref spotflow_message_context_t object.
 ///
 /// @param message_context The @ref spotflow_message_context_t object to destroy.
@@ -614,6 +721,86 @@ pub extern "C" fn spotflow_client_enqueue_message(
     }
 }
 
+/// Enqueue multiple [Messages](https://docs.spotflow.io/send-data/#message) to
+/// be sent to the Platform.
+///
+/// The IDs and the content of each [Message](https://docs.spotflow.io/send-data/#message) are handled the same as in
+/// @ref spotflow_client_enqueue_message. All the Messages are saved to the local database file in a single transaction,
+/// which is considerably faster than enqueuing them one by one. If any of them can't be enqueued, none of them is.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param message_context The options that specify how to send the [Messages](https://docs.spotflow.io/send-data/#message).
+/// @param batch_ids (Optional) The array of `count` IDs of the [Batches](https://docs.spotflow.io/send-data/#batch) the
+///                  [Messages](https://docs.spotflow.io/send-data/#message) are a part of. Use `NULL` instead of
+///                  the whole array or instead of any of its items if you don't want to specify them.
+/// @param message_ids (Optional) The array of `count` IDs of the [Messages](https://docs.spotflow.io/send-data/#message).
+///                    Use `NULL` instead of the whole array or instead of any of its items if you don't want to specify them.
+/// @param buffers The array of `count` buffers that contain the [Messages](https://docs.spotflow.io/send-data/#message).
+/// @param lengths The array of `count` lengths of the buffers in bytes.
+/// @param count The number of [Messages](https://docs.spotflow.io/send-data/#message) to enqueue.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid or there is an error in
+///              persisting the messages.
+#[no_mangle]
+pub extern "C" fn spotflow_client_enqueue_messages(
+    client: *mut DeviceClient,
+    message_context: *const MessageContext,
+    batch_ids: *const *const c_char,
+    message_ids: *const *const c_char,
+    buffers: *const *const u8,
+    lengths: *const size_t,
+    count: size_t,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    call_safe_with_unit_result(|| {
+        ensure_logging();
+
+        let client = unsafe { ptr_to_ref(*client) }?;
+        let message_context = unsafe { ptr_to_ref(message_context) }?;
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        // A NULL array means that none of the IDs is specified
+        let batch_ids = unsafe { optional_array(batch_ids, count) }?;
+        let message_ids = unsafe { optional_array(message_ids, count) }?;
+        let buffers = unsafe { buffer_to_slice(buffers, count) }?;
+        let lengths = unsafe { buffer_to_slice(lengths, count) }?;
+
+        let mut messages = Vec::with_capacity(count);
+        for (i, (&buffer, &length)) in buffers.iter().zip(lengths).enumerate() {
+            let batch_id = unsafe { optional_str_item(batch_ids, i) }?;
+            let message_id = unsafe { optional_str_item(message_ids, i) }?;
+            let payload = unsafe { buffer_to_slice(buffer, length)?.to_vec() };
+            messages.push((batch_id, message_id, payload));
+        }
+
+        client.enqueue_messages(&message_context.inner, messages)
+    })
+}
+
+unsafe fn optional_array<'a>(
+    array: *const *const c_char,
+    count: size_t,
+) -> Result<Option<&'a [*const c_char]>> {
+    if array.is_null() {
+        Ok(None)
+    } else {
+        buffer_to_slice(array, count).map(Some)
+    }
+}
+
+unsafe fn optional_str_item(
+    array: Option<&[*const c_char]>,
+    index: usize,
+) -> Result<Option<String>> {
+    match array {
+        Some(array) => Ok(ptr_to_str_option(array[index])?.map(str::to_owned)),
+        None => Ok(None),
+    }
+}
+
 /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
 /// be sent to the Platform.
 ///
@@ -791,6 +978,36 @@ pub extern "C" fn spotflow_client_wait_enqueued_messages_sent(
     }
 }
 
+/// Block the current thread until all the [Messages](https://docs.spotflow.io/send-data/#message) that
+/// have been previously enqueued are sent to the Platform, or until the timeout elapses.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param timeout_ms The maximum time to wait in milliseconds.
+/// @param all_sent (Output) Whether all the [Messages](https://docs.spotflow.io/send-data/#message) were sent
+///                 before the timeout elapsed.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid or there is an error in
+///              accessing the local database file.
+#[no_mangle]
+pub extern "C" fn spotflow_client_wait_enqueued_messages_sent_timeout(
+    client: *mut DeviceClient,
+    timeout_ms: u64,
+    all_sent: *mut bool,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    let result = call_safe_with_result(|| {
+        ensure_logging();
+
+        let client = unsafe { ptr_to_ref(*client) }?;
+        client.wait_enqueued_messages_sent_timeout(Duration::from_millis(timeout_ms))
+    });
+
+    match result {
+        Err(e) => e,
+        Ok(value) => unsafe { store_to_ptr(all_sent, value) },
+    }
+}
+
 /// Send a [Message](https://docs.spotflow.io/send-data/#message) to
 /// the Platform.
 ///
@@ -940,6 +1157,94 @@ pub extern "C" fn spotflow_client_get_pending_messages_count(
     }
 }
 
+/// Remove all the [Messages](https://docs.spotflow.io/send-data/#message) that haven't been sent to the Platform yet
+/// from the local database file.
+///
+/// The connection to the Platform stays open. The Messages that are already being sent when the queue is cleared
+/// might still reach the Platform.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param count (Output) The number of removed [Messages](https://docs.spotflow.io/send-data/#message).
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid or there is an error in
+///              accessing the local database file.
+#[no_mangle]
+pub extern "C" fn spotflow_client_clear_pending_messages(
+    client: *const DeviceClient,
+    count: *mut size_t,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    let result = call_safe_with_result(|| {
+        ensure_logging();
+
+        let client = unsafe { ptr_to_ref(*client) }?;
+        client.clear_pending_messages()
+    });
+
+    match result {
+        Err(e) => e,
+        Ok(removed) => unsafe { store_to_ptr(count, removed) },
+    }
+}
+
+/// Get the time for which the oldest [Message](https://docs.spotflow.io/send-data/#message) that
+/// hasn't been sent to the Platform yet has been waiting in the local database file.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param age_ms (Output) The age of the oldest pending [Message](https://docs.spotflow.io/send-data/#message) in milliseconds,
+///               or 0 if there are no pending Messages.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid or there is an error in
+///              accessing the local database file.
+#[no_mangle]
+pub extern "C" fn spotflow_client_get_oldest_pending_message_age(
+    client: *const DeviceClient,
+    age_ms: *mut u64,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    let result = call_safe_with_result(|| {
+        ensure_logging();
+
+        let client = unsafe { ptr_to_ref(*client) }?;
+        client.oldest_pending_message_age()
+    });
+
+    match result {
+        Err(e) => e,
+        Ok(age) => {
+            let value = age.map_or(0, |age| u64::try_from(age.as_millis()).unwrap_or(u64::MAX));
+            unsafe { store_to_ptr(age_ms, value) }
+        }
+    }
+}
+
+/// Check whether [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) was performed
+/// when the client was started, as opposed to reusing the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token)
+/// stored in the local database file.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param provisioned (Output) Whether Device Provisioning was performed.
+/// @return @ref SPOTFLOW_OK if the function succeeds, @ref SPOTFLOW_ERROR if any argument is invalid.
+#[no_mangle]
+pub extern "C" fn spotflow_client_get_provisioned(
+    client: *const DeviceClient,
+    provisioned: *mut bool,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    let result = call_safe_with_result(|| {
+        ensure_logging();
+
+        let client = unsafe { ptr_to_ref(*client) }?;
+        Ok(client.startup_info().provisioned)
+    });
+
+    match result {
+        Err(e) => e,
+        Ok(value) => unsafe { store_to_ptr(provisioned, value) },
+    }
+}
+
 /// Write the ID of the [Workspace](https://docs.spotflow.io/manage-access/workspaces/) to which the
 /// [Device](https://docs.spotflow.io/connect-devices/#device) belongs into the provided buffer.
 ///
@@ -1032,6 +1337,60 @@ pub extern "C" fn spotflow_client_get_device_id(
     }
 }
 
+/// Write the configuration that the client actually uses into the provided buffer. The content is a JSON string encoded
+/// in UTF-8 that combines the options of the client with the values loaded from the local database file, such as the
+/// Platform instance URL, the [Device ID](https://docs.spotflow.io/connect-devices/#device-id), or the
+/// [Workspace ID](https://docs.spotflow.io/manage-access/workspaces/). It's intended for diagnostics.
+///
+/// @param client The @ref spotflow_client_t object.
+/// @param buffer The buffer to write the JSON string encoded in UTF-8 into.
+/// @param buffer_length The length of the buffer in bytes.
+/// @param config_length (Output) The length of the JSON string in bytes including the trailing null character.
+/// @return @ref SPOTFLOW_OK if the configuration was written successfully, @ref SPOTFLOW_INSUFFICIENT_BUFFER if the buffer
+///         is too small (you can then resize it using `config_length` and call the function again), @ref SPOTFLOW_ERROR
+///         if any argument is invalid or there is an error in accessing the local database file.
+#[no_mangle]
+pub unsafe extern "C" fn spotflow_client_get_effective_config(
+    client: *const DeviceClient,
+    buffer: *mut c_char,
+    buffer_length: size_t,
+    config_length: *mut size_t,
+) -> CResult {
+    let client = AssertUnwindSafe(client);
+
+    let result = call_safe_with_result(|| {
+        ensure_logging();
+
+        let client = ptr_to_ref(*client)?;
+        client.effective_config()
+    });
+    let config = match result {
+        Err(e) => return e,
+        Ok(config) => config.to_string(),
+    };
+
+    if !config_length.is_null() {
+        *config_length = config.len() + 1;
+    }
+
+    if buffer_length <= config.len() {
+        update_last_error(anyhow!(
+            "The buffer for the effective configuration needs to be at least {} bytes long.",
+            config.len() + 1
+        ));
+        return CResult::SpotflowInsufficientBuffer;
+    }
+
+    if buffer.is_null() {
+        update_last_error(InvalidArgument("Buffer pointer is null.").into());
+        return CResult::SpotflowError;
+    }
+
+    std::ptr::copy_nonoverlapping(config.as_ptr(), buffer as *mut u8, config.len());
+    *buffer.add(config.len()) = 0;
+    CResult::SpotflowOk
+}
+
 /// Disconnect from the Platform and destroy the @ref spotflow_client_t object.
 ///
 /// @param client The @ref spotflow_client_t object.
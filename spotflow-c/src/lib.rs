@@ -8,7 +8,7 @@ use std::slice;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{bail, Error, Result};
-use error::{update_last_error, update_last_error_with_panic, CResult};
+use error::{update_last_error, update_last_error_with_panic, CResult, InvalidArgument};
 use libc::{c_char, size_t};
 use simple_logger::SimpleLogger;
 
@@ -110,7 +110,7 @@ fn string_to_ptr(s: String) -> *const c_char {
 /// This means among other things that `ptr` may point to non-owned location, it could have been previously freed, it may not contain a NUL terminator, it may be disposed of before the returned `&str`'s lifetime ends, etc.
 unsafe fn ptr_to_str<'a>(ptr: *const c_char) -> Result<&'a str> {
     match ptr_to_str_option(ptr)? {
-        None => bail!("String is NULL."),
+        None => bail!(InvalidArgument("String is NULL.")),
         Some(s) => Ok(s),
     }
 }
@@ -154,14 +154,14 @@ unsafe fn drop_str_ptr(ptr: *const c_char) {
 // This is almost as unsafe as dereferencing with `*`. We just check for nulls and for alignment.
 unsafe fn ptr_to_mut<'a, T>(ptr: *mut T) -> Result<&'a mut T> {
     if ptr.is_null() {
-        bail!("Pointer is null.");
+        bail!(InvalidArgument("Pointer is null."));
     }
 
     // Check for correct alignment. This can catch some bad pointers especially for larger structs such as ingress. But incorrect pointers can still fall through
     // Change this when the function is stabilized
     // if !ptr.is_aligned() {
     if !is_aligned(ptr) {
-        bail!("Pointer is not properly aligned.");
+        bail!(InvalidArgument("Pointer is not properly aligned."));
     }
 
     Ok(&mut *ptr)
@@ -170,7 +170,7 @@ unsafe fn ptr_to_mut<'a, T>(ptr: *mut T) -> Result<&'a mut T> {
 // This is almost as unsafe as dereferencing with `*`. We just check for nulls and for alignment.
 unsafe fn ptr_to_ref<'a, T>(ptr: *const T) -> Result<&'a T> {
     match ptr_to_ref_option(ptr)? {
-        None => bail!("Pointer is NULL."),
+        None => bail!(InvalidArgument("Pointer is NULL.")),
         Some(s) => Ok(s),
     }
 }
@@ -185,7 +185,7 @@ unsafe fn ptr_to_ref_option<'a, T>(ptr: *const T) -> Result<Option<&'a T>> {
     // Change this when the function is stabilized
     // if !ptr.is_aligned() {
     if !is_aligned(ptr) {
-        bail!("Pointer is not properly aligned.");
+        bail!(InvalidArgument("Pointer is not properly aligned."));
     }
 
     Ok(Some(&*ptr))
@@ -206,11 +206,11 @@ unsafe fn store_to_ptr<T>(ptr: *mut T, val: T) -> CResult {
 
 unsafe fn buffer_to_slice<'a, T>(buffer: *const T, length: size_t) -> Result<&'a [T]> {
     if buffer.is_null() {
-        bail!("Buffer pointer is null.");
+        bail!(InvalidArgument("Buffer pointer is null."));
     }
 
     if !is_aligned(buffer) {
-        bail!("Buffer pointer is not properly aligned.");
+        bail!(InvalidArgument("Buffer pointer is not properly aligned."));
     }
 
     let slice = unsafe { slice::from_raw_parts(buffer, length) };
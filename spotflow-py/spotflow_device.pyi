@@ -1,5 +1,5 @@
 import enum
-from typing import Optional, Callable
+from typing import Dict, Iterator, Optional, Callable
 
 class SpotflowError(Exception):
     pass
@@ -28,7 +28,8 @@ class DeviceClient:
               db: str,
               instance: Optional[str] = None,
               display_provisioning_operation_callback: Optional[Callable[[ProvisioningOperation], None]] = None,
-              desired_properties_updated_callback: Optional[Callable[[DesiredProperties], None]] = None) -> DeviceClient:
+              desired_properties_updated_callback: Optional[Callable[[DesiredProperties], None]] = None,
+              stream_compressions: Optional[Dict[str, Compression]] = None) -> DeviceClient:
          ...
 
     @property
@@ -37,17 +38,35 @@ class DeviceClient:
     @property
     def device_id(self) -> str: ...
 
+    @property
+    def effective_config(self) -> str: ...
+
+    @property
+    def provisioned(self) -> bool: ...
+
     def create_stream_sender(self,
                              stream_group: Optional[str] = None,
                              stream: Optional[str] = None,
-                             compression: Optional[Compression] = Compression.UNCOMPRESSED) -> StreamSender:
+                             compression: Optional[Compression] = None,
+                             properties: Optional[Dict[str, str]] = None,
+                             source_device_id: Optional[str] = None,
+                             content_type: Optional[str] = None) -> StreamSender:
         ...
 
     @property
     def pending_messages_count(self) -> int: ...
 
+    @property
+    def oldest_pending_message_age(self) -> Optional[float]: ...
+
+    def clear_pending_messages(self) -> int: ...
+
     def wait_enqueued_messages_sent(self) -> None: ...
 
+    def wait_enqueued_messages_sent_timeout(self, timeout: float) -> bool: ...
+
+    async def wait_pending_flushed(self, timeout: Optional[float] = None) -> bool: ...
+
     def get_desired_properties(self) -> DesiredProperties: ...
 
     def get_desired_properties_if_newer(self, version: Optional[int] = None) -> Optional[DesiredProperties]: ...
@@ -57,6 +76,25 @@ class DeviceClient:
     @property
     def any_pending_reported_properties_updates(self) -> bool: ...
 
+    def try_read_c2d_message(self) -> Optional[CloudToDeviceMessage]:
+        """
+        Get a Cloud-to-Device Message that was sent to this device if one is already available, without waiting.
+
+        Returns `None` if there is no message ready to be read, so it can be called regularly from the main loop.
+        The message is acknowledged when it is received in this method and will not be delivered again.
+        """
+        ...
+
+    def c2d_messages(self, timeout: Optional[float] = None) -> CloudToDeviceMessageIterator:
+        """
+        Iterate over the Cloud-to-Device Messages that were sent to this device.
+
+        Each message is acknowledged when the iterator advances to it and will not be delivered again.
+        If you specify `timeout` (in seconds), the iteration stops when no message arrives in that time.
+        Otherwise, the iterator waits for new messages indefinitely.
+        """
+        ...
+
 class StreamSender:
     def send_message(self, 
                      payload: str | bytes,
@@ -74,6 +112,14 @@ class StreamSender:
                      chunk_id: Optional[str] = None) -> None:
         ...
 
+    def enqueue_batch_slice(self, 
+                     payload: str | bytes,
+                     batch_id: Optional[str] = None,
+                     message_id: Optional[str] = None,
+                     batch_slice_id: Optional[str] = None,
+                     chunk_id: Optional[str] = None) -> str:
+        ...
+
     def enqueue_batch_completion(self, batch_id: str) -> None: ...
 
     def enqueue_message_completion(self, batch_id: str, message_id: str) -> None: ...
@@ -84,3 +130,28 @@ class DesiredProperties:
 
     @property
     def values(self) -> dict: ...
+
+class CloudToDeviceMessage:
+    """A Cloud-to-Device Message received from the Platform."""
+
+    @property
+    def content(self) -> bytes:
+        """The binary content of the message."""
+        ...
+
+    @property
+    def properties(self) -> Dict[str, str]:
+        """The additional message properties."""
+        ...
+
+class CloudToDeviceMessageIterator(Iterator[CloudToDeviceMessage]):
+    """
+    An iterator over Cloud-to-Device Messages sent to this device.
+
+    Each step waits for the next message and acknowledges it, so it will not be delivered again.
+    If a timeout was specified, the iteration ends when no message arrives in time.
+    """
+
+    def __iter__(self) -> CloudToDeviceMessageIterator: ...
+
+    def __next__(self) -> CloudToDeviceMessage: ...
@@ -1,8 +1,12 @@
+use std::time::Duration;
+
 use pyo3::{
     prelude::*,
     types::{PyBytes, PyDict},
 };
 
+use crate::SpotflowError;
+
 /// A Cloud-to-Device Message received from the Platform.
 #[pyclass]
 pub struct CloudToDeviceMessage {
@@ -13,3 +17,62 @@ pub struct CloudToDeviceMessage {
     #[pyo3(get)]
     pub properties: Py<PyDict>,
 }
+
+impl CloudToDeviceMessage {
+    pub(crate) fn new(
+        py: Python<'_>,
+        message: &spotflow::CloudToDeviceMessage,
+    ) -> PyResult<CloudToDeviceMessage> {
+        let properties = PyDict::new(py);
+        for (key, value) in &message.properties {
+            properties.set_item(key, value)?;
+        }
+
+        Ok(CloudToDeviceMessage {
+            content: PyBytes::new(py, message.content.as_slice()).into(),
+            properties: properties.into(),
+        })
+    }
+}
+
+/// An iterator over Cloud-to-Device Messages sent to this device.
+///
+/// Each step waits for the next message and acknowledges it, so it will not be delivered again.
+/// If a timeout was specified, the iteration ends when no message arrives in time.
+#[pyclass]
+pub struct CloudToDeviceMessageIterator {
+    connection: spotflow::DeviceClient,
+    timeout: Option<Duration>,
+}
+
+impl CloudToDeviceMessageIterator {
+    pub(crate) fn new(connection: spotflow::DeviceClient, timeout: Option<Duration>) -> Self {
+        CloudToDeviceMessageIterator {
+            connection,
+            timeout,
+        }
+    }
+}
+
+#[pymethods]
+impl CloudToDeviceMessageIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[allow(deprecated)]
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<CloudToDeviceMessage>> {
+        let message = py.allow_threads(|| {
+            self.connection
+                .get_c2d(self.timeout.unwrap_or(Duration::MAX))
+        });
+
+        match message {
+            // The message is acknowledged when the guard is dropped after the conversion
+            Ok(message) => CloudToDeviceMessage::new(py, &message).map(Some),
+            // Only running out of time ends the iteration, other errors are raised
+            Err(e) if spotflow::ErrorKind::of(&e) == spotflow::ErrorKind::Timeout => Ok(None),
+            Err(e) => Err(SpotflowError::new_err(e.to_string())),
+        }
+    }
+}
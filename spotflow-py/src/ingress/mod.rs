@@ -1,10 +1,11 @@
 use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::panic::RefUnwindSafe;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use pyo3::exceptions::PyException;
-use pyo3::types::{PyBytes, PyDict, PyTraceback, PyTuple};
+use pyo3::types::{PyDict, PyTraceback, PyTuple};
 use pyo3::{prelude::*, types::PyType};
 use spotflow::{
     DesiredPropertiesUpdatedCallback, DeviceClientBuilder, MessageContext,
@@ -14,7 +15,7 @@ use spotflow::{
 use crate::dps::ProvisioningOperation;
 use crate::{PythonProcessSignalsSource, SpotflowError};
 
-use self::c2d::CloudToDeviceMessage;
+use self::c2d::{CloudToDeviceMessage, CloudToDeviceMessageIterator};
 use self::twins::DesiredProperties;
 
 pub mod c2d;
@@ -137,6 +138,8 @@ impl DeviceClient {
     ///   from the Platform. The [Device configuration tutorial](https://docs.spotflow.io/configure-devices/tutorial-configure-device#1-start-device)
     ///   shows how to use this option. The function is called in a separate thread, so make sure that you properly synchronize
     ///   access to your shared resources. The whole interface of the Device SDK is thread-safe, so it's safe to use it in the function.
+    /// - **stream_compressions**: The dictionary of the default compression for each [Stream](https://docs.spotflow.io/send-data/#stream)
+    ///   name. `DeviceClient.create_stream_sender` uses it when `compression` is not provided.
     ///
     /// If the [Device](https://docs.spotflow.io/connect-devices/#device) is
     /// not yet registered in the Platform, or its
@@ -149,7 +152,7 @@ impl DeviceClient {
     /// the last run is still valid, this method succeeds even without the connection to the Internet. The Device SDK will
     /// store all outgoing communication in the local database file and send it once it connects to the Platform.
     #[classmethod]
-    #[pyo3(signature = (device_id, provisioning_token, db, instance=None, display_provisioning_operation_callback=None, desired_properties_updated_callback=None))]
+    #[pyo3(signature = (device_id, provisioning_token, db, instance=None, display_provisioning_operation_callback=None, desired_properties_updated_callback=None, stream_compressions=None))]
     #[allow(clippy::too_many_arguments)]
     fn start(
         _cls: &PyType,
@@ -160,6 +163,7 @@ impl DeviceClient {
         instance: Option<String>,
         display_provisioning_operation_callback: Option<PyObject>,
         desired_properties_updated_callback: Option<PyObject>,
+        stream_compressions: Option<HashMap<String, Compression>>,
     ) -> PyResult<DeviceClient> {
         py.allow_threads(|| {
             let mut builder = DeviceClientBuilder::new(device_id, provisioning_token, db);
@@ -180,6 +184,19 @@ impl DeviceClient {
                 ));
             }
 
+            if let Some(stream_compressions) = stream_compressions {
+                builder = builder.with_stream_compressions(
+                    stream_compressions
+                        .into_iter()
+                        .filter_map(|(stream, compression)| {
+                            compression
+                                .to_ingress_compression_option()
+                                .map(|compression| (stream, compression))
+                        })
+                        .collect(),
+                );
+            }
+
             builder
                 .with_signals_source(Box::<PythonProcessSignalsSource>::default())
                 .build()
@@ -238,6 +255,40 @@ impl DeviceClient {
         })
     }
 
+    /// (Read-only) The configuration that the client actually uses as a JSON string. It combines the options of
+    /// `DeviceClient.start` with the values loaded from the local database file, such as the Platform instance URL,
+    /// the [Device ID](https://docs.spotflow.io/connect-devices/#device-id), or the
+    /// [Workspace ID](https://docs.spotflow.io/manage-access/workspaces/). It's intended for diagnostics.
+    #[getter]
+    fn effective_config(&self, py: Python<'_>) -> PyResult<String> {
+        py.allow_threads(|| {
+            self.inner
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .effective_config()
+                .map(|config| config.to_string())
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })
+    }
+
+    /// (Read-only) Whether [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) was performed
+    /// in `DeviceClient.start`, as opposed to reusing the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token)
+    /// stored in the local database file.
+    #[getter]
+    fn provisioned(&self, py: Python<'_>) -> bool {
+        py.allow_threads(|| {
+            self.inner
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .startup_info()
+                .provisioned
+        })
+    }
+
     /// Create a `StreamSender` for sending [Messages](https://docs.spotflow.io/send-data/#message) to
     /// a [Stream](https://docs.spotflow.io/send-data/#stream) that is contained in a
     /// [Stream Group](https://docs.spotflow.io/send-data/#stream-group).
@@ -245,27 +296,39 @@ impl DeviceClient {
     /// If `stream_group` is omitted, the Platforms directs the Messages to the default Stream Group of the current
     /// [Workspace](https://docs.spotflow.io/manage-access/workspaces/). If `stream` is ommited, the Platform
     /// directs the Messages into the default Stream of the given Stream Group.
+    /// If `compression` is omitted, the default compression for the Stream set in `DeviceClient.start` is used.
+    /// Optionally, you can provide `properties` as a dictionary of user properties attached to each Message.
+    /// If this Device relays the data of another device, provide its ID as `source_device_id` so that the Platform
+    /// attributes the Messages to it. Optionally, you can provide `content_type` such as `application/json` that is
+    /// sent as the standard content type property of each Message.
     fn create_stream_sender(
         &self,
         py: Python<'_>,
         stream_group: Option<String>,
         stream: Option<String>,
         compression: Option<Compression>,
+        properties: Option<HashMap<String, String>>,
+        source_device_id: Option<String>,
+        content_type: Option<String>,
     ) -> PyResult<StreamSender> {
-        let compression = compression.unwrap_or(Compression::Uncompressed);
-
         py.allow_threads(|| {
             let connection =
                 self.inner.lock().unwrap().clone().ok_or_else(|| {
                     SpotflowError::new_err("Connection has already been shut down")
                 })?;
 
-            let mut message_context = MessageContext::new(stream_group, stream);
-            message_context.set_compression(compression.to_ingress_compression_option());
+            let mut message_context = connection.create_message_context(stream_group, stream);
+            if let Some(compression) = compression {
+                message_context.set_compression(compression.to_ingress_compression_option());
+            }
+            message_context.set_properties(properties.unwrap_or_default());
+            message_context.set_source_device_id(source_device_id);
+            message_context.set_content_type(content_type);
 
             Ok(StreamSender {
                 connection,
                 message_context,
+                batch_slices: BatchSliceCounter::default(),
             })
         })
     }
@@ -285,6 +348,40 @@ impl DeviceClient {
         })
     }
 
+    /// Remove all the [Messages](https://docs.spotflow.io/send-data/#message) that haven't been sent to the Platform yet
+    /// from the local database file and return how many of them were removed.
+    ///
+    /// The connection to the Platform stays open. The Messages that are already being sent when the queue is cleared
+    /// might still reach the Platform.
+    fn clear_pending_messages(&self, py: Python<'_>) -> PyResult<usize> {
+        py.allow_threads(|| {
+            self.inner
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .clear_pending_messages()
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })
+    }
+
+    /// (Read-only) The number of seconds for which the oldest [Message](https://docs.spotflow.io/send-data/#message) that
+    /// hasn't been sent to the Platform yet has been waiting in the local database file,
+    /// or `None` if there are no such Messages.
+    #[getter]
+    fn oldest_pending_message_age(&self, py: Python<'_>) -> PyResult<Option<f64>> {
+        py.allow_threads(|| {
+            self.inner
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .oldest_pending_message_age()
+                .map(|age| age.map(|age| age.as_secs_f64()))
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })
+    }
+
     /// Block the current thread until all the [Messages](https://docs.spotflow.io/send-data/#message) that
     /// have been previously enqueued are sent to the Platform.
     fn wait_enqueued_messages_sent(&self, py: Python<'_>) -> PyResult<()> {
@@ -299,6 +396,74 @@ impl DeviceClient {
         })
     }
 
+    /// Block the current thread until all the [Messages](https://docs.spotflow.io/send-data/#message) that
+    /// have been previously enqueued are sent to the Platform, or until `timeout` (in seconds) elapses.
+    ///
+    /// Returns `True` if all the Messages were sent and `False` if some of them were still pending when the time ran out.
+    fn wait_enqueued_messages_sent_timeout(&self, py: Python<'_>, timeout: f64) -> PyResult<bool> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|e| SpotflowError::new_err(format!("Invalid timeout: {e}")))?;
+
+        py.allow_threads(|| {
+            self.inner
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .wait_enqueued_messages_sent_timeout(timeout)
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })
+    }
+
+    /// Wait without blocking the event loop until all the [Messages](https://docs.spotflow.io/send-data/#message)
+    /// that have been previously enqueued are sent to the Platform, or until `timeout` (in seconds) elapses.
+    ///
+    /// Returns an awaitable that resolves to `True` if all the Messages were sent and `False` if some of them
+    /// were still pending when the time ran out. If `timeout` is `None`, it waits until all the Messages are sent.
+    fn wait_pending_flushed<'p>(
+        &self,
+        py: Python<'p>,
+        timeout: Option<f64>,
+    ) -> PyResult<&'p PyAny> {
+        let deadline = timeout
+            .map(|timeout| {
+                Duration::try_from_secs_f64(timeout)
+                    .map_err(|e| SpotflowError::new_err(format!("Invalid timeout: {e}")))
+            })
+            .transpose()?
+            .map(|timeout| Instant::now() + timeout);
+
+        let connection = self
+            .inner
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| SpotflowError::new_err("Connection has already been shut down"))?;
+
+        pyo3_asyncio::tokio::future_into_py(py, async move {
+            loop {
+                // Reading the count blocks on the runtime of the client, so it must not run on the asyncio runtime itself
+                let count = tokio::task::spawn_blocking({
+                    let connection = connection.clone();
+                    move || connection.pending_messages_count()
+                })
+                .await
+                .map_err(|e| SpotflowError::new_err(e.to_string()))?
+                .map_err(|e| SpotflowError::new_err(e.to_string()))?;
+
+                if count == 0 {
+                    return Ok(true);
+                }
+
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(false);
+                }
+
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        })
+    }
+
     /// Get the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
     /// if their version is higher than `version` or if `version` is `None`. Otherwise, return `None`.
     ///
@@ -448,15 +613,49 @@ impl DeviceClient {
                 .map_err(|e| SpotflowError::new_err(e.to_string()))
         })?;
 
-        let properties = PyDict::new(py);
-        for (key, value) in &message.properties {
-            properties.set_item(key, value)?;
-        }
+        CloudToDeviceMessage::new(py, &message)
+    }
 
-        Ok(CloudToDeviceMessage {
-            content: PyBytes::new(py, message.content.as_slice()).into(),
-            properties: properties.into(),
-        })
+    /// Get a Cloud-to-Device Message that was sent to this device if one is already available, without waiting.
+    ///
+    /// Returns `None` if there is no message ready to be read, so it can be called regularly from the main loop.
+    /// The message is acknowledged when it is received in this method and will not be delivered again.
+    fn try_read_c2d_message(&self, py: Python<'_>) -> PyResult<Option<CloudToDeviceMessage>> {
+        let connection = self.inner.lock().unwrap();
+        let message = py.allow_threads(|| {
+            connection
+                .as_ref()
+                .unwrap()
+                .try_get_c2d()
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })?;
+
+        message
+            .map(|message| CloudToDeviceMessage::new(py, &message))
+            .transpose()
+    }
+
+    /// Iterate over the Cloud-to-Device Messages that were sent to this device.
+    ///
+    /// Each message is acknowledged when the iterator advances to it and will not be delivered again.
+    /// If you specify `timeout` (in seconds), the iteration stops when no message arrives in that time.
+    /// Otherwise, the iterator waits for new messages indefinitely.
+    fn c2d_messages(&self, timeout: Option<f64>) -> PyResult<CloudToDeviceMessageIterator> {
+        let timeout = timeout
+            .map(|timeout| {
+                Duration::try_from_secs_f64(timeout)
+                    .map_err(|e| SpotflowError::new_err(format!("Invalid timeout: {e}")))
+            })
+            .transpose()?;
+
+        let connection = self
+            .inner
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| SpotflowError::new_err("Connection has already been shut down"))?;
+
+        Ok(CloudToDeviceMessageIterator::new(connection, timeout))
     }
 
     /// The ID of the [Site](https://docs.spotflow.io/connect-devices/#site) the
@@ -483,6 +682,38 @@ impl DeviceClient {
 pub struct StreamSender {
     connection: spotflow::DeviceClient,
     message_context: MessageContext,
+    batch_slices: BatchSliceCounter,
+}
+
+// Keeps track of the Batch Slice IDs used by `StreamSender.enqueue_batch_slice` in the current Batch
+#[derive(Default)]
+struct BatchSliceCounter {
+    batch_id: Option<String>,
+    next_batch_slice_id: u64,
+}
+
+impl BatchSliceCounter {
+    fn next(&mut self, batch_id: &Option<String>, batch_slice_id: Option<String>) -> String {
+        if self.batch_id != *batch_id {
+            self.batch_id = batch_id.clone();
+            self.next_batch_slice_id = 1;
+        }
+
+        match batch_slice_id {
+            Some(batch_slice_id) => {
+                // Continue numbering after an explicit numeric ID so that the following ones don't collide with it
+                if let Ok(id) = batch_slice_id.parse::<u64>() {
+                    self.next_batch_slice_id = id + 1;
+                }
+                batch_slice_id
+            }
+            None => {
+                let id = self.next_batch_slice_id;
+                self.next_batch_slice_id += 1;
+                id.to_string()
+            }
+        }
+    }
 }
 
 #[pymethods]
@@ -566,6 +797,41 @@ impl StreamSender {
         })
     }
 
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
+    /// be sent to the Platform as the next Batch Slice of the given [Batch](https://docs.spotflow.io/send-data/#batch).
+    ///
+    /// The `StreamSender` numbers the Batch Slices of each Batch as `"1"`, `"2"`, `"3"`, and so on,
+    /// and starts from `"1"` again when `batch_id` changes. If you provide `batch_slice_id`, it's used instead, and
+    /// if it's a number, the following Batch Slices continue from it. The method returns the Batch Slice ID that was used.
+    ///
+    /// Otherwise, the method behaves the same as `enqueue_message`.
+    fn enqueue_batch_slice(
+        &mut self,
+        py: Python<'_>,
+        payload: Vec<u8>,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        batch_slice_id: Option<String>,
+        chunk_id: Option<String>,
+    ) -> PyResult<String> {
+        let batch_slice_id = self.batch_slices.next(&batch_id, batch_slice_id);
+
+        py.allow_threads(|| {
+            self.connection
+                .enqueue_message_advanced(
+                    &self.message_context,
+                    batch_id,
+                    Some(batch_slice_id.clone()),
+                    message_id,
+                    chunk_id,
+                    payload,
+                )
+                .map_err(|e| SpotflowError::new_err(e.to_string()))
+        })?;
+
+        Ok(batch_slice_id)
+    }
+
     /// Enqueue the manual completion of the current [Batch](https://docs.spotflow.io/send-data/#batch) to
     /// be sent to the Platform.
     ///
@@ -11,7 +11,22 @@ CREATE TABLE IF NOT EXISTS Messages (
     close_option        TEXT NOT NULL,
     compression         TEXT NOT NULL,
     batch_slice_id      TEXT,
-    chunk_id            TEXT
+    chunk_id            TEXT,
+    expiry              TEXT, -- DATETIME
+    properties          TEXT, -- JSON
+    enqueued_at         TEXT, -- DATETIME
+    source_device_id    TEXT,
+    ordered             INTEGER NOT NULL DEFAULT 1, -- BOOLEAN
+    idempotency_key     TEXT,
+    content_type        TEXT
+) STRICT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS MessagesIdempotencyKey ON Messages (idempotency_key);
+
+CREATE TABLE IF NOT EXISTS Batches (
+    batch_id            TEXT PRIMARY KEY,
+    enqueued            INTEGER NOT NULL,
+    completed           INTEGER NOT NULL DEFAULT 0 -- BOOLEAN
 ) STRICT;
 
 CREATE TABLE IF NOT EXISTS CloudToDeviceMessages (
@@ -55,5 +70,6 @@ CREATE TABLE IF NOT EXISTS SdkConfiguration (
     rt_expiration       TEXT, -- DATETIME
     requested_device_id TEXT,
     workspace_id        TEXT NOT NULL,
-    device_id           TEXT NOT NULL
+    device_id           TEXT NOT NULL,
+    device_instance_id  TEXT
 ) STRICT;
@@ -1,10 +1,11 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Context, Result};
 use http::{
     uri::{PathAndQuery, Scheme},
     Uri,
 };
+use native_tls::{Identity, TlsConnector};
 use serde::Deserialize;
 use thiserror::Error;
 use ureq::Response;
@@ -19,6 +20,43 @@ pub(crate) enum RequestError {
     Other(#[from] anyhow::Error),
 }
 
+// Builds the TLS connector presenting the given client certificate in the HTTPS requests to the Platform.
+// Loading the identity also checks that the private key belongs to the certificate.
+pub(crate) fn client_certificate_connector(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<TlsConnector> {
+    let identity = Identity::from_pkcs8(cert_pem, key_pem).context(
+        "Unable to load the client certificate. Check that both the certificate and the private key are PEM-encoded, \
+        that the private key is in the PKCS #8 format, and that the private key belongs to the certificate.",
+    )?;
+
+    TlsConnector::builder()
+        .identity(identity)
+        .build()
+        .context("Unable to configure TLS with the client certificate. Check that the private key belongs to the certificate.")
+}
+
+/// Settings shared by all the HTTPS requests to the Platform.
+#[derive(Clone, Debug)]
+pub(crate) struct HttpConfig {
+    // Carries the client certificate if the Device authenticates with one
+    pub tls_connector: Option<TlsConnector>,
+    pub timeout: Duration,
+    // Required by the network of the Device, for example, by a proxy
+    pub extra_headers: HashMap<String, String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            tls_connector: None,
+            timeout: Duration::from_secs(10),
+            extra_headers: HashMap::new(),
+        }
+    }
+}
+
 fn get_problem_title(details: &Option<Box<ProblemDetails>>) -> String {
     details
         .as_ref()
@@ -43,8 +81,16 @@ pub(crate) fn put(
     relative_uri: &Uri,
     token: impl AsRef<str>,
     data: impl serde::Serialize,
+    http_config: &HttpConfig,
 ) -> Result<Response, RequestError> {
-    send(&http::Method::PUT, base_uri, relative_uri, token, data)
+    send(
+        &http::Method::PUT,
+        base_uri,
+        relative_uri,
+        token,
+        data,
+        http_config,
+    )
 }
 
 pub(crate) fn post(
@@ -52,8 +98,16 @@ pub(crate) fn post(
     relative_uri: &Uri,
     token: impl AsRef<str>,
     data: impl serde::Serialize,
+    http_config: &HttpConfig,
 ) -> Result<Response, RequestError> {
-    send(&http::Method::POST, base_uri, relative_uri, token, data)
+    send(
+        &http::Method::POST,
+        base_uri,
+        relative_uri,
+        token,
+        data,
+        http_config,
+    )
 }
 
 pub(crate) fn send(
@@ -62,6 +116,7 @@ pub(crate) fn send(
     relative_uri: &Uri,
     token: impl AsRef<str>,
     data: impl serde::Serialize,
+    http_config: &HttpConfig,
 ) -> Result<Response, RequestError> {
     let Some(authority) = base_uri.authority() else {
         return Err(anyhow!("Provided base URI {base_uri:?} does not contain the authority (e.g., 'api.eu1.spotflow.io').").into());
@@ -78,12 +133,14 @@ pub(crate) fn send(
         .build()
         .with_context(|| format!("Unable to build URI from {base_uri:?} and {relative_uri:?}"))?;
 
-    log::debug!("Sending request to {uri}");
+    debug!("Sending request to {uri}");
 
     let auth_header = format!("DeviceToken {}", token.as_ref());
 
-    let connector =
-        Arc::new(native_tls::TlsConnector::new().expect("Unable to build TLS connector"));
+    let connector = Arc::new(match &http_config.tls_connector {
+        Some(tls_connector) => tls_connector.clone(),
+        None => TlsConnector::new().expect("Unable to build TLS connector"),
+    });
     let agent = ureq::AgentBuilder::new().tls_connector(connector).build();
 
     let request = match *method {
@@ -92,15 +149,20 @@ pub(crate) fn send(
         _ => unimplemented!("Method {} is not implemented.", method),
     };
 
+    let request = http_config
+        .extra_headers
+        .iter()
+        .fold(request, |request, (name, value)| request.set(name, value));
+
     let result = request
-        .timeout(Duration::from_secs(10))
+        .timeout(http_config.timeout)
         .set("Content-Type", "application/json")
         .set("Authorization", &auth_header)
         .send_json(data);
 
     match result {
         Ok(response) => {
-            log::debug!(
+            debug!(
                 "Request to {uri} succeeded with status code {}",
                 response.status()
             );
@@ -109,16 +171,14 @@ pub(crate) fn send(
         Err(ureq::Error::Status(status, response)) => {
             let response_body = response.into_string().unwrap_or_default();
 
-            log::debug!(
-                "Request to {uri} failed with status code {status}. Response: {response_body}"
-            );
+            debug!("Request to {uri} failed with status code {status}. Response: {response_body}");
 
             let problem_details = serde_json::from_str(&response_body).ok();
 
             Err(RequestError::Status(status, problem_details))
         }
         Err(ureq::Error::Transport(e)) => {
-            log::debug!("Request to {uri} failed with transport error: {e}");
+            debug!("Request to {uri} failed with transport error: {e}");
             Err(RequestError::Transport(Box::new(e)))
         }
     }
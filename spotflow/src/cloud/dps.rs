@@ -6,7 +6,7 @@ use serde_json::json;
 use thiserror::Error;
 
 use super::{
-    api_core::{post, put, RequestError},
+    api_core::{post, put, HttpConfig, RequestError},
     log_workspace_disabled_error,
 };
 
@@ -20,6 +20,22 @@ pub enum InitProvisioningError {
     Other(#[from] anyhow::Error),
 }
 
+impl InitProvisioningError {
+    // Distinguishes the Device being offline from the Platform rejecting the request
+    pub fn is_network(&self) -> bool {
+        match self {
+            InitProvisioningError::Other(e) => {
+                matches!(e.downcast_ref(), Some(RequestError::Transport(_)))
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unable to provision the Device because it's offline and there are no stored credentials. Connect the Device to the Internet first.")]
+pub struct NoNetworkNoCredentials;
+
 #[derive(Debug, Error)]
 pub enum CompletionError {
     #[error("Provisioning Operation was not approved yet")]
@@ -64,11 +80,11 @@ pub struct RegistrationToken {
 }
 
 impl RegistrationToken {
-    pub fn is_expired(&self) -> bool {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
         // TODO: Make more reliable in the presence of clockskew (see expect_clockskew)
         match self.expiration {
             None => false,
-            Some(datetime) => datetime < Utc::now(),
+            Some(datetime) => datetime < now,
         }
     }
 }
@@ -95,6 +111,7 @@ pub struct Provisioning {
     instance_url: Uri,
     pt: ProvisioningToken,
     device_id: Option<String>,
+    http_config: HttpConfig,
 }
 
 impl Provisioning {
@@ -103,6 +120,7 @@ impl Provisioning {
             instance_url,
             pt: token,
             device_id: None,
+            http_config: HttpConfig::default(),
         }
     }
 
@@ -115,6 +133,11 @@ impl Provisioning {
         self
     }
 
+    pub fn with_http_config(&mut self, http_config: HttpConfig) -> &mut Provisioning {
+        self.http_config = http_config;
+        self
+    }
+
     pub fn init(&mut self) -> Result<InitProvisioningResponse, InitProvisioningError> {
         let relative_url = Uri::from_static("/provisioning-operations/init");
         let body = match &self.device_id {
@@ -123,18 +146,24 @@ impl Provisioning {
             }),
             None => json!({}),
         };
-        post(&self.instance_url, &relative_url, &self.pt, body)
-            .map_err(|e| match e {
-                RequestError::Status(401, _) => InitProvisioningError::InvalidProvisioningToken,
-                RequestError::Status(423, _) => {
-                    log_workspace_disabled_error();
-                    InitProvisioningError::WorkspaceDisabled
-                }
-                _ => InitProvisioningError::Other(e.into()),
-            })?
-            .into_json()
-            .context("Failed deserializing response from JSON")
-            .map_err(Into::into)
+        post(
+            &self.instance_url,
+            &relative_url,
+            &self.pt,
+            body,
+            &self.http_config,
+        )
+        .map_err(|e| match e {
+            RequestError::Status(401, _) => InitProvisioningError::InvalidProvisioningToken,
+            RequestError::Status(423, _) => {
+                log_workspace_disabled_error();
+                InitProvisioningError::WorkspaceDisabled
+            }
+            _ => InitProvisioningError::Other(e.into()),
+        })?
+        .into_json()
+        .context("Failed deserializing response from JSON")
+        .map_err(Into::into)
     }
 
     pub fn complete(&mut self, operation_id: &str) -> Result<RegistrationToken, CompletionError> {
@@ -143,7 +172,13 @@ impl Provisioning {
             "provisioningOperationId": operation_id,
         });
 
-        match put(&self.instance_url, &relative_url, &self.pt, data) {
+        match put(
+            &self.instance_url,
+            &relative_url,
+            &self.pt,
+            data,
+            &self.http_config,
+        ) {
             Ok(response) => {
                 if response.status() == 202 {
                     Err(CompletionError::NotReady)
@@ -176,13 +211,14 @@ pub fn refresh(
     instance_url: &Uri,
     pt: &ProvisioningToken,
     rt: &RegistrationToken,
+    http_config: &HttpConfig,
 ) -> Result<RegistrationToken, RefreshError> {
     let relative_url = Uri::from_static("/devices/registration-tokens/refresh");
     let data = json!({
         "registrationToken": rt.token,
     });
 
-    put(instance_url, &relative_url, &pt.token, data)
+    put(instance_url, &relative_url, &pt.token, data, http_config)
         .map_err(|e| match e {
             RequestError::Status(423, _) => {
                 log_workspace_disabled_error();
@@ -207,6 +243,19 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn unreachable_instance_is_network_error() {
+        let pt = ProvisioningToken {
+            token: String::from("token"),
+        };
+        // Nothing listens on this port, so the connection is refused
+        let mut provisioning = Provisioning::new(Uri::from_static("https://127.0.0.1:1"), pt);
+
+        let error = provisioning.init().unwrap_err();
+        assert!(error.is_network());
+        assert!(!InitProvisioningError::InvalidProvisioningToken.is_network());
+    }
+
     #[test]
     #[ignore]
     fn provisioning() {
@@ -275,7 +324,8 @@ mod tests {
             expiration: result.expiration,
         };
 
-        let res = register(&instance_uri, &rt).expect("Unable to register device.");
+        let res = register(&instance_uri, &rt, &HttpConfig::default())
+            .expect("Unable to register device.");
         println!("{res:#?}");
     }
 
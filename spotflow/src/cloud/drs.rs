@@ -5,7 +5,7 @@ use serde::Deserialize;
 use serde_json::json;
 use thiserror::Error;
 
-use super::api_core::{put, RequestError};
+use super::api_core::{put, HttpConfig, RequestError};
 use super::dps::RegistrationToken;
 use super::duration_wrapper::DurationWrapper;
 use super::log_workspace_disabled_error;
@@ -94,13 +94,14 @@ impl RegistrationResponse {
 pub fn register(
     instance_url: &Uri,
     rt: &RegistrationToken,
+    http_config: &HttpConfig,
 ) -> Result<RegistrationResponse, RegistrationError> {
     let relative_url = Uri::from_static("/devices/register");
     let data = json!({
         "connectionStringType": "SharedAccessSignature",
     });
 
-    put(instance_url, &relative_url, &rt.token, data)
+    put(instance_url, &relative_url, &rt.token, data, http_config)
         .map_err(|e| match e {
             RequestError::Status(401, _) => RegistrationError::InvalidRegistrationToken,
             RequestError::Status(423, _) => {
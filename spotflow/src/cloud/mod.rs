@@ -3,8 +3,10 @@ pub mod dps;
 pub mod drs;
 mod duration_wrapper;
 
+pub(crate) use api_core::{client_certificate_connector, HttpConfig, RequestError};
+
 fn log_workspace_disabled_error() {
-    log::error!(
+    error!(
         "Workspace is disabled, no data can be sent to the Platform now. \
         Enable the Workspace or connect again with a Provisioning Token for an enabled Workspace."
     );
@@ -1,7 +1,12 @@
 use std::panic::RefUnwindSafe;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+#[cfg(feature = "cbor")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tokio::sync::watch;
 
 /// A wrapper of [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties).
 #[derive(Clone, Debug)]
@@ -12,10 +17,238 @@ pub struct DesiredProperties {
     pub values: String,
 }
 
+impl DesiredProperties {
+    /// Deserialize the values of the individual properties into `T`, for example, the configuration struct of the application.
+    ///
+    /// Fails with the description of the problem, such as a missing field, if the values don't match `T`.
+    pub fn values_as<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_str(&self.values).with_context(|| {
+            format!(
+                "Unable to deserialize version {} of the Desired Properties into {}",
+                self.version,
+                std::any::type_name::<T>()
+            )
+        })
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl DesiredProperties {
+    /// Get the values of the individual properties encoded in CBOR instead of JSON.
+    pub fn as_cbor(&self) -> Result<Vec<u8>> {
+        json_to_cbor(&self.values)
+    }
+}
+
 /// Handles updates of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties).
 pub trait DesiredPropertiesUpdatedCallback: Send + Sync + RefUnwindSafe {
     /// Handle the updated version of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties).
     fn properties_updated(&self, properties: DesiredProperties) -> Result<()>;
+
+    /// Handle the updated version of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// together with the paths of the properties that were added, removed, or changed since the previous version.
+    ///
+    /// The names of nested properties in the paths are separated by dots, for example, `serial.baudRate`. When the
+    /// Desired Properties are received for the first time, the paths of all the top-level properties are included.
+    /// The default implementation ignores the paths and calls [`DesiredPropertiesUpdatedCallback::properties_updated`].
+    fn properties_updated_with_changes(
+        &self,
+        properties: DesiredProperties,
+        changed_paths: Vec<String>,
+    ) -> Result<()> {
+        _ = changed_paths;
+        self.properties_updated(properties)
+    }
+}
+
+/// A gap between the version of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+/// known to the [Device](https://docs.spotflow.io/connect-devices/#device) and the version of a received patch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DesiredPropertiesGap {
+    /// The version of the Desired Properties known to the Device.
+    pub current_version: u64,
+    /// The version of the received patch, which can't be applied.
+    pub patch_version: u64,
+}
+
+/// Observes the gaps in the versions of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+/// patches, for example, for connectivity diagnostics.
+pub trait DesiredPropertiesGapCallback: Send + Sync + RefUnwindSafe {
+    /// Handle a patch that can't be applied because of the gap in versions. The Device SDK then requests the whole
+    /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) regardless of this callback.
+    fn gap_detected(&self, gap: DesiredPropertiesGap);
+}
+
+/// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) that were refused because they
+/// exceed the limit set by [`DeviceClientBuilder::with_max_desired_properties_bytes`](crate::DeviceClientBuilder::with_max_desired_properties_bytes).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DesiredPropertiesTooLarge {
+    /// The version of the refused Desired Properties.
+    pub version: u64,
+    /// The size of the refused Desired Properties serialized in JSON, in bytes.
+    pub size: usize,
+    /// The maximum allowed size, in bytes.
+    pub limit: usize,
+}
+
+/// Observes the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) that were refused
+/// because they're too large, for example, to report a misconfigured Device.
+pub trait DesiredPropertiesTooLargeCallback: Send + Sync + RefUnwindSafe {
+    /// Handle the Desired Properties that weren't applied because they're too large. The Device keeps the previous
+    /// version of the Desired Properties.
+    fn too_large(&self, refused: DesiredPropertiesTooLarge);
+}
+
+/// Observes the updates of the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
+/// that were delivered to the Platform, for example, to confirm that the Device applied a configuration.
+pub trait ReportedPropertiesSentCallback: Send + Sync + RefUnwindSafe {
+    /// Handle an update of the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties) that
+    /// the Platform confirmed. The `properties` are the JSON document passed to
+    /// [`DeviceClient::update_reported_properties`](crate::DeviceClient::update_reported_properties).
+    fn reported_properties_sent(&self, properties: &str);
+}
+
+/// A builder of the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties) document
+/// that is passed to [`DeviceClient::update_reported_properties`](crate::DeviceClient::update_reported_properties).
+///
+/// The properties are edited by their paths, where the names of nested properties are separated by dots, for example,
+/// `serial.baudRate`. Each value is set as a JSON merge patch, so setting a nested property creates the objects on
+/// its path. Removing a property that doesn't exist has no effect. Because
+/// [`DeviceClient::update_reported_properties`](crate::DeviceClient::update_reported_properties) replaces the whole
+/// Reported Properties, start from the current ones using [`ReportedPropertiesBuilder::from_json`] and
+/// [`DeviceClient::reported_properties_with_pending_updates`](crate::DeviceClient::reported_properties_with_pending_updates)
+/// to keep the properties you don't edit.
+#[derive(Clone, Debug, Default)]
+pub struct ReportedPropertiesBuilder {
+    properties: Map<String, Value>,
+}
+
+impl ReportedPropertiesBuilder {
+    /// Create a builder of an empty Reported Properties document.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a builder starting from the given Reported Properties encoded in JSON.
+    ///
+    /// Fails if `properties` isn't a valid JSON object.
+    pub fn from_json(properties: &str) -> Result<Self> {
+        let properties = serde_json::from_str(properties)
+            .context("Unable to deserialize Reported Properties from JSON")?;
+        Ok(Self { properties })
+    }
+
+    /// Set the value of the property with the given path.
+    #[must_use]
+    pub fn set(mut self, path: &str, value: Value) -> Self {
+        self.apply(path, value);
+        self
+    }
+
+    /// Remove the property with the given path.
+    #[must_use]
+    pub fn remove(mut self, path: &str) -> Self {
+        // A JSON merge patch would create the missing objects on the path, so the path is followed instead
+        let (parents, name) = path
+            .rsplit_once('.')
+            .map_or((None, path), |(parents, name)| (Some(parents), name));
+
+        let mut object = &mut self.properties;
+        for parent in parents.into_iter().flat_map(|parents| parents.split('.')) {
+            match object.get_mut(parent) {
+                Some(Value::Object(nested)) => object = nested,
+                _ => return self,
+            }
+        }
+        object.remove(name);
+
+        self
+    }
+
+    /// Get the Reported Properties document encoded in JSON.
+    #[must_use]
+    pub fn build(&self) -> String {
+        Value::Object(self.properties.clone()).to_string()
+    }
+
+    fn apply(&mut self, path: &str, value: Value) {
+        let nested = path.rsplit('.').fold(value, |value, name| {
+            let mut object = Map::new();
+            object.insert(name.to_owned(), value);
+            Value::Object(object)
+        });
+
+        let mut properties = Value::Object(std::mem::take(&mut self.properties));
+        json_patch::merge(&mut properties, &nested);
+        if let Value::Object(properties) = properties {
+            self.properties = properties;
+        }
+    }
+}
+
+// Converts a property document from JSON to CBOR
+#[cfg(feature = "cbor")]
+pub(crate) fn json_to_cbor(properties: &str) -> Result<Vec<u8>> {
+    let value: Value =
+        serde_json::from_str(properties).context("Unable to deserialize properties from JSON")?;
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&value, &mut cbor).context("Unable to serialize properties to CBOR")?;
+    Ok(cbor)
+}
+
+// Converts a property document from CBOR to JSON, byte strings are encoded in base64 because JSON doesn't support them
+#[cfg(feature = "cbor")]
+pub(crate) fn cbor_to_json(properties: &[u8]) -> Result<String> {
+    let value: ciborium::Value =
+        ciborium::from_reader(properties).context("Unable to deserialize properties from CBOR")?;
+    Ok(cbor_value_to_json(value)?.to_string())
+}
+
+#[cfg(feature = "cbor")]
+fn cbor_value_to_json(value: ciborium::Value) -> Result<Value> {
+    use anyhow::{anyhow, bail};
+    use ciborium::Value as Cbor;
+
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(value) => Value::Bool(value),
+        Cbor::Integer(value) => {
+            let value = i128::from(value);
+            if let Ok(value) = u64::try_from(value) {
+                Value::from(value)
+            } else {
+                Value::from(
+                    i64::try_from(value)
+                        .map_err(|_| anyhow!("The integer {value} doesn't fit into JSON"))?,
+                )
+            }
+        }
+        Cbor::Float(value) => serde_json::Number::from_f64(value)
+            .map(Value::Number)
+            .ok_or_else(|| anyhow!("The number {value} can't be represented in JSON"))?,
+        Cbor::Text(value) => Value::String(value),
+        Cbor::Bytes(value) => Value::String(STANDARD.encode(value)),
+        Cbor::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(cbor_value_to_json)
+                .collect::<Result<_>>()?,
+        ),
+        Cbor::Map(entries) => {
+            let mut object = Map::new();
+            for (key, value) in entries {
+                let Cbor::Text(key) = key else {
+                    bail!("The names of properties must be text strings");
+                };
+                object.insert(key, cbor_value_to_json(value)?);
+            }
+            Value::Object(object)
+        }
+        // Tags only add meaning to the inner value, which is kept
+        Cbor::Tag(_, value) => cbor_value_to_json(*value)?,
+        _ => bail!("Unsupported CBOR value in properties"),
+    })
 }
 
 #[async_trait]
@@ -23,6 +256,8 @@ pub trait TwinsClient: Send + Sync {
     // Makes the connection update the twins from cloud
     async fn get_twins(&self);
     async fn get_reported_properties(&self) -> Option<String>;
+    // Reported properties with all the updates that have not yet been sent upstream applied
+    async fn get_reported_properties_with_pending_updates(&self) -> Result<Option<String>>;
     async fn set_reported_properties(&self, patch: &str) -> Result<()>;
     async fn patch_reported_properties(&self, patch: &str) -> Result<()>;
     async fn get_desired_properties(&self) -> Result<DesiredProperties>;
@@ -30,5 +265,110 @@ pub trait TwinsClient: Send + Sync {
     async fn desired_properties_changed(&self) -> Result<DesiredProperties>;
     // Whether there are any Reported Properties that have not yet been sent upstream
     async fn pending_reported_properties_updates(&self) -> Result<bool>;
+    // The count of Reported Properties updates that have not yet been sent upstream, updated as it changes
+    async fn subscribe_pending_reported_properties_updates(&self)
+        -> Result<watch::Receiver<usize>>;
     async fn wait_properties_ready(&self) -> Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn build(builder: &ReportedPropertiesBuilder) -> Value {
+        serde_json::from_str(&builder.build()).unwrap()
+    }
+
+    #[test]
+    fn set_nested_properties() {
+        let builder = ReportedPropertiesBuilder::new()
+            .set("firmware", json!("1.2.3"))
+            .set("serial.baudRate", json!(9600))
+            .set("serial.parity", json!("none"));
+
+        assert_eq!(
+            build(&builder),
+            json!({"firmware": "1.2.3", "serial": {"baudRate": 9600, "parity": "none"}})
+        );
+    }
+
+    #[test]
+    fn remove_properties() {
+        let builder = ReportedPropertiesBuilder::from_json(
+            r#"{"firmware": "1.2.3", "serial": {"baudRate": 9600, "parity": "none"}}"#,
+        )
+        .unwrap()
+        .remove("serial.parity")
+        .remove("firmware")
+        .remove("missing.property");
+
+        assert_eq!(build(&builder), json!({"serial": {"baudRate": 9600}}));
+    }
+
+    #[test]
+    fn replace_object_with_value() {
+        let builder = ReportedPropertiesBuilder::from_json(r#"{"serial": {"baudRate": 9600}}"#)
+            .unwrap()
+            .set("serial", json!(false));
+
+        assert_eq!(build(&builder), json!({"serial": false}));
+    }
+
+    #[test]
+    fn deserialize_desired_properties() {
+        #[derive(Debug, serde::Deserialize, PartialEq)]
+        #[serde(rename_all = "camelCase")]
+        struct Serial {
+            baud_rate: u32,
+        }
+
+        let properties = DesiredProperties {
+            version: 3,
+            values: r#"{"serial": {"baudRate": 9600}, "firmware": "1.2.3"}"#.to_owned(),
+        };
+
+        let serial: Map<String, Value> = properties.values_as().unwrap();
+        assert_eq!(
+            serde_json::from_value::<Serial>(serial["serial"].clone()).unwrap(),
+            Serial { baud_rate: 9600 }
+        );
+
+        let error = properties.values_as::<Serial>().unwrap_err();
+        assert!(format!("{error:#}").contains("baudRate"));
+    }
+
+    #[test]
+    fn invalid_json() {
+        assert!(ReportedPropertiesBuilder::from_json("[1, 2]").is_err());
+        assert!(ReportedPropertiesBuilder::from_json("{").is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        let json = r#"{"firmware":"1.2.3","serial":{"baudRate":9600,"enabled":true,"offset":-1.5,"pins":[1,2]}}"#;
+
+        let cbor = json_to_cbor(json).unwrap();
+        let converted: Value = serde_json::from_str(&cbor_to_json(&cbor).unwrap()).unwrap();
+
+        assert_eq!(converted, serde_json::from_str::<Value>(json).unwrap());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_bytes_to_base64() {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(
+            &ciborium::Value::Map(vec![(
+                ciborium::Value::Text("key".to_owned()),
+                ciborium::Value::Bytes(vec![1, 2, 3]),
+            )]),
+            &mut cbor,
+        )
+        .unwrap();
+
+        assert_eq!(cbor_to_json(&cbor).unwrap(), r#"{"key":"AQID"}"#);
+    }
+}
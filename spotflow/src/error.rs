@@ -0,0 +1,107 @@
+use std::error::Error as StdError;
+
+use crate::cloud::{
+    dps::{CompletionError, InitProvisioningError, NoNetworkNoCredentials, RefreshError},
+    drs::RegistrationError,
+    RequestError,
+};
+#[cfg(feature = "c2d")]
+use crate::ingress::c2d::ReceiveTimeout;
+use crate::persistence::sqlite;
+
+/// The category of an error returned by the Device SDK.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The communication with the Platform failed, for example, because the Device is offline.
+    Network,
+    /// A value provided to the Device SDK is invalid.
+    InvalidArgument,
+    /// The [Workspace](https://docs.spotflow.io/connect-devices/#workspace) the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) belongs to is disabled.
+    WorkspaceDisabled,
+    /// Reading from or writing to the local database file failed.
+    Persistence,
+    /// The local database file can't grow because the storage is full. Free some space, for example, by removing
+    /// the pending [Messages](https://docs.spotflow.io/send-data/#message) that aren't needed anymore.
+    StorageFull,
+    /// The [Device](https://docs.spotflow.io/connect-devices/#device) is offline and has no stored credentials, so it can't be provisioned.
+    /// See [`DeviceClientBuilder::with_fail_if_offline`](crate::DeviceClientBuilder::with_fail_if_offline).
+    NoNetworkNoCredentials,
+    /// No Cloud-to-Device Message was received before the given timeout elapsed.
+    Timeout,
+    /// The error doesn't belong to any of the other categories.
+    Other,
+}
+
+impl ErrorKind {
+    /// Get the category of the given error by inspecting the chain of its causes.
+    #[must_use]
+    pub fn of(error: &anyhow::Error) -> ErrorKind {
+        error
+            .chain()
+            .find_map(Self::of_cause)
+            .unwrap_or(ErrorKind::Other)
+    }
+
+    fn of_cause(cause: &(dyn StdError + 'static)) -> Option<ErrorKind> {
+        #[cfg(feature = "c2d")]
+        if cause.is::<ReceiveTimeout>() {
+            return Some(ErrorKind::Timeout);
+        }
+
+        // The transparent `Other` variants hide the wrapped error from the chain, so it's inspected separately
+        let wrapped = if cause.is::<NoNetworkNoCredentials>() {
+            return Some(ErrorKind::NoNetworkNoCredentials);
+        } else if let Some(e) = cause.downcast_ref::<RegistrationError>() {
+            match e {
+                RegistrationError::WorkspaceDisabled => return Some(ErrorKind::WorkspaceDisabled),
+                RegistrationError::InvalidRegistrationToken => return None,
+                RegistrationError::Other(e) => e,
+            }
+        } else if let Some(e) = cause.downcast_ref::<InitProvisioningError>() {
+            match e {
+                InitProvisioningError::WorkspaceDisabled => {
+                    return Some(ErrorKind::WorkspaceDisabled)
+                }
+                InitProvisioningError::InvalidProvisioningToken => {
+                    return Some(ErrorKind::InvalidArgument)
+                }
+                InitProvisioningError::Other(e) => e,
+            }
+        } else if let Some(e) = cause.downcast_ref::<CompletionError>() {
+            match e {
+                CompletionError::WorkspaceDisabled => return Some(ErrorKind::WorkspaceDisabled),
+                CompletionError::NotReady | CompletionError::Closed(_) => return None,
+                CompletionError::Other(e) => e,
+            }
+        } else if let Some(e) = cause.downcast_ref::<RefreshError>() {
+            match e {
+                RefreshError::WorkspaceDisabled => return Some(ErrorKind::WorkspaceDisabled),
+                RefreshError::Other(e) => e,
+            }
+        } else if let Some(e) = cause.downcast_ref::<RequestError>() {
+            match e {
+                RequestError::Status(..) | RequestError::Transport(_) => {
+                    return Some(ErrorKind::Network)
+                }
+                RequestError::Other(e) => e,
+            }
+        } else if cause.is::<ureq::Error>()
+            || cause.is::<ureq::Transport>()
+            || cause.is::<rumqttc::ConnectionError>()
+        {
+            return Some(ErrorKind::Network);
+        } else if let Some(sqlite::Error::StorageFull(_)) = cause.downcast_ref() {
+            return Some(ErrorKind::StorageFull);
+        } else if cause.is::<sqlite::Error>() || cause.is::<sqlx::Error>() {
+            return Some(ErrorKind::Persistence);
+        } else {
+            return None;
+        };
+
+        match ErrorKind::of(wrapped) {
+            ErrorKind::Other => None,
+            kind => Some(kind),
+        }
+    }
+}
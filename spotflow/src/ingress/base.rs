@@ -1,132 +1,378 @@
 use core::str;
+#[cfg(feature = "c2d")]
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
+    io::{BufRead, Read, Write},
     panic::RefUnwindSafe,
     path::Path,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    sync::{Arc, Mutex as StdMutex, PoisonError, RwLock},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     connection::{
-        twins::{DesiredProperties, DesiredPropertiesUpdatedCallback, TwinsClient},
+        twins::{
+            DesiredProperties, DesiredPropertiesGapCallback, DesiredPropertiesUpdatedCallback,
+            TwinsClient,
+        },
         ConnectionImplementation,
     },
-    ProcessSignalsSource,
+    EmptyProcessSignalsSource, ProcessSignalsSource,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use http::Uri;
+use rand::Rng;
+#[cfg(feature = "c2d")]
+use tokio::sync::Mutex;
 use tokio::{
-    runtime::Runtime,
-    sync::{mpsc, watch, Mutex},
+    runtime::Handle,
+    sync::{mpsc, oneshot, watch},
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::cloud::drs::RegistrationResponse;
+use crate::cloud::{dps::ProvisioningToken, drs::RegistrationResponse, HttpConfig};
 use crate::persistence::{
-    self, sqlite::SdkConfiguration, sqlite_channel, CloseOption, CloudToDeviceMessage,
-    ConfigurationStore, DeviceMessage, Producer, Store,
+    self, export,
+    sqlite::{SdkConfiguration, SqliteStore},
+    BatchSummary, CloseOption, CloudToDeviceProducer, ConfigurationStore, DeviceMessage, Producer,
+    Store, TwinsStore,
 };
+#[cfg(feature = "c2d")]
+use crate::persistence::{sqlite_channel, CloudToDeviceMessage};
 
 use crate::iothub::{
-    token_handler::{RegistrationCommand, TokenHandler},
-    twins::IotHubTwinsClient,
-    IotHubConnection,
+    precompress_content,
+    token_handler::{InstanceSwitch, RegistrationCommand, TokenHandler},
+    twins::{DesiredPropertiesLimit, IotHubTwinsClient},
+    IotHubConnection, MqttConfig, ReportedPropertiesSentNotifier, State,
 };
+use crate::utils::clock::Clock;
+use crate::utils::runtime::ClientRuntime;
 
-use super::{c2d::CloudToDeviceMessageGuard, Compression, MessageContext};
+#[cfg(feature = "c2d")]
+use super::c2d::{AckMode, CloudToDeviceMessageGuard, ReceiveTimeout};
+use super::link::{LinkMonitor, LinkQuality};
+#[cfg(feature = "packet-observer")]
+use super::packets::PacketObserverSender;
+use super::throughput::SendRate;
+use super::{
+    builder, events::EventsDesiredPropertiesUpdatedCallback, ChannelConfig, ConnectionStatus,
+    EnqueueReport, EventPublisher, MessageContext, PendingMessagesLoadedCallback, SdkEvent,
+};
 
 pub struct BaseConnection<T: ?Sized + Send + Sync> {
     configuration_store: ConfigurationStore,
-    twins_client: IotHubTwinsClient,
+    // Replaced on each connection, but all the instances share the same Device Twin
+    twins_client: Arc<RwLock<IotHubTwinsClient>>,
     d2c_producer: Producer,
+    #[cfg(feature = "c2d")]
     c2d_consumer: Arc<Mutex<sqlite_channel::Receiver<CloudToDeviceMessage>>>,
+    #[cfg(feature = "c2d")]
     c2d_handler_registered: AtomicBool,
     signals_src: Option<Box<dyn ProcessSignalsSource>>,
-    thread: Option<JoinHandle<()>>,
-    runtime: Runtime,
-    implementation: Option<Box<T>>,
+    runtime: ClientRuntime,
+    session: StdMutex<Option<Session<T>>>,
+    session_factory: SessionFactory<T>,
+    registration: watch::Receiver<Option<RegistrationResponse>>,
+    registration_command_sender: mpsc::UnboundedSender<RegistrationCommand>,
+    http_config: HttpConfig,
+    desired_properties_initialized: watch::Receiver<bool>,
+    max_message_bytes: Option<usize>,
+    clock: Arc<dyn Clock>,
+    link_monitor: Arc<LinkMonitor>,
+    send_rate: Arc<SendRate>,
+    events: EventPublisher,
     cancellation: CancellationToken,
 }
 
+// Creates a new connection to the Platform, reusing the Device Twin of the previous one
+type SessionFactory<T> = Box<dyn Fn(&Producer, IotHubTwinsClient) -> Session<T> + Send + Sync>;
+
+// A single connection to the Platform, everything else is kept when the Device disconnects
+struct Session<T: ?Sized> {
+    implementation: Option<Box<T>>,
+    thread: Option<JoinHandle<()>>,
+    connection_state: watch::Receiver<State>,
+    twins_client: IotHubTwinsClient,
+}
+
+impl<T: ?Sized> Drop for Session<T> {
+    fn drop(&mut self) {
+        debug!("Session is being dropped");
+        drop(self.implementation.take());
+
+        // Join the thread where all async tasks of the session were run
+        // Only the MQTT loop and Sender are blocking the join of the thread, other tasks will be dropped (possibly while they're awaiting) when these two finish and the thread is joined.
+        debug!("Waiting for the execution thread to be joined");
+        crate::utils::thread::join(&mut self.thread);
+
+        debug!("Session is dropped");
+    }
+}
+
 impl<F: Send + Sync> BaseConnection<IotHubConnection<F>> {
     // Startup
     // ================================================================================
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn init_ingress(
         config: SdkConfiguration,
         store_path: &Path,
         method_handler: Option<F>,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
         signals_src: Option<Box<dyn ProcessSignalsSource>>,
         initial_registration_response: Option<RegistrationResponse>,
+        events: EventPublisher,
     ) -> Result<BaseConnection<dyn ConnectionImplementation + Send + Sync>>
     where
         F: Fn(String, &[u8]) -> (i32, Vec<u8>) + RefUnwindSafe + 'static,
     {
-        // One thread is currently not enough, `runtime::Builder::new_current_thread` deadlocks when reconnect example is run.
-        // We also force the number of threads to be at least 2 -- one worker thread plus one thread we spawn ourselves
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(1)
-            .enable_all()
-            .build()
-            .context("Unable to build tokio runtime")?;
+        let rt = if let Some(handle) = config.runtime_handle.clone() {
+            ClientRuntime::Shared(handle)
+        } else {
+            // One thread is currently not enough, `runtime::Builder::new_current_thread` deadlocks when reconnect example is run.
+            // We also force the number of threads to be at least 2 -- at least one worker thread plus one thread we spawn ourselves
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(config.worker_threads)
+                .enable_all()
+                .build()
+                .context("Unable to build tokio runtime")?;
+            ClientRuntime::Owned(rt)
+        };
 
         let cancellation = CancellationToken::new();
 
-        let store = rt.block_on(persistence::create(
-            store_path,
-            &config,
-            cancellation.clone(),
-        ))?;
+        let store = rt.block_on(persistence::create(store_path, &config))?;
+
+        let http_config = HttpConfig {
+            tls_connector: config.tls_connector.clone(),
+            timeout: config.http_timeout,
+            extra_headers: config.extra_http_headers.clone(),
+        };
 
         let (registration_watch, registration_command_sender) = rt.block_on(TokenHandler::init(
             config.instance_url,
+            http_config.clone(),
             config.provisioning_token,
             config.registration_token,
             store.configuration_store.clone(),
             initial_registration_response,
+            config.token_refresh_failed_callback,
+            config.clock,
         ))?;
 
+        // The updates of Desired Properties are published as events even if the user didn't register a callback
+        let desired_properties_updated_callback: Box<dyn DesiredPropertiesUpdatedCallback> =
+            Box::new(EventsDesiredPropertiesUpdatedCallback::new(
+                desired_properties_updated_callback,
+                events.clone(),
+            ));
+
+        #[cfg(feature = "packet-observer")]
+        let packet_observer = config
+            .raw_packet_observer
+            .map(|observer| PacketObserverSender::start(observer, rt.handle()));
+
         Ok(Self::start(
             rt,
             store,
             registration_watch,
             registration_command_sender,
+            http_config,
             method_handler,
-            desired_properties_updated_callback,
+            Some(desired_properties_updated_callback),
+            desired_properties_gap_callback,
             signals_src,
+            config.pending_messages_loaded_callback,
+            config.channel_config,
+            MqttConfig {
+                tls_config: config.mqtt_tls_config,
+                clean_session: config.clean_session,
+                transport: config.transport,
+                max_reconnect_attempts: config.max_reconnect_attempts,
+                publish_timeout: config.publish_timeout,
+                link_monitor: Arc::new(LinkMonitor::default()),
+                send_rate: Arc::new(SendRate::default()),
+                last_will: config.last_will,
+                api_version: config.iothub_api_version,
+                reported_properties_sent: config
+                    .reported_properties_sent_callback
+                    .map(ReportedPropertiesSentNotifier::new),
+                desired_properties_limit: config.max_desired_properties_bytes.map(|max_bytes| {
+                    DesiredPropertiesLimit {
+                        max_bytes,
+                        callback: config.desired_properties_too_large_callback,
+                    }
+                }),
+                #[cfg(feature = "packet-observer")]
+                packet_observer,
+            },
+            config.max_message_bytes,
+            events,
             cancellation,
         ))
     }
 
     #[allow(clippy::too_many_arguments)]
     fn start(
-        rt: Runtime,
+        rt: ClientRuntime,
         store: Store,
         registration_watch: watch::Receiver<Option<RegistrationResponse>>,
         registration_command_sender: mpsc::UnboundedSender<RegistrationCommand>,
+        http_config: HttpConfig,
         method_handler: Option<F>,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
         signals_src: Option<Box<dyn ProcessSignalsSource>>,
+        pending_messages_loaded_callback: Option<Arc<dyn PendingMessagesLoadedCallback>>,
+        channel_config: ChannelConfig,
+        mqtt_config: MqttConfig,
+        max_message_bytes: Option<usize>,
+        events: EventPublisher,
         cancellation: CancellationToken,
     ) -> BaseConnection<dyn ConnectionImplementation + Send + Sync>
     where
         F: Fn(String, &[u8]) -> (i32, Vec<u8>) + RefUnwindSafe + 'static,
     {
+        let registration = registration_watch.clone();
+        let command_sender = registration_command_sender.clone();
+        let method_handler = method_handler.map(Arc::new);
+        let clock = store.store.clock().clone();
+        let link_monitor = mqtt_config.link_monitor.clone();
+        let send_rate = mqtt_config.send_rate.clone();
+
+        // The first session creates the Device Twin, the following ones only reuse it
+        let first_session = Self::start_session(
+            rt.handle(),
+            &store.d2c_producer,
+            store.store.clone(),
+            store.c2d_producer.clone(),
+            store.twins_store.clone(),
+            registration_watch.clone(),
+            registration_command_sender.clone(),
+            method_handler.clone(),
+            desired_properties_updated_callback,
+            desired_properties_gap_callback,
+            pending_messages_loaded_callback,
+            None,
+            channel_config,
+            mqtt_config.clone(),
+            events.clone(),
+            cancellation.child_token(),
+        );
+
+        let desired_properties_initialized = rt.block_on(
+            first_session
+                .twins_client
+                .subscribe_desired_properties_initialized(),
+        );
+
+        let session_factory: SessionFactory<dyn ConnectionImplementation + Send + Sync> = {
+            let handle = rt.handle().clone();
+            let sqlite = store.store;
+            let c2d_producer = store.c2d_producer;
+            let twins_store = store.twins_store;
+            let events = events.clone();
+            let cancellation = cancellation.clone();
+            Box::new(move |d2c_producer, previous_twins_client| {
+                Self::start_session(
+                    &handle,
+                    d2c_producer,
+                    sqlite.clone(),
+                    c2d_producer.clone(),
+                    twins_store.clone(),
+                    registration_watch.clone(),
+                    registration_command_sender.clone(),
+                    method_handler.clone(),
+                    None,
+                    None,
+                    None,
+                    Some(previous_twins_client),
+                    channel_config,
+                    mqtt_config.clone(),
+                    events.clone(),
+                    cancellation.child_token(),
+                )
+            })
+        };
+
+        BaseConnection {
+            d2c_producer: store.d2c_producer,
+            #[cfg(feature = "c2d")]
+            c2d_consumer: Arc::new(Mutex::new(store.c2d_consumer)),
+            twins_client: Arc::new(RwLock::new(first_session.twins_client.clone())),
+            configuration_store: store.configuration_store,
+            session: StdMutex::new(Some(first_session)),
+            session_factory,
+            registration,
+            registration_command_sender: command_sender,
+            http_config,
+            desired_properties_initialized,
+            max_message_bytes,
+            clock,
+            link_monitor,
+            send_rate,
+            #[cfg(feature = "c2d")]
+            c2d_handler_registered: AtomicBool::new(false),
+            signals_src,
+            runtime: rt,
+            events,
+            cancellation,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn start_session(
+        handle: &Handle,
+        d2c_producer: &Producer,
+        sqlite: SqliteStore,
+        c2d_producer: CloudToDeviceProducer,
+        twins_store: TwinsStore,
+        registration_watch: watch::Receiver<Option<RegistrationResponse>>,
+        registration_command_sender: mpsc::UnboundedSender<RegistrationCommand>,
+        method_handler: Option<Arc<F>>,
+        desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+        pending_messages_loaded_callback: Option<Arc<dyn PendingMessagesLoadedCallback>>,
+        previous_twins_client: Option<IotHubTwinsClient>,
+        channel_config: ChannelConfig,
+        mqtt_config: MqttConfig,
+        events: EventPublisher,
+        cancellation: CancellationToken,
+    ) -> Session<dyn ConnectionImplementation + Send + Sync>
+    where
+        F: Fn(String, &[u8]) -> (i32, Vec<u8>) + RefUnwindSafe + 'static,
+    {
+        let (d2c_consumer, d2c_acknowledger) = {
+            // The task loading the persisted messages must be spawned on the runtime
+            let _guard = handle.enter();
+            d2c_producer.subscribe(
+                channel_config.message_buffer_capacity,
+                pending_messages_loaded_callback,
+                cancellation.clone(),
+            )
+        };
+
         let mut iothub = IotHubConnection::create(
-            rt.handle().clone(),
-            store.store,
-            store.d2c_consumer,
-            store.d2c_acknowledger,
-            store.c2d_producer,
-            store.twins_store,
+            handle.clone(),
+            sqlite,
+            d2c_consumer,
+            d2c_acknowledger,
+            c2d_producer,
+            twins_store,
             registration_watch,
             registration_command_sender,
-            method_handler,
+            method_handler.map(|method_handler| {
+                move |method_name: String, payload: &[u8]| method_handler(method_name, payload)
+            }),
             desired_properties_updated_callback,
-            cancellation.clone(),
+            desired_properties_gap_callback,
+            previous_twins_client,
+            channel_config,
+            mqtt_config,
+            events.clone(),
+            cancellation,
         );
 
         let connection_task = iothub.connect();
@@ -134,41 +380,42 @@ impl<F: Send + Sync> BaseConnection<IotHubConnection<F>> {
         let tokio_thread = thread::Builder::new()
             .name("Tokio MQTT thread".into())
             .spawn({
-                let rt = rt.handle().clone();
+                let rt = handle.clone();
                 move || {
-                    log::debug!("Tokio MQTT thread is starting.");
+                    debug!("Tokio MQTT thread is starting.");
 
                     rt.block_on(async move {
                         let tasks = match connection_task.await {
                             Ok(tasks) => tasks,
                             Err(e) => {
-                                log::error!("Failed setting up connection: {}", e);
+                                error!("Failed setting up connection: {}", e);
+                                events.publish(SdkEvent::Error(format!(
+                                    "Failed setting up connection: {e}"
+                                )));
                                 return;
                             }
                         };
-                        log::debug!("Connection is set up.");
+                        debug!("Connection is set up.");
                         for task in tasks {
                             if let Err(cause) = task.await {
-                                log::error!("Task failed: {:?}", cause);
+                                error!("Task failed: {:?}", cause);
+                                events.publish(SdkEvent::Error(format!("Task failed: {cause}")));
                             }
                         }
                     });
-                    log::debug!("Tokio MQTT thread has finished.");
+                    debug!("Tokio MQTT thread has finished.");
                 }
             })
             .expect("Unable to spawn thread");
 
-        BaseConnection {
-            d2c_producer: store.d2c_producer,
-            c2d_consumer: Arc::new(Mutex::new(store.c2d_consumer)),
-            twins_client: iothub.twins_client().unwrap(),
-            configuration_store: store.configuration_store,
+        let connection_state = iothub.subscribe_to_state();
+        let twins_client = iothub.twins_client().unwrap();
+
+        Session {
             implementation: Some(Box::new(iothub)),
-            c2d_handler_registered: AtomicBool::new(false),
-            signals_src,
             thread: Some(tokio_thread),
-            runtime: rt,
-            cancellation,
+            connection_state,
+            twins_client,
         }
     }
 }
@@ -187,6 +434,16 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
             .block_on(self.configuration_store.load_device_id())
     }
 
+    pub fn device_instance_id(&self) -> Result<String> {
+        self.runtime
+            .block_on(self.configuration_store.load_device_instance_id())
+    }
+
+    pub fn instance_url(&self) -> Result<Uri> {
+        self.runtime
+            .block_on(self.configuration_store.load_instance_url())
+    }
+
     pub fn site_id(&self) -> Option<String> {
         self.configuration_store.site_id().map(str::to_owned)
     }
@@ -195,10 +452,281 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
         self.runtime.block_on(self.d2c_producer.count())
     }
 
+    pub fn set_sending_paused(&self, paused: bool) {
+        self.d2c_producer.set_sending_paused(paused);
+    }
+
+    pub fn is_sending_paused(&self) -> bool {
+        self.d2c_producer.is_sending_paused()
+    }
+
+    pub fn estimated_drain_time(&self) -> Result<Option<Duration>> {
+        let pending = self.pending_messages_count()?;
+        Ok(self.send_rate.drain_time(pending))
+    }
+
+    pub fn link_quality(&self) -> LinkQuality {
+        self.link_monitor.quality()
+    }
+
+    pub fn runtime_handle(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+
+    pub fn clear_pending_messages(&self) -> Result<usize> {
+        self.runtime.block_on(self.d2c_producer.clear())
+    }
+
+    pub fn oldest_pending_message_age(&self) -> Result<Option<Duration>> {
+        let enqueued_at = self
+            .runtime
+            .block_on(self.d2c_producer.oldest_enqueued_at())?;
+
+        // The system clock might have been moved back since the message was enqueued
+        Ok(enqueued_at.map(|enqueued_at| {
+            (self.clock.now_utc() - enqueued_at)
+                .to_std()
+                .unwrap_or_default()
+        }))
+    }
+
+    pub fn pending_batches(&self) -> Result<Vec<BatchSummary>> {
+        self.runtime.block_on(self.d2c_producer.pending_batches())
+    }
+
+    pub fn pending_message_parts(&self, batch_id: &str, message_id: &str) -> Result<usize> {
+        self.runtime.block_on(
+            self.d2c_producer
+                .pending_message_parts(batch_id, message_id),
+        )
+    }
+
+    pub fn batch_progress(&self, batch_id: &str) -> Result<Option<f32>> {
+        self.runtime
+            .block_on(self.d2c_producer.batch_progress(batch_id))
+    }
+
+    pub fn export_pending_messages(&self, mut writer: impl Write) -> Result<usize> {
+        let mut exported = 0;
+        let mut last_id = -1;
+        loop {
+            let messages = self
+                .runtime
+                .block_on(self.d2c_producer.list_after(last_id))?;
+
+            let Some(last) = messages.last() else {
+                break;
+            };
+            last_id = last
+                .id
+                .expect("ID is not empty after being stored in store");
+
+            for msg in &messages {
+                export::write_message(&mut writer, msg)?;
+            }
+            exported += messages.len();
+        }
+
+        writer
+            .flush()
+            .context("Unable to flush the exported messages")?;
+
+        Ok(exported)
+    }
+
+    // All the messages are parsed before storing them so that either all of them are imported or none
+    pub fn import_pending_messages(&self, reader: impl BufRead) -> Result<usize> {
+        let msgs = export::read_messages(reader).collect::<Result<Vec<_>>>()?;
+
+        self.check_not_failed()?;
+        let ids = self
+            .runtime
+            .block_on(self.d2c_producer.add_all_encoded(msgs))?;
+
+        Ok(ids.len())
+    }
+
+    pub fn iot_hub_hostname(&self) -> Result<String> {
+        self.registration
+            .borrow()
+            .as_ref()
+            .map(|registration| registration.iot_hub_host_name.clone())
+            .ok_or_else(|| anyhow!("The Device has not been registered to an IoT Hub yet"))
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        let session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(session) = session.as_ref() else {
+            return ConnectionStatus::Disconnected;
+        };
+
+        let status = ConnectionStatus::from(&*session.connection_state.borrow());
+        status
+    }
+
+    // Returns false if the connection wasn't established before the timeout elapsed
+    pub fn wait_connected(&self, timeout: Duration) -> Result<bool> {
+        let mut connection_state = {
+            let session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+            let Some(session) = session.as_ref() else {
+                bail!("The client is disconnected.");
+            };
+            session.connection_state.clone()
+        };
+
+        self.runtime.block_on(async {
+            let wait = async {
+                loop {
+                    match &*connection_state.borrow() {
+                        State::Ready => return Ok(true),
+                        State::Failed(e) => bail!("The client stopped reconnecting after exceeding the maximum number of reconnect attempts: {e}"),
+                        State::Connecting | State::ConnectionError(_) => {}
+                    }
+
+                    connection_state
+                        .changed()
+                        .await
+                        .context("The connection was closed before it was established")?;
+                }
+            };
+
+            tokio::time::timeout(timeout, wait)
+                .await
+                .unwrap_or(Ok(false))
+        })
+    }
+
+    // Messages enqueued after the client gave up reconnecting would never be sent
+    fn check_not_failed(&self) -> Result<()> {
+        if let ConnectionStatus::Failed(e) = self.connection_status() {
+            bail!("The client stopped reconnecting after exceeding the maximum number of reconnect attempts: {e}");
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_events(&self) -> mpsc::Receiver<SdkEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn connect(&self) -> Result<()> {
+        let mut session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        if session.is_some() {
+            bail!("The client is already connected.");
+        }
+
+        debug!("Reconnecting to the Platform");
+        *session = Some(self.new_session());
+
+        Ok(())
+    }
+
+    fn new_session(&self) -> Session<dyn ConnectionImplementation + Send + Sync> {
+        let new_session = (self.session_factory)(&self.d2c_producer, self.twins_client());
+        *self
+            .twins_client
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = new_session.twins_client.clone();
+        new_session
+    }
+
+    pub fn disconnect(&self) {
+        let session = self
+            .session
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+
+        // Dropping the session closes the connection and waits until its tasks finish
+        if let Some(session) = session {
+            drop(session);
+            self.events.publish(SdkEvent::ConnectionStatusChanged(
+                ConnectionStatus::Disconnected,
+            ));
+        }
+    }
+
+    pub fn reprovision_to_instance(
+        &self,
+        instance_url: Uri,
+        provisioning_token: ProvisioningToken,
+    ) -> Result<()> {
+        let device_id = self
+            .runtime
+            .block_on(self.configuration_store.load_requested_device_id());
+        let signals_src: &dyn ProcessSignalsSource = self
+            .signals_src
+            .as_ref()
+            .map_or(EmptyProcessSignalsSource::instance(), Box::as_ref);
+
+        // The current connection is kept until the Device is provisioned in the new instance
+        let (registration_token, registration_response) = builder::provision(
+            &instance_url,
+            &provisioning_token,
+            device_id.as_deref(),
+            &self.http_config,
+            None,
+            false,
+            None,
+            &|phase| {
+                self.events
+                    .publish(SdkEvent::ProvisioningPhaseChanged(phase));
+                Ok(())
+            },
+            &|_| Ok(()),
+            signals_src,
+        )?;
+
+        let (done_sender, done_receiver) = oneshot::channel();
+        self.switch_instance(
+            InstanceSwitch {
+                instance_url,
+                provisioning_token,
+                registration_token,
+                registration_response,
+                done: done_sender,
+            },
+            done_receiver,
+        )
+    }
+
+    // The client is connected again even if the switch fails, so that it keeps using the current instance
+    fn switch_instance(
+        &self,
+        switch: InstanceSwitch,
+        done: oneshot::Receiver<Result<()>>,
+    ) -> Result<()> {
+        let connected = self
+            .session
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some();
+        self.disconnect();
+
+        let switched = self
+            .registration_command_sender
+            .send(RegistrationCommand::SwitchInstance(Box::new(switch)))
+            .map_err(|_| anyhow!("The registration worker is no longer running"))
+            .and_then(|()| {
+                self.runtime.block_on(done).context(
+                    "The registration worker stopped before switching the Platform instance",
+                )?
+            });
+
+        // The pending messages are kept in the local database file and sent through the new connection
+        let reconnected = if connected { self.connect() } else { Ok(()) };
+
+        switched.and(reconnected)
+    }
+
     // Potentially useful method, but the interface must be stabilized first
     #[allow(dead_code)]
     pub fn connection_error(&mut self) -> Option<Arc<dyn std::error::Error>> {
-        self.implementation.as_mut().and_then(|i| i.error())
+        self.session
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+            .and_then(|session| session.implementation.as_mut())
+            .and_then(|i| i.error())
     }
 
     // Device to Cloud Messages
@@ -211,21 +739,200 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
         message_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        let message = self.new_message(message_context, batch_id, message_id, payload)?;
+
+        self.publish_message(message)
+    }
+
+    pub fn enqueue_message_once(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+        idempotency_key: &str,
+    ) -> Result<bool> {
+        let message = self.new_message(message_context, batch_id, message_id, payload)?;
+        self.check_not_failed()?;
+
+        self.runtime
+            .block_on(self.d2c_producer.add_once(message, idempotency_key))
+    }
+
+    pub fn enqueue_messages(
+        &self,
+        message_context: &MessageContext,
+        messages: Vec<(Option<String>, Option<String>, Vec<u8>)>,
+    ) -> Result<()> {
+        let messages = messages
+            .into_iter()
+            .map(|(batch_id, message_id, payload)| {
+                self.new_message(message_context, batch_id, message_id, payload)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.check_not_failed()?;
+
+        self.runtime
+            .block_on(self.d2c_producer.add_all(messages))
+            .map(|_| ())
+    }
+
+    pub fn enqueue_message_tracked(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<oneshot::Receiver<Result<()>>> {
+        let message = self.new_message(message_context, batch_id, message_id, payload)?;
+        self.check_not_failed()?;
+
+        self.runtime
+            .block_on(self.d2c_producer.add_tracked(message))
+    }
+
+    fn new_message(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<DeviceMessage> {
+        message_context.check_payload_encoding(&payload)?;
+        let (payload, compression) = self.prepare_content(message_context, payload, false)?;
+
+        Ok(DeviceMessage {
+            id: None,
+            site_id: self.site_id(),
+            stream_group: message_context.stream_group.clone(),
+            stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
+            batch_id,
+            message_id,
+            content: payload,
+            close_option: CloseOption::None,
+            compression,
+            batch_slice_id: None,
+            chunk_id: None,
+            expiry: message_context.expiry(self.clock.now_utc()),
+            properties: message_context.serialized_properties()?,
+            ordered: message_context.ordered,
+            content_type: message_context.content_type.clone(),
+        })
+    }
+
+    pub fn enqueue_message_from_reader(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        mut reader: impl Read,
+    ) -> Result<()> {
+        let Some(chunk_size) = message_context.chunk_size else {
+            let mut payload = Vec::new();
+            reader
+                .read_to_end(&mut payload)
+                .context("Unable to read the message content")?;
+
+            return self.enqueue_message(message_context, batch_id, message_id, payload);
+        };
+
+        if chunk_size == 0 {
+            bail!("The chunk size must be greater than zero.");
+        }
+        let Some(message_id) = message_id else {
+            bail!("The message ID must be provided when the message is split into chunks.");
+        };
+
+        for chunk_index in 0usize.. {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            reader
+                .by_ref()
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)
+                .context("Unable to read the message content")?;
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            self.enqueue_message_advanced(
+                message_context,
+                batch_id.clone(),
+                None,
+                Some(message_id.clone()),
+                Some(chunk_index.to_string()),
+                chunk,
+            )?;
+        }
+
+        let completion = DeviceMessage {
+            id: None,
+            site_id: self.site_id(),
+            stream_group: message_context.stream_group.clone(),
+            stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
+            batch_id,
+            message_id: Some(message_id),
+            content: Vec::new(),
+            close_option: CloseOption::CloseMessageOnly,
+            compression: persistence::Compression::None,
+            batch_slice_id: None,
+            chunk_id: None,
+            expiry: None,
+            properties: None,
+            // The completion must not overtake the completed Messages
+            ordered: true,
+            content_type: None,
+        };
+
+        self.publish_message(completion)
+    }
+
+    pub fn enqueue_message_with_report(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<EnqueueReport> {
+        message_context.check_payload_encoding(&payload)?;
+        let (payload, compression) = self.prepare_content(message_context, payload, true)?;
+
+        let applied_compression = if matches!(compression, persistence::Compression::None) {
+            None
+        } else {
+            message_context.compression.clone()
+        };
+        let stored_bytes = payload.len();
+
         let message = DeviceMessage {
             id: None,
             site_id: self.site_id(),
             stream_group: message_context.stream_group.clone(),
             stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
             batch_id,
             message_id,
             content: payload,
             close_option: CloseOption::None,
-            compression: Compression::to_persisted_compression(&message_context.compression),
+            compression,
             batch_slice_id: None,
             chunk_id: None,
+            expiry: message_context.expiry(self.clock.now_utc()),
+            properties: message_context.serialized_properties()?,
+            ordered: message_context.ordered,
+            content_type: message_context.content_type.clone(),
         };
 
-        self.publish_message(message)
+        self.check_not_failed()?;
+        let row_id = self.runtime.block_on(self.d2c_producer.add(message))?;
+
+        Ok(EnqueueReport {
+            row_id,
+            applied_compression,
+            stored_bytes,
+        })
     }
 
     pub fn enqueue_message_advanced(
@@ -237,23 +944,65 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
         chunk_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        // A chunk can end in the middle of a multi-byte character so only whole Messages are validated
+        if chunk_id.is_none() {
+            message_context.check_payload_encoding(&payload)?;
+        }
+        let (payload, compression) = self.prepare_content(message_context, payload, false)?;
+
         let message = DeviceMessage {
             id: None,
             site_id: self.site_id(),
             stream_group: message_context.stream_group.clone(),
             stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
             batch_id,
             message_id,
             content: payload,
             close_option: CloseOption::None,
-            compression: Compression::to_persisted_compression(&message_context.compression),
+            compression,
             batch_slice_id,
             chunk_id,
+            expiry: message_context.expiry(self.clock.now_utc()),
+            properties: message_context.serialized_properties()?,
+            ordered: message_context.ordered,
+            content_type: message_context.content_type.clone(),
         };
 
         self.publish_message(message)
     }
 
+    // The payload is compressed before it's stored if its compressed size is needed, so that it's compressed only once
+    // Rejects the messages that would exceed the configured size even after they are compressed
+    fn prepare_content(
+        &self,
+        message_context: &MessageContext,
+        payload: Vec<u8>,
+        precompress: bool,
+    ) -> Result<(Vec<u8>, persistence::Compression)> {
+        let (payload, compression) = message_context.prepare_payload(payload)?;
+
+        let exceeds_max_size = self
+            .max_message_bytes
+            .is_some_and(|max_message_bytes| payload.len() > max_message_bytes);
+        if !precompress && !exceeds_max_size {
+            return Ok((payload, compression));
+        }
+
+        let (payload, compression) = precompress_content(payload, compression)?;
+
+        if let Some(max_message_bytes) = self.max_message_bytes {
+            let sent_bytes = payload.len();
+            if sent_bytes > max_message_bytes {
+                bail!(
+                    "The message has {sent_bytes} bytes after compression, which exceeds the maximum message size of {max_message_bytes} bytes."
+                );
+            }
+        }
+
+        Ok((payload, compression))
+    }
+
     pub fn enqueue_batch_completion(
         &self,
         message_context: &MessageContext,
@@ -264,6 +1013,7 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
             site_id: self.site_id(),
             stream_group: message_context.stream_group.clone(),
             stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
             batch_id: Some(batch_id),
             message_id: None,
             content: Vec::new(),
@@ -271,6 +1021,11 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
             compression: persistence::Compression::None,
             batch_slice_id: None,
             chunk_id: None,
+            expiry: None,
+            properties: None,
+            // The completion must not overtake the completed Messages
+            ordered: true,
+            content_type: None,
         };
 
         self.publish_message(message)
@@ -287,6 +1042,7 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
             site_id: self.site_id(),
             stream_group: message_context.stream_group.clone(),
             stream: message_context.stream.clone(),
+            source_device_id: message_context.source_device_id.clone(),
             batch_id: Some(batch_id),
             message_id: Some(message_id),
             content: Vec::new(),
@@ -294,18 +1050,36 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
             compression: persistence::Compression::None,
             batch_slice_id: None,
             chunk_id: None,
+            expiry: None,
+            properties: None,
+            // The completion must not overtake the completed Messages
+            ordered: true,
+            content_type: None,
         };
 
         self.publish_message(message)
     }
 
     pub fn wait_enqueued_messages_sent(&self) -> Result<()> {
+        self.wait_enqueued_messages_sent_until(None).map(|_| ())
+    }
+
+    // Returns false if the deadline passed before all the messages were sent
+    pub fn wait_enqueued_messages_sent_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_enqueued_messages_sent_until(Instant::now().checked_add(timeout))
+    }
+
+    fn wait_enqueued_messages_sent_until(&self, deadline: Option<Instant>) -> Result<bool> {
         self.runtime.block_on(async {
             loop {
                 let cnt = self.d2c_producer.count().await?;
 
                 if cnt == 0 {
-                    break;
+                    return Ok(true);
+                }
+
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(false);
                 }
 
                 if let Some(signals_src) = &self.signals_src {
@@ -314,9 +1088,7 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
 
                 tokio::time::sleep(Duration::from_millis(200)).await;
             }
-            Ok::<(), anyhow::Error>(())
-        })?;
-        Ok(())
+        })
     }
 
     pub fn send_message(
@@ -351,11 +1123,15 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
     }
 
     fn publish_message(&self, message: DeviceMessage) -> Result<()> {
-        self.runtime.block_on(self.d2c_producer.add(message))
+        self.check_not_failed()?;
+        self.runtime
+            .block_on(self.d2c_producer.add(message))
+            .map(|_| ())
     }
 
     // Cloud to Device Messages
     // --------------------------------------------------------------------------------
+    #[cfg(feature = "c2d")]
     pub fn process_c2d<G>(&self, callback: G) -> Result<()>
     where
         G: Fn(&CloudToDeviceMessage) + Send + 'static,
@@ -384,7 +1160,7 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
                     let msg = match consumer.recv(&None).await {
                         Ok(msg) => msg,
                         Err(e) => {
-                            log::warn!("Processing of C2D messages failed: {:?}", e);
+                            warn!("Processing of C2D messages failed: {:?}", e);
                             // If there is a transient issue a retry might help
                             // If there is a persistent issue let's not retry too aggressively
                             tokio::time::sleep(Duration::from_secs(30)).await;
@@ -395,7 +1171,7 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
                     if let Err(e) = consumer.ack(&msg).await {
                         // TODO add some retrying here, possibly prevent further processing
                         // We cannot remove the message from the store -- this will result in the message being retrieved again in the next iteration and subsequent restarts
-                        log::warn!("Unable to remove C2D message to prevent duplicate processing, it will be processed again: {:?}", e);
+                        warn!("Unable to remove C2D message to prevent duplicate processing, it will be processed again: {:?}", e);
                         tokio::time::sleep(Duration::from_secs(30)).await;
                     }
                 }
@@ -405,13 +1181,19 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
     }
 
     // Checks if a cloud-to-device message can be obtained without blocking
+    #[cfg(feature = "c2d")]
     pub fn pending_c2d(&self) -> Result<usize> {
         self.runtime.block_on(self.c2d_consumer.try_lock()?.count())
     }
 
-    // Gets a cloud-to-device message and returns a guard that acknowledges the message when it is dropped
+    // Gets a cloud-to-device message and returns a guard that acknowledges the message according to the given mode
     // If process_c2d has been called dropping this guard will block indefinetly.
-    pub fn get_c2d(&self, timeout: Duration) -> Result<CloudToDeviceMessageGuard<'_>> {
+    #[cfg(feature = "c2d")]
+    pub fn get_c2d(
+        &self,
+        timeout: Duration,
+        ack_mode: AckMode,
+    ) -> Result<CloudToDeviceMessageGuard<'_>> {
         let msg = self.runtime.block_on(async {
             let cancellation = CancellationToken::new();
 
@@ -425,72 +1207,352 @@ impl BaseConnection<dyn ConnectionImplementation + Send + Sync> {
 
             self.c2d_consumer
                 .try_lock()?
-                .recv(&Some(cancellation))
+                .recv(&Some(cancellation.clone()))
                 .await
+                .map_err(|e| {
+                    // The waiting is cancelled only by the task above once the timeout elapses
+                    if cancellation.is_cancelled() {
+                        e.context(ReceiveTimeout)
+                    } else {
+                        e
+                    }
+                })
         })?;
 
         Ok(CloudToDeviceMessageGuard::new(
             msg,
             self.runtime.handle(),
             self.c2d_consumer.clone(),
+            ack_mode,
         ))
     }
 
+    // Gets a cloud-to-device message only if one has already been received, never blocks waiting for a new one
+    #[cfg(feature = "c2d")]
+    pub fn try_get_c2d(&self, ack_mode: AckMode) -> Result<Option<CloudToDeviceMessageGuard<'_>>> {
+        let msg = self
+            .runtime
+            .block_on(async { self.c2d_consumer.try_lock()?.try_recv().await })?;
+
+        Ok(msg.map(|msg| {
+            CloudToDeviceMessageGuard::new(
+                msg,
+                self.runtime.handle(),
+                self.c2d_consumer.clone(),
+                ack_mode,
+            )
+        }))
+    }
+
+    // Acknowledges the buffered cloud-to-device messages matching the predicate so that they are never delivered
+    #[cfg(feature = "c2d")]
+    pub fn drain_c2d_matching<F>(&self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&CloudToDeviceMessage) -> bool,
+    {
+        self.runtime.block_on(async {
+            self.c2d_consumer
+                .try_lock()?
+                .remove_matching(predicate)
+                .await
+        })
+    }
+
     // Twins
     // --------------------------------------------------------------------------------
 
     // Returns the latest twins
     pub fn desired_properties(&self) -> Result<DesiredProperties> {
         self.runtime
-            .block_on(self.twins_client.get_desired_properties())
+            .block_on(self.twins_client().get_desired_properties())
+    }
+
+    pub fn refresh_twins(&self, wait: bool) -> Result<()> {
+        self.runtime.block_on(self.twins_client().refresh(wait))
+    }
+
+    pub fn desired_properties_initialized(&self) -> bool {
+        *self.desired_properties_initialized.borrow()
     }
 
     pub fn desired_properties_if_newer(&self, version: u64) -> Option<DesiredProperties> {
         self.runtime
-            .block_on(self.twins_client.get_desired_properties_if_newer(version))
+            .block_on(self.twins_client().get_desired_properties_if_newer(version))
+    }
+
+    pub fn wait_desired_property(
+        &self,
+        path: &str,
+        predicate: impl Fn(&serde_json::Value) -> bool,
+        timeout: Duration,
+    ) -> Result<bool> {
+        let twins_client = self.twins_client();
+        let satisfied = |properties: &DesiredProperties| -> Result<bool> {
+            let values: serde_json::Value = serde_json::from_str(&properties.values)
+                .context("Unable to deserialize Desired Properties")?;
+            Ok(path
+                .split('.')
+                .try_fold(&values, |value, name| value.get(name))
+                .is_some_and(&predicate))
+        };
+
+        self.runtime.block_on(async {
+            let wait = async {
+                // Otherwise, the current Desired Properties are checked once they arrive
+                if self.desired_properties_initialized()
+                    && satisfied(&twins_client.get_desired_properties().await?)?
+                {
+                    return Ok(true);
+                }
+
+                loop {
+                    let properties = twins_client.desired_properties_changed().await?;
+                    if satisfied(&properties)? {
+                        return Ok(true);
+                    }
+                }
+            };
+
+            tokio::time::timeout(timeout, wait)
+                .await
+                .unwrap_or(Ok(false))
+        })
     }
 
     pub fn reported_properties(&self) -> Option<String> {
         self.runtime
-            .block_on(self.twins_client.get_reported_properties())
+            .block_on(self.twins_client().get_reported_properties())
+    }
+
+    pub fn reported_properties_with_pending_updates(&self) -> Result<Option<String>> {
+        self.runtime.block_on(
+            self.twins_client()
+                .get_reported_properties_with_pending_updates(),
+        )
     }
 
     pub fn wait_desired_properties_changed(&self) -> Result<DesiredProperties> {
         self.runtime
-            .block_on(self.twins_client.desired_properties_changed())
+            .block_on(self.twins_client().desired_properties_changed())
     }
 
     pub fn update_reported_properties(&self, properties: &str) -> Result<()> {
         self.runtime
-            .block_on(self.twins_client.set_reported_properties(properties))
+            .block_on(self.twins_client().set_reported_properties(properties))
     }
 
     pub fn patch_reported_properties(&self, patch: &str) -> Result<()> {
         self.runtime
-            .block_on(self.twins_client.patch_reported_properties(patch))
+            .block_on(self.twins_client().patch_reported_properties(patch))
     }
 
     pub fn any_pending_reported_properties_updates(&self) -> Result<bool> {
         self.runtime
-            .block_on(self.twins_client.pending_reported_properties_updates())
+            .block_on(self.twins_client().pending_reported_properties_updates())
+    }
+
+    pub fn subscribe_reported_pending(&self) -> Result<watch::Receiver<usize>> {
+        self.runtime.block_on(
+            self.twins_client()
+                .subscribe_pending_reported_properties_updates(),
+        )
     }
 
     pub fn wait_properties_ready(&self) -> Result<()> {
         self.runtime
-            .block_on(self.twins_client.wait_properties_ready())
+            .block_on(self.twins_client().wait_properties_ready())
     }
+
+    pub fn start_periodic_reported_properties<G>(
+        &self,
+        interval: Duration,
+        mut callback: G,
+    ) -> Result<()>
+    where
+        G: FnMut() -> String + Send + 'static,
+    {
+        if interval.is_zero() {
+            bail!("The interval of the periodic reporting must not be zero.");
+        }
+
+        // The task must not hold the connection, otherwise it could be dropped inside the runtime
+        let twins_client = self.twins_client.clone();
+        let cancellation = self.cancellation.clone();
+
+        self.runtime.spawn(async move {
+            loop {
+                tokio::select! {
+                    () = cancellation.cancelled() => {
+                        debug!("Periodic reporting of Reported Properties has stopped.");
+                        return;
+                    },
+                    () = tokio::time::sleep(interval.saturating_add(reporting_jitter(interval))) => {},
+                }
+
+                let properties = callback();
+                let twins_client = twins_client
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+
+                if let Err(e) = twins_client.set_reported_properties(&properties).await {
+                    warn!("Unable to update Reported Properties periodically: {e:?}");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn twins_client(&self) -> IotHubTwinsClient {
+        self.twins_client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl From<&State> for ConnectionStatus {
+    fn from(state: &State) -> Self {
+        match state {
+            State::Connecting => ConnectionStatus::Connecting,
+            State::Ready => ConnectionStatus::Connected,
+            State::ConnectionError(e) => ConnectionStatus::Error(e.to_string()),
+            State::Failed(e) => ConnectionStatus::Failed(e.to_string()),
+        }
+    }
+}
+
+// Random delay of up to a tenth of the interval so that a fleet of Devices started at once doesn't report in sync
+fn reporting_jitter(interval: Duration) -> Duration {
+    rand::thread_rng().gen_range(Duration::ZERO..=interval / 10)
 }
 
 impl<T: ?Sized + Send + Sync> Drop for BaseConnection<T> {
     fn drop(&mut self) {
-        log::debug!("Base connection is being dropped");
-        drop(self.implementation.take());
+        debug!("Base connection is being dropped");
+        drop(
+            self.session
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take(),
+        );
 
-        // Join the thread where all async tasks were run and other processor threads (such as c2d)
-        // Only the MQTT loop and Sender are blocking the join of the main thread, other tasks will be dropped (possibly while they're awaiting) when these two finish and the thread is joined.
-        log::debug!("Waiting for the execution thread to be joined");
-        crate::utils::thread::join(&mut self.thread);
+        // Stop the remaining processing that isn't bound to a single session (such as c2d)
+        self.cancellation.cancel();
+
+        debug!("Base connection is dropped");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cloud::dps::RegistrationToken;
+
+    // The tests only check whether the client is connected, so the session doesn't open any connection
+    fn idle_session(
+        twins_client: IotHubTwinsClient,
+    ) -> Session<dyn ConnectionImplementation + Send + Sync> {
+        Session {
+            implementation: None,
+            thread: None,
+            connection_state: watch::channel(State::Connecting).1,
+            twins_client,
+        }
+    }
+
+    #[test]
+    fn failed_instance_switch_keeps_client_connected() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let store = runtime.block_on(Store::in_memory()).unwrap();
+        let twins_client = runtime.block_on(IotHubTwinsClient::init(
+            store.twins_store.clone(),
+            mpsc::channel(1).0,
+            persistence::sqlite_channel::channel(store.store.clone(), None).0,
+            watch::channel(0).1,
+            None,
+            None,
+            None,
+        ));
+
+        // The registration worker rejects the switch, for example, because the new instance is unreachable
+        let (registration_command_sender, mut registration_commands) = mpsc::unbounded_channel();
+        runtime.spawn(async move {
+            if let Some(RegistrationCommand::SwitchInstance(switch)) =
+                registration_commands.recv().await
+            {
+                _ = switch
+                    .done
+                    .send(Err(anyhow!("The new instance is unreachable")));
+            }
+        });
+
+        let connection: BaseConnection<dyn ConnectionImplementation + Send + Sync> =
+            BaseConnection {
+                configuration_store: store.configuration_store,
+                twins_client: Arc::new(RwLock::new(twins_client.clone())),
+                d2c_producer: store.d2c_producer,
+                #[cfg(feature = "c2d")]
+                c2d_consumer: Arc::new(Mutex::new(store.c2d_consumer)),
+                #[cfg(feature = "c2d")]
+                c2d_handler_registered: AtomicBool::new(false),
+                signals_src: None,
+                session: StdMutex::new(Some(idle_session(twins_client))),
+                session_factory: Box::new(|_, twins_client| idle_session(twins_client)),
+                registration: watch::channel(None).1,
+                registration_command_sender,
+                http_config: HttpConfig::default(),
+                desired_properties_initialized: watch::channel(true).1,
+                max_message_bytes: None,
+                clock: crate::utils::clock::system_clock(),
+                link_monitor: Arc::default(),
+                send_rate: Arc::default(),
+                events: EventPublisher::new(10),
+                cancellation: CancellationToken::new(),
+                runtime: ClientRuntime::Owned(runtime),
+            };
+
+        let registration_response = serde_json::from_value(serde_json::json!({
+            "connectionString": "HostName=hub;DeviceId=workspace:device;SharedAccessSignature=sas",
+            "iotHubHostName": "hub",
+            "connectionStringType": "SharedAccessSignature",
+            "connectionStringExpiration": "2100-01-01T00:00:00Z",
+            "tokenRemainingLifetime": "01:00:00",
+        }))
+        .unwrap();
+        let (done, done_receiver) = oneshot::channel();
+        let switched = connection.switch_instance(
+            InstanceSwitch {
+                instance_url: Uri::from_static("https://unreachable.example.com"),
+                provisioning_token: ProvisioningToken {
+                    token: String::from("provisioning"),
+                },
+                registration_token: RegistrationToken {
+                    token: String::from("registration"),
+                    expiration: None,
+                },
+                registration_response,
+                done,
+            },
+            done_receiver,
+        );
+
+        assert!(switched.is_err());
+        assert!(connection
+            .session
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some());
+    }
 
-        log::debug!("Base connection is dropped");
+    #[test]
+    fn reporting_jitter_is_bounded() {
+        assert_eq!(reporting_jitter(Duration::ZERO), Duration::ZERO);
+        assert!(reporting_jitter(Duration::from_secs(60)) <= Duration::from_secs(6));
+        assert!(reporting_jitter(Duration::MAX) <= Duration::MAX / 10);
     }
 }
@@ -1,28 +1,49 @@
-use crate::connection::twins::DesiredPropertiesUpdatedCallback;
+use crate::connection::twins::{
+    DesiredPropertiesGapCallback, DesiredPropertiesTooLargeCallback,
+    DesiredPropertiesUpdatedCallback, ReportedPropertiesSentCallback,
+};
 use crate::{
-    cloud,
+    cloud::{self, HttpConfig},
     persistence::sqlite::{SdkConfiguration, SdkConfigurationFragment, SqliteStore},
 };
 use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
     panic::RefUnwindSafe,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
+use tokio::runtime::Handle;
 
 use http::Uri;
 
 use crate::cloud::{
     dps::{
-        self, CompletionError, InitProvisioningError, InitProvisioningResponse, Provisioning,
-        ProvisioningOperationClosedReason, ProvisioningToken, RegistrationToken,
+        self, CompletionError, InitProvisioningError, InitProvisioningResponse,
+        NoNetworkNoCredentials, Provisioning, ProvisioningOperationClosedReason, ProvisioningToken,
+        RegistrationToken,
     },
     drs::{RegistrationError, RegistrationResponse},
 };
 
+use crate::iothub::{self, LastWillMessage};
+use crate::logging::{self, LogSink};
+use crate::utils::clock::{self, Clock};
 use crate::{EmptyProcessSignalsSource, ProcessSignalsSource};
 
-use super::DeviceClient;
+#[cfg(feature = "c2d")]
+use super::BufferPolicy;
+#[cfg(feature = "packet-observer")]
+use super::RawPacketObserver;
+use super::{
+    BatchIdGenerator, ChannelConfig, Compression, DeviceClient, EventPublisher, IdGenerators,
+    LastWillQos, MessageIdGenerator, RecoveryPolicy, SdkEvent, TransportMode,
+};
 
 // Defining a super-trait for what traits must the handler implement Fn(...) + Send + RefUnwindSafe + 'static
 pub trait Handler:
@@ -64,12 +85,192 @@ impl From<InitProvisioningResponse> for ProvisioningOperation {
     }
 }
 
+/// The result of [`DeviceClientBuilder::provision_only`].
+#[derive(Clone, Debug)]
+pub struct ProvisioningResult {
+    /// The ID of the [Workspace](https://docs.spotflow.io/manage-access/workspaces/) to which the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) belongs.
+    pub workspace_id: String,
+    /// The [Device ID](https://docs.spotflow.io/connect-devices/#device-id) assigned to the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device).
+    pub device_id: String,
+}
+
+/// The configuration that the [`DeviceClient`] resolved from the options of [`DeviceClientBuilder`] and from the
+/// local database file, see [`DeviceClient::effective_config`].
+///
+/// Its [`Display`](std::fmt::Display) implementation writes it as a JSON object, which is useful for diagnostics.
+#[derive(Clone, Debug, Serialize)]
+pub struct EffectiveConfig {
+    /// The URL of the Platform instance the [Device](https://docs.spotflow.io/connect-devices/#device) is registered in.
+    pub instance_url: String,
+    /// The ID of the [Workspace](https://docs.spotflow.io/manage-access/workspaces/) to which the Device belongs.
+    pub workspace_id: String,
+    /// The [Device ID](https://docs.spotflow.io/connect-devices/#device-id) assigned to the Device.
+    pub device_id: String,
+    /// The Device ID requested in [`DeviceClientBuilder::new`], it differs from `device_id` if it was overridden
+    /// during the approval of the [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation).
+    pub requested_device_id: Option<String>,
+    /// The ID of the Site set by [`DeviceClientBuilder::with_site_id`].
+    pub site_id: Option<String>,
+    /// The host name of the IoT Hub the Device is connected to, `None` if the Device hasn't been registered yet.
+    pub iot_hub_hostname: Option<String>,
+    /// The version of the IoT Hub API set by [`DeviceClientBuilder::with_iothub_api_version`].
+    pub iothub_api_version: String,
+    /// The default compressions of the [Streams](https://docs.spotflow.io/send-data/#stream) set by
+    /// [`DeviceClientBuilder::with_stream_compressions`].
+    pub stream_compressions: BTreeMap<String, Compression>,
+    /// The interval in seconds in which the client checks that the connection to the Platform is alive.
+    pub keep_alive_secs: u64,
+    /// Whether the MQTT connection starts a clean session, see [`DeviceClientBuilder::with_clean_session`].
+    pub clean_session: bool,
+    /// The transport of the MQTT connection set by [`DeviceClientBuilder::with_transport`].
+    pub transport: TransportMode,
+    /// The maximum number of reconnect attempts set by [`DeviceClientBuilder::with_max_reconnect_attempts`].
+    pub max_reconnect_attempts: Option<u32>,
+    /// The time in seconds after which an unacknowledged [Message](https://docs.spotflow.io/send-data/#message) causes a reconnect,
+    /// see [`DeviceClientBuilder::with_publish_timeout`].
+    pub publish_timeout_secs: u64,
+    /// The maximum size of a [Message](https://docs.spotflow.io/send-data/#message) set by
+    /// [`DeviceClientBuilder::with_max_message_bytes`].
+    pub max_message_bytes: Option<usize>,
+    /// The number of worker threads set by [`DeviceClientBuilder::with_worker_threads`].
+    pub worker_threads: usize,
+    /// Whether the client could start before receiving the Device Twin, see [`DeviceClientBuilder::with_offline_start`].
+    pub offline_start: bool,
+}
+
+impl Display for EffectiveConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// The information about how the [`DeviceClient`] obtained its credentials when it was built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StartupInfo {
+    /// Whether [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) was performed.
+    pub provisioned: bool,
+    /// Whether the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token) stored
+    /// in the local database file was reused.
+    pub used_cached_token: bool,
+}
+
 /// Displays the details of the current [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation).
 pub trait ProvisioningOperationDisplayHandler {
     /// Display the details of the current [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation) to the user.
     fn display(&self, provisioning_operation: &ProvisioningOperation) -> Result<()>;
 }
 
+/// A phase of [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProvisioningPhase {
+    /// The [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation) is being initialized.
+    Initializing,
+    /// The [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation) is waiting for the approval.
+    WaitingForApproval,
+    /// The [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation) was approved and
+    /// the [Device](https://docs.spotflow.io/connect-devices/#device) is being registered.
+    Registering,
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) was successfully completed.
+    Done,
+}
+
+/// Handles the progress of [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning).
+pub trait ProvisioningProgressCallback {
+    /// Handle the transition of [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) to a new phase.
+    fn phase_changed(&self, phase: ProvisioningPhase) -> Result<()>;
+}
+
+/// The [Device ID](https://docs.spotflow.io/connect-devices/#device-id) assigned to the
+/// [Device](https://docs.spotflow.io/connect-devices/#device) that differs from the requested one,
+/// see [`DeviceClientBuilder::with_device_id_assigned_callback`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceIdAssignment {
+    /// The Device ID requested in [`DeviceClientBuilder::new`].
+    pub requested: String,
+    /// The Device ID the Device is registered with.
+    pub assigned: String,
+}
+
+/// Handles the [Device IDs](https://docs.spotflow.io/connect-devices/#device-id) that were overridden during
+/// the approval of the [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation).
+pub trait DeviceIdAssignedCallback {
+    /// Handle the Device ID the Device is registered with instead of the requested one.
+    fn device_id_assigned(&self, assignment: &DeviceIdAssignment) -> Result<()>;
+}
+
+/// The state of the [Workspace](https://docs.spotflow.io/connect-devices/#workspace) the
+/// [Device](https://docs.spotflow.io/connect-devices/#device) belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkspaceState {
+    /// The Workspace is enabled and accepts the communication from the Device.
+    Enabled,
+    /// The Workspace was disabled by its administrator, no data can be exchanged with the Platform until it's enabled again.
+    Disabled,
+}
+
+/// Handles the changes of the [Workspace](https://docs.spotflow.io/connect-devices/#workspace) state.
+pub trait WorkspaceStateCallback {
+    /// Handle the detection that the Workspace was disabled, or that it was enabled again after being disabled.
+    fn state_changed(&self, state: WorkspaceState) -> Result<()>;
+}
+
+/// Handles the repeated failures to refresh the credentials the [Device](https://docs.spotflow.io/connect-devices/#device)
+/// uses to connect to the Platform, see [`DeviceClientBuilder::with_token_refresh_failed_callback`].
+pub trait TokenRefreshFailedCallback: Send + Sync {
+    /// Handle the given number of consecutive failed attempts to refresh the credentials. `error` is the cause of the last one.
+    fn refresh_failed(&self, consecutive_failures: usize, error: &anyhow::Error);
+}
+
+/// Handles the [Messages](https://docs.spotflow.io/send-data/#message) that were found pending in the local database file
+/// on startup, see [`DeviceClientBuilder::with_pending_messages_loaded_callback`].
+pub trait PendingMessagesLoadedCallback: Send + Sync {
+    /// Handle the number of Messages that were enqueued but not sent before the client was started.
+    fn pending_messages_loaded(&self, count: usize);
+}
+
+/// A step of the migration of the local database file to a newer schema, see [`DeviceClientBuilder::with_migration_callback`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseMigration {
+    /// The version of the schema before the step.
+    pub from: String,
+    /// The version of the schema after the step.
+    pub to: String,
+}
+
+/// Handles the migrations of the local database file to a newer schema, see [`DeviceClientBuilder::with_migration_callback`].
+pub trait MigrationCallback: Send + Sync {
+    /// Handle a successful step of the migration.
+    fn migrated(&self, migration: &DatabaseMigration);
+}
+
+/// Handles the creation of a new local database file, see [`DeviceClientBuilder::with_database_created_callback`].
+pub trait DatabaseCreatedCallback: Send + Sync {
+    /// Handle the creation of a new local database file on the given path.
+    fn database_created(&self, path: &Path);
+}
+
+/// Transforms the payloads of [Messages](https://docs.spotflow.io/send-data/#message) before they are stored in
+/// the local database file and after they are loaded from it, see [`DeviceClientBuilder::with_payload_codec`].
+pub trait PayloadCodec: Send + Sync {
+    /// Transform the payload of a Message before it's stored in the local database file, for example, encrypt it.
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>>;
+
+    /// Restore the payload of a Message transformed by [`PayloadCodec::encode`] before it's sent to the Platform.
+    fn decode(&self, payload: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Encrypts the credentials stored in the local database file, see [`DeviceClientBuilder::with_credential_encryption`].
+pub trait Cipher: Send + Sync {
+    /// Encrypt a token before it's stored in the local database file.
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypt a token encrypted by [`Cipher::encrypt`] after it's loaded from the local database file.
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
 enum ErrorAction<E> {
     Retry(E),
     Fail(E),
@@ -83,8 +284,48 @@ pub struct DeviceClientBuilder {
     site_id: Option<String>,
     instance: Option<String>,
     display_provisioning_operation_callback: Option<Box<dyn ProvisioningOperationDisplayHandler>>,
+    provisioning_progress_callback: Option<Box<dyn ProvisioningProgressCallback>>,
+    device_id_assigned_callback: Option<Box<dyn DeviceIdAssignedCallback>>,
+    workspace_state_callback: Option<Box<dyn WorkspaceStateCallback>>,
     desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+    desired_properties_gap_callback: Option<Box<dyn DesiredPropertiesGapCallback>>,
+    reported_properties_sent_callback: Option<Arc<dyn ReportedPropertiesSentCallback>>,
+    max_desired_properties_bytes: Option<usize>,
+    desired_properties_too_large_callback: Option<Arc<dyn DesiredPropertiesTooLargeCallback>>,
     signals_src: Option<Box<dyn ProcessSignalsSource>>,
+    channel_config: ChannelConfig,
+    recovery_policy: RecoveryPolicy,
+    stream_compressions: HashMap<String, Compression>,
+    id_generators: IdGenerators,
+    worker_threads: usize,
+    runtime_handle: Option<Handle>,
+    client_certificate: Option<(String, String)>,
+    provisioning_http_timeout: Duration,
+    extra_http_headers: HashMap<String, String>,
+    registration_retry_limit: Option<usize>,
+    fail_if_offline: bool,
+    token_refresh_failed_callback: Option<(usize, Arc<dyn TokenRefreshFailedCallback>)>,
+    pending_messages_loaded_callback: Option<Arc<dyn PendingMessagesLoadedCallback>>,
+    migration_callback: Option<Arc<dyn MigrationCallback>>,
+    database_created_callback: Option<Arc<dyn DatabaseCreatedCallback>>,
+    payload_codec: Option<Arc<dyn PayloadCodec>>,
+    credential_cipher: Option<Arc<dyn Cipher>>,
+    #[cfg(feature = "c2d")]
+    c2d_buffer_policy: BufferPolicy,
+    #[cfg(feature = "packet-observer")]
+    raw_packet_observer: Option<Arc<dyn RawPacketObserver>>,
+    credential_backup_path: Option<PathBuf>,
+    offline_start: bool,
+    clean_session: bool,
+    transport: TransportMode,
+    max_reconnect_attempts: Option<u32>,
+    publish_timeout: Duration,
+    last_will: Option<LastWillMessage>,
+    iothub_api_version: String,
+    max_message_bytes: Option<usize>,
+    log_sink: Option<Arc<dyn LogSink>>,
+    clock: Arc<dyn Clock>,
+    events: EventPublisher,
 }
 
 impl DeviceClientBuilder {
@@ -115,8 +356,48 @@ impl DeviceClientBuilder {
             site_id: None,
             instance: None,
             display_provisioning_operation_callback: None,
+            provisioning_progress_callback: None,
+            device_id_assigned_callback: None,
+            workspace_state_callback: None,
             desired_properties_updated_callback: None,
+            desired_properties_gap_callback: None,
+            max_desired_properties_bytes: None,
+            desired_properties_too_large_callback: None,
+            reported_properties_sent_callback: None,
             signals_src: None,
+            channel_config: ChannelConfig::default(),
+            recovery_policy: RecoveryPolicy::default(),
+            stream_compressions: HashMap::new(),
+            id_generators: IdGenerators::default(),
+            worker_threads: 1,
+            runtime_handle: None,
+            client_certificate: None,
+            provisioning_http_timeout: Duration::from_secs(10),
+            extra_http_headers: HashMap::new(),
+            registration_retry_limit: None,
+            fail_if_offline: false,
+            token_refresh_failed_callback: None,
+            pending_messages_loaded_callback: None,
+            migration_callback: None,
+            database_created_callback: None,
+            payload_codec: None,
+            credential_cipher: None,
+            #[cfg(feature = "c2d")]
+            c2d_buffer_policy: BufferPolicy::default(),
+            #[cfg(feature = "packet-observer")]
+            raw_packet_observer: None,
+            credential_backup_path: None,
+            offline_start: false,
+            clean_session: false,
+            transport: TransportMode::default(),
+            max_reconnect_attempts: None,
+            publish_timeout: iothub::DEFAULT_PUBLISH_TIMEOUT,
+            last_will: None,
+            iothub_api_version: iothub::DEFAULT_API_VERSION.to_owned(),
+            max_message_bytes: None,
+            log_sink: None,
+            clock: clock::system_clock(),
+            events: EventPublisher::new(ChannelConfig::default().internal_channel_capacity),
         }
     }
 
@@ -151,6 +432,50 @@ impl DeviceClientBuilder {
         self
     }
 
+    /// Set the callback that is called whenever [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning)
+    /// performed by [`DeviceClientBuilder::build`] moves to a new [`ProvisioningPhase`].
+    ///
+    /// Unlike [`DeviceClientBuilder::with_display_provisioning_operation_callback`], which displays the verification code,
+    /// this callback is useful to show the overall progress to the user.
+    #[must_use]
+    pub fn with_provisioning_progress_callback(
+        mut self,
+        callback: Box<dyn ProvisioningProgressCallback>,
+    ) -> DeviceClientBuilder {
+        self.provisioning_progress_callback = Some(callback);
+        self
+    }
+
+    /// Set the callback that is called when [`DeviceClientBuilder::build`] finds out that the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) is registered with a different
+    /// [Device ID](https://docs.spotflow.io/connect-devices/#device-id) than the one requested in [`DeviceClientBuilder::new`].
+    ///
+    /// The technician can override the Device ID when approving the
+    /// [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation). The callback is called
+    /// on each build as long as the Device IDs differ, so that you don't have to compare them using [`DeviceClient::device_id`].
+    #[must_use]
+    pub fn with_device_id_assigned_callback(
+        mut self,
+        callback: Box<dyn DeviceIdAssignedCallback>,
+    ) -> DeviceClientBuilder {
+        self.device_id_assigned_callback = Some(callback);
+        self
+    }
+
+    /// Set the callback that is called when [`DeviceClientBuilder::build`] finds out that the
+    /// [Workspace](https://docs.spotflow.io/connect-devices/#workspace) is disabled, and again when it's enabled.
+    ///
+    /// A disabled Workspace requires an action of its administrator, so the callback allows distinguishing it from the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) being temporarily offline.
+    #[must_use]
+    pub fn with_workspace_state_callback(
+        mut self,
+        callback: Box<dyn WorkspaceStateCallback>,
+    ) -> DeviceClientBuilder {
+        self.workspace_state_callback = Some(callback);
+        self
+    }
+
     /// Set the callback that is called right after [`DeviceClientBuilder::build`] with the current version of the
     /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) and then whenever the
     /// [Device](https://docs.spotflow.io/connect-devices/#device) receives their update from the Platform.
@@ -165,6 +490,61 @@ impl DeviceClientBuilder {
         self
     }
 
+    /// Set the callback that is called when a patch of the
+    /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) can't be applied because
+    /// its version doesn't follow the current one. The Device SDK then requests the whole Desired Properties on its own.
+    /// The callback is called in a separate thread, so it doesn't delay the communication with the Platform.
+    #[must_use]
+    pub fn with_desired_properties_gap_callback(
+        mut self,
+        callback: Box<dyn DesiredPropertiesGapCallback>,
+    ) -> DeviceClientBuilder {
+        self.desired_properties_gap_callback = Some(callback);
+        self
+    }
+
+    /// Set the maximum size of the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// serialized in JSON, in bytes.
+    ///
+    /// Use it to protect a Device with little memory from a misconfigured Desired Properties document. When the received
+    /// Desired Properties or the result of applying their patch exceed the limit, the Device SDK logs a warning, calls
+    /// the callback set by [`DeviceClientBuilder::with_desired_properties_too_large_callback`], and keeps the previous
+    /// version of the Desired Properties instead of applying and storing the new one. If the Device has no Desired
+    /// Properties yet, [`DeviceClientBuilder::build`] waits until they fit the limit unless the offline start is enabled.
+    /// The default is [`None`], which means that the size isn't limited. [`DeviceClientBuilder::build`] fails if the
+    /// value is zero.
+    #[must_use]
+    pub fn with_max_desired_properties_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_desired_properties_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Set the callback that is called when the [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// are refused because they exceed the limit set by [`DeviceClientBuilder::with_max_desired_properties_bytes`].
+    /// The callback is called in a separate thread, so it doesn't delay the communication with the Platform.
+    #[must_use]
+    pub fn with_desired_properties_too_large_callback(
+        mut self,
+        callback: Box<dyn DesiredPropertiesTooLargeCallback>,
+    ) -> DeviceClientBuilder {
+        self.desired_properties_too_large_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Set the callback that is called when the Platform confirms an update of the
+    /// [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties).
+    /// Use it to learn that a particular update was delivered without polling
+    /// [`DeviceClient::any_pending_reported_properties_updates`].
+    /// The callback is called in a separate thread, so it doesn't delay the communication with the Platform.
+    #[must_use]
+    pub fn with_reported_properties_sent_callback(
+        mut self,
+        callback: Box<dyn ReportedPropertiesSentCallback>,
+    ) -> DeviceClientBuilder {
+        self.reported_properties_sent_callback = Some(Arc::from(callback));
+        self
+    }
+
     /// Set the source of the system signals that can request the process to stop.
     #[must_use]
     pub fn with_signals_source(mut self, signals_src: Box<dyn ProcessSignalsSource>) -> Self {
@@ -172,6 +552,412 @@ impl DeviceClientBuilder {
         self
     }
 
+    /// Set the capacities of the internal buffers used for the communication with the Platform.
+    ///
+    /// See [`ChannelConfig`] for the default values. [`DeviceClientBuilder::build`] fails if any of the capacities is zero.
+    #[must_use]
+    pub fn with_channel_capacities(mut self, channel_config: ChannelConfig) -> Self {
+        self.events = EventPublisher::new(channel_config.internal_channel_capacity);
+        self.channel_config = channel_config;
+        self
+    }
+
+    /// Set the number of worker threads of the runtime that communicates with the Platform in the background.
+    ///
+    /// The default value is 1, which is enough for most [Devices](https://docs.spotflow.io/connect-devices/#device).
+    /// More threads can help if the [Messages](https://docs.spotflow.io/send-data/#message) are compressed with
+    /// [`Compression::SmallestSize`], but each thread needs its own stack and thus increases the memory usage.
+    /// [`DeviceClientBuilder::build`] fails if the value is zero.
+    #[must_use]
+    pub fn with_worker_threads(mut self, worker_threads: usize) -> Self {
+        self.worker_threads = worker_threads;
+        self
+    }
+
+    /// Run the communication with the Platform on an existing Tokio runtime instead of creating a new one.
+    ///
+    /// Use it to reduce the number of threads when a gateway runs several [`DeviceClient`]s, each of them representing
+    /// a different [Device](https://docs.spotflow.io/connect-devices/#device). Create the runtime once and pass a clone
+    /// of its handle to the builder of each client. Each client still needs its own local database file.
+    ///
+    /// A single client can't represent several Devices, so [`MessageContext`](super::MessageContext) can't select the
+    /// Device that sends a Message. The MQTT connection to the Platform is authenticated as a single Device, which means
+    /// that each client opens its own connection. If the Devices only relay data through the gateway, consider using a
+    /// single client and [`MessageContext::set_source_device_id`](super::MessageContext::set_source_device_id) instead.
+    /// The Messages are then sent as the gateway and only attributed to the source Devices.
+    ///
+    /// The runtime must be multi-threaded with both I/O and time drivers enabled, and it must outlive the client. The
+    /// number of worker threads set by [`DeviceClientBuilder::with_worker_threads`] is ignored. Don't call the blocking
+    /// methods of the client from the tasks of the runtime.
+    #[must_use]
+    pub fn with_runtime_handle(mut self, handle: Handle) -> Self {
+        self.runtime_handle = Some(handle);
+        self
+    }
+
+    /// Set how to handle the local database file if it's corrupted, for example, after an unclean power loss.
+    ///
+    /// The default is [`RecoveryPolicy::Fail`], so that problems with the storage aren't hidden.
+    #[must_use]
+    pub fn with_corruption_recovery(mut self, recovery_policy: RecoveryPolicy) -> Self {
+        self.recovery_policy = recovery_policy;
+        self
+    }
+
+    /// Authenticate the connections to the Platform with a TLS client certificate (mutual TLS).
+    ///
+    /// Both `cert_pem` and `key_pem` must be PEM-encoded, and the private key must be in the PKCS #8 format.
+    /// The certificate is presented both during [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning)
+    /// and when sending and receiving data. [`DeviceClientBuilder::build`] fails if the certificate can't be loaded,
+    /// for example, because the private key doesn't belong to it.
+    #[must_use]
+    pub fn with_client_certificate(mut self, cert_pem: String, key_pem: String) -> Self {
+        self.client_certificate = Some((cert_pem, key_pem));
+        self
+    }
+
+    /// Set the timeout of each HTTP request to the Platform made during
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) and Device registration.
+    ///
+    /// The default value is 10 seconds. [`DeviceClientBuilder::build`] fails if the value is zero.
+    #[must_use]
+    pub fn with_provisioning_http_timeout(mut self, timeout: Duration) -> Self {
+        self.provisioning_http_timeout = timeout;
+        self
+    }
+
+    /// Set additional HTTP headers to include in each HTTP request to the Platform, for example, when the network of the
+    /// Device requires them to let the requests through.
+    ///
+    /// The headers are sent only with the requests made during
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning), Device registration, and
+    /// the refreshing of the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token).
+    /// They aren't sent over the MQTT connection used to exchange [Messages](https://docs.spotflow.io/send-data/#message)
+    /// and Device Twins. [`DeviceClientBuilder::build`] fails if a header name or value is invalid or if a header
+    /// would replace `Authorization` or `Content-Type`, which the Device SDK sets itself.
+    #[must_use]
+    pub fn with_extra_http_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.extra_http_headers = headers;
+        self
+    }
+
+    /// Set how many times to attempt initiating [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning)
+    /// and registering the Device when the requests fail, for example, because the Device is offline.
+    ///
+    /// [`DeviceClientBuilder::build`] fails with an error once the limit is reached. The default is [`None`], which means that
+    /// the attempts are repeated until they succeed. [`DeviceClientBuilder::build`] fails if the value is zero.
+    #[must_use]
+    pub fn with_registration_retry_limit(mut self, retry_limit: Option<usize>) -> Self {
+        self.registration_retry_limit = retry_limit;
+        self
+    }
+
+    /// Set whether [`DeviceClientBuilder::build`] fails right away when the Device is offline and has no stored credentials.
+    ///
+    /// By default, the first attempt to initiate [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning)
+    /// is repeated until it succeeds (or until the limit set by [`DeviceClientBuilder::with_registration_retry_limit`] is reached).
+    /// If `fail_if_offline` is `true` and the local database file doesn't contain a valid
+    /// [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token),
+    /// [`DeviceClientBuilder::build`] fails with an error of the kind [`ErrorKind::NoNetworkNoCredentials`](crate::ErrorKind::NoNetworkNoCredentials)
+    /// as soon as the first attempt fails because of a network error. Other errors, such as an invalid
+    /// [Provisioning Token](https://docs.spotflow.io/connect-devices/#provisioning-token), are handled as usual.
+    #[must_use]
+    pub fn with_fail_if_offline(mut self, fail_if_offline: bool) -> Self {
+        self.fail_if_offline = fail_if_offline;
+        self
+    }
+
+    /// Set the callback that is called when the client fails to refresh its credentials `failure_threshold` times in a row.
+    ///
+    /// The client refreshes the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token) and
+    /// the credentials for the connection to the Platform in the background and retries the failed attempts indefinitely.
+    /// If the failures persist, for example, because the [Device](https://docs.spotflow.io/connect-devices/#device) was
+    /// deleted or its [Provisioning Token](https://docs.spotflow.io/connect-devices/#provisioning-token) was revoked,
+    /// the callback lets the application decide whether to alert the user or to start over with Device Provisioning.
+    /// The callback is called again after each further `failure_threshold` failures until an attempt succeeds.
+    ///
+    /// The attempts also fail while the [Workspace](https://docs.spotflow.io/connect-devices/#workspace) is disabled.
+    /// Use [`ErrorKind::of`](crate::ErrorKind::of) on the error to recognize [`ErrorKind::WorkspaceDisabled`](crate::ErrorKind::WorkspaceDisabled);
+    /// the client doesn't need to be provisioned again in such case, because it recovers on its own once the Workspace is enabled.
+    /// [`DeviceClientBuilder::build`] fails if `failure_threshold` is zero.
+    #[must_use]
+    pub fn with_token_refresh_failed_callback(
+        mut self,
+        failure_threshold: usize,
+        callback: Box<dyn TokenRefreshFailedCallback>,
+    ) -> Self {
+        self.token_refresh_failed_callback = Some((failure_threshold, Arc::from(callback)));
+        self
+    }
+
+    /// Set the callback that is called once after the client is started with the number of
+    /// [Messages](https://docs.spotflow.io/send-data/#message) that were found pending in the local database file.
+    ///
+    /// The Messages were enqueued during the previous runs but not sent yet, so the count shows how much data accumulated
+    /// while the [Device](https://docs.spotflow.io/connect-devices/#device) was offline. The callback is called in a separate
+    /// thread before the first of these Messages is sent, so it doesn't delay sending them.
+    #[must_use]
+    pub fn with_pending_messages_loaded_callback(
+        mut self,
+        callback: Box<dyn PendingMessagesLoadedCallback>,
+    ) -> Self {
+        self.pending_messages_loaded_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Set the path to a file where the credentials of the [Device](https://docs.spotflow.io/connect-devices/#device)
+    /// are backed up, preferably on a different storage than the local database file.
+    ///
+    /// The client copies the credentials and the related configuration to this file each time they change. If the local
+    /// database file is missing or its credentials can't be read, for example, because the file was corrupted, the client
+    /// restores the credentials from the backup and doesn't need to repeat
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning). Only the credentials are restored,
+    /// the [Messages](https://docs.spotflow.io/send-data/#message) that weren't sent yet are lost with the local database file.
+    /// Failures to update the backup are only logged.
+    #[must_use]
+    pub fn with_credential_backup_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.credential_backup_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set the callback that is called when the local database file created by an older version of the Device SDK
+    /// is migrated to the current schema.
+    ///
+    /// The migration may consist of multiple steps, the callback is called after each of them succeeds with the versions
+    /// of the schema before and after the step. The callback is called during [`DeviceClientBuilder::build`], so it should
+    /// return quickly.
+    #[must_use]
+    pub fn with_migration_callback(mut self, callback: Box<dyn MigrationCallback>) -> Self {
+        self.migration_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Set the callback that is called when a new local database file is created instead of opening an existing one.
+    ///
+    /// This happens on the first start of the [Device](https://docs.spotflow.io/connect-devices/#device), after the file
+    /// is deleted, or when a corrupted file is recreated because of [`RecoveryPolicy::RebuildPreservingCredentials`].
+    /// Use it, for example, to detect that the storage of the Device was wiped or tampered with. The callback is called
+    /// during [`DeviceClientBuilder::build`], so it should return quickly.
+    #[must_use]
+    pub fn with_database_created_callback(
+        mut self,
+        callback: Box<dyn DatabaseCreatedCallback>,
+    ) -> Self {
+        self.database_created_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Set the codec that transforms the payloads of [Messages](https://docs.spotflow.io/send-data/#message) before
+    /// they are stored in the local database file and restores them before they are sent to the Platform.
+    ///
+    /// Use it, for example, to encrypt the pending Messages at rest with a library of your choice. By default, the
+    /// payloads are stored unchanged. The compression is applied to the restored payload right before sending, so it
+    /// works the same way regardless of the codec. Only [`Compression::Brotli`], the Messages exceeding
+    /// [`DeviceClientBuilder::with_max_message_bytes`] before compression, and the Messages enqueued by
+    /// [`DeviceClient::enqueue_message_with_report`] are compressed before they're encoded. The local database file
+    /// must be always opened with the same codec, the Messages that can't be decoded are discarded. Exported pending
+    /// Messages keep the encoded payloads, so import them only to a client that uses the same codec.
+    #[must_use]
+    pub fn with_payload_codec(mut self, codec: Box<dyn PayloadCodec>) -> Self {
+        self.payload_codec = Some(Arc::from(codec));
+        self
+    }
+
+    /// Set the cipher that encrypts the [Provisioning Token](https://docs.spotflow.io/connect-devices/#provisioning-token)
+    /// and the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token) stored in the local
+    /// database file.
+    ///
+    /// Unlike [`DeviceClientBuilder::with_payload_codec`], it protects only the credentials, so it doesn't slow down
+    /// storing the [Messages](https://docs.spotflow.io/send-data/#message). By default, the tokens are stored in plain
+    /// text. Tokens stored in plain text by an earlier run are still loaded, and they're encrypted when they're saved
+    /// again, which happens at the latest in [`DeviceClientBuilder::build`]. Once the tokens are encrypted, the local
+    /// database file must be always opened with the same cipher; otherwise, the Device is provisioned again.
+    #[must_use]
+    pub fn with_credential_encryption(mut self, cipher: Box<dyn Cipher>) -> Self {
+        self.credential_cipher = Some(Arc::from(cipher));
+        self
+    }
+
+    /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
+    #[deprecated]
+    #[doc(hidden)]
+    #[must_use]
+    pub fn with_c2d_buffer_policy(mut self, policy: BufferPolicy) -> Self {
+        self.c2d_buffer_policy = policy;
+        self
+    }
+
+    /// Set the observer that is notified about every MQTT packet received from the Platform or sent to it.
+    ///
+    /// This is only a debugging aid for diagnosing protocol issues and it's available only with the `packet-observer` feature.
+    /// The observer is called in a separate thread so that it never blocks the connection. If it can't keep up with the traffic,
+    /// the descriptions of some packets are dropped.
+    #[cfg(feature = "packet-observer")]
+    #[must_use]
+    pub fn with_raw_packet_observer(mut self, observer: Box<dyn RawPacketObserver>) -> Self {
+        self.raw_packet_observer = Some(Arc::from(observer));
+        self
+    }
+
+    /// Set the version of the IoT Hub API that the client uses in the MQTT connection to the Platform.
+    ///
+    /// The default is `2018-06-30`. Change it only to test the compatibility of the Device SDK with other versions.
+    /// The version must be in the form `YYYY-MM-DD`, optionally followed by a suffix such as `-preview`.
+    #[must_use]
+    pub fn with_iothub_api_version(mut self, api_version: String) -> Self {
+        self.iothub_api_version = api_version;
+        self
+    }
+
+    /// Set the sink that receives the diagnostic records of the Device SDK instead of the global logger of the `log` crate.
+    ///
+    /// Use it when the application can't install a global logger for the Device SDK, for example, because it uses
+    /// its own one, or when it needs to separate the records of the Device SDK from its own records.
+    /// The sink is installed when [`DeviceClientBuilder::build`] or [`DeviceClientBuilder::provision_only`] is called and
+    /// receives the records of all the instances of [`DeviceClient`] in the process from then on.
+    #[must_use]
+    pub fn with_log_sink(mut self, log_sink: Box<dyn LogSink>) -> Self {
+        self.log_sink = Some(Arc::from(log_sink));
+        self
+    }
+
+    /// Set whether [`DeviceClientBuilder::build`] can return before the Device Twin is received from the Platform.
+    ///
+    /// By default, [`DeviceClientBuilder::build`] waits until the current
+    /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) are received.
+    /// If this option is enabled and the local database file contains the Desired Properties from a previous run,
+    /// the client is returned immediately with these Desired Properties and the Device Twin is refreshed in the background.
+    /// This speeds up the start on unreliable networks.
+    #[must_use]
+    pub fn with_offline_start(mut self, offline_start: bool) -> Self {
+        self.offline_start = offline_start;
+        self
+    }
+
+    /// Set whether the MQTT connection to the Platform starts a clean session each time the client connects.
+    ///
+    /// The default is `false`, which means that the Platform keeps the session while the Device is disconnected.
+    /// The Device SDK subscribes to all the topics on each connection, so the clean session doesn't affect the subscriptions.
+    /// However, with the clean session, Cloud-to-Device Messages sent while the Device is disconnected are not delivered
+    /// once it connects again, so use it only when you don't rely on them, for example, in test environments.
+    #[must_use]
+    pub fn with_clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// Set the transport of the MQTT connection to the Platform.
+    ///
+    /// The default is [`TransportMode::Mqtt`], which connects to the port 8883. If the network of the Device allows only
+    /// the outbound connections to the port 443, use `TransportMode::WebSocket`, which is available with the `websocket`
+    /// feature. The transport doesn't affect the HTTPS requests made during
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) and Device registration,
+    /// which always use the port 443.
+    #[must_use]
+    pub fn with_transport(mut self, transport: TransportMode) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Set how many times in a row the client tries to reconnect to the Platform before it gives up.
+    ///
+    /// The default is `None`, which means that the client keeps reconnecting indefinitely. Once the limit is exceeded,
+    /// [`DeviceClient::connection_status`] returns [`ConnectionStatus::Failed`](super::ConnectionStatus::Failed) and enqueuing new
+    /// [Messages](https://docs.spotflow.io/send-data/#message) fails instead of storing them in the local database file.
+    /// The count of the attempts is reset each time the connection is established. This is useful for short-lived jobs
+    /// that should rather fail than wait for the connection forever.
+    #[must_use]
+    pub fn with_max_reconnect_attempts(mut self, max_reconnect_attempts: Option<u32>) -> Self {
+        self.max_reconnect_attempts = max_reconnect_attempts;
+        self
+    }
+
+    /// Set how long the client waits for the Platform to acknowledge a [Message](https://docs.spotflow.io/send-data/#message)
+    /// before it considers the connection stuck.
+    ///
+    /// When the oldest unacknowledged Message isn't acknowledged within this time, the client drops the connection, connects again,
+    /// and resends all the unacknowledged Messages. The default is 60 seconds. Set a longer timeout if the Device sends large
+    /// Messages over a slow network.
+    #[must_use]
+    pub fn with_publish_timeout(mut self, publish_timeout: Duration) -> Self {
+        self.publish_timeout = publish_timeout;
+        self
+    }
+
+    /// Set the [Message](https://docs.spotflow.io/send-data/#message) that the Platform sends on behalf of the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) when the connection is lost unexpectedly,
+    /// for example, to track whether the Device is online.
+    ///
+    /// The Message is sent to the given [Stream Group](https://docs.spotflow.io/send-data/#stream-group) and
+    /// [Stream](https://docs.spotflow.io/send-data/#stream) like any other Message. If they are `None`, the defaults are used.
+    /// The Message isn't sent when the client disconnects gracefully, that is, when [`DeviceClient`] is dropped or
+    /// [`DeviceClient::disconnect`] is called. `qos` sets whether the Platform may lose the Message or may deliver it
+    /// more than once, see [`LastWillQos`].
+    #[must_use]
+    pub fn with_last_will(
+        mut self,
+        stream_group: Option<String>,
+        stream: Option<String>,
+        payload: Vec<u8>,
+        qos: LastWillQos,
+    ) -> Self {
+        self.last_will = Some(LastWillMessage {
+            stream_group,
+            stream,
+            payload,
+            qos,
+        });
+        self
+    }
+
+    /// Set the maximum size of a [Message](https://docs.spotflow.io/send-data/#message) in bytes after it's compressed.
+    ///
+    /// The methods enqueueing Messages fail immediately if the Message is larger, instead of storing it to the local
+    /// database file. The default is [`None`], which means that the size isn't limited because the Messages larger
+    /// than 256 KiB are sent using a file upload. [`DeviceClientBuilder::build`] fails if the value is zero.
+    #[must_use]
+    pub fn with_max_message_bytes(mut self, max_message_bytes: Option<usize>) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+
+    /// Set the default compression of [Messages](https://docs.spotflow.io/send-data/#message) sent to the
+    /// [Streams](https://docs.spotflow.io/send-data/#stream) with the given names.
+    ///
+    /// The defaults are applied to the [`MessageContext`](super::MessageContext) instances created by
+    /// [`DeviceClient::create_message_context`].
+    #[must_use]
+    pub fn with_stream_compressions(
+        mut self,
+        stream_compressions: HashMap<String, Compression>,
+    ) -> Self {
+        self.stream_compressions = stream_compressions;
+        self
+    }
+
+    /// Set the generator of the [Message](https://docs.spotflow.io/send-data/#message) IDs that is used
+    /// when a Message is enqueued or sent without `message_id`.
+    ///
+    /// The generated ID is stored together with the Message in the local database file, so it doesn't change
+    /// when the Message is sent again, for example, after reconnecting.
+    #[must_use]
+    pub fn with_message_id_generator(mut self, generator: Box<dyn MessageIdGenerator>) -> Self {
+        self.id_generators.message_id = Some(Arc::from(generator));
+        self
+    }
+
+    /// Set the generator of the [Batch](https://docs.spotflow.io/send-data/#batch) IDs that is used
+    /// when a [Message](https://docs.spotflow.io/send-data/#message) is enqueued or sent without `batch_id`.
+    ///
+    /// The generator is called for each such Message, so it must return the same ID for all the Messages
+    /// that belong to the same Batch. The generated ID is stored together with the Message in the local database file.
+    #[must_use]
+    pub fn with_batch_id_generator(mut self, generator: Box<dyn BatchIdGenerator>) -> Self {
+        self.id_generators.batch_id = Some(Arc::from(generator));
+        self
+    }
+
     /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
     #[deprecated]
     #[doc(hidden)]
@@ -201,76 +987,228 @@ impl DeviceClientBuilder {
         self.build_impl(None::<NoneHandler>)
     }
 
+    /// Perform [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) if needed and store
+    /// the obtained credentials to the local database file without connecting to the Platform to send or receive data.
+    ///
+    /// The method works the same as [`DeviceClientBuilder::build`] in obtaining the credentials. A [`DeviceClient`]
+    /// built later with the same configuration reuses the stored
+    /// [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token).
+    pub fn provision_only(self) -> Result<ProvisioningResult> {
+        let (config, _, _) = self.obtain_configuration()?;
+
+        // Process the communication with SQLite on the current thread
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| anyhow!("Unable to create a tokio single-threaded runtime for saving data to the local database file: {e}"))?;
+
+        runtime.block_on(SqliteStore::init(&self.database_file, &config))?;
+
+        Ok(ProvisioningResult {
+            workspace_id: config.workspace_id,
+            device_id: config.device_id,
+        })
+    }
+
     fn build_impl<F>(self, method_handler: Option<F>) -> Result<DeviceClient>
     where
         F: Handler,
     {
+        let (config, registration_response, startup_info) = self.obtain_configuration()?;
+
+        DeviceClient::new(
+            config,
+            &self.database_file,
+            method_handler,
+            self.desired_properties_updated_callback,
+            self.desired_properties_gap_callback.map(Arc::from),
+            self.signals_src,
+            registration_response,
+            self.stream_compressions,
+            self.id_generators,
+            startup_info,
+            self.events,
+            self.offline_start,
+        )
+    }
+
+    fn obtain_configuration(
+        &self,
+    ) -> Result<(SdkConfiguration, Option<RegistrationResponse>, StartupInfo)> {
+        if let Some(log_sink) = &self.log_sink {
+            logging::set_sink(log_sink.clone());
+        }
+
         // Validate the options
         if self.database_file.as_os_str().is_empty() {
             bail!("The path to the local database file cannot be empty; provide a value.");
         }
 
+        self.channel_config.validate()?;
+
+        #[cfg(feature = "c2d")]
+        if self.c2d_buffer_policy == BufferPolicy::KeepLatest(0) {
+            bail!("The number of kept Cloud-to-Device Messages must be greater than zero.");
+        }
+
+        if self.worker_threads == 0 {
+            bail!("The number of worker threads must be greater than zero.");
+        }
+
+        if self.provisioning_http_timeout.is_zero() {
+            bail!("The provisioning HTTP timeout must be greater than zero.");
+        }
+
+        for (name, value) in &self.extra_http_headers {
+            let header = http::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| anyhow!("The HTTP header name '{name}' is invalid: {e}"))?;
+            if header == http::header::AUTHORIZATION || header == http::header::CONTENT_TYPE {
+                bail!("The HTTP header '{name}' is set by the Device SDK and cannot be replaced.");
+            }
+            http::HeaderValue::from_str(value)
+                .map_err(|e| anyhow!("The value of the HTTP header '{name}' is invalid: {e}"))?;
+        }
+
+        if self.registration_retry_limit == Some(0) {
+            bail!("The registration retry limit must be greater than zero.");
+        }
+
+        if matches!(self.token_refresh_failed_callback, Some((0, _))) {
+            bail!("The token refresh failure threshold must be greater than zero.");
+        }
+
+        if self.max_desired_properties_bytes == Some(0) {
+            bail!("The maximum size of Desired Properties must be greater than zero.");
+        }
+
+        if self.max_message_bytes == Some(0) {
+            bail!("The maximum message size must be greater than zero.");
+        }
+
+        if !is_api_version(&self.iothub_api_version) {
+            bail!(
+                "The IoT Hub API version '{}' must be in the form YYYY-MM-DD, optionally followed by a suffix such as '-preview'.",
+                self.iothub_api_version
+            );
+        }
+
+        let tls_connector = self
+            .client_certificate
+            .as_ref()
+            .map(|(cert_pem, key_pem)| {
+                cloud::client_certificate_connector(cert_pem.as_bytes(), key_pem.as_bytes())
+            })
+            .transpose()?;
+        let mqtt_tls_config = match &self.client_certificate {
+            Some((cert_pem, key_pem)) => Some(iothub::client_certificate_tls_config(
+                cert_pem.as_bytes(),
+                key_pem.as_bytes(),
+            )?),
+            // Unlike native-tls, rustls doesn't load the root certificates of the system by itself
+            #[cfg(feature = "websocket")]
+            None if matches!(self.transport, TransportMode::WebSocket) => {
+                Some(iothub::websocket_tls_config()?)
+            }
+            None => None,
+        };
+
+        let http_config = HttpConfig {
+            tls_connector,
+            timeout: self.provisioning_http_timeout,
+            extra_headers: self.extra_http_headers.clone(),
+        };
+
         // Look up the last stored configuration from the local database file
         let db_config = if self.database_file.exists() {
-            // Process the communication with SQLite on the current thread
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                    .enable_all()
-                    .build()
-                    .map_err(|e| anyhow!("Unable to create a tokio single-threaded runtime for loading data from the local database file: {e}"))?;
-
-            runtime.block_on(SqliteStore::load_available_configuration(
-                &self.database_file,
-            ))
+            load_stored_configuration(&self.database_file, self.credential_cipher.clone())?
         } else {
             SdkConfigurationFragment::default()
         };
 
-        // Compute the URL of the Platform instance
-
-        let instance = if let Some(instance) = &self.instance {
-            // Strip optional protocol prefix
-            instance.strip_prefix("https://").unwrap_or(instance)
-        } else {
-            "api.eu1.spotflow.io"
+        // Restore the credentials from the backup if the local database file lost them
+        let db_config = match &self.credential_backup_path {
+            Some(backup_path) if db_config.registration_token.is_none() && backup_path.exists() => {
+                let backup_config =
+                    load_stored_configuration(backup_path, self.credential_cipher.clone())?;
+                if backup_config.registration_token.is_some() {
+                    info!(
+                        "Restoring the credentials from the backup file on the path '{}'.",
+                        backup_path.to_string_lossy()
+                    );
+                    backup_config
+                } else {
+                    db_config
+                }
+            }
+            _ => db_config,
         };
 
-        log::debug!("Connecting to the Platform instance '{}'", &instance);
-
-        let instance_url = format!("https://{instance}")
-            .parse::<Uri>()
-            .map_err(|e| anyhow!("Unable to parse the Platform instance URL: {e}"))?;
+        let instance_url = instance_url(self.instance.as_deref().unwrap_or("api.eu1.spotflow.io"))?;
 
         let signals_src: &dyn ProcessSignalsSource = self
             .signals_src
             .as_ref()
             .map_or(EmptyProcessSignalsSource::instance(), Box::as_ref);
 
-        let (registration_token, workspace_id, device_id, registration_response) = self
-            .obtain_valid_credentials(
+        let last_workspace_state = Cell::new(None);
+        let report_workspace_state =
+            |state| self.report_workspace_state(&last_workspace_state, state);
+
+        let (registration_token, workspace_id, device_id, registration_response, startup_info) =
+            self.obtain_valid_credentials(
                 db_config,
                 &self.provisioning_token,
                 &instance_url,
+                &http_config,
+                &report_workspace_state,
                 signals_src,
             )?;
 
         signals_src.check_signals()?;
 
-        DeviceClient::new(
-            SdkConfiguration {
-                instance_url,
-                provisioning_token: self.provisioning_token,
-                registration_token,
-                requested_device_id: self.device_id,
-                workspace_id,
-                device_id,
-                site_id: self.site_id,
-            },
-            &self.database_file,
-            method_handler,
-            self.desired_properties_updated_callback,
-            self.signals_src,
-            registration_response,
-        )
+        let config = SdkConfiguration {
+            instance_url,
+            provisioning_token: self.provisioning_token.clone(),
+            registration_token,
+            requested_device_id: self.device_id.clone(),
+            workspace_id,
+            device_id,
+            site_id: self.site_id.clone(),
+            channel_config: self.channel_config,
+            recovery_policy: self.recovery_policy,
+            worker_threads: self.worker_threads,
+            runtime_handle: self.runtime_handle.clone(),
+            tls_connector: http_config.tls_connector,
+            mqtt_tls_config,
+            clean_session: self.clean_session,
+            transport: self.transport,
+            max_reconnect_attempts: self.max_reconnect_attempts,
+            publish_timeout: self.publish_timeout,
+            last_will: self.last_will.clone(),
+            iothub_api_version: self.iothub_api_version.clone(),
+            max_message_bytes: self.max_message_bytes,
+            token_refresh_failed_callback: self.token_refresh_failed_callback.clone(),
+            pending_messages_loaded_callback: self.pending_messages_loaded_callback.clone(),
+            migration_callback: self.migration_callback.clone(),
+            database_created_callback: self.database_created_callback.clone(),
+            payload_codec: self.payload_codec.clone(),
+            credential_cipher: self.credential_cipher.clone(),
+            #[cfg(feature = "c2d")]
+            c2d_buffer_policy: self.c2d_buffer_policy,
+            reported_properties_sent_callback: self.reported_properties_sent_callback.clone(),
+            max_desired_properties_bytes: self.max_desired_properties_bytes,
+            desired_properties_too_large_callback: self
+                .desired_properties_too_large_callback
+                .clone(),
+            credential_backup_path: self.credential_backup_path.clone(),
+            #[cfg(feature = "packet-observer")]
+            raw_packet_observer: self.raw_packet_observer.clone(),
+            http_timeout: http_config.timeout,
+            extra_http_headers: http_config.extra_headers,
+            clock: self.clock.clone(),
+        };
+
+        Ok((config, registration_response, startup_info))
     }
 
     fn obtain_valid_credentials(
@@ -278,6 +1216,8 @@ impl DeviceClientBuilder {
         db_config: SdkConfigurationFragment,
         options_provisioning_token: &ProvisioningToken,
         instance_url: &Uri,
+        http_config: &HttpConfig,
+        report_workspace_state: &dyn Fn(WorkspaceState) -> Result<()>,
         signals_src: &dyn ProcessSignalsSource,
     ) -> Result<
         (
@@ -285,6 +1225,7 @@ impl DeviceClientBuilder {
             String,
             String,
             Option<RegistrationResponse>,
+            StartupInfo,
         ),
         anyhow::Error,
     > {
@@ -304,11 +1245,15 @@ impl DeviceClientBuilder {
                 .token
                 .eq(&options_provisioning_token.token)
                 && db_config.requested_device_id.eq(&self.device_id)
-                && !db_registration_token.is_expired()
+                && !db_registration_token.is_expired(self.clock.now_utc())
             {
                 // Check if the registration token is still valid and optionally update the current Device ID
-                let (is_considered_valid, registration_response) =
-                    register_if_connected(&db_registration_token, instance_url);
+                let (is_considered_valid, registration_response) = register_if_connected(
+                    &db_registration_token,
+                    instance_url,
+                    http_config,
+                    report_workspace_state,
+                )?;
 
                 signals_src.check_signals()?;
 
@@ -321,10 +1266,11 @@ impl DeviceClientBuilder {
                 };
 
                 if is_considered_valid {
+                    self.report_device_id(&device_id)?;
                     if registration_response.is_some() {
-                        log::info!("The Registration Token stored in the local database file is still valid, skipping Device Provisioning.");
+                        info!("The Registration Token stored in the local database file is still valid, skipping Device Provisioning.");
                     } else {
-                        log::info!(
+                        info!(
                             "It wasn't possible to check the validity of the Registration Token stored in the local database file. \
                             It's considered valid, because it hasn't expired yet. Skipping Device Provisioning.");
                     }
@@ -333,19 +1279,34 @@ impl DeviceClientBuilder {
                         workspace_id,
                         device_id,
                         registration_response,
+                        StartupInfo {
+                            provisioned: false,
+                            used_cached_token: true,
+                        },
                     ));
                 }
             }
         }
 
         // Otherwise, perform the device provisioning
-        let (registration_token, registration_response) =
-            self.provision_device(options_provisioning_token, instance_url, signals_src)?;
+        let (registration_token, registration_response) = self.provision_device(
+            options_provisioning_token,
+            instance_url,
+            http_config,
+            report_workspace_state,
+            signals_src,
+        )?;
+        let device_id = registration_response.device_id()?.to_owned();
+        self.report_device_id(&device_id)?;
         Ok((
             registration_token,
             registration_response.workspace_id()?.to_owned(),
-            registration_response.device_id()?.to_owned(),
+            device_id,
             Some(registration_response),
+            StartupInfo {
+                provisioned: true,
+                used_cached_token: false,
+            },
         ))
     }
 
@@ -353,109 +1314,281 @@ impl DeviceClientBuilder {
         &self,
         provisioning_token: &cloud::dps::ProvisioningToken,
         instance_url: &Uri,
+        http_config: &HttpConfig,
+        report_workspace_state: &dyn Fn(WorkspaceState) -> Result<()>,
         signals_src: &dyn ProcessSignalsSource,
     ) -> Result<(RegistrationToken, RegistrationResponse)> {
-        log::info!("Starting device provisioning");
+        provision(
+            instance_url,
+            provisioning_token,
+            self.device_id.as_deref(),
+            http_config,
+            self.registration_retry_limit,
+            self.fail_if_offline,
+            self.display_provisioning_operation_callback.as_deref(),
+            &|phase| self.report_provisioning_phase(phase),
+            report_workspace_state,
+            signals_src,
+        )
+    }
 
-        let mut provisioning =
-            dps::Provisioning::new(instance_url.clone(), provisioning_token.clone());
+    // Only the first detection of a disabled Workspace and the following recovery are reported
+    fn report_workspace_state(
+        &self,
+        last_state: &Cell<Option<WorkspaceState>>,
+        state: WorkspaceState,
+    ) -> Result<()> {
+        let previous_state = last_state.replace(Some(state));
+        let changed = match state {
+            WorkspaceState::Disabled => previous_state != Some(WorkspaceState::Disabled),
+            WorkspaceState::Enabled => previous_state == Some(WorkspaceState::Disabled),
+        };
 
-        if let Some(device_id) = &self.device_id {
-            provisioning.with_device_id(device_id);
+        if !changed {
+            return Ok(());
         }
 
-        loop {
-            let init_response = init_operation(&mut provisioning, signals_src)?;
+        debug!("The Workspace state changed to {:?}", state);
 
-            log::debug!(
-                "Provisioning operation '{}' initialized, displaying details to the user",
-                &init_response.provisioning_operation_id
-            );
+        if let Some(callback) = &self.workspace_state_callback {
+            callback.state_changed(state).map_err(|e| {
+                anyhow!("Error when calling custom callback to report Workspace state: {e}")
+            })?;
+        }
 
-            display_operation_details(
-                &init_response.clone().into(),
-                &self.display_provisioning_operation_callback,
-            )?;
+        Ok(())
+    }
 
-            log::debug!("Waiting for the approval of the provisioning operation");
+    fn report_device_id(&self, device_id: &str) -> Result<()> {
+        let Some(requested) = &self.device_id else {
+            return Ok(());
+        };
+        if requested == device_id {
+            return Ok(());
+        }
 
-            let registration_token =
-                match complete_operation(&mut provisioning, &init_response, signals_src) {
-                    Ok(registration_token) => registration_token,
-                    Err(ErrorAction::Retry(e)) => {
-                        log::warn!("{e}");
-                        continue;
-                    }
-                    Err(ErrorAction::Fail(e)) => {
-                        log::error!("{e}");
-                        return Err(e);
-                    }
-                };
+        info!("The Device is registered with the Device ID '{device_id}' instead of the requested '{requested}'");
 
-            log::debug!("Provisioning operation approved, performing registration");
+        if let Some(callback) = &self.device_id_assigned_callback {
+            let assignment = DeviceIdAssignment {
+                requested: requested.clone(),
+                assigned: device_id.to_owned(),
+            };
+            callback.device_id_assigned(&assignment).map_err(|e| {
+                anyhow!("Error when calling custom callback to report the assigned Device ID: {e}")
+            })?;
+        }
 
-            let registration_response =
-                match register_device(instance_url, &registration_token, signals_src) {
-                    Ok(response) => response,
-                    Err(ErrorAction::Retry(e)) => {
-                        log::warn!("{e}");
-                        continue;
-                    }
-                    Err(ErrorAction::Fail(e)) => {
-                        log::error!("{e}");
-                        return Err(e);
-                    }
-                };
+        Ok(())
+    }
+
+    fn report_provisioning_phase(&self, phase: ProvisioningPhase) -> Result<()> {
+        debug!("Device Provisioning is in phase {:?}", phase);
 
-            log::info!("Device Provisioning was successfully completed");
+        self.events
+            .publish(SdkEvent::ProvisioningPhaseChanged(phase));
 
-            return Ok((registration_token, registration_response));
+        if let Some(callback) = &self.provisioning_progress_callback {
+            callback.phase_changed(phase).map_err(|e| {
+                anyhow!("Error when calling custom callback to report provisioning progress: {e}")
+            })?;
         }
+
+        Ok(())
     }
 }
 
+// Computes the URL of the Platform instance from its host name
+pub(super) fn instance_url(instance: &str) -> Result<Uri> {
+    // Strip optional protocol prefix
+    let instance = instance.strip_prefix("https://").unwrap_or(instance);
+
+    debug!("Connecting to the Platform instance '{}'", &instance);
+
+    format!("https://{instance}")
+        .parse::<Uri>()
+        .map_err(|e| anyhow!("Unable to parse the Platform instance URL: {e}"))
+}
+
+// Performs Device Provisioning, shared by the builder and by moving a running client to another Platform instance
+#[allow(clippy::too_many_arguments)]
+pub(super) fn provision(
+    instance_url: &Uri,
+    provisioning_token: &ProvisioningToken,
+    device_id: Option<&str>,
+    http_config: &HttpConfig,
+    retry_limit: Option<usize>,
+    fail_if_offline: bool,
+    display_callback: Option<&dyn ProvisioningOperationDisplayHandler>,
+    report_provisioning_phase: &dyn Fn(ProvisioningPhase) -> Result<()>,
+    report_workspace_state: &dyn Fn(WorkspaceState) -> Result<()>,
+    signals_src: &dyn ProcessSignalsSource,
+) -> Result<(RegistrationToken, RegistrationResponse)> {
+    info!("Starting device provisioning");
+
+    let mut provisioning = dps::Provisioning::new(instance_url.clone(), provisioning_token.clone());
+
+    if let Some(device_id) = device_id {
+        provisioning.with_device_id(device_id);
+    }
+
+    provisioning.with_http_config(http_config.clone());
+
+    loop {
+        report_provisioning_phase(ProvisioningPhase::Initializing)?;
+
+        let init_response =
+            init_operation(&mut provisioning, retry_limit, fail_if_offline, signals_src)?;
+
+        debug!(
+            "Provisioning operation '{}' initialized, displaying details to the user",
+            &init_response.provisioning_operation_id
+        );
+
+        display_operation_details(&init_response.clone().into(), display_callback)?;
+
+        debug!("Waiting for the approval of the provisioning operation");
+
+        report_provisioning_phase(ProvisioningPhase::WaitingForApproval)?;
+
+        let registration_token =
+            match complete_operation(&mut provisioning, &init_response, signals_src) {
+                Ok(registration_token) => registration_token,
+                Err(ErrorAction::Retry(e)) => {
+                    warn!("{e}");
+                    continue;
+                }
+                Err(ErrorAction::Fail(e)) => {
+                    error!("{e}");
+                    return Err(e);
+                }
+            };
+
+        debug!("Provisioning operation approved, performing registration");
+
+        report_provisioning_phase(ProvisioningPhase::Registering)?;
+
+        let registration_response = match register_device(
+            instance_url,
+            &registration_token,
+            http_config,
+            retry_limit,
+            report_workspace_state,
+            signals_src,
+        ) {
+            Ok(response) => response,
+            Err(ErrorAction::Retry(e)) => {
+                warn!("{e}");
+                continue;
+            }
+            Err(ErrorAction::Fail(e)) => {
+                error!("{e}");
+                return Err(e);
+            }
+        };
+
+        info!("Device Provisioning was successfully completed");
+
+        report_provisioning_phase(ProvisioningPhase::Done)?;
+
+        return Ok((registration_token, registration_response));
+    }
+}
+
+// Checks the form loosely, the Platform rejects the connection if the version doesn't exist
+pub(super) fn load_stored_configuration(
+    path: &Path,
+    cipher: Option<Arc<dyn Cipher>>,
+) -> Result<SdkConfigurationFragment> {
+    // Process the communication with SQLite on the current thread
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| anyhow!("Unable to create a tokio single-threaded runtime for loading data from the local database file: {e}"))?;
+
+    Ok(runtime.block_on(SqliteStore::load_available_configuration(path, cipher)))
+}
+
+fn is_api_version(version: &str) -> bool {
+    let Some(date) = version.get(..10) else {
+        return false;
+    };
+    let suffix = &version[10..];
+
+    let date_valid = date.char_indices().all(|(i, c)| match i {
+        4 | 7 => c == '-',
+        _ => c.is_ascii_digit(),
+    });
+    let suffix_valid = suffix.is_empty()
+        || suffix
+            .strip_prefix('-')
+            .is_some_and(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric()));
+
+    date_valid && suffix_valid
+}
+
 fn register_if_connected(
     db_registration_token: &RegistrationToken,
     instance_url: &Uri,
-) -> (bool, Option<RegistrationResponse>) {
-    match cloud::drs::register(instance_url, db_registration_token) {
-        Ok(response) => (true, Some(response)),
-        Err(RegistrationError::InvalidRegistrationToken) => (false, None),
-        Err(RegistrationError::WorkspaceDisabled) => {
-            log::warn!(
+    http_config: &HttpConfig,
+    report_workspace_state: &dyn Fn(WorkspaceState) -> Result<()>,
+) -> Result<(bool, Option<RegistrationResponse>)> {
+    Ok(
+        match cloud::drs::register(instance_url, db_registration_token, http_config) {
+            Ok(response) => {
+                report_workspace_state(WorkspaceState::Enabled)?;
+                (true, Some(response))
+            }
+            Err(RegistrationError::InvalidRegistrationToken) => (false, None),
+            Err(RegistrationError::WorkspaceDisabled) => {
+                report_workspace_state(WorkspaceState::Disabled)?;
+                warn!(
                 "Unable to check the Registration Token validity because the Workspace is disabled. \
                 Expecting the Registration Token to be valid based on its expiration time.");
-            (true, None)
-        }
-        Err(RegistrationError::Other(e)) => {
-            // We don't want to force another device provisioning just because the Device is temporarily disconnected from the Internet
-            // or there's another transient error.
-            log::warn!(
+                (true, None)
+            }
+            Err(RegistrationError::Other(e)) => {
+                // We don't want to force another device provisioning just because the Device is temporarily disconnected from the Internet
+                // or there's another transient error.
+                warn!(
                 "An attempt to check the Registration Token validity failed because of a different reason than the validity itself. \
                 Expecting the Registration Token to be valid based on its expiration time. \
                 Error: {e}");
-            (true, None)
-        }
-    }
+                (true, None)
+            }
+        },
+    )
 }
 
 fn init_operation(
     provisioning: &mut Provisioning,
+    retry_limit: Option<usize>,
+    fail_if_offline: bool,
     signals_src: &dyn ProcessSignalsSource,
 ) -> Result<InitProvisioningResponse> {
+    let mut attempts = 0;
     let init_response = loop {
         let init_response = provisioning.init();
         match init_response {
             Ok(init_response) => break init_response,
-            Err(InitProvisioningError::InvalidProvisioningToken) => {
-                bail!(
+            Err(e @ InitProvisioningError::InvalidProvisioningToken) => {
+                return Err(anyhow::Error::new(e).context(format!(
                     "Unable to initiate a Provisioning Operation: Invalid Provisioning Token. \
                     Check that your Provisioning Token is valid and that you're connecting to the right Platform instance \
                     (the current instance URL: '{}').",
-                    provisioning.instance_url())
+                    provisioning.instance_url())));
+            }
+            Err(e) if fail_if_offline && attempts == 0 && e.is_network() => {
+                return Err(anyhow::Error::new(e).context(NoNetworkNoCredentials));
             }
             Err(e) => {
-                log::warn!("An attempt to initiate provisioning operation failed: {e}");
+                attempts += 1;
+                if retry_limit.is_some_and(|limit| attempts >= limit) {
+                    let message = format!("Unable to initiate a Provisioning Operation, giving up after {attempts} attempts. Error: {e}");
+                    return Err(anyhow::Error::new(e).context(message));
+                }
+
+                warn!("An attempt to initiate provisioning operation failed: {e}");
 
                 signals_src.check_signals()?;
                 std::thread::sleep(std::time::Duration::from_millis(5000));
@@ -469,7 +1602,7 @@ fn init_operation(
 
 fn display_operation_details(
     provisioning_operation: &ProvisioningOperation,
-    callback: &Option<Box<dyn ProvisioningOperationDisplayHandler>>,
+    callback: Option<&dyn ProvisioningOperationDisplayHandler>,
 ) -> Result<(), anyhow::Error> {
     if let Some(handler) = callback {
         handler.display(provisioning_operation).map_err(|e| {
@@ -522,11 +1655,16 @@ fn complete_operation(
 fn register_device(
     instance_url: &Uri,
     registration_token: &RegistrationToken,
+    http_config: &HttpConfig,
+    retry_limit: Option<usize>,
+    report_workspace_state: &dyn Fn(WorkspaceState) -> Result<()>,
     signals_src: &dyn ProcessSignalsSource,
 ) -> Result<RegistrationResponse, ErrorAction<anyhow::Error>> {
+    let mut attempts = 0;
     loop {
-        match cloud::drs::register(instance_url, registration_token) {
+        let error = match cloud::drs::register(instance_url, registration_token, http_config) {
             Ok(response) => {
+                report_workspace_state(WorkspaceState::Enabled).map_err(ErrorAction::Fail)?;
                 return Ok(response);
             }
             Err(RegistrationError::InvalidRegistrationToken) => {
@@ -535,13 +1673,22 @@ fn register_device(
                 )));
             }
             Err(RegistrationError::WorkspaceDisabled) => {
-                log::warn!("An attempt to register the Device failed because the Workspace is disabled, retrying.");
-            }
-            Err(RegistrationError::Other(e)) => {
-                log::warn!("An attempt to register the Device failed, retrying. Error: {e}");
+                report_workspace_state(WorkspaceState::Disabled).map_err(ErrorAction::Fail)?;
+                anyhow::Error::new(RegistrationError::WorkspaceDisabled)
             }
+            Err(RegistrationError::Other(e)) => e,
+        };
+
+        attempts += 1;
+        if retry_limit.is_some_and(|limit| attempts >= limit) {
+            let message = format!(
+                "Unable to register the Device, giving up after {attempts} attempts. Error: {error}"
+            );
+            return Err(ErrorAction::Fail(error.context(message)));
         }
 
+        warn!("An attempt to register the Device failed, retrying. Error: {error}");
+
         signals_src.check_signals().map_err(ErrorAction::Fail)?;
 
         std::thread::sleep(std::time::Duration::from_millis(5000));
@@ -1,14 +1,54 @@
 use std::{ops::Deref, sync::Arc};
 
 use crate::persistence::sqlite_channel;
+use anyhow::Result;
 use tokio::{runtime::Handle, sync::Mutex};
 
 pub use crate::persistence::CloudToDeviceMessage;
 
+#[derive(Debug, thiserror::Error)]
+#[error("No Cloud-to-Device Message was received before the timeout elapsed.")]
+pub(crate) struct ReceiveTimeout;
+
+/// Determines when a received Cloud-to-Device Message is removed from the local database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AckMode {
+    /// The message is acknowledged when the guard returned with it is dropped.
+    #[default]
+    OnDrop,
+    /// The message is acknowledged only by calling `ack` on the guard returned with it.
+    /// If the guard is dropped without that, the message is delivered again.
+    Manual,
+}
+
+/// Determines how many received Cloud-to-Device Messages are kept in the local database until they are read.
+///
+/// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[doc(hidden)]
+pub enum BufferPolicy {
+    /// All the messages are kept until they are read.
+    #[default]
+    Unbounded,
+    /// Only the given number of the latest messages is kept, the older ones are removed when a new one is received.
+    KeepLatest(usize),
+}
+
+impl BufferPolicy {
+    pub(crate) fn retained(self) -> Option<usize> {
+        match self {
+            BufferPolicy::Unbounded => None,
+            BufferPolicy::KeepLatest(count) => Some(count),
+        }
+    }
+}
+
 pub struct CloudToDeviceMessageGuard<'a> {
     msg: CloudToDeviceMessage,
     runtime: &'a Handle,
     consumer: Arc<Mutex<sqlite_channel::Receiver<CloudToDeviceMessage>>>,
+    ack_mode: AckMode,
+    settled: bool,
 }
 
 impl<'a> CloudToDeviceMessageGuard<'a> {
@@ -16,13 +56,36 @@ impl<'a> CloudToDeviceMessageGuard<'a> {
         msg: CloudToDeviceMessage,
         runtime: &'a Handle,
         consumer: Arc<Mutex<sqlite_channel::Receiver<CloudToDeviceMessage>>>,
+        ack_mode: AckMode,
     ) -> Self {
         CloudToDeviceMessageGuard {
             msg,
             runtime,
             consumer,
+            ack_mode,
+            settled: false,
         }
     }
+
+    /// Remove the message from the local database so that it's not delivered again.
+    pub fn ack(mut self) -> Result<()> {
+        self.settled = true;
+        self.runtime
+            .block_on(async { self.consumer.lock().await.ack(&self.msg).await })
+    }
+
+    /// Keep the message in the local database and deliver it again.
+    ///
+    /// The messages received after this one that haven't been acknowledged yet are delivered again too.
+    pub fn nack(mut self) {
+        self.settled = true;
+        self.release();
+    }
+
+    fn release(&self) {
+        self.runtime
+            .block_on(async { self.consumer.lock().await.release(&self.msg) });
+    }
 }
 
 impl<'a> Deref for CloudToDeviceMessageGuard<'a> {
@@ -35,12 +98,21 @@ impl<'a> Deref for CloudToDeviceMessageGuard<'a> {
 
 impl<'a> Drop for CloudToDeviceMessageGuard<'a> {
     fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+
+        if self.ack_mode == AckMode::Manual {
+            self.release();
+            return;
+        }
+
         let ack_result = self
             .runtime
             .block_on(async { self.consumer.lock().await.ack(&self.msg).await });
 
         if let Err(e) = ack_result {
-            log::warn!(
+            warn!(
                 "Unable to remove message to prevent further processing: {:?}",
                 e
             );
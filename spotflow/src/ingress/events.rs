@@ -0,0 +1,129 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Result;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::connection::twins::{DesiredProperties, DesiredPropertiesUpdatedCallback};
+
+use super::{ConnectionStatus, ProvisioningPhase};
+
+/// An event in the lifecycle of [`DeviceClient`](crate::DeviceClient) received through
+/// [`DeviceClient::subscribe_events`](crate::DeviceClient::subscribe_events).
+#[derive(Clone, Debug)]
+pub enum SdkEvent {
+    /// The state of the connection to the Platform changed.
+    ConnectionStatusChanged(ConnectionStatus),
+    /// The Platform acknowledged the oldest enqueued [Message](https://docs.spotflow.io/send-data/#message).
+    MessageSent,
+    /// The [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) were updated.
+    DesiredPropertiesUpdated(DesiredProperties),
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) moved to a new phase.
+    ProvisioningPhaseChanged(ProvisioningPhase),
+    /// The background thread communicating with the Platform encountered an error.
+    Error(String),
+}
+
+// Distributes the events to all the subscribers without ever blocking the internal tasks
+#[derive(Clone, Debug)]
+pub(crate) struct EventPublisher {
+    inner: Arc<Mutex<Subscribers>>,
+}
+
+#[derive(Debug)]
+struct Subscribers {
+    capacity: usize,
+    senders: Vec<mpsc::Sender<SdkEvent>>,
+    // Events published before the first subscription, they are handed over to the first subscriber
+    pending: Option<VecDeque<SdkEvent>>,
+}
+
+impl EventPublisher {
+    pub(crate) fn new(capacity: usize) -> Self {
+        EventPublisher {
+            inner: Arc::new(Mutex::new(Subscribers {
+                capacity,
+                senders: Vec::new(),
+                pending: Some(VecDeque::new()),
+            })),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<SdkEvent> {
+        let mut subscribers = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let (sender, receiver) = mpsc::channel(subscribers.capacity);
+        if let Some(pending) = subscribers.pending.take() {
+            for event in pending {
+                // The number of pending events is limited by the capacity of the channel
+                _ = sender.try_send(event);
+            }
+        }
+        subscribers.senders.push(sender);
+
+        receiver
+    }
+
+    pub(crate) fn publish(&self, event: SdkEvent) {
+        let mut subscribers = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let capacity = subscribers.capacity;
+
+        if let Some(pending) = &mut subscribers.pending {
+            if pending.len() < capacity {
+                pending.push_back(event);
+            } else {
+                debug!("Dropping event {event:?} because no one subscribed to the events yet");
+            }
+            return;
+        }
+
+        subscribers
+            .senders
+            .retain(|sender| match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(event)) => {
+                    debug!("Dropping event {event:?} because the events channel is full");
+                    true
+                }
+                // The subscriber dropped its receiver
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+}
+
+// Publishes the updates of the Desired Properties in addition to calling the user-provided callback
+pub(crate) struct EventsDesiredPropertiesUpdatedCallback {
+    inner: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+    events: EventPublisher,
+}
+
+impl EventsDesiredPropertiesUpdatedCallback {
+    pub(crate) fn new(
+        inner: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        events: EventPublisher,
+    ) -> Self {
+        EventsDesiredPropertiesUpdatedCallback { inner, events }
+    }
+}
+
+impl DesiredPropertiesUpdatedCallback for EventsDesiredPropertiesUpdatedCallback {
+    fn properties_updated(&self, properties: DesiredProperties) -> Result<()> {
+        self.properties_updated_with_changes(properties, Vec::new())
+    }
+
+    fn properties_updated_with_changes(
+        &self,
+        properties: DesiredProperties,
+        changed_paths: Vec<String>,
+    ) -> Result<()> {
+        self.events
+            .publish(SdkEvent::DesiredPropertiesUpdated(properties.clone()));
+
+        match &self.inner {
+            Some(inner) => inner.properties_updated_with_changes(properties, changed_paths),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+// Only the reconnects in this window count, older ones don't say anything about the current state of the link
+const RECONNECT_WINDOW: Duration = Duration::from_secs(10 * 60);
+// The latency is averaged over this many last acknowledgments
+const LATENCY_SAMPLES: usize = 20;
+
+const DEGRADED_RECONNECTS: usize = 1;
+const POOR_RECONNECTS: usize = 3;
+const DEGRADED_LATENCY: Duration = Duration::from_secs(2);
+const POOR_LATENCY: Duration = Duration::from_secs(10);
+
+/// A coarse assessment of the connection to the Platform, see [`LinkQuality`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkQualityLevel {
+    /// The connection is stable and the [Messages](https://docs.spotflow.io/send-data/#message) are acknowledged quickly.
+    Good,
+    /// The connection was lost recently or the [Messages](https://docs.spotflow.io/send-data/#message) take long
+    /// to be acknowledged.
+    Degraded,
+    /// The connection is repeatedly lost or the [Messages](https://docs.spotflow.io/send-data/#message) take very long
+    /// to be acknowledged.
+    Poor,
+}
+
+/// The quality of the connection to the Platform observed recently, see [`DeviceClient::link_quality`](super::DeviceClient::link_quality).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkQuality {
+    /// The assessment of the connection based on the other values.
+    pub level: LinkQualityLevel,
+    /// The number of times the connection was established again in the last 10 minutes.
+    pub recent_reconnects: usize,
+    /// The average time between publishing a [Message](https://docs.spotflow.io/send-data/#message) and receiving its
+    /// acknowledgment, computed from the last 20 Messages. `None` if no Message was acknowledged yet.
+    pub ack_latency: Option<Duration>,
+}
+
+impl LinkQuality {
+    fn new(recent_reconnects: usize, ack_latency: Option<Duration>) -> Self {
+        let latency = ack_latency.unwrap_or_default();
+        let level = if recent_reconnects >= POOR_RECONNECTS || latency >= POOR_LATENCY {
+            LinkQualityLevel::Poor
+        } else if recent_reconnects >= DEGRADED_RECONNECTS || latency >= DEGRADED_LATENCY {
+            LinkQualityLevel::Degraded
+        } else {
+            LinkQualityLevel::Good
+        };
+
+        LinkQuality {
+            level,
+            recent_reconnects,
+            ack_latency,
+        }
+    }
+}
+
+// Collects the reconnects and the acknowledgment latencies of all the connections of a client
+#[derive(Debug, Default)]
+pub(crate) struct LinkMonitor {
+    samples: Mutex<LinkSamples>,
+}
+
+#[derive(Debug, Default)]
+struct LinkSamples {
+    reconnects: VecDeque<Instant>,
+    ack_latencies: VecDeque<Duration>,
+}
+
+impl LinkSamples {
+    fn forget_old_reconnects(&mut self, now: Instant) {
+        while let Some(reconnect) = self.reconnects.front() {
+            if now.duration_since(*reconnect) <= RECONNECT_WINDOW {
+                break;
+            }
+            self.reconnects.pop_front();
+        }
+    }
+}
+
+impl LinkMonitor {
+    pub(crate) fn reconnected(&self) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.forget_old_reconnects(now);
+        samples.reconnects.push_back(now);
+    }
+
+    pub(crate) fn acknowledged(&self, latency: Duration) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        if samples.ack_latencies.len() == LATENCY_SAMPLES {
+            samples.ack_latencies.pop_front();
+        }
+        samples.ack_latencies.push_back(latency);
+    }
+
+    pub(crate) fn quality(&self) -> LinkQuality {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.forget_old_reconnects(Instant::now());
+
+        let count = samples.ack_latencies.len();
+        let ack_latency = (count > 0).then(|| {
+            // The number of samples is bounded by LATENCY_SAMPLES
+            #[allow(clippy::cast_possible_truncation)]
+            let count = count as u32;
+            samples.ack_latencies.iter().sum::<Duration>() / count
+        });
+
+        LinkQuality::new(samples.reconnects.len(), ack_latency)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quality_levels() {
+        assert_eq!(LinkQuality::new(0, None).level, LinkQualityLevel::Good);
+        assert_eq!(
+            LinkQuality::new(0, Some(Duration::from_millis(200))).level,
+            LinkQualityLevel::Good
+        );
+        assert_eq!(LinkQuality::new(1, None).level, LinkQualityLevel::Degraded);
+        assert_eq!(
+            LinkQuality::new(0, Some(Duration::from_secs(3))).level,
+            LinkQualityLevel::Degraded
+        );
+        assert_eq!(LinkQuality::new(3, None).level, LinkQualityLevel::Poor);
+        assert_eq!(
+            LinkQuality::new(0, Some(Duration::from_secs(15))).level,
+            LinkQualityLevel::Poor
+        );
+    }
+
+    #[test]
+    fn latency_is_averaged() {
+        let monitor = LinkMonitor::default();
+        monitor.acknowledged(Duration::from_millis(100));
+        monitor.acknowledged(Duration::from_millis(300));
+        monitor.reconnected();
+
+        let quality = monitor.quality();
+        assert_eq!(quality.ack_latency, Some(Duration::from_millis(200)));
+        assert_eq!(quality.recent_reconnects, 1);
+        assert_eq!(quality.level, LinkQualityLevel::Degraded);
+    }
+}
@@ -1,31 +1,79 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Write as _};
+use std::future::Future;
+use std::io::{BufReader, Read, Write};
 use std::panic::RefUnwindSafe;
 use std::time::Duration;
 use std::{path::Path, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use base::BaseConnection;
+#[cfg(feature = "c2d")]
 use c2d::CloudToDeviceMessageGuard;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
 
+use crate::cloud::dps::ProvisioningToken;
 use crate::cloud::drs::RegistrationResponse;
 pub use crate::connection::twins::DesiredProperties;
+pub use crate::connection::twins::DesiredPropertiesGap;
+pub use crate::connection::twins::DesiredPropertiesGapCallback;
+pub use crate::connection::twins::DesiredPropertiesTooLarge;
+pub use crate::connection::twins::DesiredPropertiesTooLargeCallback;
 pub use crate::connection::twins::DesiredPropertiesUpdatedCallback;
+pub use crate::connection::twins::ReportedPropertiesBuilder;
+pub use crate::connection::twins::ReportedPropertiesSentCallback;
+use crate::iothub;
+use crate::iothub::compress_content_brotli;
 use crate::persistence::sqlite::SdkConfiguration;
+pub use crate::persistence::BatchSummary;
+use crate::utils::metrics;
 
 mod base;
 mod builder;
+#[cfg(feature = "c2d")]
 pub mod c2d;
+mod events;
+pub(crate) mod link;
+#[cfg(feature = "packet-observer")]
+pub(crate) mod packets;
+pub(crate) mod throughput;
+mod upload;
 
+pub use builder::Cipher;
+pub use builder::DatabaseCreatedCallback;
+pub use builder::DatabaseMigration;
 pub use builder::DeviceClientBuilder;
+pub use builder::DeviceIdAssignedCallback;
+pub use builder::DeviceIdAssignment;
+pub use builder::EffectiveConfig;
+pub use builder::MigrationCallback;
+pub use builder::PayloadCodec;
+pub use builder::PendingMessagesLoadedCallback;
 pub use builder::ProvisioningOperation;
 pub use builder::ProvisioningOperationDisplayHandler;
-pub use c2d::CloudToDeviceMessage;
+pub use builder::ProvisioningPhase;
+pub use builder::ProvisioningProgressCallback;
+pub use builder::ProvisioningResult;
+pub use builder::StartupInfo;
+pub use builder::TokenRefreshFailedCallback;
+pub use builder::WorkspaceState;
+pub use builder::WorkspaceStateCallback;
+#[cfg(feature = "c2d")]
+pub use c2d::{AckMode, BufferPolicy, CloudToDeviceMessage};
+pub(crate) use events::EventPublisher;
+pub use events::SdkEvent;
+pub use link::{LinkQuality, LinkQualityLevel};
+#[cfg(feature = "packet-observer")]
+pub use packets::{PacketDirection, RawPacket, RawPacketObserver};
+pub use upload::ChunkedUpload;
 
 use crate::connection::ConnectionImplementation;
 
 use crate::{persistence, ProcessSignalsSource};
 
 /// The compression to use for sending [Messages](https://docs.spotflow.io/send-data/#message).
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub enum Compression {
     /// Compress the message using the fastest compression algorithm settings.
     Fastest,
@@ -33,6 +81,20 @@ pub enum Compression {
     /// Beware that this may be significantly slower than the fastest compression.
     /// We recommend to test the performance of your application with this setting before using it in production.
     SmallestSize,
+    /// Compress the message using gzip so that it can be decoded by consumers that don't support Brotli.
+    /// The Message is sent with the `content-encoding=gzip` property.
+    Gzip,
+    /// Compress the message using Brotli with the given parameters instead of one of the presets.
+    ///
+    /// The `quality` must be from 0 to 11 (the presets [`Compression::Fastest`] and [`Compression::SmallestSize`] use 1 and 11),
+    /// and the `window` is the base-2 logarithm of the window size, which must be from 10 to 24. Unlike with the presets,
+    /// the message is compressed right when it's enqueued and it's stored compressed in the local database file.
+    Brotli {
+        /// The quality of the compression from 0 to 11, higher values produce smaller messages but take longer.
+        quality: u8,
+        /// The base-2 logarithm of the window size from 10 to 24.
+        window: u8,
+    },
 }
 
 impl Compression {
@@ -40,18 +102,157 @@ impl Compression {
         match compression {
             Some(Compression::Fastest) => persistence::Compression::BrotliFastest,
             Some(Compression::SmallestSize) => persistence::Compression::BrotliSmallestSize,
+            Some(Compression::Gzip) => persistence::Compression::Gzip,
+            Some(Compression::Brotli { .. }) => persistence::Compression::Brotli,
             None => persistence::Compression::None,
         }
     }
 }
 
+/// The capacities of the internal buffers used for the communication with the Platform.
+///
+/// The default values are suitable for most [Devices](https://docs.spotflow.io/connect-devices/#device).
+/// Increase them if the [Device](https://docs.spotflow.io/connect-devices/#device) produces
+/// [Messages](https://docs.spotflow.io/send-data/#message) at very high rates. All the capacities must be non-zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// The number of requests that the MQTT client can buffer before they are sent. The default value is 10.
+    pub mqtt_request_capacity: usize,
+    /// The number of [Messages](https://docs.spotflow.io/send-data/#message) loaded from the local database file
+    /// that are buffered before they are sent. The default value is 100.
+    pub message_buffer_capacity: usize,
+    /// The number of items buffered by the internal channels processing Device Twins and by each channel returned from
+    /// [`DeviceClient::subscribe_events`]. The default value is 100.
+    pub internal_channel_capacity: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            mqtt_request_capacity: 10,
+            message_buffer_capacity: 100,
+            internal_channel_capacity: 100,
+        }
+    }
+}
+
+impl ChannelConfig {
+    fn validate(&self) -> Result<()> {
+        if self.mqtt_request_capacity == 0 {
+            bail!("The MQTT request capacity must be greater than zero.");
+        }
+        if self.message_buffer_capacity == 0 {
+            bail!("The message buffer capacity must be greater than zero.");
+        }
+        if self.internal_channel_capacity == 0 {
+            bail!("The internal channel capacity must be greater than zero.");
+        }
+
+        Ok(())
+    }
+}
+
+/// The way to handle a corrupted local database file when the [`DeviceClient`] is being built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Fail to build the [`DeviceClient`] if the local database file is corrupted.
+    #[default]
+    Fail,
+    /// If the local database file doesn't pass the integrity check, move it aside and create a new one.
+    /// The credentials that can still be read from the corrupted file are stored in the new one, so that
+    /// the [Device](https://docs.spotflow.io/connect-devices/#device) keeps its identity. All the
+    /// [Messages](https://docs.spotflow.io/send-data/#message) that weren't sent yet are lost.
+    RebuildPreservingCredentials,
+}
+
+/// The transport of the MQTT connection to the Platform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum TransportMode {
+    /// Connect using MQTT over TLS on the port 8883.
+    #[default]
+    Mqtt,
+    /// Connect using MQTT over WebSocket on the port 443, which is useful when a firewall blocks the port 8883.
+    /// Available only with the `websocket` feature.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// The delivery guarantee of the last will [Message](https://docs.spotflow.io/send-data/#message), see
+/// [`DeviceClientBuilder::with_last_will`]. The Platform doesn't support delivering a Message exactly once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LastWillQos {
+    /// The Message is delivered at most once, it can be lost.
+    AtMostOnce,
+    /// The Message is delivered at least once, it can be delivered more times.
+    #[default]
+    AtLeastOnce,
+}
+
+/// The state of the connection to the Platform.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// The connection is established.
+    Connected,
+    /// The connection hasn't been established yet.
+    Connecting,
+    /// The connection failed with the given error, the client is trying to reconnect.
+    Error(String),
+    /// The connection was closed by [`DeviceClient::disconnect`].
+    Disconnected,
+    /// The connection failed with the given error more times in a row than allowed by
+    /// [`DeviceClientBuilder::with_max_reconnect_attempts`], the client doesn't try to reconnect anymore.
+    Failed(String),
+}
+
+/// The outcome of enqueuing a [Message](https://docs.spotflow.io/send-data/#message) using
+/// [`DeviceClient::enqueue_message_with_report`].
+#[derive(Clone, Debug)]
+pub struct EnqueueReport {
+    /// The ID of the [Message](https://docs.spotflow.io/send-data/#message) in the queue in the local database file.
+    pub row_id: i32,
+    /// The compression that will be applied when sending the [Message](https://docs.spotflow.io/send-data/#message).
+    /// It's `None` if no compression was requested or if the compression would not decrease the size of the content.
+    pub applied_compression: Option<Compression>,
+    /// The size of the content stored in the local database file in bytes.
+    pub stored_bytes: usize,
+}
+
+/// The IDs that a [Message](https://docs.spotflow.io/send-data/#message) was enqueued with, returned by
+/// [`DeviceClient::enqueue_message_with_ids`] and [`DeviceClient::send_message_with_ids`].
+///
+/// The IDs are either the ones provided by the caller or the ones generated by the generators set by
+/// [`DeviceClientBuilder::with_batch_id_generator`] and [`DeviceClientBuilder::with_message_id_generator`].
+/// An ID is `None` if it was left to be filled in by the Platform using the Stream's autofill pattern,
+/// the Platform doesn't report these IDs back to the [Device](https://docs.spotflow.io/connect-devices/#device).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageIds {
+    /// The ID of the [Batch](https://docs.spotflow.io/send-data/#batch) the Message belongs to.
+    pub batch_id: Option<String>,
+    /// The ID of the Message.
+    pub message_id: Option<String>,
+}
+
 /// A set of options for sending [Messages](https://docs.spotflow.io/send-data/#message) to
 /// a [Stream](https://docs.spotflow.io/send-data/#stream).
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct MessageContext {
     stream_group: Option<String>,
     stream: Option<String>,
     compression: Option<Compression>,
+    compression_threshold: usize,
+    time_to_live: Option<Duration>,
+    properties: HashMap<String, String>,
+    chunk_size: Option<usize>,
+    source_device_id: Option<String>,
+    content_type: Option<String>,
+    ordered: bool,
+    require_utf8: bool,
+}
+
+impl Default for MessageContext {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
 }
 
 impl MessageContext {
@@ -63,6 +264,14 @@ impl MessageContext {
             stream_group,
             stream,
             compression: None,
+            compression_threshold: 0,
+            time_to_live: None,
+            properties: HashMap::new(),
+            chunk_size: None,
+            source_device_id: None,
+            content_type: None,
+            ordered: true,
+            require_utf8: false,
         }
     }
 
@@ -79,6 +288,14 @@ impl MessageContext {
         self.stream_group = stream_group;
     }
 
+    /// Set the [Stream Group](https://docs.spotflow.io/send-data/#stream-group) where
+    /// [Messages](https://docs.spotflow.io/send-data/#message) will be sent to.
+    #[must_use]
+    pub fn with_stream_group(mut self, stream_group: impl Into<String>) -> Self {
+        self.stream_group = Some(stream_group.into());
+        self
+    }
+
     /// Get the [Stream](https://docs.spotflow.io/send-data/#stream) where
     /// [Messages](https://docs.spotflow.io/send-data/#message) will be sent to.
     #[must_use]
@@ -92,6 +309,14 @@ impl MessageContext {
         self.stream = stream;
     }
 
+    /// Set the [Stream](https://docs.spotflow.io/send-data/#stream) where
+    /// [Messages](https://docs.spotflow.io/send-data/#message) will be sent to.
+    #[must_use]
+    pub fn with_stream(mut self, stream: impl Into<String>) -> Self {
+        self.stream = Some(stream.into());
+        self
+    }
+
     /// Get the compression to use for sending [Messages](https://docs.spotflow.io/send-data/#message).
     #[must_use]
     pub fn compression(&self) -> Option<Compression> {
@@ -102,6 +327,246 @@ impl MessageContext {
     pub fn set_compression(&mut self, compression: Option<Compression>) {
         self.compression = compression;
     }
+
+    /// Get the minimum size in bytes of [Messages](https://docs.spotflow.io/send-data/#message) that are compressed.
+    #[must_use]
+    pub fn compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /// Set the minimum size in bytes of [Messages](https://docs.spotflow.io/send-data/#message) that are compressed.
+    ///
+    /// Messages smaller than the threshold are sent uncompressed even if the compression is set, because compressing
+    /// tiny payloads adds overhead and can even make them larger. The default is `0`, which means that all the
+    /// Messages are compressed.
+    pub fn set_compression_threshold(&mut self, compression_threshold: usize) {
+        self.compression_threshold = compression_threshold;
+    }
+
+    /// Set the compression to use for sending [Messages](https://docs.spotflow.io/send-data/#message).
+    #[must_use]
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Get the time after which [Messages](https://docs.spotflow.io/send-data/#message) expire.
+    #[must_use]
+    pub fn time_to_live(&self) -> Option<Duration> {
+        self.time_to_live
+    }
+
+    /// Set the time after which [Messages](https://docs.spotflow.io/send-data/#message) expire.
+    ///
+    /// The time is measured from the moment the [Message](https://docs.spotflow.io/send-data/#message) is enqueued.
+    /// Messages that expire before they are sent are removed from the queue in the local database file and are never sent.
+    /// Messages that are sent carry their expiry time so that the Platform can drop them if they expire later.
+    pub fn set_time_to_live(&mut self, time_to_live: Option<Duration>) {
+        self.time_to_live = time_to_live;
+    }
+
+    /// Get the user properties attached to [Messages](https://docs.spotflow.io/send-data/#message).
+    #[must_use]
+    pub fn properties(&self) -> &HashMap<String, String> {
+        &self.properties
+    }
+
+    /// Set the user properties attached to [Messages](https://docs.spotflow.io/send-data/#message).
+    ///
+    /// The properties are key-value pairs of arbitrary metadata, for example, `schemaVersion` or `unit`.
+    /// They are sent together with each [Message](https://docs.spotflow.io/send-data/#message) and the Platform can use them for routing.
+    pub fn set_properties(&mut self, properties: HashMap<String, String>) {
+        self.properties = properties;
+    }
+
+    /// Get the maximum size of a chunk in bytes when [`DeviceClient::enqueue_message_from_reader`] splits the
+    /// [Message](https://docs.spotflow.io/send-data/#message) using Message Chunking.
+    #[must_use]
+    pub fn chunk_size(&self) -> Option<usize> {
+        self.chunk_size
+    }
+
+    /// Set the maximum size of a chunk in bytes when [`DeviceClient::enqueue_message_from_reader`] splits the
+    /// [Message](https://docs.spotflow.io/send-data/#message) using Message Chunking.
+    /// If the value is `None`, the [Message](https://docs.spotflow.io/send-data/#message) isn't split.
+    pub fn set_chunk_size(&mut self, chunk_size: Option<usize>) {
+        self.chunk_size = chunk_size;
+    }
+
+    /// Get the ID of the Device that originally produced the data of [Messages](https://docs.spotflow.io/send-data/#message).
+    #[must_use]
+    pub fn source_device_id(&self) -> Option<&str> {
+        self.source_device_id.as_deref()
+    }
+
+    /// Set the ID of the Device that originally produced the data of [Messages](https://docs.spotflow.io/send-data/#message).
+    ///
+    /// Use it when this [Device](https://docs.spotflow.io/connect-devices/#device) acts as a gateway relaying the data of other devices.
+    /// The connection to the Platform still uses the identity of the gateway, but the Platform attributes the data to the given Device.
+    pub fn set_source_device_id(&mut self, source_device_id: Option<String>) {
+        self.source_device_id = source_device_id;
+    }
+
+    /// Get the content type of [Messages](https://docs.spotflow.io/send-data/#message), for example, `application/json`.
+    #[must_use]
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Set the content type of [Messages](https://docs.spotflow.io/send-data/#message), for example, `application/json`
+    /// or `application/octet-stream`.
+    ///
+    /// Unlike the user properties, the content type is sent as the standard content type property of the Message,
+    /// so the Platform can route the Messages by it. If the value is `None`, no content type is sent.
+    pub fn set_content_type(&mut self, content_type: Option<String>) {
+        self.content_type = content_type;
+    }
+
+    /// Get whether [Messages](https://docs.spotflow.io/send-data/#message) are sent strictly in the order they were enqueued.
+    #[must_use]
+    pub fn ordered(&self) -> bool {
+        self.ordered
+    }
+
+    /// Set whether [Messages](https://docs.spotflow.io/send-data/#message) are sent strictly in the order they were enqueued.
+    ///
+    /// The default is `true`, which means that each Message is sent only after all the previous ones were acknowledged by the Platform.
+    /// If the order doesn't matter, for example, for metrics, set it to `false`. Such Messages are sent without waiting for
+    /// the acknowledgments of the previous ones, which considerably increases the throughput. An ordered Message is still sent
+    /// only after all the previous Messages, including the unordered ones, were acknowledged.
+    pub fn set_ordered(&mut self, ordered: bool) {
+        self.ordered = ordered;
+    }
+
+    /// Get whether the payloads of [Messages](https://docs.spotflow.io/send-data/#message) must be valid UTF-8.
+    #[must_use]
+    pub fn require_utf8(&self) -> bool {
+        self.require_utf8
+    }
+
+    /// Set whether the payloads of [Messages](https://docs.spotflow.io/send-data/#message) must be valid UTF-8.
+    ///
+    /// If it's `true`, enqueuing a Message whose payload isn't valid UTF-8 fails, which helps catching encoding bugs
+    /// in the Streams that contain only text, for example, JSON. The default is `false`, which allows sending binary payloads.
+    /// The chunks of Messages split by [`DeviceClient::enqueue_message_from_reader`] aren't validated.
+    pub fn set_require_utf8(&mut self, require_utf8: bool) {
+        self.require_utf8 = require_utf8;
+    }
+
+    fn check_payload_encoding(&self, payload: &[u8]) -> Result<()> {
+        if self.require_utf8 {
+            std::str::from_utf8(payload).context("The message payload is not valid UTF-8")?;
+        }
+
+        Ok(())
+    }
+
+    // The Messages below the threshold are stored without compression so that the sender sends them as they are
+    fn persisted_compression(&self, payload: &[u8]) -> persistence::Compression {
+        if payload.len() < self.compression_threshold {
+            return persistence::Compression::None;
+        }
+
+        Compression::to_persisted_compression(&self.compression)
+    }
+
+    // The parameters of the custom Brotli compression aren't stored, so the payload is compressed before it's stored
+    fn prepare_payload(&self, payload: Vec<u8>) -> Result<(Vec<u8>, persistence::Compression)> {
+        let compression = self.persisted_compression(&payload);
+        let (Some(Compression::Brotli { quality, window }), persistence::Compression::Brotli) =
+            (&self.compression, compression)
+        else {
+            return Ok((payload, compression));
+        };
+
+        match compress_content_brotli(&payload, *quality, *window)? {
+            Some(compressed_payload) => Ok((compressed_payload, compression)),
+            None => Ok((payload, persistence::Compression::None)),
+        }
+    }
+
+    fn serialized_properties(&self) -> Result<Option<String>> {
+        if self.properties.is_empty() {
+            return Ok(None);
+        }
+
+        let properties = serde_json::to_string(&self.properties)
+            .context("Unable to serialize message properties")?;
+
+        Ok(Some(properties))
+    }
+
+    fn expiry(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let time_to_live = chrono::Duration::from_std(self.time_to_live?).ok()?;
+        now.checked_add_signed(time_to_live)
+    }
+}
+
+/// Generates the IDs of [Messages](https://docs.spotflow.io/send-data/#message) that are enqueued without a `message_id`.
+///
+/// It's implemented for all closures taking the [`MessageContext`] of the [Message](https://docs.spotflow.io/send-data/#message),
+/// see [`DeviceClientBuilder::with_message_id_generator`].
+pub trait MessageIdGenerator: Send + Sync {
+    /// Generate the ID of a [Message](https://docs.spotflow.io/send-data/#message) sent with the given [`MessageContext`].
+    fn generate_message_id(&self, message_context: &MessageContext) -> String;
+}
+
+impl<F> MessageIdGenerator for F
+where
+    F: Fn(&MessageContext) -> String + Send + Sync,
+{
+    fn generate_message_id(&self, message_context: &MessageContext) -> String {
+        self(message_context)
+    }
+}
+
+/// Generates the IDs of [Batches](https://docs.spotflow.io/send-data/#batch) for [Messages](https://docs.spotflow.io/send-data/#message)
+/// that are enqueued without a `batch_id`.
+///
+/// It's implemented for all closures taking the [`MessageContext`] of the [Message](https://docs.spotflow.io/send-data/#message),
+/// see [`DeviceClientBuilder::with_batch_id_generator`].
+pub trait BatchIdGenerator: Send + Sync {
+    /// Generate the ID of the [Batch](https://docs.spotflow.io/send-data/#batch) that a [Message](https://docs.spotflow.io/send-data/#message)
+    /// sent with the given [`MessageContext`] belongs to.
+    fn generate_batch_id(&self, message_context: &MessageContext) -> String;
+}
+
+impl<F> BatchIdGenerator for F
+where
+    F: Fn(&MessageContext) -> String + Send + Sync,
+{
+    fn generate_batch_id(&self, message_context: &MessageContext) -> String {
+        self(message_context)
+    }
+}
+
+// Fills in the IDs that weren't provided when enqueueing Messages, so that the generated IDs are persisted with them
+#[derive(Clone, Default)]
+struct IdGenerators {
+    message_id: Option<Arc<dyn MessageIdGenerator>>,
+    batch_id: Option<Arc<dyn BatchIdGenerator>>,
+}
+
+impl IdGenerators {
+    fn fill(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+    ) -> (Option<String>, Option<String>) {
+        let batch_id = batch_id.or_else(|| {
+            self.batch_id
+                .as_ref()
+                .map(|generator| generator.generate_batch_id(message_context))
+        });
+        let message_id = message_id.or_else(|| {
+            self.message_id
+                .as_ref()
+                .map(|generator| generator.generate_message_id(message_context))
+        });
+
+        (batch_id, message_id)
+    }
 }
 
 /// A client communicating with the Platform.
@@ -115,36 +580,83 @@ impl MessageContext {
 #[derive(Clone)]
 pub struct DeviceClient {
     connection: Arc<BaseConnection<dyn ConnectionImplementation + Send + Sync>>,
+    stream_compressions: Arc<HashMap<String, Compression>>,
+    id_generators: IdGenerators,
+    startup_info: StartupInfo,
+    options: Arc<ResolvedOptions>,
+}
+
+// The options captured from the builder that are reported in [`EffectiveConfig`]
+struct ResolvedOptions {
+    requested_device_id: Option<String>,
+    iothub_api_version: String,
+    clean_session: bool,
+    transport: TransportMode,
+    max_reconnect_attempts: Option<u32>,
+    publish_timeout: Duration,
+    max_message_bytes: Option<usize>,
+    worker_threads: usize,
+    offline_start: bool,
 }
 
 impl DeviceClient {
     /// Starts an ingress and saves the provided tokens and URLs to a state file. If the provided file does not exist this function creates it.
-    /// It also makes sure that both desired and reported properties of the Device Twin are available.
+    /// It also makes sure that both desired and reported properties of the Device Twin are available, unless the offline start is enabled.
+    #[allow(clippy::too_many_arguments)]
     fn new<F>(
         config: SdkConfiguration,
         path: &Path,
         method_handler: Option<F>,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
         signals_src: Option<Box<dyn ProcessSignalsSource>>,
         initial_registration_response: Option<RegistrationResponse>,
+        stream_compressions: HashMap<String, Compression>,
+        id_generators: IdGenerators,
+        startup_info: StartupInfo,
+        events: EventPublisher,
+        offline_start: bool,
     ) -> Result<DeviceClient>
     where
         F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'static,
     {
+        let options = ResolvedOptions {
+            requested_device_id: config.requested_device_id.clone(),
+            iothub_api_version: config.iothub_api_version.clone(),
+            clean_session: config.clean_session,
+            transport: config.transport,
+            max_reconnect_attempts: config.max_reconnect_attempts,
+            publish_timeout: config.publish_timeout,
+            max_message_bytes: config.max_message_bytes,
+            worker_threads: config.worker_threads,
+            offline_start,
+        };
+
         let connection = BaseConnection::init_ingress(
             config,
             path,
             method_handler,
             desired_properties_updated_callback,
+            desired_properties_gap_callback,
             signals_src,
             initial_registration_response,
+            events,
         )?;
 
-        connection.wait_properties_ready()?;
+        // The Desired Properties persisted by a previous run are enough for the offline start
+        if !(offline_start && connection.desired_properties_initialized()) {
+            connection.wait_properties_ready()?;
+        }
 
         let connection = Arc::new(connection);
 
-        Ok(DeviceClient { connection })
+        Ok(DeviceClient {
+            connection,
+            stream_compressions: Arc::new(stream_compressions),
+            id_generators,
+            startup_info,
+            options: Arc::new(options),
+        })
     }
 
     /// Get the ID of the [Workspace](https://docs.spotflow.io/manage-access/workspaces/) to which the
@@ -160,6 +672,182 @@ impl DeviceClient {
         self.connection.device_id()
     }
 
+    /// Get the ID that was generated for the local database file when it was created.
+    ///
+    /// Unlike the [Device ID](https://docs.spotflow.io/connect-devices/#device-id), it's generated locally and doesn't
+    /// change after [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) is performed again.
+    /// It changes only when the local database file is deleted or recreated, so it's useful to correlate logs across restarts.
+    pub fn instance_id(&self) -> Result<String> {
+        self.connection.device_instance_id()
+    }
+
+    /// Check whether the local database file on the given path holds credentials that haven't expired yet, so that
+    /// [`DeviceClientBuilder::build`] won't need to perform [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning).
+    ///
+    /// The check only reads the local database file, it doesn't connect to the Platform. Therefore, it can't find out
+    /// whether the [Registration Token](https://docs.spotflow.io/connect-devices/#registration-token) was revoked.
+    /// `build` also provisions the Device again if it's called with a different
+    /// [Provisioning Token](https://docs.spotflow.io/connect-devices/#provisioning-token) or Device ID. Returns `false`
+    /// if the file doesn't exist or if its credentials were encrypted by [`DeviceClientBuilder::with_credential_encryption`].
+    pub fn is_provisioned(db_path: impl AsRef<Path>) -> Result<bool> {
+        let config = builder::load_stored_configuration(db_path.as_ref(), None)?;
+
+        Ok(config.workspace_id.is_some()
+            && config.device_id.is_some()
+            && config
+                .registration_token
+                .is_some_and(|token| !token.is_expired(Utc::now())))
+    }
+
+    /// Get the information about how the client obtained its credentials when it was built, for example, whether
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) was performed.
+    #[must_use]
+    pub fn startup_info(&self) -> StartupInfo {
+        self.startup_info
+    }
+
+    /// The host name of the IoT Hub the Device is connected to.
+    ///
+    /// This is a diagnostic value, it can change when the Device is registered again, for example after a failover.
+    pub fn iot_hub_hostname(&self) -> Result<String> {
+        self.connection.iot_hub_hostname()
+    }
+
+    /// Get the configuration that the client actually uses, combining the options of [`DeviceClientBuilder`] with
+    /// the values loaded from the local database file.
+    ///
+    /// The values may differ from the requested ones, for example, the [Device ID](https://docs.spotflow.io/connect-devices/#device-id)
+    /// can be overridden during the approval of the [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation)
+    /// and the Platform instance can be changed by [`DeviceClient::reprovision_to_instance`]. Use the
+    /// [`Display`](std::fmt::Display) implementation of [`EffectiveConfig`] to log it for diagnostics.
+    pub fn effective_config(&self) -> Result<EffectiveConfig> {
+        Ok(EffectiveConfig {
+            instance_url: self.connection.instance_url()?.to_string(),
+            workspace_id: self.workspace_id()?,
+            device_id: self.device_id()?,
+            requested_device_id: self.options.requested_device_id.clone(),
+            site_id: self.connection.site_id(),
+            iot_hub_hostname: self.iot_hub_hostname().ok(),
+            iothub_api_version: self.options.iothub_api_version.clone(),
+            stream_compressions: self
+                .stream_compressions
+                .iter()
+                .map(|(stream, compression)| (stream.clone(), compression.clone()))
+                .collect(),
+            keep_alive_secs: iothub::KEEP_ALIVE.as_secs(),
+            clean_session: self.options.clean_session,
+            transport: self.options.transport,
+            max_reconnect_attempts: self.options.max_reconnect_attempts,
+            publish_timeout_secs: self.options.publish_timeout.as_secs(),
+            max_message_bytes: self.options.max_message_bytes,
+            worker_threads: self.options.worker_threads,
+            offline_start: self.options.offline_start,
+        })
+    }
+
+    /// Create a [`MessageContext`] for sending [Messages](https://docs.spotflow.io/send-data/#message) to
+    /// the given [Stream Group](https://docs.spotflow.io/send-data/#stream-group) and [Stream](https://docs.spotflow.io/send-data/#stream).
+    ///
+    /// Unlike [`MessageContext::new`], the compression is set to the default configured for the
+    /// [Stream](https://docs.spotflow.io/send-data/#stream) in [`DeviceClientBuilder::with_stream_compressions`].
+    #[must_use]
+    pub fn create_message_context(
+        &self,
+        stream_group: Option<String>,
+        stream: Option<String>,
+    ) -> MessageContext {
+        let compression = stream
+            .as_ref()
+            .and_then(|stream| self.stream_compressions.get(stream))
+            .cloned();
+
+        let mut message_context = MessageContext::new(stream_group, stream);
+        message_context.set_compression(compression);
+        message_context
+    }
+
+    /// Get the current state of the connection to the Platform.
+    ///
+    /// The method doesn't block, it returns the state observed by the background thread
+    /// that communicates with the Platform.
+    #[must_use]
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection.connection_status()
+    }
+
+    /// Block the current thread until the connection to the Platform is established, or until `timeout` elapses.
+    ///
+    /// Returns `true` if the client is connected and `false` if the time ran out. It's useful especially after
+    /// the offline start, for example, to obtain the current
+    /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) once the
+    /// [Device](https://docs.spotflow.io/connect-devices/#device) goes online.
+    /// Returns an error if the client stopped reconnecting after exceeding the maximum number of reconnect attempts.
+    pub fn wait_connected(&self, timeout: Duration) -> Result<bool> {
+        self.connection.wait_connected(timeout)
+    }
+
+    /// Subscribe to the events in the lifecycle of the client, such as the changes of the connection state,
+    /// acknowledgments of sent [Messages](https://docs.spotflow.io/send-data/#message), updates of the
+    /// [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties), or errors.
+    ///
+    /// The events published before the first subscription, such as the phases of
+    /// [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) performed by
+    /// [`DeviceClientBuilder::build`], are delivered to the first subscriber. Each subscriber receives all the
+    /// following events. The capacity of the channel is given by [`ChannelConfig::internal_channel_capacity`].
+    /// The client never waits for the events to be consumed: if the channel is full, the new events are dropped.
+    #[must_use]
+    pub fn subscribe_events(&self) -> tokio::sync::mpsc::Receiver<SdkEvent> {
+        self.connection.subscribe_events()
+    }
+
+    /// Close the connection to the Platform and stop the background thread that communicates with it.
+    ///
+    /// The client can still be used to enqueue [Messages](https://docs.spotflow.io/send-data/#message) and
+    /// update [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties); they are stored in
+    /// the local database file and sent after [`DeviceClient::connect`] is called. Don't call the methods that
+    /// wait for the communication with the Platform, such as [`DeviceClient::wait_enqueued_messages_sent`], because
+    /// they would block until the client is connected again.
+    pub fn disconnect(&self) {
+        self.connection.disconnect();
+    }
+
+    /// Connect to the Platform again after [`DeviceClient::disconnect`] was called.
+    ///
+    /// The connection reuses the credentials stored in the local database file. All the
+    /// [Messages](https://docs.spotflow.io/send-data/#message) that were enqueued while the client was disconnected
+    /// are sent once the connection is established. The method fails if the client is already connected.
+    pub fn connect(&self) -> Result<()> {
+        self.connection.connect()
+    }
+
+    /// Move the [Device](https://docs.spotflow.io/connect-devices/#device) to another Platform instance without
+    /// deleting the local database file.
+    ///
+    /// The method performs [Device Provisioning](https://docs.spotflow.io/connect-devices/#device-provisioning) in the
+    /// instance `new_instance` (for example, `"api.eu1.spotflow.io"`) using `provisioning_token` and waits for the approval
+    /// of the [Provisioning Operation](https://docs.spotflow.io/connect-devices/#provisioning-operation), whose details
+    /// are printed to the standard output. The client stays connected to the current instance in the meantime. Once
+    /// the Device is registered, the new instance and the new credentials replace the stale ones in the local database
+    /// file and the client connects to the new instance. The [Messages](https://docs.spotflow.io/send-data/#message)
+    /// that haven't been sent yet are kept and sent to the new instance. If the method fails, the client keeps
+    /// using the current instance.
+    ///
+    /// Build the [`DeviceClient`] with the new instance and [Provisioning Token](https://docs.spotflow.io/connect-devices/#provisioning-token)
+    /// on the next start, otherwise [`DeviceClientBuilder::build`] performs Device Provisioning again.
+    pub fn reprovision_to_instance(
+        &self,
+        new_instance: &str,
+        provisioning_token: String,
+    ) -> Result<()> {
+        let instance_url = builder::instance_url(new_instance)?;
+        self.connection.reprovision_to_instance(
+            instance_url,
+            ProvisioningToken {
+                token: provisioning_token,
+            },
+        )
+    }
+
     /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
     /// be sent to the Platform.
     ///
@@ -183,10 +871,135 @@ impl DeviceClient {
         message_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
         self.connection
             .enqueue_message(message_context, batch_id, message_id, payload)
     }
 
+    /// Enqueue multiple [Messages](https://docs.spotflow.io/send-data/#message) to
+    /// be sent to the Platform.
+    ///
+    /// Each item of `messages` consists of the `batch_id`, the `message_id`, and the content of a single Message, which
+    /// are handled the same as in [`DeviceClient::enqueue_message`]. All the Messages are saved to the local database
+    /// file in a single transaction, which is considerably faster than enqueuing them one by one. If any of them can't be
+    /// enqueued, none of them is.
+    pub fn enqueue_messages(
+        &self,
+        message_context: &MessageContext,
+        messages: Vec<(Option<String>, Option<String>, Vec<u8>)>,
+    ) -> Result<()> {
+        let messages = messages
+            .into_iter()
+            .map(|(batch_id, message_id, payload)| {
+                let (batch_id, message_id) =
+                    self.id_generators
+                        .fill(message_context, batch_id, message_id);
+                (batch_id, message_id, payload)
+            })
+            .collect();
+        self.connection.enqueue_messages(message_context, messages)
+    }
+
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) whose content is read from `reader` to
+    /// be sent to the Platform.
+    ///
+    /// If [`MessageContext::chunk_size`] is set, the content is read and stored one chunk at a time, so the whole
+    /// [Message](https://docs.spotflow.io/send-data/#message) is never held in memory. The chunks get the IDs `0`, `1`, etc.,
+    /// and the [Message](https://docs.spotflow.io/send-data/#message) is completed after the last one. In this case,
+    /// you must provide the `message_id`. Otherwise, the content is read whole and the method works
+    /// the same as [`DeviceClient::enqueue_message`].
+    pub fn enqueue_message_from_reader(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        reader: impl Read,
+    ) -> Result<()> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        self.connection
+            .enqueue_message_from_reader(message_context, batch_id, message_id, reader)
+    }
+
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
+    /// be sent to the Platform and return the IDs it was enqueued with.
+    ///
+    /// The method works the same as [`DeviceClient::enqueue_message`], but it additionally returns the effective
+    /// `batch_id` and `message_id` including the generated ones, see [`MessageIds`]. Use them to correlate the Message
+    /// with the data in the Platform.
+    pub fn enqueue_message_with_ids(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<MessageIds> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        self.connection.enqueue_message(
+            message_context,
+            batch_id.clone(),
+            message_id.clone(),
+            payload,
+        )?;
+
+        Ok(MessageIds {
+            batch_id,
+            message_id,
+        })
+    }
+
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
+    /// be sent to the Platform unless a Message with the same `idempotency_key` is already waiting to be sent.
+    ///
+    /// The method works the same as [`DeviceClient::enqueue_message`], but it returns `false` without enqueuing the Message
+    /// if the local database file still contains a Message enqueued with the same `idempotency_key`. Use it to avoid
+    /// duplicates when your application enqueues the same data again, for example, after recovering from a crash.
+    /// The keys are kept only until the Messages are sent, so a Message can be enqueued again with the same key afterwards.
+    pub fn enqueue_message_idempotent(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+        idempotency_key: &str,
+    ) -> Result<bool> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        self.connection.enqueue_message_once(
+            message_context,
+            batch_id,
+            message_id,
+            payload,
+            idempotency_key,
+        )
+    }
+
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
+    /// be sent to the Platform and return an [`EnqueueReport`] describing how it was stored.
+    ///
+    /// The method works the same as [`DeviceClient::enqueue_message`], but it additionally
+    /// finds out whether the [Message](https://docs.spotflow.io/send-data/#message) will be compressed.
+    /// To find that out, the content is compressed before it's stored instead of right before it's sent.
+    pub fn enqueue_message_with_report(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<EnqueueReport> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        self.connection
+            .enqueue_message_with_report(message_context, batch_id, message_id, payload)
+    }
+
     /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to
     /// be sent to the Platform.
     ///
@@ -213,6 +1026,9 @@ impl DeviceClient {
         chunk_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
         self.connection.enqueue_message_advanced(
             message_context,
             batch_id,
@@ -223,6 +1039,27 @@ impl DeviceClient {
         )
     }
 
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to the given
+    /// [Stream Group](https://docs.spotflow.io/send-data/#stream-group) and [Stream](https://docs.spotflow.io/send-data/#stream)
+    /// without creating a [`MessageContext`] first.
+    ///
+    /// This is a shorthand for calling [`DeviceClient::enqueue_message`] with a [`MessageContext`] that has only
+    /// the Stream Group, the Stream, and the compression set. It's convenient when sending
+    /// [Messages](https://docs.spotflow.io/send-data/#message) to many different Streams from one place.
+    pub fn enqueue_message_to(
+        &self,
+        stream_group: Option<String>,
+        stream: Option<String>,
+        compression: Option<Compression>,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        let message_context =
+            MessageContext::new(stream_group, stream).with_compression(compression);
+        self.enqueue_message(&message_context, batch_id, message_id, payload)
+    }
+
     /// Enqueue the manual completion of the current [Batch](https://docs.spotflow.io/send-data/#batch) to
     /// be sent to the Platform.
     ///
@@ -258,6 +1095,48 @@ impl DeviceClient {
             .enqueue_message_completion(message_context, batch_id, message_id)
     }
 
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) to the given
+    /// [Stream](https://docs.spotflow.io/send-data/#stream) split into chunks of at most `chunk_size` bytes and return
+    /// a [`ChunkedUpload`] for monitoring how many of them were sent to the Platform.
+    ///
+    /// The chunks get the IDs `0`, `1`, etc., and the [Message](https://docs.spotflow.io/send-data/#message) is completed
+    /// after the last one using [`DeviceClient::enqueue_message_completion`]. The method returns right after it saves all
+    /// the chunks to the queue in the local database file. Use [`DeviceClient::enqueue_message_from_reader`] instead if the
+    /// content doesn't fit in memory.
+    pub fn upload_chunked(
+        &self,
+        stream: impl Into<String>,
+        batch_id: String,
+        message_id: String,
+        payload: &[u8],
+        chunk_size: usize,
+    ) -> Result<ChunkedUpload<'_>> {
+        if chunk_size == 0 {
+            bail!("The chunk size must be greater than zero.");
+        }
+
+        let message_context = self.create_message_context(None, Some(stream.into()));
+        let chunks = payload.chunks(chunk_size);
+        let chunk_count = chunks.len();
+        for (chunk_index, chunk) in chunks.enumerate() {
+            self.connection.enqueue_message_advanced(
+                &message_context,
+                Some(batch_id.clone()),
+                None,
+                Some(message_id.clone()),
+                Some(chunk_index.to_string()),
+                chunk.to_vec(),
+            )?;
+        }
+        self.connection.enqueue_message_completion(
+            &message_context,
+            batch_id.clone(),
+            message_id.clone(),
+        )?;
+
+        Ok(ChunkedUpload::new(self, batch_id, message_id, chunk_count))
+    }
+
     /// Send a [Message](https://docs.spotflow.io/send-data/#message) to
     /// the Platform.
     ///
@@ -280,10 +1159,74 @@ impl DeviceClient {
         message_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
         self.connection
             .send_message(message_context, batch_id, message_id, payload)
     }
 
+    /// Send a [Message](https://docs.spotflow.io/send-data/#message) to
+    /// the Platform and return the IDs it was sent with.
+    ///
+    /// The method works the same as [`DeviceClient::send_message`], including blocking the current thread until the Message
+    /// is sent, but it additionally returns the effective `batch_id` and `message_id` including the generated ones,
+    /// see [`MessageIds`].
+    pub fn send_message_with_ids(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<MessageIds> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        self.connection.send_message(
+            message_context,
+            batch_id.clone(),
+            message_id.clone(),
+            payload,
+        )?;
+
+        Ok(MessageIds {
+            batch_id,
+            message_id,
+        })
+    }
+
+    /// Enqueue a [Message](https://docs.spotflow.io/send-data/#message) and return a future that completes when
+    /// the Platform acknowledges this particular Message.
+    ///
+    /// Unlike [`DeviceClient::send_message`], this method doesn't block the current thread until the Message is sent,
+    /// and the future doesn't wait for the Messages enqueued after this one. The Message is enqueued right away,
+    /// even if the future is never awaited. The future can be awaited in any async runtime.
+    /// It fails if the Message couldn't be enqueued, if it expired before it was sent, or if it was removed by
+    /// [`DeviceClient::clear_pending_messages`]. If the [`DeviceClient`] is dropped before the Message is sent,
+    /// the future fails too, but the Message stays in the queue and is sent by the next instance.
+    ///
+    /// The requirements on `batch_id` and `message_id` are the same as in [`DeviceClient::send_message`].
+    pub fn send_message_tracked(
+        &self,
+        message_context: &MessageContext,
+        batch_id: Option<String>,
+        message_id: Option<String>,
+        payload: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send + 'static {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
+        let delivery =
+            self.connection
+                .enqueue_message_tracked(message_context, batch_id, message_id, payload);
+
+        async move {
+            delivery?
+                .await
+                .context("The Message was removed from the queue before it was sent")?
+        }
+    }
+
     /// Send a [Message](https://docs.spotflow.io/send-data/#message) to
     /// the Platform.
     ///
@@ -309,6 +1252,9 @@ impl DeviceClient {
         chunk_id: Option<String>,
         payload: Vec<u8>,
     ) -> Result<()> {
+        let (batch_id, message_id) = self
+            .id_generators
+            .fill(message_context, batch_id, message_id);
         self.connection.send_message_advanced(
             message_context,
             batch_id,
@@ -319,18 +1265,213 @@ impl DeviceClient {
         )
     }
 
+    /// Periodically update the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
+    /// with the JSON object returned by `callback`.
+    ///
+    /// The `callback` is called every `interval` prolonged by a small random delay, so that
+    /// [Devices](https://docs.spotflow.io/connect-devices/#device) started at the same time don't report simultaneously.
+    /// The reporting stops when the [`DeviceClient`] is dropped. The `callback` is called in a background thread
+    /// that also communicates with the Platform, so it should return quickly. The method fails if `interval` is zero.
+    pub fn start_periodic_reported_properties<G>(
+        &self,
+        interval: Duration,
+        callback: G,
+    ) -> Result<()>
+    where
+        G: FnMut() -> String + Send + 'static,
+    {
+        self.connection
+            .start_periodic_reported_properties(interval, callback)
+    }
+
     /// Get the number of [Messages](https://docs.spotflow.io/send-data/#message) that
     /// have been persisted in the local database file but haven't been sent to the Platform yet.
     pub fn pending_messages_count(&self) -> Result<usize> {
         self.connection.pending_messages_count()
     }
 
+    /// Estimate how long it will take to send all the [Messages](https://docs.spotflow.io/send-data/#message) that are
+    /// pending in the queue, for example, to show the progress of a large backfill.
+    ///
+    /// The estimate divides [`DeviceClient::pending_messages_count`] by the average number of Messages sent per second
+    /// during the last minute. It returns `None` if no Message was sent during the last minute, so there's no rate to
+    /// base the estimate on.
+    pub fn estimated_drain_time(&self) -> Result<Option<Duration>> {
+        self.connection.estimated_drain_time()
+    }
+
+    /// Stop sending [Messages](https://docs.spotflow.io/send-data/#message) to the Platform without disconnecting from it,
+    /// for example, during a period when the [Device](https://docs.spotflow.io/connect-devices/#device) must not transmit.
+    ///
+    /// The Messages are still enqueued to the local database file while sending is paused, and they are sent after
+    /// [`DeviceClient::resume_sending`] is called. The Messages that were already sent are still acknowledged by the Platform.
+    /// The pause lasts until the [`DeviceClient`] is dropped, it isn't saved in the local database file.
+    pub fn pause_sending(&self) {
+        self.connection.set_sending_paused(true);
+    }
+
+    /// Resume sending [Messages](https://docs.spotflow.io/send-data/#message) to the Platform after
+    /// [`DeviceClient::pause_sending`], starting with the Messages enqueued in the meantime.
+    pub fn resume_sending(&self) {
+        self.connection.set_sending_paused(false);
+    }
+
+    /// Get whether sending [Messages](https://docs.spotflow.io/send-data/#message) is paused by [`DeviceClient::pause_sending`].
+    #[must_use]
+    pub fn is_sending_paused(&self) -> bool {
+        self.connection.is_sending_paused()
+    }
+
+    /// Get the quality of the connection to the Platform observed recently.
+    ///
+    /// It's assessed from the number of reconnects in the last 10 minutes and from how long it takes the Platform
+    /// to acknowledge [Messages](https://docs.spotflow.io/send-data/#message), see [`LinkQuality`]. Use it, for example,
+    /// to decide whether to send data right away or to accumulate it while the connection is poor.
+    #[must_use]
+    pub fn link_quality(&self) -> LinkQuality {
+        self.connection.link_quality()
+    }
+
+    /// Get the handle of the Tokio runtime that drives the connection to the Platform.
+    ///
+    /// **Advanced**: Use it only to spawn your own tasks on the same runtime instead of creating another one, for example,
+    /// a periodic reading of a sensor. The tasks share the worker threads with the MQTT connection, so they must never block.
+    /// The handle must not be used after the [`DeviceClient`] is dropped because the runtime is shut down together with the client
+    /// and all the tasks still running on it are cancelled.
+    #[must_use]
+    pub fn runtime_handle(&self) -> tokio::runtime::Handle {
+        self.connection.runtime_handle()
+    }
+
+    /// Remove all the [Messages](https://docs.spotflow.io/send-data/#message) that haven't been sent to the Platform yet
+    /// from the local database file and return how many of them were removed.
+    ///
+    /// The connection to the Platform stays open. The Messages that are already being sent when the queue is cleared
+    /// might still reach the Platform. The Messages that were already sent are not counted.
+    pub fn clear_pending_messages(&self) -> Result<usize> {
+        self.connection.clear_pending_messages()
+    }
+
+    /// Get the current metrics of the client in the Prometheus text exposition format.
+    ///
+    /// The result contains the number of pending [Messages](https://docs.spotflow.io/send-data/#message)
+    /// (`spotflow_queue_depth`), the age of the oldest one in seconds (`spotflow_oldest_message_age_seconds`),
+    /// the total number of sent Messages (`spotflow_messages_sent_total`), and the total number of reconnections
+    /// (`spotflow_reconnects_total`). The totals are counted across all the clients in the process since it started.
+    /// If the local database file can't be read, the metrics that depend on it are omitted.
+    /// The result can be returned as it is from an HTTP endpoint scraped by Prometheus.
+    #[must_use]
+    pub fn metrics_text(&self) -> String {
+        let mut text = String::new();
+
+        let mut write_metric = |name: &str, metric_type: &str, help: &str, value: &dyn Display| {
+            // Writing to a String never fails
+            _ = writeln!(text, "# HELP {name} {help}");
+            _ = writeln!(text, "# TYPE {name} {metric_type}");
+            _ = writeln!(text, "{name} {value}");
+        };
+
+        match self.pending_messages_count() {
+            Ok(depth) => write_metric(
+                "spotflow_queue_depth",
+                "gauge",
+                "The number of Messages waiting to be sent.",
+                &depth,
+            ),
+            Err(e) => warn!("Unable to get the number of pending Messages for metrics: {e:?}"),
+        }
+
+        match self.oldest_pending_message_age() {
+            Ok(age) => write_metric(
+                "spotflow_oldest_message_age_seconds",
+                "gauge",
+                "The time for which the oldest pending Message has been waiting.",
+                &age.unwrap_or_default().as_secs_f64(),
+            ),
+            Err(e) => {
+                warn!("Unable to get the age of the oldest pending Message for metrics: {e:?}");
+            }
+        }
+
+        write_metric(
+            "spotflow_messages_sent_total",
+            "counter",
+            "The number of Messages acknowledged by the Platform.",
+            &metrics::messages_sent_total(),
+        );
+        write_metric(
+            "spotflow_reconnects_total",
+            "counter",
+            "The number of times the connection was established again after it had failed.",
+            &metrics::reconnects_total(),
+        );
+
+        text
+    }
+
+    /// Get the time for which the oldest [Message](https://docs.spotflow.io/send-data/#message) that
+    /// hasn't been sent to the Platform yet has been waiting in the local database file.
+    /// Returns `None` if there are no such [Messages](https://docs.spotflow.io/send-data/#message).
+    pub fn oldest_pending_message_age(&self) -> Result<Option<Duration>> {
+        self.connection.oldest_pending_message_age()
+    }
+
+    /// Get the summaries of the [Batches](https://docs.spotflow.io/send-data/#batch) that have
+    /// [Messages](https://docs.spotflow.io/send-data/#message) waiting in the local database file, starting with the oldest one.
+    /// Messages that don't belong to any Batch are not included.
+    pub fn pending_batches(&self) -> Result<Vec<BatchSummary>> {
+        self.connection.pending_batches()
+    }
+
+    /// Get the fraction of the [Messages](https://docs.spotflow.io/send-data/#message) of the given
+    /// [Batch](https://docs.spotflow.io/send-data/#batch) that were already sent to the Platform, from `0.0` to `1.0`.
+    ///
+    /// The total number of the Messages is known only after the completion of the Batch is enqueued using
+    /// [`DeviceClient::enqueue_batch_completion`], so `None` is returned before that.
+    /// `None` is also returned for unknown Batches and for Batches enqueued by older versions of the Device SDK.
+    /// The progress of a finished Batch is kept only until another Batch is completed.
+    pub fn batch_progress(&self, batch_id: &str) -> Result<Option<f32>> {
+        self.connection.batch_progress(batch_id)
+    }
+
+    /// Write all the [Messages](https://docs.spotflow.io/send-data/#message) that haven't been sent to the Platform yet
+    /// to `writer` and return how many of them were written. The Messages stay in the local database file.
+    ///
+    /// Each Message is written as a JSON object on a separate line. The object contains the
+    /// [Stream Group](https://docs.spotflow.io/send-data/#stream-group), the [Stream](https://docs.spotflow.io/send-data/#stream),
+    /// the IDs, the other options, and the content encoded in base64. If the Message is compressed, the content is
+    /// exported compressed. If a codec is set by [`DeviceClientBuilder::with_payload_codec`], the content is exported
+    /// encoded. Use [`DeviceClient::import_pending_messages`] to enqueue the exported Messages again,
+    /// for example, in another local database file.
+    pub fn export_pending_messages(&self, writer: impl Write) -> Result<usize> {
+        self.connection.export_pending_messages(writer)
+    }
+
+    /// Enqueue the [Messages](https://docs.spotflow.io/send-data/#message) previously written by
+    /// [`DeviceClient::export_pending_messages`] and return how many of them were enqueued.
+    ///
+    /// The Messages are enqueued in the same order as they were exported and in a single transaction. If a line can't be
+    /// parsed, contains invalid properties, or a Message can't be stored, the method fails and none of the Messages are
+    /// enqueued.
+    pub fn import_pending_messages(&self, reader: impl Read) -> Result<usize> {
+        self.connection
+            .import_pending_messages(BufReader::new(reader))
+    }
+
     /// Block the current thread until all the [Messages](https://docs.spotflow.io/send-data/#message) that
     /// have been previously enqueued are sent to the Platform.
     pub fn wait_enqueued_messages_sent(&self) -> Result<()> {
         self.connection.wait_enqueued_messages_sent()
     }
 
+    /// Block the current thread until all the [Messages](https://docs.spotflow.io/send-data/#message) that
+    /// have been previously enqueued are sent to the Platform, or until `timeout` elapses.
+    ///
+    /// Returns `true` if all the Messages were sent and `false` if some of them were still pending when the time ran out.
+    pub fn wait_enqueued_messages_sent_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.connection.wait_enqueued_messages_sent_timeout(timeout)
+    }
+
     /// Get the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties).
     ///
     /// Only the latest version is returned, any versions between the last obtained one and the current one are skipped.
@@ -338,6 +1479,27 @@ impl DeviceClient {
         self.connection.desired_properties()
     }
 
+    /// Request the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties) and
+    /// [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties) from the Platform.
+    ///
+    /// This is useful when the Device suspects that its Desired Properties are stale, for example, after a long period
+    /// without connection. If `wait` is `true`, the method blocks until the response arrives. Otherwise, it returns right
+    /// after the request is enqueued, and the response is processed in the background the same way as the updates
+    /// pushed by the Platform.
+    pub fn refresh_twins(&self, wait: bool) -> Result<()> {
+        self.connection.refresh_twins(wait)
+    }
+
+    /// Get whether the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// have been obtained at least once and handed over to the callback registered by
+    /// [`DeviceClientBuilder::with_desired_properties_updated_callback`].
+    ///
+    /// The method doesn't block, so it can be used to gate the application logic on the configuration being delivered.
+    #[must_use]
+    pub fn desired_properties_initialized(&self) -> bool {
+        self.connection.desired_properties_initialized()
+    }
+
     /// Get the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
     /// if their version is higher than `version`. Otherwise, return `None`.
     ///
@@ -347,6 +1509,49 @@ impl DeviceClient {
         self.connection.desired_properties_if_newer(version)
     }
 
+    /// Get the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// deserialized into `T`, for example, the configuration struct of the application.
+    ///
+    /// Fails with the description of the problem, such as a missing field, if the Desired Properties don't match `T`.
+    /// See [`DeviceClient::desired_properties`] for more details.
+    pub fn desired_properties_as<T: DeserializeOwned>(&self) -> Result<T> {
+        self.desired_properties()?.values_as()
+    }
+
+    /// Get the current [Desired Properties](https://docs.spotflow.io/configure-devices/#desired-properties)
+    /// deserialized into `T` together with their version if the version is higher than `version`. Otherwise, return `None`.
+    ///
+    /// Fails with the description of the problem, such as a missing field, if the Desired Properties don't match `T`.
+    /// See [`DeviceClient::desired_properties_if_newer`] for more details.
+    pub fn desired_properties_as_if_newer<T: DeserializeOwned>(
+        &self,
+        version: u64,
+    ) -> Result<Option<(u64, T)>> {
+        self.desired_properties_if_newer(version)
+            .map(|properties| Ok((properties.version, properties.values_as()?)))
+            .transpose()
+    }
+
+    /// Block the current thread until the value of the given
+    /// [Desired Property](https://docs.spotflow.io/configure-devices/#desired-properties) satisfies `predicate`,
+    /// or until `timeout` elapses.
+    ///
+    /// The names of nested properties in `path` are separated by dots, for example, `serial.baudRate`. The `predicate`
+    /// is called with the current value first and then with each new version of the Desired Properties. If the property
+    /// is missing, the `predicate` isn't called. Only the latest version is checked, any versions between the
+    /// previous one and the current one are skipped.
+    ///
+    /// Returns `true` if the value satisfied the `predicate` and `false` if the time ran out.
+    pub fn wait_desired_property(
+        &self,
+        path: &str,
+        predicate: impl Fn(&serde_json::Value) -> bool,
+        timeout: Duration,
+    ) -> Result<bool> {
+        self.connection
+            .wait_desired_property(path, predicate, timeout)
+    }
+
     /// Enqueue an update of the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
     /// to be sent to the Platform.
     ///
@@ -354,17 +1559,50 @@ impl DeviceClient {
     /// The update will be sent asynchronously when possible.
     /// The update may be sent later depending on Internet connectivity and other factors.
     /// To be sure that it has been sent to the Platform, call [`DeviceClient::any_pending_reported_properties_updates`].
+    /// Use [`ReportedPropertiesBuilder`] to create `properties` without writing the JSON manually.
     pub fn update_reported_properties(&self, properties: &str) -> Result<()> {
         self.connection.update_reported_properties(properties)
     }
 
+    /// Enqueue an update of the [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
+    /// encoded in CBOR to be sent to the Platform.
+    ///
+    /// The Platform stores the Reported Properties in JSON, so `properties` are converted before they're saved.
+    /// Byte strings are encoded in base64. Otherwise, this method behaves like [`DeviceClient::update_reported_properties`].
+    /// Available only with the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn update_reported_properties_cbor(&self, properties: &[u8]) -> Result<()> {
+        let properties = crate::connection::twins::cbor_to_json(properties)?;
+        self.connection.update_reported_properties(&properties)
+    }
+
     /// Get whether are there any updates to [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
     /// that are yet to be sent to the Platform.
     pub fn any_pending_reported_properties_updates(&self) -> Result<bool> {
         self.connection.any_pending_reported_properties_updates()
     }
 
+    /// Subscribe to the count of updates to [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
+    /// that are yet to be sent to the Platform.
+    ///
+    /// The returned receiver starts with the current count and is notified whenever the count changes, for example,
+    /// when an update is enqueued or when the Platform acknowledges it. It keeps working after the client reconnects.
+    /// Unlike [`DeviceClient::any_pending_reported_properties_updates`], it doesn't require polling.
+    pub fn subscribe_reported_pending(&self) -> Result<tokio::sync::watch::Receiver<usize>> {
+        self.connection.subscribe_reported_pending()
+    }
+
+    /// Get the current [Reported Properties](https://docs.spotflow.io/configure-devices/#reported-properties)
+    /// including all the updates that are yet to be sent to the Platform.
+    ///
+    /// Use this method to read back the Reported Properties right after calling [`DeviceClient::update_reported_properties`].
+    /// Returns `None` if the Reported Properties haven't been received from the Platform yet.
+    pub fn reported_properties_with_pending_updates(&self) -> Result<Option<String>> {
+        self.connection.reported_properties_with_pending_updates()
+    }
+
     /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
     #[deprecated]
     #[doc(hidden)]
     pub fn process_c2d<G>(&self, callback: G) -> Result<()>
@@ -375,6 +1613,7 @@ impl DeviceClient {
     }
 
     /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
     #[deprecated]
     #[doc(hidden)]
     pub fn pending_c2d(&self) -> Result<usize> {
@@ -382,10 +1621,53 @@ impl DeviceClient {
     }
 
     /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
     #[deprecated]
     #[doc(hidden)]
     pub fn get_c2d(&self, timeout: Duration) -> Result<CloudToDeviceMessageGuard<'_>> {
-        self.connection.get_c2d(timeout)
+        self.connection.get_c2d(timeout, AckMode::OnDrop)
+    }
+
+    /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
+    #[deprecated]
+    #[doc(hidden)]
+    pub fn get_c2d_with_ack_mode(
+        &self,
+        timeout: Duration,
+        ack_mode: AckMode,
+    ) -> Result<CloudToDeviceMessageGuard<'_>> {
+        self.connection.get_c2d(timeout, ack_mode)
+    }
+
+    /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
+    #[deprecated]
+    #[doc(hidden)]
+    pub fn try_get_c2d(&self) -> Result<Option<CloudToDeviceMessageGuard<'_>>> {
+        self.connection.try_get_c2d(AckMode::OnDrop)
+    }
+
+    /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
+    #[deprecated]
+    #[doc(hidden)]
+    pub fn try_get_c2d_with_ack_mode(
+        &self,
+        ack_mode: AckMode,
+    ) -> Result<Option<CloudToDeviceMessageGuard<'_>>> {
+        self.connection.try_get_c2d(ack_mode)
+    }
+
+    /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+    #[cfg(feature = "c2d")]
+    #[deprecated]
+    #[doc(hidden)]
+    pub fn drain_c2d_matching<F>(&self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&CloudToDeviceMessage) -> bool,
+    {
+        self.connection.drain_c2d_matching(predicate)
     }
 
     /// **Warning**: Deprecated, don't use.
@@ -423,4 +1705,91 @@ mod test {
         check_if_sync::<DeviceClient>();
         check_if_send::<DeviceClient>();
     }
+
+    #[test]
+    fn channel_config_validation() {
+        assert!(ChannelConfig::default().validate().is_ok());
+
+        let config = ChannelConfig {
+            message_buffer_capacity: 0,
+            ..ChannelConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn expiry_is_measured_from_given_time() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut context = MessageContext::default();
+        assert_eq!(context.expiry(now), None);
+
+        context.set_time_to_live(Some(Duration::from_secs(90)));
+        assert_eq!(
+            context.expiry(now),
+            Some(now + chrono::Duration::seconds(90))
+        );
+    }
+
+    #[test]
+    fn payload_encoding_validation() {
+        let mut context = MessageContext::default();
+        assert!(context.check_payload_encoding(&[0xff, 0xfe]).is_ok());
+
+        context.set_require_utf8(true);
+        assert!(context
+            .check_payload_encoding(b"{\"temperature\": 21.5}")
+            .is_ok());
+        assert!(context.check_payload_encoding(&[0xff, 0xfe]).is_err());
+    }
+
+    #[test]
+    fn custom_brotli_compression() {
+        let payload = b"temperature=21.5;".repeat(100);
+        let mut context = MessageContext::default();
+
+        context.set_compression(Some(Compression::Brotli {
+            quality: 5,
+            window: 16,
+        }));
+        let (stored, compression) = context.prepare_payload(payload.clone()).unwrap();
+        assert!(matches!(compression, persistence::Compression::Brotli));
+        assert!(stored.len() < payload.len());
+
+        context.set_compression(Some(Compression::Brotli {
+            quality: 12,
+            window: 16,
+        }));
+        assert!(context.prepare_payload(payload.clone()).is_err());
+
+        context.set_compression(Some(Compression::Fastest));
+        let (stored, compression) = context.prepare_payload(payload.clone()).unwrap();
+        assert!(matches!(
+            compression,
+            persistence::Compression::BrotliFastest
+        ));
+        assert_eq!(stored, payload);
+    }
+
+    #[test]
+    fn precompressed_content() {
+        let payload = b"temperature=21.5;".repeat(100);
+
+        let (stored, compression) =
+            crate::iothub::precompress_content(payload.clone(), persistence::Compression::Gzip)
+                .unwrap();
+        assert!(matches!(
+            compression,
+            persistence::Compression::PrecompressedGzip
+        ));
+        assert!(stored.len() < payload.len());
+
+        // Incompressible content is stored as it is, so the sender doesn't mark it as compressed
+        let (stored, compression) =
+            crate::iothub::precompress_content(vec![42], persistence::Compression::BrotliFastest)
+                .unwrap();
+        assert!(matches!(compression, persistence::Compression::None));
+        assert_eq!(stored, vec![42]);
+    }
 }
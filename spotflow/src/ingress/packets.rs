@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use rumqttc::{Event, Outgoing, Packet};
+use tokio::{runtime::Handle, sync::mpsc};
+
+// The descriptions are dropped when the observer can't keep up so that the event loop never waits for it
+const OBSERVER_CAPACITY: usize = 1024;
+
+/// The direction in which an MQTT packet was transferred, see [`RawPacket`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// The packet was received from the Platform.
+    Incoming,
+    /// The packet was sent to the Platform.
+    Outgoing,
+}
+
+/// A lightweight description of an MQTT packet transferred between the
+/// [Device](https://docs.spotflow.io/connect-devices/#device) and the Platform, see [`RawPacketObserver`].
+#[derive(Clone, Debug)]
+pub struct RawPacket {
+    /// Whether the packet was received or sent.
+    pub direction: PacketDirection,
+    /// The type of the packet, for example, `"Publish"` or `"PubAck"`.
+    pub kind: &'static str,
+    /// The MQTT topic of the packet if it has one.
+    pub topic: Option<String>,
+    /// The MQTT packet identifier if the packet has one.
+    pub pkid: Option<u16>,
+}
+
+/// Observes the MQTT packets transferred between the [Device](https://docs.spotflow.io/connect-devices/#device) and the Platform,
+/// see [`DeviceClientBuilder::with_raw_packet_observer`](super::DeviceClientBuilder::with_raw_packet_observer).
+///
+/// This is only a debugging aid for diagnosing protocol issues, the packets may change between the versions of the Device SDK.
+pub trait RawPacketObserver: Send + Sync {
+    /// Handle the description of a packet that was just received or sent.
+    fn packet_observed(&self, packet: &RawPacket);
+}
+
+// Passes the descriptions of the packets from the event loop to the observer running in a separate thread
+#[derive(Clone, Debug)]
+pub(crate) struct PacketObserverSender {
+    sender: mpsc::Sender<RawPacket>,
+}
+
+impl PacketObserverSender {
+    pub(crate) fn start(observer: Arc<dyn RawPacketObserver>, runtime: &Handle) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<RawPacket>(OBSERVER_CAPACITY);
+
+        runtime.spawn(async move {
+            while let Some(packet) = receiver.recv().await {
+                let observer = observer.clone();
+                if let Err(e) =
+                    tokio::task::spawn_blocking(move || observer.packet_observed(&packet)).await
+                {
+                    warn!("Raw packet observer failed: {e:?}");
+                }
+            }
+        });
+
+        PacketObserverSender { sender }
+    }
+
+    pub(crate) fn observe(&self, event: &Event) {
+        let packet = match event {
+            Event::Incoming(packet) => describe_incoming(packet),
+            Event::Outgoing(packet) => describe_outgoing(packet),
+        };
+
+        // The packet is dropped if the observer is too slow or has already stopped
+        _ = self.sender.try_send(packet);
+    }
+}
+
+fn describe_incoming(packet: &Packet) -> RawPacket {
+    let (kind, topic, pkid) = match packet {
+        Packet::Connect(_) => ("Connect", None, None),
+        Packet::ConnAck(_) => ("ConnAck", None, None),
+        Packet::Publish(publish) => ("Publish", Some(publish.topic.clone()), Some(publish.pkid)),
+        Packet::PubAck(ack) => ("PubAck", None, Some(ack.pkid)),
+        Packet::PubRec(rec) => ("PubRec", None, Some(rec.pkid)),
+        Packet::PubRel(rel) => ("PubRel", None, Some(rel.pkid)),
+        Packet::PubComp(comp) => ("PubComp", None, Some(comp.pkid)),
+        Packet::Subscribe(subscribe) => ("Subscribe", None, Some(subscribe.pkid)),
+        Packet::SubAck(ack) => ("SubAck", None, Some(ack.pkid)),
+        Packet::Unsubscribe(unsubscribe) => ("Unsubscribe", None, Some(unsubscribe.pkid)),
+        Packet::UnsubAck(ack) => ("UnsubAck", None, Some(ack.pkid)),
+        Packet::PingReq => ("PingReq", None, None),
+        Packet::PingResp => ("PingResp", None, None),
+        Packet::Disconnect => ("Disconnect", None, None),
+    };
+
+    RawPacket {
+        direction: PacketDirection::Incoming,
+        kind,
+        topic,
+        pkid,
+    }
+}
+
+fn describe_outgoing(packet: &Outgoing) -> RawPacket {
+    let (kind, topic, pkid) = match packet {
+        Outgoing::Publish(pkid, topic) => ("Publish", Some(topic.clone()), Some(*pkid)),
+        Outgoing::Subscribe(pkid) => ("Subscribe", None, Some(*pkid)),
+        Outgoing::Unsubscribe(pkid) => ("Unsubscribe", None, Some(*pkid)),
+        Outgoing::PubAck(pkid) => ("PubAck", None, Some(*pkid)),
+        Outgoing::PubRec(pkid) => ("PubRec", None, Some(*pkid)),
+        Outgoing::PubRel(pkid) => ("PubRel", None, Some(*pkid)),
+        Outgoing::PubComp(pkid) => ("PubComp", None, Some(*pkid)),
+        Outgoing::PingReq => ("PingReq", None, None),
+        Outgoing::PingResp => ("PingResp", None, None),
+        Outgoing::Disconnect => ("Disconnect", None, None),
+        Outgoing::AwaitAck(pkid) => ("AwaitAck", None, Some(*pkid)),
+    };
+
+    RawPacket {
+        direction: PacketDirection::Outgoing,
+        kind,
+        topic,
+        pkid,
+    }
+}
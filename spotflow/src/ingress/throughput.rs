@@ -0,0 +1,86 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+// Only the Messages sent in this window count, older ones don't say anything about the current rate
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+// The Messages are counted in buckets of this length so that the memory doesn't grow with the rate
+const BUCKET: Duration = Duration::from_secs(1);
+
+// Collects the Messages sent by all the connections of a client to compute the moving average of the sending rate
+#[derive(Debug, Default)]
+pub(crate) struct SendRate {
+    buckets: Mutex<VecDeque<(Instant, usize)>>,
+}
+
+fn forget_old_buckets(buckets: &mut VecDeque<(Instant, usize)>, now: Instant) {
+    while let Some((start, _)) = buckets.front() {
+        if now.duration_since(*start) <= RATE_WINDOW {
+            break;
+        }
+        buckets.pop_front();
+    }
+}
+
+impl SendRate {
+    pub(crate) fn sent(&self) {
+        self.sent_at(Instant::now());
+    }
+
+    fn sent_at(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        forget_old_buckets(&mut buckets, now);
+
+        match buckets.back_mut() {
+            Some((start, count)) if now.duration_since(*start) < BUCKET => *count += 1,
+            _ => buckets.push_back((now, 1)),
+        }
+    }
+
+    // The time to send the given number of Messages at the recent rate, `None` if no Message was sent recently
+    pub(crate) fn drain_time(&self, pending: usize) -> Option<Duration> {
+        self.drain_time_at(pending, Instant::now())
+    }
+
+    fn drain_time_at(&self, pending: usize, now: Instant) -> Option<Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        forget_old_buckets(&mut buckets, now);
+
+        let (oldest, _) = buckets.front()?;
+        // Sending started only recently if the window isn't full yet, so the rate is computed from the elapsed time
+        let elapsed = now.duration_since(*oldest).max(BUCKET);
+        let sent = buckets.iter().map(|(_, count)| count).sum::<usize>();
+
+        // The counts never get anywhere near 2^52
+        #[allow(clippy::cast_precision_loss)]
+        let rate = sent as f64 / elapsed.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        Some(Duration::from_secs_f64(pending as f64 / rate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drain_time_from_recent_rate() {
+        let rate = SendRate::default();
+        let start = Instant::now();
+        assert_eq!(rate.drain_time_at(10, start), None);
+
+        // 20 Messages in 10 seconds
+        for i in 0..20 {
+            rate.sent_at(start + Duration::from_millis(i * 500));
+        }
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(rate.drain_time_at(10, now), Some(Duration::from_secs(5)));
+        assert_eq!(rate.drain_time_at(0, now), Some(Duration::ZERO));
+
+        // The rate isn't known once the window passes without sending anything
+        assert_eq!(rate.drain_time_at(10, now + RATE_WINDOW * 2), None);
+    }
+}
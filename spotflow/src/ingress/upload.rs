@@ -0,0 +1,71 @@
+use anyhow::Result;
+
+use super::DeviceClient;
+
+/// A [Message](https://docs.spotflow.io/send-data/#message) split into chunks by [`DeviceClient::upload_chunked`].
+///
+/// Use it to monitor how many of the chunks were already sent to the Platform.
+pub struct ChunkedUpload<'a> {
+    client: &'a DeviceClient,
+    batch_id: String,
+    message_id: String,
+    chunk_count: usize,
+}
+
+impl<'a> ChunkedUpload<'a> {
+    pub(super) fn new(
+        client: &'a DeviceClient,
+        batch_id: String,
+        message_id: String,
+        chunk_count: usize,
+    ) -> Self {
+        ChunkedUpload {
+            client,
+            batch_id,
+            message_id,
+            chunk_count,
+        }
+    }
+
+    /// Get the ID of the [Batch](https://docs.spotflow.io/send-data/#batch) the
+    /// [Message](https://docs.spotflow.io/send-data/#message) belongs to.
+    #[must_use]
+    pub fn batch_id(&self) -> &str {
+        &self.batch_id
+    }
+
+    /// Get the ID of the uploaded [Message](https://docs.spotflow.io/send-data/#message).
+    #[must_use]
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// Get the number of chunks the [Message](https://docs.spotflow.io/send-data/#message) was split into.
+    #[must_use]
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_count
+    }
+
+    /// Get the number of the chunks that are still waiting in the local database file, including
+    /// the completion of the [Message](https://docs.spotflow.io/send-data/#message).
+    pub fn pending_parts(&self) -> Result<usize> {
+        self.client
+            .connection
+            .pending_message_parts(&self.batch_id, &self.message_id)
+    }
+
+    /// Get the fraction of the chunks that were already sent to the Platform, from `0.0` to `1.0`.
+    /// The completion of the [Message](https://docs.spotflow.io/send-data/#message) counts as one more chunk.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn progress(&self) -> Result<f32> {
+        let total = self.chunk_count + 1;
+        let sent = total.saturating_sub(self.pending_parts()?);
+        Ok(sent as f32 / total as f32)
+    }
+
+    /// Get whether all the chunks and the completion of the [Message](https://docs.spotflow.io/send-data/#message)
+    /// were sent to the Platform.
+    pub fn is_sent(&self) -> Result<bool> {
+        Ok(self.pending_parts()? == 0)
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
-    collections::HashSet,
+    io,
+    ops::ControlFlow,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -17,7 +18,13 @@ use tokio_util::sync::CancellationToken;
 
 use super::token_handler::{RegistrationCommand, RegistrationCommandSender, RegistrationWatch};
 use super::topics;
+use crate::ingress::link::LinkMonitor;
+#[cfg(feature = "packet-observer")]
+use crate::ingress::packets::PacketObserverSender;
+use crate::ingress::{EventPublisher, SdkEvent};
 use crate::persistence::Acknowledger;
+use crate::utils::clock::Clock;
+use crate::utils::metrics;
 
 use super::{
     handlers::{AsyncHandler, Handler},
@@ -38,7 +45,7 @@ impl SubscriptionTask {
             acked += self.receiver.recv().await.map_err(|_|
                 anyhow!("Channel for subscribe acknowledgements was closed before startup finished, possibly because subscription failed.")
             )?;
-            log::debug!("Subscription {acked}/{} acknowledged", self.total);
+            debug!("Subscription {acked}/{} acknowledged", self.total);
         }
 
         Ok(())
@@ -48,27 +55,43 @@ impl SubscriptionTask {
 pub(super) struct EventLoop {
     device_id: String,
     state: watch::Sender<State>,
-    pending_d2c: HashSet<u16>,
     suback_sender: broadcast::Sender<usize>,
     registration_watch: RegistrationWatch,
     registration_command_sender: RegistrationCommandSender,
     acknowledger: Acknowledger,
+    events: EventPublisher,
     cancellation: CancellationToken,
     rumqttc_eventloop: rumqttc::EventLoop,
     publish_handlers: Vec<Box<dyn Handler + Send + Sync>>,
     async_publish_handlers: Vec<Box<dyn AsyncHandler + Send + Sync>>,
+    max_reconnect_attempts: Option<u32>,
+    // The time after which an unacknowledged device-to-cloud message causes a reconnect
+    publish_timeout: Duration,
+    // The number of connection errors since the connection was last established
+    failed_attempts: u32,
+    link_monitor: Arc<LinkMonitor>,
+    // The registration worker compares the time of a refresh request with its own clock
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "packet-observer")]
+    packet_observer: Option<PacketObserverSender>,
 }
 
 impl EventLoop {
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         rumqttc_eventloop: rumqttc::EventLoop,
         registration_watch: RegistrationWatch,
         registration_command_sender: RegistrationCommandSender,
         acknowledger: Acknowledger,
+        state_sender: watch::Sender<State>,
+        events: EventPublisher,
+        max_reconnect_attempts: Option<u32>,
+        publish_timeout: Duration,
+        link_monitor: Arc<LinkMonitor>,
+        clock: Arc<dyn Clock>,
         cancellation: CancellationToken,
     ) -> Self {
         let (suback_sender, _) = broadcast::channel(10);
-        let (state_sender, _) = watch::channel(State::Ready);
 
         let registration = registration_watch.borrow();
         let device_id = registration
@@ -84,11 +107,18 @@ impl EventLoop {
             state: state_sender,
             suback_sender,
 
-            pending_d2c: HashSet::new(),
             publish_handlers: Vec::new(),
             async_publish_handlers: Vec::new(),
+            max_reconnect_attempts,
+            publish_timeout,
+            failed_attempts: 0,
+            link_monitor,
+            clock,
+            #[cfg(feature = "packet-observer")]
+            packet_observer: None,
 
             acknowledger,
+            events,
             rumqttc_eventloop,
             registration_watch,
             registration_command_sender,
@@ -100,6 +130,11 @@ impl EventLoop {
         self.state.subscribe()
     }
 
+    #[cfg(feature = "packet-observer")]
+    pub(super) fn set_packet_observer(&mut self, packet_observer: PacketObserverSender) {
+        self.packet_observer = Some(packet_observer);
+    }
+
     pub(super) fn register_handler(&mut self, handler: impl Handler + Send + Sync + 'static) {
         self.publish_handlers.push(Box::new(handler));
     }
@@ -140,31 +175,100 @@ impl EventLoop {
 
     pub(super) async fn run(&mut self) {
         loop {
+            let publish_deadline = self
+                .acknowledger
+                .oldest_unacknowledged()
+                .map(|published| published + self.publish_timeout);
             select! {
                 () = self.cancellation.cancelled() => {
-                    log::debug!("Stopping MQTT because of cancellation");
+                    debug!("Stopping MQTT because of cancellation");
                     break;
                 },
-                notification = self.rumqttc_eventloop.poll() => self.process_notification(notification).await,
+                notification = self.rumqttc_eventloop.poll() => {
+                    if self.process_notification(notification).await.is_break() {
+                        break;
+                    }
+                },
+                () = Self::sleep_until(publish_deadline) => {
+                    self.reconnect_stuck_publish();
+                },
             }
         }
     }
 
-    async fn process_notification(&mut self, notification: Result<Event, ConnectionError>) {
+    // Never finishes if there is no deadline
+    async fn sleep_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    // Drops the current connection so that the next poll establishes a new one and retransmits the unacknowledged messages
+    fn reconnect_stuck_publish(&mut self) {
+        warn!(
+            "A device-to-cloud message has not been acknowledged within {} seconds, reconnecting to IoT Hub.",
+            self.publish_timeout.as_secs()
+        );
+        self.state
+            .send_replace(State::ConnectionError(Arc::new(ConnectionError::Io(
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for a message acknowledgment",
+                ),
+            ))));
+        self.drop_connection();
+        // The messages are published again on the new connection, so their timeouts start over
+        self.acknowledger.restart_publish_timeouts();
+    }
+
+    // rumqttc doesn't expose dropping the connection, so its event loop is replaced by a new one that isn't connected yet.
+    // The new event loop keeps the channel of the requests from the client and starts with the unacknowledged packets.
+    fn drop_connection(&mut self) {
+        let previous = &mut self.rumqttc_eventloop;
+        // The packets left from an earlier connection go first, they are older
+        let mut pending = previous.pending.by_ref().collect::<Vec<_>>();
+        pending.extend(previous.state.clean());
+
+        let mut rumqttc_eventloop = rumqttc::EventLoop::new(previous.options.clone(), 1);
+        rumqttc_eventloop.requests_tx = previous.requests_tx.clone();
+        rumqttc_eventloop.requests_rx = previous.requests_rx.clone();
+        rumqttc_eventloop.pending = pending.into_iter();
+
+        // Dropping the previous event loop closes its connection
+        self.rumqttc_eventloop = rumqttc_eventloop;
+    }
+
+    async fn process_notification(
+        &mut self,
+        notification: Result<Event, ConnectionError>,
+    ) -> ControlFlow<()> {
         match notification {
             Ok(event) => {
+                #[cfg(feature = "packet-observer")]
+                if let Some(packet_observer) = &self.packet_observer {
+                    packet_observer.observe(&event);
+                }
                 match event {
                     Event::Incoming(inner) => self.process_incoming_message(inner).await,
                     Event::Outgoing(inner) => self.process_outgoing_message(inner),
                 };
             }
             Err(e) => {
-                log::debug!("Error in MQTT: {e:?}");
+                debug!("Error in MQTT: {e:?}");
                 let e = Arc::new(e);
                 self.state.send_replace(State::ConnectionError(e.clone()));
                 if self.cancellation.is_cancelled() {
-                    log::info!("Shutting down during errored state because of cancellation.");
-                    return;
+                    info!("Shutting down during errored state because of cancellation.");
+                    return ControlFlow::Continue(());
+                }
+                self.failed_attempts += 1;
+                if let Some(max_reconnect_attempts) = self.max_reconnect_attempts {
+                    if self.failed_attempts > max_reconnect_attempts {
+                        error!("Giving up on connecting to IoT Hub after {max_reconnect_attempts} reconnect attempts: {e:?}");
+                        self.state.send_replace(State::Failed(e));
+                        return ControlFlow::Break(());
+                    }
                 }
                 // This panics if the TokenHandler has already failed
                 if self
@@ -172,7 +276,7 @@ impl EventLoop {
                     .has_changed()
                     .expect("Unable to get registration updates")
                 {
-                    log::debug!("Updating IoT Hub authentication.");
+                    debug!("Updating IoT Hub authentication.");
                     let (username, _) = self
                         .rumqttc_eventloop
                         .options
@@ -197,26 +301,33 @@ impl EventLoop {
                     {
                         match self.registration_command_sender.send(
                             RegistrationCommand::RefreshRegistration {
-                                time: Instant::now(),
+                                time: self.clock.now_instant(),
                             },
                         ) {
-                            Ok(()) => log::debug!("Requesting IoT Hub authentication refresh."),
-                            Err(e) => log::error!(
-                                "Unable to request IoT Hub authentication refresh: {e:?}"
-                            ),
+                            Ok(()) => debug!("Requesting IoT Hub authentication refresh."),
+                            Err(e) => {
+                                error!("Unable to request IoT Hub authentication refresh: {e:?}");
+                            }
                         }
                     }
-                    log::debug!("5 second backoff for eventloop to self-heal.");
+                    debug!("5 second backoff for eventloop to self-heal.");
                     // We will wait and hope everything will sort itself out
                     tokio::time::sleep(Duration::from_secs(5)).await;
                 }
             }
         }
+
+        ControlFlow::Continue(())
     }
 
     async fn process_incoming_message(&mut self, packet: Packet) {
-        log::trace!("Received = {:?}", packet);
-        self.state.send_replace(State::Ready);
+        trace!("Received = {:?}", packet);
+        let previous_state = self.state.send_replace(State::Ready);
+        if let State::ConnectionError(_) = previous_state {
+            metrics::reconnected();
+            self.link_monitor.reconnected();
+        }
+        self.failed_attempts = 0;
         match packet {
             Packet::Publish(publish) => {
                 for handler in &mut self.async_publish_handlers {
@@ -235,22 +346,26 @@ impl EventLoop {
                         }
                     }
                 }
-                log::warn!(
+                warn!(
                     "Ignoring message received on unexpected topic {:?}",
                     &publish.topic,
                 );
             }
             Packet::PubAck(ack) => {
-                if self.pending_d2c.contains(&ack.pkid) {
-                    // We are going to assume that IoT Hub confirms messages in order.
-                    // This seems to not be a hard requirement by MQTT but seems to be safe to do.
-                    // We are sending messages in order they were saved in SQLite and we depend on AUTOINCREMEMNT.
-                    log::trace!("Got acknowledgment for device-to-cloud message");
-                    if let Err(e) = self.acknowledger.remove_oldest().await {
-                        log::error!("Unable to remove acknowledged device-to-cloud message. This or subsequent messages may be duplicated and received at a later time. Inner: {}", e);
+                // The messages are matched by their packet IDs because unordered messages may be acknowledged in any order
+                match self.acknowledger.acknowledge(ack.pkid).await {
+                    Ok(Some(latency)) => {
+                        trace!("Got acknowledgment for device-to-cloud message");
+                        self.link_monitor.acknowledged(latency);
+                        self.events.publish(SdkEvent::MessageSent);
+                    }
+                    // We got PUBACK for stuff like reported properties update -- we can ignore these here
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Unable to remove acknowledged device-to-cloud message. This message may be duplicated and received at a later time. Inner: {}", e);
+                        self.events.publish(SdkEvent::MessageSent);
                     }
                 }
-                // Else we got PUBACK for stuff like reported properties update -- we can ignore these here
             }
             Packet::SubAck(ack) => {
                 if ack
@@ -259,11 +374,11 @@ impl EventLoop {
                     .any(|r| *r == SubscribeReasonCode::Failure)
                 {
                     // We just log here but we ignore the subscription failure. Worst case we do not receive C2D or other messages but the rest of SDK will continue working
-                    log::warn!("Unable to subscribe to some topics");
+                    warn!("Unable to subscribe to some topics");
                 }
                 // Ignore errors -- if this fails the client has already shut down, it is not interested in this, and the event loop will soon shutdown too
                 let new_subscription_cnt = ack.return_codes.len();
-                log::debug!("Subscribed to {new_subscription_cnt} additional topics");
+                debug!("Subscribed to {new_subscription_cnt} additional topics");
                 _ = self.suback_sender.send(new_subscription_cnt);
             }
             Packet::UnsubAck(_) => todo!(),
@@ -283,17 +398,17 @@ impl EventLoop {
     }
 
     fn process_outgoing_message(&mut self, packet: Outgoing) {
-        log::trace!("Sending = {:?}", packet);
+        trace!("Sending = {:?}", packet);
         match packet {
             // If we sent disconnect we are shutting down so the task may end
             // No more MQTT messages may be processed anyway
             Outgoing::Disconnect => {
-                log::debug!("Stopping MQTT because of disconnect packet");
+                debug!("Stopping MQTT because of disconnect packet");
                 self.cancellation.cancel();
             }
             Outgoing::Publish(publish, topic) => {
                 if topic.starts_with(&topics::publish_topic(&self.device_id)) {
-                    self.pending_d2c.insert(publish);
+                    self.acknowledger.packet_published(publish);
                 }
                 // Else this is request-response type of exchange such as reported properties update
                 // We do not care about packet IDs or anything like that
@@ -308,7 +423,7 @@ impl EventLoop {
                 unreachable!("Azure IoT Hub does not support QoS 2")
             }
             Outgoing::AwaitAck(_) => {
-                log::warn!("MQTT is blocking until an out-of-order message is acknowledged.");
+                warn!("MQTT is blocking until an out-of-order message is acknowledged.");
             }
             Outgoing::PubAck(_) | Outgoing::PingReq | Outgoing::PingResp => {}
         }
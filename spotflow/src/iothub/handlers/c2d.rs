@@ -38,7 +38,7 @@ impl AsyncHandler for CloudToDeviceHandler {
         // devices/{device_id}/messages/devicebound/{property_bag}
 
         let topic = &publish.topic;
-        log::debug!("Received C2D message on topic {topic}");
+        debug!("Received C2D message on topic {topic}");
 
         let Some(properties) = publish.topic.strip_prefix(&self.c2d_prefix) else {
             // Ignore malformed requests
@@ -46,7 +46,7 @@ impl AsyncHandler for CloudToDeviceHandler {
         };
 
         let Ok(properties) = query::parse(properties).inspect_err(|e| {
-            log::error!("Failed parsing cloud to device message topic `{topic}`: {e:?}");
+            error!("Failed parsing cloud to device message topic `{topic}`: {e:?}");
         }) else {
             return;
         };
@@ -61,7 +61,7 @@ impl AsyncHandler for CloudToDeviceHandler {
 
         if let Err(e) = self.producer.send(&msg).await {
             // Not much we can do about this. The message will be ignored and lost.
-            log::error!(
+            error!(
                 "Cannot store a cloud-to-device message. It will not be processed: {}",
                 e
             );
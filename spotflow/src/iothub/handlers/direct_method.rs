@@ -29,7 +29,7 @@ impl DirectMethodHandler {
     {
         let (sender, receiver) = mpsc::sync_channel::<Invocation>(50);
 
-        log::debug!("Starting direct method processing thread");
+        debug!("Starting direct method processing thread");
         // This is thread and not a simple Tokio task because the handler could potentially block
         // The thread ends when the channel sender is dropped (and all methods are done).
         let thread = thread::spawn({
@@ -45,13 +45,10 @@ impl DirectMethodHandler {
                     match result {
                         Err(cause) => {
                             if let Some(s) = cause.downcast_ref::<&'static str>() {
-                                log::error!("Direct method processing failed with panic: {}", s);
+                                error!("Direct method processing failed with panic: {}", s);
                             }
                             if let Some(s) = cause.downcast_ref::<String>() {
-                                log::error!(
-                                    "Direct method message processing failed with panic: {}",
-                                    s
-                                );
+                                error!("Direct method message processing failed with panic: {}", s);
                             }
                         }
                         Ok((status, payload)) => {
@@ -61,7 +58,7 @@ impl DirectMethodHandler {
                     }
                 }
 
-                log::debug!("Direct method handler is stopping.");
+                debug!("Direct method handler is stopping.");
             }
         });
 
@@ -84,7 +81,7 @@ impl Handler for DirectMethodHandler {
         // $iothub/methods/POST/{method name}/?$rid={request id}
         let topic = &publish.topic;
 
-        log::debug!("Received direct method call on topic {topic}");
+        debug!("Received direct method call on topic {topic}");
 
         // Because the method name may contain slashes we need to look for `/` from the right
         let Some(topic_without_prefix) = topic.strip_prefix(topics::METHODS_PREFIX) else {
@@ -104,17 +101,17 @@ impl Handler for DirectMethodHandler {
         let properties = match query::parse(properties) {
             Ok(properties) => properties,
             Err(e) => {
-                log::error!("Failed parsing method call topic `{topic}`: {e:?}");
+                error!("Failed parsing method call topic `{topic}`: {e:?}");
                 return;
             }
         };
 
         let Some(Some(request_id)) = properties.get("$rid") else {
-            log::error!("Request ID is missing in method call on topic `{topic}`");
+            error!("Request ID is missing in method call on topic `{topic}`");
             return;
         };
 
-        log::debug!("Invoking method named {method_name}");
+        debug!("Invoking method named {method_name}");
 
         match self
             .sender
@@ -126,9 +123,9 @@ impl Handler for DirectMethodHandler {
                 request_id: request_id.to_owned(),
             }) {
                 Err(TrySendError::Full(invocation)) =>
-                    log::warn!("Received unexpectedly many direct method calls before they could be processed. Ignoring call to {} with request ID {}.", invocation.method_name, invocation.request_id),
+                    warn!("Received unexpectedly many direct method calls before they could be processed. Ignoring call to {} with request ID {}.", invocation.method_name, invocation.request_id),
                 Err(TrySendError::Disconnected(invocation)) =>
-                    log::error!("Received direct method call after processor shut down. Ignoring call to {} with request ID {}.", invocation.method_name, invocation.request_id),
+                    error!("Received direct method call after processor shut down. Ignoring call to {} with request ID {}.", invocation.method_name, invocation.request_id),
                 Ok(()) => {},
         }
     }
@@ -148,14 +145,14 @@ fn join<T>(handle: &mut Option<JoinHandle<T>>) {
         let thread = handle.thread();
         let id = thread.id();
         let name = thread.name().map(ToString::to_string).unwrap_or_default();
-        log::trace!("Joining thread {:?} named `{}`", id, name);
+        trace!("Joining thread {:?} named `{}`", id, name);
         if let Err(cause) = handle.join() {
             if let Some(s) = cause.downcast_ref::<&'static str>() {
-                log::error!("Thread `{}` failed with panic: {}", name, s,);
+                error!("Thread `{}` failed with panic: {}", name, s,);
             } else if let Some(s) = cause.downcast_ref::<String>() {
-                log::error!("Thread `{}` failed with panic: {}", name, s,);
+                error!("Thread `{}` failed with panic: {}", name, s,);
             } else {
-                log::error!("Thread `{}` failed with panic that is not a string.", name,);
+                error!("Thread `{}` failed with panic that is not a string.", name,);
             }
         }
     }
@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 use rumqttc::Publish;
 
+#[cfg(feature = "c2d")]
 pub(super) mod c2d;
 pub(super) mod direct_method;
 pub(super) mod twins;
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::connection::twins::TwinsClient;
+use crate::connection::twins::{ReportedPropertiesSentCallback, TwinsClient};
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use rumqttc::{AsyncClient, Publish};
@@ -55,12 +55,37 @@ impl AsyncHandler for TwinsHandler {
                 _ = self.response_channel.send(publish.clone()).await;
             }
             topic => {
-                log::error!("Unhandled topic {}", topic);
+                error!("Unhandled topic {}", topic);
             }
         }
     }
 }
 
+// Calls the user callback about the confirmed updates of Reported Properties in a separate thread
+#[derive(Clone)]
+pub(crate) struct ReportedPropertiesSentNotifier {
+    callback: Arc<dyn ReportedPropertiesSentCallback>,
+}
+
+impl ReportedPropertiesSentNotifier {
+    pub(crate) fn new(callback: Arc<dyn ReportedPropertiesSentCallback>) -> Self {
+        ReportedPropertiesSentNotifier { callback }
+    }
+
+    fn notify(&self, update: &ReportedPropertiesUpdate) {
+        let callback = self.callback.clone();
+        let properties = update.patch.to_string();
+        tokio::task::spawn_blocking(move || callback.reported_properties_sent(&properties));
+    }
+}
+
+impl std::fmt::Debug for ReportedPropertiesSentNotifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReportedPropertiesSentNotifier")
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug)]
 enum ResponseType {
     PatchReportedProperties(ReportedPropertiesUpdate),
@@ -87,6 +112,7 @@ pub(crate) struct TwinsMiddleware {
     was_disconnected: bool,
 
     reported_properties_updates: sqlite_channel::Receiver<ReportedPropertiesUpdate>,
+    reported_properties_sent: Option<ReportedPropertiesSentNotifier>,
     get_twins: mpsc::Receiver<()>,
     desired_properties_updates: mpsc::Receiver<Publish>,
     desired_properties_changed: watch::Sender<u64>,
@@ -102,6 +128,7 @@ impl TwinsMiddleware {
         twins: IotHubTwinsClient,
         get_twins: mpsc::Receiver<()>,
         reported_properties_updates: sqlite_channel::Receiver<ReportedPropertiesUpdate>,
+        reported_properties_sent: Option<ReportedPropertiesSentNotifier>,
         desired_properties_updates: mpsc::Receiver<Publish>,
         desired_properties_changed: watch::Sender<u64>,
         response_channel: mpsc::Receiver<Publish>,
@@ -116,6 +143,7 @@ impl TwinsMiddleware {
             was_disconnected: false,
             get_twins,
             reported_properties_updates,
+            reported_properties_sent,
             desired_properties_updates,
             desired_properties_changed,
             response_channel,
@@ -143,7 +171,7 @@ impl TwinsMiddleware {
                     self.handle_connection_state_change(&state).await.context("Handling connection state change failed")
                 }
             ) {
-                log::error!("Failed processing twin message: {:?}", e);
+                error!("Failed processing twin message: {:?}", e);
             }
         }
     }
@@ -179,7 +207,7 @@ impl TwinsMiddleware {
             .await
             .insert(rid.clone(), ResponseType::PatchReportedProperties(update));
 
-        log::debug!("Updating reported properties with request ID {rid}");
+        debug!("Updating reported properties with request ID {rid}");
         self.mqtt_client
             .try_publish(
                 topics::patch_reported_properties(&rid),
@@ -190,7 +218,7 @@ impl TwinsMiddleware {
             .context("Unable to enqueue publish to update reported properties")?;
 
         if let Err(e) = self.twins.update_reported_properties(&patch).await {
-            log::warn!("There was an error during updating local copy of reported properties. Requesting full copy. Original error: {:?}", e);
+            warn!("There was an error during updating local copy of reported properties. Requesting full copy. Original error: {:?}", e);
             self.get_twins().await.context("Error during requesting full twin update because of failed local reported properties update")?;
         }
 
@@ -203,7 +231,7 @@ impl TwinsMiddleware {
             .lock()
             .await
             .insert(rid.clone(), ResponseType::GetTwins);
-        log::debug!("Requesting device twins with request ID {rid}");
+        debug!("Requesting device twins with request ID {rid}");
         self.mqtt_client
             .try_publish(
                 topics::get_twins(&rid),
@@ -236,7 +264,7 @@ impl TwinsMiddleware {
 
         let topic = &publish.topic;
 
-        log::debug!("Received device twin desired properties update on topic {topic}");
+        debug!("Received device twin desired properties update on topic {topic}");
         let Ok(parts): Result<[_; 6], _> = topic.split('/').collect::<Vec<_>>().try_into() else {
             bail!("Received message on invalid topic '{topic}'.");
         };
@@ -271,7 +299,7 @@ impl TwinsMiddleware {
         if let Err(PropertiesUpdateError::PatchVersionMismatch { .. }) =
             self.update_desired_properties(version, payload).await
         {
-            log::info!("Received invalid desired properties update. Requesting full twin update.");
+            info!("Received invalid desired properties update. Requesting full twin update.");
             self.get_twins().await?;
         }
 
@@ -283,15 +311,14 @@ impl TwinsMiddleware {
         // $iothub/twin/res/{status}/?$rid={request id}
         let topic = &publish.topic;
 
-        log::debug!("Received device twin desired properties or reported properties change result on topic {topic}");
+        debug!("Received device twin desired properties or reported properties change result on topic {topic}");
         let Ok(parts): Result<[_; 5], _> = topic.split('/').collect::<Vec<_>>().try_into() else {
             bail!("Received message on an invalid topic '{topic}'.");
         };
 
-        let status = parts[3];
-        if status.parse::<usize>().is_err() {
+        let Ok(status) = parts[3].parse::<usize>() else {
             bail!("Received message on an invalid topic '{topic}'.");
-        }
+        };
 
         let Some(properties) = parts[4].strip_prefix('?') else {
             bail!("Received message with malformed properties '{}'.", parts[4]);
@@ -308,17 +335,26 @@ impl TwinsMiddleware {
 
         match self.requests.lock().await.remove(&request_id) {
             None => {
-                log::warn!("Ignoring response to request `{}`", request_id);
+                warn!("Ignoring response to request `{}`", request_id);
             }
             Some(ResponseType::GetTwins) => self
                 .set_twins(publish.payload.as_ref())
                 .await
                 .context("Failed setting twins")?,
-            Some(ResponseType::PatchReportedProperties(update)) => self
-                .reported_properties_updates
-                .ack(&update)
-                .await
-                .context("Failed removing reported properties update request")?,
+            Some(ResponseType::PatchReportedProperties(update)) => {
+                self.reported_properties_updates
+                    .ack(&update)
+                    .await
+                    .context("Failed removing reported properties update request")?;
+
+                if (200..300).contains(&status) {
+                    if let Some(notifier) = &self.reported_properties_sent {
+                        notifier.notify(&update);
+                    }
+                } else {
+                    warn!("The Platform rejected the reported properties update `{request_id}` with status {status}");
+                }
+            }
         }
 
         Ok(())
@@ -335,14 +371,15 @@ impl TwinsMiddleware {
 
     async fn handle_connection_state_change(&mut self, state: &State) -> Result<()> {
         match state {
+            State::Connecting => {}
             State::Ready => {
                 if self.was_disconnected {
-                    log::info!("Reconnected. Requesting full twin update.");
+                    info!("Reconnected. Requesting full twin update.");
                     self.get_twins().await?;
                     self.was_disconnected = false;
                 }
             }
-            State::ConnectionError(_) => {
+            State::ConnectionError(_) | State::Failed(_) => {
                 self.was_disconnected = true;
             }
         }
@@ -50,6 +50,54 @@ fn diff_objects(
     Ok(Some(serde_json::Value::Object(result)))
 }
 
+// Lists the paths of the values that were added, removed, or changed, the names of nested properties are separated by dots
+// The property names of a Device Twin can't contain dots, so the paths are unambiguous
+pub(crate) fn changed_paths(
+    original: &serde_json::Value,
+    desired: &serde_json::Value,
+) -> Vec<String> {
+    let mut paths = Vec::new();
+    collect_changed_paths(original, desired, "", &mut paths);
+    paths
+}
+
+fn collect_changed_paths(
+    original: &serde_json::Value,
+    desired: &serde_json::Value,
+    prefix: &str,
+    paths: &mut Vec<String>,
+) {
+    if original == desired {
+        return;
+    }
+
+    let (Some(original), Some(desired)) = (original.as_object(), desired.as_object()) else {
+        paths.push(prefix.to_owned());
+        return;
+    };
+
+    let child_path = |name: &str| {
+        if prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{prefix}.{name}")
+        }
+    };
+
+    for (name, desired_child) in desired {
+        match original.get(name) {
+            None => paths.push(child_path(name)),
+            Some(original_child) => {
+                collect_changed_paths(original_child, desired_child, &child_path(name), paths);
+            }
+        }
+    }
+
+    for name in original.keys().filter(|name| !desired.contains_key(*name)) {
+        paths.push(child_path(name));
+    }
+}
+
 #[cfg(test)]
 mod test {
     use serde_json::json;
@@ -196,4 +244,19 @@ mod test {
         json_patch::merge(&mut x, &diff);
         assert_eq!(y, x);
     }
+
+    #[test]
+    fn changed_paths_same() {
+        let x = json!({"a": "a", "b": {"c": "c"}});
+        assert!(changed_paths(&x, &x.clone()).is_empty());
+    }
+
+    #[test]
+    fn changed_paths_nested() {
+        let x = json!({"a": "a", "b": {"c": "c", "d": "d"}, "e": "e"});
+        let y = json!({"a": "a", "b": {"c": "x", "d": "d"}, "f": {"g": "g"}});
+        let mut paths = changed_paths(&x, &y);
+        paths.sort();
+        assert_eq!(paths, vec!["b.c", "e", "f"]);
+    }
 }
@@ -2,11 +2,18 @@ use std::{future::Future, panic::RefUnwindSafe, pin::Pin, sync::Arc, time::Durat
 
 use crate::cloud::drs::{ConnectionStringType, RegistrationResponse};
 use crate::connection::{
-    twins::{DesiredPropertiesUpdatedCallback, TwinsClient},
+    twins::{DesiredPropertiesGapCallback, DesiredPropertiesUpdatedCallback, TwinsClient},
     ConnectionImplementation, JoinHandleVec,
 };
+use crate::ingress::link::LinkMonitor;
+#[cfg(feature = "packet-observer")]
+use crate::ingress::packets::PacketObserverSender;
+use crate::ingress::throughput::SendRate;
 use anyhow::{anyhow, bail, Context, Result};
-use rumqttc::{AsyncClient, ConnectionError, MqttOptions, TlsConfiguration, Transport};
+use rumqttc::{
+    AsyncClient, ClientConfig, ConnectionError, LastWill, MqttOptions, QoS, TlsConfiguration,
+    Transport,
+};
 use token_handler::{RegistrationCommand, RegistrationCommandSender};
 use tokio::{
     runtime::Handle,
@@ -18,20 +25,26 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 
 use eventloop::EventLoop;
+#[cfg(feature = "c2d")]
+use handlers::c2d::CloudToDeviceHandler;
+pub(crate) use handlers::twins::ReportedPropertiesSentNotifier;
 use handlers::{
-    c2d::CloudToDeviceHandler,
     direct_method::DirectMethodHandler,
     twins::{TwinsHandler, TwinsMiddleware},
 };
 use sender::Sender;
+pub(crate) use sender::{compress_content_brotli, precompress_content};
 use topics::publish_topic;
 
+use crate::ingress::{
+    ChannelConfig, ConnectionStatus, EventPublisher, LastWillQos, SdkEvent, TransportMode,
+};
 use crate::persistence::{
     sqlite::SqliteStore, sqlite_channel, twins::ReportedPropertiesUpdate, Acknowledger,
-    CloudToDeviceMessage, Consumer, TwinsStore,
+    CloudToDeviceProducer, Consumer, TwinsStore,
 };
 // use spotflow_connection::twins::TwinsClient;
-use twins::IotHubTwinsClient;
+use twins::{DesiredPropertiesLimit, IotHubTwinsClient};
 
 mod eventloop;
 mod handlers;
@@ -44,10 +57,134 @@ pub mod twins;
 
 #[derive(Debug, Clone)]
 pub enum State {
+    // The connection has not been established yet
+    Connecting,
     Ready,
     // Make own custom error which implements clone and get rid of Arc
     // Create watch to notify users of errors as they happen
     ConnectionError(Arc<ConnectionError>),
+    // The maximum number of reconnect attempts was exceeded, the event loop has stopped
+    Failed(Arc<ConnectionError>),
+}
+
+/// Settings of the MQTT connection to IoT Hub.
+#[derive(Clone, Debug)]
+pub(crate) struct MqttConfig {
+    // Carries the client certificate if the Device authenticates with one
+    pub tls_config: Option<Arc<ClientConfig>>,
+    // IoT Hub doesn't keep the subscriptions and the undelivered messages for clean sessions
+    pub clean_session: bool,
+    // Whether to connect using MQTT over TLS or over WebSocket
+    pub transport: TransportMode,
+    // The number of consecutive failed connection attempts after which the SDK stops reconnecting, `None` for unlimited
+    pub max_reconnect_attempts: Option<u32>,
+    // The time after which an unacknowledged device-to-cloud message causes a reconnect
+    pub publish_timeout: Duration,
+    // Shared by all the connections so that the quality of the link is assessed across reconnects
+    pub link_monitor: Arc<LinkMonitor>,
+    // Shared by all the connections so that the sending rate is averaged across reconnects
+    pub send_rate: Arc<SendRate>,
+    pub last_will: Option<LastWillMessage>,
+    // The version of the IoT Hub API sent in the MQTT username
+    pub api_version: String,
+    // Notified when IoT Hub confirms an update of Reported Properties
+    pub reported_properties_sent: Option<ReportedPropertiesSentNotifier>,
+    // Refuses the Desired Properties that are too large
+    pub desired_properties_limit: Option<DesiredPropertiesLimit>,
+    #[cfg(feature = "packet-observer")]
+    pub packet_observer: Option<PacketObserverSender>,
+}
+
+// Builds the TLS configuration presenting the given client certificate to IoT Hub. The MQTT client accepts a custom
+// identity only through rustls, so the server is verified against the root certificates of the system like with native-tls.
+pub(crate) fn client_certificate_tls_config(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<Arc<ClientConfig>> {
+    let certs = rustls_pemfile::certs(&mut &*cert_pem)
+        .context("Unable to load the client certificate. Check that it's PEM-encoded.")?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut &*key_pem)
+        .context("Unable to load the private key. Check that it's PEM-encoded.")?
+        .into_iter()
+        .next()
+        .context("Unable to load the private key. Check that it's in the PKCS #8 format.")?;
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(native_root_certificates()?)
+        .with_single_cert(certs, rustls::PrivateKey(key))
+        .context("Unable to configure TLS with the client certificate.")?;
+
+    Ok(Arc::new(config))
+}
+
+// Builds the TLS configuration of MQTT over WebSocket, which the MQTT client supports only through rustls
+#[cfg(feature = "websocket")]
+pub(crate) fn websocket_tls_config() -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(native_root_certificates()?)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+fn native_root_certificates() -> Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    let native_roots = rustls_native_certs::load_native_certs()
+        .context("Unable to load the root certificates of the system")?
+        .into_iter()
+        .map(|cert| cert.0)
+        .collect::<Vec<_>>();
+    // The certificates that rustls doesn't support aren't needed to verify IoT Hub
+    roots.add_parsable_certificates(&native_roots);
+
+    Ok(roots)
+}
+
+pub(crate) const DEFAULT_API_VERSION: &str = "2018-06-30";
+
+// The interval of the MQTT pings that keep the connection to IoT Hub open
+pub(crate) const KEEP_ALIVE: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) const DEFAULT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A Message that IoT Hub sends on behalf of the Device when the connection is lost without a proper disconnect
+#[derive(Clone, Debug)]
+pub(crate) struct LastWillMessage {
+    pub stream_group: Option<String>,
+    pub stream: Option<String>,
+    pub payload: Vec<u8>,
+    pub qos: LastWillQos,
+}
+
+impl LastWillMessage {
+    // IoT Hub accepts the will only on the topic for Device-to-Cloud Messages, so it's routed to a Stream like any other Message
+    fn to_last_will(&self, device_id: &str) -> LastWill {
+        let mut properties = Vec::new();
+        if let Some(stream_group) = &self.stream_group {
+            properties.push(format!(
+                "stream-group-name={}",
+                urlencoding::encode(stream_group)
+            ));
+        }
+        if let Some(stream) = &self.stream {
+            properties.push(format!("stream-name={}", urlencoding::encode(stream)));
+        }
+
+        let topic = format!("{}{}", publish_topic(device_id), properties.join("&"));
+
+        let qos = match self.qos {
+            LastWillQos::AtMostOnce => QoS::AtMostOnce,
+            LastWillQos::AtLeastOnce => QoS::AtLeastOnce,
+        };
+
+        // IoT Hub doesn't support retained messages
+        LastWill::new(topic, self.payload.clone(), qos, false)
+    }
 }
 
 #[derive(Debug)]
@@ -61,16 +198,23 @@ pub struct IotHubConnection<F> {
     store: SqliteStore,
     d2c_consumer: Option<Consumer>,
     d2c_acknowledger: Option<Acknowledger>,
-    c2d_producer: Option<sqlite_channel::Sender<CloudToDeviceMessage>>,
+    c2d_producer: Option<CloudToDeviceProducer>,
     twins_store: TwinsStore,
     registration_watch: Receiver<Option<RegistrationResponse>>,
     registration_command_sender: RegistrationCommandSender,
     cancellation: CancellationToken,
     method_handler: Option<F>,
     desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+    desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+    previous_twins_client: Option<IotHubTwinsClient>,
 
     connection_receiver: Option<oneshot::Receiver<OnlineConnection>>,
     twins_client: Option<IotHubTwinsClient>,
+    state_sender: Option<watch::Sender<State>>,
+    state_receiver: watch::Receiver<State>,
+    channel_config: ChannelConfig,
+    mqtt_config: MqttConfig,
+    events: EventPublisher,
 }
 
 impl<F> IotHubConnection<F> {
@@ -80,17 +224,24 @@ impl<F> IotHubConnection<F> {
         store: SqliteStore,
         d2c_consumer: Consumer,
         d2c_acknowledger: Acknowledger,
-        c2d_producer: sqlite_channel::Sender<CloudToDeviceMessage>,
+        c2d_producer: CloudToDeviceProducer,
         twins_store: TwinsStore,
         registration_watch: Receiver<Option<RegistrationResponse>>,
         registration_command_sender: mpsc::UnboundedSender<RegistrationCommand>,
         method_handler: Option<F>,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+        previous_twins_client: Option<IotHubTwinsClient>,
+        channel_config: ChannelConfig,
+        mqtt_config: MqttConfig,
+        events: EventPublisher,
         cancellation: CancellationToken,
     ) -> Self
     where
         F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + RefUnwindSafe + 'static,
     {
+        let (state_sender, state_receiver) = watch::channel(State::Connecting);
+
         IotHubConnection {
             runtime,
             store,
@@ -103,23 +254,36 @@ impl<F> IotHubConnection<F> {
             cancellation,
             method_handler,
             desired_properties_updated_callback,
+            desired_properties_gap_callback,
+            previous_twins_client,
 
             connection_receiver: None,
             twins_client: None,
+            state_sender: Some(state_sender),
+            state_receiver,
+            channel_config,
+            mqtt_config,
+            events,
         }
     }
 
+    pub fn subscribe_to_state(&self) -> watch::Receiver<State> {
+        self.state_receiver.clone()
+    }
+
     async fn connect_iothub(
         registration_watch: &mut watch::Receiver<Option<RegistrationResponse>>,
+        request_capacity: usize,
+        mqtt_config: &MqttConfig,
     ) -> Result<(AsyncClient, rumqttc::EventLoop)> {
         while registration_watch.borrow_and_update().is_none() {
-            log::trace!("Awaiting first registration");
+            trace!("Awaiting first registration");
             registration_watch.changed().await.expect(
                 "Registrator worker stopped running before receiving first successful registration",
             );
         }
 
-        log::debug!("First registration is done");
+        debug!("First registration is done");
         let registration = {
             let registration = registration_watch.borrow();
             registration
@@ -139,23 +303,46 @@ impl<F> IotHubConnection<F> {
             );
         }
 
-        let username = format!("{iothub}/{device_id}/?api-version=2018-06-30");
+        let api_version = &mqtt_config.api_version;
+        let username = format!("{iothub}/{device_id}/?api-version={api_version}");
         let password = registration
             .sas()
             .context("Unable to parse SAS token from DRS response")?;
         // let password = format!("{}", registration.connection_string);
 
-        let mut options = MqttOptions::new(device_id, iothub, 8883);
-        options.set_keep_alive(Duration::from_secs(5 * 60));
+        // IoT Hub accepts MQTT over WebSocket on the standard HTTPS port, the credentials stay the same
+        let mut options = match mqtt_config.transport {
+            TransportMode::Mqtt => MqttOptions::new(device_id, iothub, 8883),
+            #[cfg(feature = "websocket")]
+            TransportMode::WebSocket => MqttOptions::new(
+                device_id,
+                format!("wss://{iothub}:443/$iothub/websocket"),
+                443,
+            ),
+        };
+        if let Some(last_will) = &mqtt_config.last_will {
+            options.set_last_will(last_will.to_last_will(device_id));
+        }
+        options.set_keep_alive(KEEP_ALIVE);
         options.set_credentials(username, password);
-        options.set_transport(Transport::Tls(TlsConfiguration::Native));
-        options.set_clean_session(false);
+        let tls_configuration = match &mqtt_config.tls_config {
+            Some(tls_config) => TlsConfiguration::Rustls(tls_config.clone()),
+            None => TlsConfiguration::Native,
+        };
+        options.set_transport(match mqtt_config.transport {
+            TransportMode::Mqtt => Transport::Tls(tls_configuration),
+            #[cfg(feature = "websocket")]
+            TransportMode::WebSocket => Transport::Wss(tls_configuration),
+        });
+        // The subscriptions are renewed on each connection, so they don't have to be kept in the session
+        options.set_clean_session(mqtt_config.clean_session);
         options.set_manual_acks(true);
         // We cannot guarantee data won't be sent twice because IoT Hub supports only MQTT QoS 1.
-        // Ingress cannot currently deduplicate messages that aren't next to each other
-        options.set_inflight(1);
+        // Ingress cannot currently deduplicate messages that aren't next to each other, so the Sender publishes
+        // an ordered message only after all the previous ones are acknowledged. Only unordered messages use the higher limit.
+        options.set_inflight(10);
 
-        Ok(AsyncClient::new(options, 10))
+        Ok(AsyncClient::new(options, request_capacity))
     }
 
     // pub fn twins_client(&self) -> Result<Box<dyn spotflow_connection::twins::TwinsClient>> {
@@ -174,22 +361,43 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
     ConnectionImplementation for IotHubConnection<F>
 {
     fn connect(&mut self) -> Pin<Box<dyn Future<Output = Result<JoinHandleVec>> + Send>> {
-        let (response_sender, response_receiver) = mpsc::channel(100);
-        let (desired_properties_sender, desired_properties_receiver) = mpsc::channel(100);
+        let capacity = self.channel_config.internal_channel_capacity;
+        let (response_sender, response_receiver) = mpsc::channel(capacity);
+        let (desired_properties_sender, desired_properties_receiver) = mpsc::channel(capacity);
+        let pending_reported_properties = match &self.previous_twins_client {
+            Some(previous_twins_client) => {
+                previous_twins_client.pending_reported_properties_count()
+            }
+            None => Arc::new(watch::channel(0).0),
+        };
         let (reported_properties_sender, reported_properties_receiver) =
-            sqlite_channel::channel::<ReportedPropertiesUpdate>(self.store.clone());
-        let (get_twins_sender, get_twins_receiver) = mpsc::channel(100);
+            sqlite_channel::channel_with_count::<ReportedPropertiesUpdate>(
+                self.store.clone(),
+                None,
+                pending_reported_properties,
+            );
+        let (get_twins_sender, get_twins_receiver) = mpsc::channel(capacity);
         let (desired_properties_changed_sender, desired_properties_changed_receiver) =
             watch::channel(0);
         let (conn_sender, conn_receiver) = oneshot::channel();
 
-        let twins_client = self.runtime.block_on(IotHubTwinsClient::init(
-            self.twins_store.clone(),
-            get_twins_sender,
-            reported_properties_sender,
-            desired_properties_changed_receiver,
-            self.desired_properties_updated_callback.take(),
-        ));
+        // When reconnecting, keep the Device Twin (including the callbacks) from the previous connection
+        let twins_client = match self.previous_twins_client.take() {
+            Some(previous_twins_client) => previous_twins_client.with_channels(
+                get_twins_sender,
+                reported_properties_sender,
+                desired_properties_changed_receiver,
+            ),
+            None => self.runtime.block_on(IotHubTwinsClient::init(
+                self.twins_store.clone(),
+                get_twins_sender,
+                reported_properties_sender,
+                desired_properties_changed_receiver,
+                self.desired_properties_updated_callback.take(),
+                self.desired_properties_gap_callback.take(),
+                self.mqtt_config.desired_properties_limit.clone(),
+            )),
+        };
 
         self.connection_receiver = Some(conn_receiver);
         self.twins_client = Some(twins_client.clone());
@@ -201,33 +409,58 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
             let method_handler = self.method_handler.take();
             let d2c_acknowledger = self.d2c_acknowledger.take().unwrap();
             let d2c_consumer = self.d2c_consumer.take().unwrap();
+            #[cfg_attr(not(feature = "c2d"), allow(unused_variables))]
             let c2d_producer = self.c2d_producer.take().unwrap();
+            let state_sender = self.state_sender.take().unwrap();
+            let mqtt_request_capacity = self.channel_config.mqtt_request_capacity;
+            let mqtt_config = self.mqtt_config.clone();
+            let events = self.events.clone();
+            let send_rate = self.mqtt_config.send_rate.clone();
+            let clock = self.store.clock().clone();
             async move {
-                log::debug!("Registering to the platform");
-                let (client, rumqttc_eventloop) =
-                    Self::connect_iothub(&mut registration_watch).await?;
-                log::debug!("Getting device ID");
+                debug!("Registering to the platform");
+                let (client, rumqttc_eventloop) = Self::connect_iothub(
+                    &mut registration_watch,
+                    mqtt_request_capacity,
+                    &mqtt_config,
+                )
+                .await?;
+                debug!("Getting device ID");
                 let device_id = rumqttc_eventloop.options.client_id();
 
-                log::debug!("Building eventloop");
+                debug!("Building eventloop");
 
                 let mut ingress_eventloop = EventLoop::new(
                     rumqttc_eventloop,
                     registration_watch.clone(),
                     registration_command_sender,
                     d2c_acknowledger,
+                    state_sender,
+                    events.clone(),
+                    mqtt_config.max_reconnect_attempts,
+                    mqtt_config.publish_timeout,
+                    mqtt_config.link_monitor.clone(),
+                    clock,
                     cancellation.clone(),
                 );
 
-                log::debug!("Building and registering handlers");
+                #[cfg(feature = "packet-observer")]
+                if let Some(packet_observer) = &mqtt_config.packet_observer {
+                    ingress_eventloop.set_packet_observer(packet_observer.clone());
+                }
+
+                debug!("Building and registering handlers");
 
                 // Register handlers for incoming publish packets
                 let twins_handler = TwinsHandler::new(desired_properties_sender, response_sender);
                 ingress_eventloop.register_async_handler(twins_handler);
 
-                let c2d_handler =
-                    CloudToDeviceHandler::new(client.clone(), &device_id, c2d_producer);
-                ingress_eventloop.register_async_handler(c2d_handler);
+                #[cfg(feature = "c2d")]
+                {
+                    let c2d_handler =
+                        CloudToDeviceHandler::new(client.clone(), &device_id, c2d_producer);
+                    ingress_eventloop.register_async_handler(c2d_handler);
+                }
 
                 if let Some(method_handler) = method_handler {
                     let method_handler = DirectMethodHandler::new(client.clone(), method_handler);
@@ -236,18 +469,33 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
 
                 let connection_state_rx = ingress_eventloop.subscribe_to_state();
 
+                let mut events_state_rx = connection_state_rx.clone();
+                let events_task = tokio::spawn(async move {
+                    debug!("Events task is starting.");
+                    loop {
+                        let status = ConnectionStatus::from(&*events_state_rx.borrow_and_update());
+                        events.publish(SdkEvent::ConnectionStatusChanged(status));
+
+                        if events_state_rx.changed().await.is_err() {
+                            // The event loop has finished
+                            break;
+                        }
+                    }
+                    debug!("Events task has ended.");
+                });
+
                 // This is done before starting the eventloop so that this is the first thing that's in the queue.
-                log::debug!("Subscribing to topics");
+                debug!("Subscribing to topics");
                 let subscribe_task = ingress_eventloop.subscribe_all(client.clone()).await;
 
-                log::debug!("Starting IotHub event loop");
+                debug!("Starting IotHub event loop");
                 let mqtt_client_task = tokio::spawn(async move {
-                    log::debug!("MQTT task is starting.");
+                    debug!("MQTT task is starting.");
                     ingress_eventloop.run().await;
-                    log::debug!("MQTT task has ended.");
+                    debug!("MQTT task has ended.");
                 });
 
-                log::debug!("Awaiting acknowledgment of subscriptions");
+                debug!("Awaiting acknowledgment of subscriptions");
                 subscribe_task.wait().await?;
 
                 let publish_topic = publish_topic(&device_id);
@@ -257,6 +505,7 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
                     twins_client.clone(),
                     get_twins_receiver,
                     reported_properties_receiver,
+                    mqtt_config.reported_properties_sent.clone(),
                     desired_properties_receiver,
                     desired_properties_changed_sender,
                     response_receiver,
@@ -265,9 +514,9 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
                 );
 
                 let twins_task = tokio::spawn(async move {
-                    log::debug!("Twins task is starting.");
+                    debug!("Twins task is starting.");
                     twins_middleware.process().await;
-                    log::debug!("Twins task has ended.");
+                    debug!("Twins task has ended.");
                 });
 
                 // Request twins and wait until a response arrives
@@ -279,13 +528,14 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
                     registration_watch.clone(),
                     publish_topic,
                     d2c_consumer,
+                    send_rate,
                     cancellation.child_token(),
                 );
 
                 let sender_task = tokio::spawn(async move {
-                    log::debug!("Sender task is starting.");
+                    debug!("Sender task is starting.");
                     sender.process_saved().await;
-                    log::debug!("Sender task has ended.");
+                    debug!("Sender task has ended.");
                 });
 
                 conn_sender
@@ -297,7 +547,7 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
                         anyhow!("Nothing is listening for estabilishing MQTT connection.")
                     })?;
 
-                Ok(vec![mqtt_client_task, sender_task, twins_task])
+                Ok(vec![mqtt_client_task, sender_task, twins_task, events_task])
             }
         };
 
@@ -311,8 +561,8 @@ impl<F: Fn(String, &[u8]) -> (i32, Vec<u8>) + Send + Sync + RefUnwindSafe + 'sta
             .try_recv()
             .ok()
             .and_then(|o| match &*o.state.borrow() {
-                State::Ready => None,
-                State::ConnectionError(e) => {
+                State::Connecting | State::Ready => None,
+                State::ConnectionError(e) | State::Failed(e) => {
                     let cast: Arc<dyn std::error::Error> = e.to_owned();
                     Some(cast)
                 }
@@ -338,12 +588,12 @@ impl<F> Drop for IotHubConnection<F> {
                     .await
                     .is_err()
                 {
-                    log::warn!("Connection was not closed within timeout after disconnect was called. Cancelling execution of the SDK.");
+                    warn!("Connection was not closed within timeout after disconnect was called. Cancelling execution of the SDK.");
                     self.cancellation.cancel();
                 }
             });
         } else {
-            log::info!("Connection was not properly set up before shutdown, cancelling all execution of the SDK.");
+            info!("Connection was not properly set up before shutdown, cancelling all execution of the SDK.");
             self.cancellation.cancel();
         }
     }
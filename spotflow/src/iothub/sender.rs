@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::{collections::HashMap, io::Write, sync::Arc};
 
 use crate::cloud::drs::RegistrationResponse;
+use crate::ingress::throughput::SendRate;
 use crate::persistence::{CloseOption, Compression, Consumer, DeviceMessage};
 use anyhow::{bail, Context, Result};
 use brotli::{enc::BrotliEncoderParams, BrotliCompress};
+use chrono::SecondsFormat;
+use flate2::write::GzEncoder;
 use rumqttc::{AsyncClient, QoS};
 use serde::Deserialize;
 use serde_json::json;
@@ -17,6 +20,7 @@ pub(super) struct Sender {
     registration_watch: watch::Receiver<Option<RegistrationResponse>>,
     topic: String,
     message_queue: Consumer,
+    send_rate: Arc<SendRate>,
     cancellation: CancellationToken,
 }
 
@@ -26,6 +30,7 @@ impl Sender {
         registration_watch: watch::Receiver<Option<RegistrationResponse>>,
         topic: String,
         message_queue: Consumer,
+        send_rate: Arc<SendRate>,
         cancellation: CancellationToken,
     ) -> Self {
         Self {
@@ -33,6 +38,7 @@ impl Sender {
             registration_watch,
             topic,
             message_queue,
+            send_rate,
             cancellation,
         }
     }
@@ -44,12 +50,18 @@ impl Sender {
                 // At this point we panic. I don't know what else to do as this is core functionality.
                 // In a better world I will let the user know that the SDK stopped working and they need to restart or something.
                 // For now this should panic on our own thread (not on user's thread) and cascade to the SDK itself which will probably return Error when the user tries to send more messages.
-                Some(msg) = self.message_queue.get_message() => self.publish_iothub(msg).await.unwrap(),
+                Some(msg) = self.message_queue.get_message() => {
+                    // A message received right before sending was paused is held until it's resumed
+                    select!(
+                        () = self.cancellation.cancelled() => break,
+                        () = self.message_queue.wait_resumed() => self.publish_iothub(msg).await.unwrap(),
+                    );
+                },
             );
         }
     }
 
-    async fn publish_iothub(&self, msg: DeviceMessage) -> Result<()> {
+    async fn publish_iothub(&self, mut msg: DeviceMessage) -> Result<()> {
         fn encode_property(key: &str, value: &str) -> String {
             let value = urlencoding::encode(value);
             format!("{key}={value}")
@@ -59,12 +71,35 @@ impl Sender {
             .id
             .expect("We have a saved message without an ID. This should never happen.");
 
+        if self.message_queue.is_expired(&msg) {
+            info!(
+                "Message {} expired at {:?} before it could be sent, removing it",
+                id, msg.expiry
+            );
+            return self
+                .message_queue
+                .discard(&msg, "expired before it was sent")
+                .await;
+        }
+
+        // The content is compressed only after it's decoded so that the codec doesn't affect the compression
+        if let Err(e) = self.message_queue.decode(&mut msg) {
+            error!(
+                "Message {} couldn't be decoded and will not be sent, removing it: {:?}",
+                id, e
+            );
+            return self
+                .message_queue
+                .discard(&msg, "couldn't be decoded before it was sent")
+                .await;
+        }
+
         let mut properties = Vec::new();
 
         if let Some(stream_group) = &msg.stream_group {
             properties.push(encode_property("stream-group-name", stream_group));
         } else {
-            log::info!(
+            info!(
                 "The Stream Group of Message {} is not specified, \
                 the default Stream Group of the current Workspace will be filled in by the Platform.",
                 id
@@ -74,13 +109,17 @@ impl Sender {
         if let Some(stream) = &msg.stream {
             properties.push(encode_property("stream-name", stream));
         } else {
-            log::info!(
+            info!(
                 "The Stream of Message {} is not specified, \
                 the default Stream of the current Stream Group will be filled in by the Platform.",
                 id
             );
         }
 
+        if let Some(source_device_id) = &msg.source_device_id {
+            properties.push(encode_property("source-device-id", source_device_id));
+        }
+
         if let Some(site_id) = &msg.site_id {
             properties.push(encode_property("site-id", site_id));
         }
@@ -101,34 +140,68 @@ impl Sender {
             properties.push(encode_property("chunk-id", chunk_id));
         }
 
-        let content = match (
-            msg.content.is_empty(),
-            get_compression_quality(msg.compression),
-        ) {
-            (false, Some(compression_quality)) => {
-                log::trace!("Compressing message {}", id);
-                let compressed_content = compress_message(&msg.content, compression_quality)?;
-
-                if compressed_content.len() < msg.content.len() {
-                    properties.push(String::from("content-encoding=br"));
-                    compressed_content
-                } else {
-                    log::trace!(
-                        "Compressing message {} would not decrease its size (original: {}B, compressed: {}B), sending uncompressed",
-                        id, msg.content.len(), compressed_content.len());
-                    msg.content
+        if let Some(json) = &msg.properties {
+            let user_properties: HashMap<String, String> = match serde_json::from_str(json) {
+                Ok(user_properties) => user_properties,
+                Err(e) => {
+                    error!(
+                        "Message {} has malformed properties and will not be sent, removing it: {:?}",
+                        id, e
+                    );
+                    return self
+                        .message_queue
+                        .discard(&msg, "has malformed properties")
+                        .await;
                 }
+            };
+
+            for (key, value) in &user_properties {
+                properties.push(encode_property(&urlencoding::encode(key), value));
+            }
+        }
+
+        if let Some(content_type) = &msg.content_type {
+            properties.push(encode_property("$.ct", content_type));
+        }
+
+        if let Some(expiry) = &msg.expiry {
+            properties.push(encode_property(
+                "$.exp",
+                &expiry.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ));
+        }
+
+        let content = match compress_content(&msg.content, msg.compression)? {
+            // The content was compressed before it was stored
+            None if matches!(
+                msg.compression,
+                Compression::Brotli | Compression::PrecompressedGzip
+            ) =>
+            {
+                properties.push(format!(
+                    "content-encoding={}",
+                    content_encoding(msg.compression)
+                ));
+                msg.content
+            }
+            Some(compressed_content) => {
+                trace!("Message {} was compressed", id);
+                properties.push(format!(
+                    "content-encoding={}",
+                    content_encoding(msg.compression)
+                ));
+                compressed_content
             }
-            _ => msg.content,
+            None => msg.content,
         };
 
         let content = if is_file_upload(&content) {
-            log::trace!("Sending message {} through file upload", id);
+            trace!("Sending message {} through file upload", id);
             properties.push(String::from("has-externalized-payload=true"));
             let blob_name = loop {
                 match self.publish_file(content.as_ref()) {
                     Ok(name) => break name,
-                    Err(e) => log::error!("Failed uploading file: {:?}", e),
+                    Err(e) => error!("Failed uploading file: {:?}", e),
                 }
             };
             format!(r#"{{"link":"{blob_name}"}}"#).into_bytes()
@@ -155,7 +228,22 @@ impl Sender {
 
         let topic = format!("{}{}", &self.topic, properties);
 
-        log::trace!("Sending message {}", id);
+        if msg.ordered {
+            trace!(
+                "Waiting for the previous messages to be acknowledged before sending message {}",
+                id
+            );
+            select!(
+                () = self.cancellation.cancelled() => {
+                    trace!("Message not sent during shutdown");
+                    return Ok(());
+                },
+                () = self.message_queue.wait_all_acknowledged() => {},
+            );
+        }
+
+        trace!("Sending message {}", id);
+        self.message_queue.published(id);
         let res = self
             .mqtt
             .publish(topic.to_string(), QoS::AtLeastOnce, false, content)
@@ -166,14 +254,15 @@ impl Sender {
             // This should only happen when MQTT AsyncClient has already closed its eventloop, which in turn should only happen during ingress shutdown
             // The following if should never be true
             if !self.cancellation.is_cancelled() {
-                log::error!("Unable to publish message even though the client is not stopping");
+                error!("Unable to publish message even though the client is not stopping");
                 bail!("rumqttc event loop has closed its request queue even though the client has not cancelled its own token.");
             }
-            log::trace!("Message not sent during shutdown");
+            trace!("Message not sent during shutdown");
             return Ok(());
         }
 
-        log::trace!("Message sent {}", id);
+        trace!("Message sent {}", id);
+        self.send_rate.sent();
 
         Ok(())
     }
@@ -239,30 +328,124 @@ impl Sender {
     }
 }
 
-fn get_compression_quality(compression: Compression) -> Option<i32> {
+/// Compress the content of a message using the given compression.
+///
+/// Returns `None` if the message should be sent uncompressed, either because no compression was requested
+/// or because the compression would not decrease the size of the content.
+pub(crate) fn compress_content(
+    content: &[u8],
+    compression: Compression,
+) -> Result<Option<Vec<u8>>> {
+    if content.is_empty() {
+        return Ok(None);
+    }
+
+    let compressed_content = match compression {
+        // The content is already compressed
+        Compression::None | Compression::Brotli | Compression::PrecompressedGzip => {
+            return Ok(None)
+        }
+        Compression::BrotliFastest => compress_message(content, 1)?,
+        Compression::BrotliSmallestSize => compress_message(content, 11)?,
+        Compression::Gzip => compress_message_gzip(content)?,
+    };
+
+    if compressed_content.len() < content.len() {
+        Ok(Some(compressed_content))
+    } else {
+        trace!(
+            "Compressing message would not decrease its size (original: {}B, compressed: {}B), sending uncompressed",
+            content.len(), compressed_content.len());
+        Ok(None)
+    }
+}
+
+/// Compress the content of a message before it's stored, so that the sender sends it as it is.
+///
+/// Returns the content with the compression it should be stored with. The content is returned unchanged
+/// if the compression would not decrease its size.
+pub(crate) fn precompress_content(
+    content: Vec<u8>,
+    compression: Compression,
+) -> Result<(Vec<u8>, Compression)> {
+    let precompressed = match compression {
+        Compression::None | Compression::Brotli | Compression::PrecompressedGzip => {
+            return Ok((content, compression))
+        }
+        Compression::BrotliFastest | Compression::BrotliSmallestSize => Compression::Brotli,
+        Compression::Gzip => Compression::PrecompressedGzip,
+    };
+
+    match compress_content(&content, compression)? {
+        Some(compressed_content) => Ok((compressed_content, precompressed)),
+        None => Ok((content, Compression::None)),
+    }
+}
+
+fn content_encoding(compression: Compression) -> &'static str {
     match compression {
-        Compression::None => None,
-        Compression::BrotliFastest => Some(1),
-        Compression::BrotliSmallestSize => Some(11),
+        Compression::Gzip | Compression::PrecompressedGzip => "gzip",
+        Compression::None
+        | Compression::BrotliFastest
+        | Compression::BrotliSmallestSize
+        | Compression::Brotli => "br",
     }
 }
 
-fn compress_message(content: &Vec<u8>, quality: i32) -> Result<Vec<u8>, anyhow::Error> {
+/// Compress the content of a message using Brotli with the given parameters.
+///
+/// Returns `None` if the compression would not decrease the size of the content.
+pub(crate) fn compress_content_brotli(
+    content: &[u8],
+    quality: u8,
+    window: u8,
+) -> Result<Option<Vec<u8>>> {
+    if quality > 11 {
+        bail!("The quality of the Brotli compression must be from 0 to 11, but it's {quality}.");
+    }
+    if !(10..=24).contains(&window) {
+        bail!("The window of the Brotli compression must be from 10 to 24, but it's {window}.");
+    }
+
+    if content.is_empty() {
+        return Ok(None);
+    }
+
+    let brotli_params = BrotliEncoderParams {
+        quality: i32::from(quality),
+        lgwin: i32::from(window),
+        ..Default::default()
+    };
+
+    let mut compressed_content = Vec::new();
+    BrotliCompress(&mut &*content, &mut compressed_content, &brotli_params)?;
+
+    if compressed_content.len() < content.len() {
+        Ok(Some(compressed_content))
+    } else {
+        Ok(None)
+    }
+}
+
+fn compress_message(mut content: &[u8], quality: i32) -> Result<Vec<u8>, anyhow::Error> {
     let brotli_params = BrotliEncoderParams {
         quality,
         ..Default::default()
     };
 
     let mut compressed_content = Vec::new();
-    BrotliCompress(
-        &mut content.as_slice(),
-        &mut compressed_content,
-        &brotli_params,
-    )?;
+    BrotliCompress(&mut content, &mut compressed_content, &brotli_params)?;
 
     Ok(compressed_content)
 }
 
+fn compress_message_gzip(content: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(content)?;
+
+    Ok(encoder.finish()?)
+}
+
 fn is_file_upload(content: &[u8]) -> bool {
     // The limit is 256 KiB for telemetry messages including headers
     // This is coarse but should work well enough
@@ -1,14 +1,18 @@
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use http::Uri;
 use tokio::select;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 
 use crate::cloud::dps::{self, ProvisioningToken, RegistrationToken};
 use crate::cloud::drs::{self, RegistrationResponse};
+use crate::cloud::HttpConfig;
+use crate::ingress::TokenRefreshFailedCallback;
 use crate::persistence::ConfigurationStore;
+use crate::utils::clock::Clock;
 
 pub(crate) type RegistrationWatch = watch::Receiver<Option<RegistrationResponse>>;
 pub(crate) type RegistrationCommandSender = mpsc::UnboundedSender<RegistrationCommand>;
@@ -16,11 +20,22 @@ pub(crate) type RegistrationCommandSender = mpsc::UnboundedSender<RegistrationCo
 pub enum RegistrationCommand {
     RefreshRegistrationToken { time: Instant },
     RefreshRegistration { time: Instant },
+    SwitchInstance(Box<InstanceSwitch>),
+}
+
+// The credentials obtained by Device Provisioning in another Platform instance that replace the current ones
+pub struct InstanceSwitch {
+    pub instance_url: Uri,
+    pub provisioning_token: ProvisioningToken,
+    pub registration_token: RegistrationToken,
+    pub registration_response: RegistrationResponse,
+    pub done: oneshot::Sender<Result<()>>,
 }
 
 #[derive(Debug)]
 pub struct TokenHandler {
     instance_url: Uri,
+    http_config: HttpConfig,
     tokens: TokenCache,
     store: ConfigurationStore,
     registration_sender: watch::Sender<Option<RegistrationResponse>>,
@@ -28,6 +43,42 @@ pub struct TokenHandler {
     command_receiver: mpsc::UnboundedReceiver<RegistrationCommand>,
     last_token_refresh_attempt: Instant,
     last_registration_refresh_attempt: Instant,
+    refresh_failures: RefreshFailures,
+    clock: Arc<dyn Clock>,
+}
+
+// Counts the consecutive failures of both the registration and the registration token refresh
+struct RefreshFailures {
+    consecutive: usize,
+    callback: Option<(usize, Arc<dyn TokenRefreshFailedCallback>)>,
+}
+
+impl RefreshFailures {
+    fn failed(&mut self, error: &anyhow::Error) {
+        self.consecutive += 1;
+
+        if let Some((threshold, callback)) = &self.callback {
+            if self.consecutive % threshold == 0 {
+                callback.refresh_failed(self.consecutive, error);
+            }
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.consecutive = 0;
+    }
+}
+
+impl std::fmt::Debug for RefreshFailures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RefreshFailures")
+            .field("consecutive", &self.consecutive)
+            .field(
+                "threshold",
+                &self.callback.as_ref().map(|(threshold, _)| threshold),
+            )
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug)]
@@ -44,12 +95,16 @@ struct ConnectionToken {
 }
 
 impl TokenHandler {
+    #[allow(clippy::too_many_arguments)]
     pub async fn init(
         instance_url: Uri,
+        http_config: HttpConfig,
         provisioning_token: ProvisioningToken,
         registration_token: RegistrationToken,
         store: ConfigurationStore,
         initial_registration_response: Option<RegistrationResponse>,
+        refresh_failed_callback: Option<(usize, Arc<dyn TokenRefreshFailedCallback>)>,
+        clock: Arc<dyn Clock>,
     ) -> Result<(RegistrationWatch, RegistrationCommandSender)> {
         store.save_provisioning_token(&provisioning_token).await?;
         store.save_registration_token(&registration_token).await?;
@@ -70,13 +125,19 @@ impl TokenHandler {
 
         let handler = TokenHandler {
             instance_url,
+            http_config,
             tokens: cache,
             store,
             registration_sender,
             command_sender: command_sender.clone(),
             command_receiver,
-            last_token_refresh_attempt: Instant::now(),
-            last_registration_refresh_attempt: Instant::now(),
+            last_token_refresh_attempt: clock.now_instant(),
+            last_registration_refresh_attempt: clock.now_instant(),
+            refresh_failures: RefreshFailures {
+                consecutive: 0,
+                callback: refresh_failed_callback,
+            },
+            clock,
         };
 
         tokio::spawn(async {
@@ -90,7 +151,11 @@ impl TokenHandler {
         // If there is an existing registration response (e.g., from Device Provisioning), use it. Otherwise, register.
         let mut registration_response = match initial_registration_response {
             Some(registration_response) => Ok(registration_response),
-            None => drs::register(&self.instance_url, &self.tokens.registration_token),
+            None => drs::register(
+                &self.instance_url,
+                &self.tokens.registration_token,
+                &self.http_config,
+            ),
         };
 
         // Repeat registration attempts until it succeeds
@@ -104,13 +169,32 @@ impl TokenHandler {
             };
 
             match processing_result {
-                Ok(()) => break,
+                Ok(()) => {
+                    self.refresh_failures.succeeded();
+                    break;
+                }
                 Err(e) => {
-                    log::warn!("First registration has failed, waiting for 30 seconds and trying again. Error: {e:?}");
-                    tokio::time::sleep(Duration::from_secs(30)).await;
+                    self.refresh_failures.failed(&e);
+                    warn!("First registration has failed, waiting for 30 seconds and trying again. Error: {e:?}");
+
+                    // The registration may keep failing because the Device was moved to another Platform instance
+                    // The other commands are irrelevant until the first registration succeeds
+                    let switched = select! {
+                        () = tokio::time::sleep(Duration::from_secs(30)) => false,
+                        Some(command) = self.command_receiver.recv() => match command {
+                            RegistrationCommand::SwitchInstance(switch) => self.switch_instance(*switch).await,
+                            _ => false,
+                        },
+                    };
+                    if switched {
+                        break;
+                    }
 
-                    registration_response =
-                        drs::register(&self.instance_url, &self.tokens.registration_token);
+                    registration_response = drs::register(
+                        &self.instance_url,
+                        &self.tokens.registration_token,
+                        &self.http_config,
+                    );
                 }
             }
         }
@@ -118,8 +202,8 @@ impl TokenHandler {
         loop {
             // Add next commands to the queue according to the expiration of the tokens
 
-            let instant_now = Instant::now();
-            let utc_now = Utc::now();
+            let instant_now = self.clock.now_instant();
+            let utc_now = self.clock.now_utc();
 
             let sas_expiry = &self
                 .tokens
@@ -132,7 +216,7 @@ impl TokenHandler {
                     .command_sender
                     .send(RegistrationCommand::RefreshRegistration { time: instant_now })
                 {
-                    log::warn!("Unable to send refresh registration command: {:?}", e);
+                    warn!("Unable to send refresh registration command: {:?}", e);
                 }
             }
 
@@ -146,7 +230,7 @@ impl TokenHandler {
                     .command_sender
                     .send(RegistrationCommand::RefreshRegistrationToken { time: instant_now })
                 {
-                    log::warn!("Unable to send refresh registration token command: {:?}", e);
+                    warn!("Unable to send refresh registration token command: {:?}", e);
                 }
             }
 
@@ -170,10 +254,11 @@ impl TokenHandler {
     ) -> Result<()> {
         // Get the time of expiration of the registration token. If none was provided it does not expire. It gets lowered a bit to account for clockskew
         let registration_token_expiry = registration_response.token_remaining_lifetime.map(|t| {
+            let now = self.clock.now_utc();
             Self::expect_clockskew(
-                Utc::now()
-                    + chrono::Duration::from_std(t.into())
-                        .unwrap_or_else(|_| chrono::Duration::max_value()),
+                now + chrono::Duration::from_std(t.into())
+                    .unwrap_or_else(|_| chrono::Duration::max_value()),
+                now,
             )
         });
 
@@ -181,8 +266,8 @@ impl TokenHandler {
             .connection_string_expiration
             .expect("The registration did not return SAS token");
 
-        log::debug!("Registration token expires at {registration_token_expiry:?}");
-        log::debug!("SAS token expires at {sas_expiry}");
+        debug!("Registration token expires at {registration_token_expiry:?}");
+        debug!("SAS token expires at {sas_expiry}");
 
         self.tokens.iothub_sas_token = Some(ConnectionToken {
             valid_until: sas_expiry,
@@ -199,10 +284,9 @@ impl TokenHandler {
         let workspace_id = registration_response.workspace_id()?;
         self.store.save_workspace_id(workspace_id).await?;
 
-        log::info!(
+        info!(
             "Startup registration in the Workspace with ID {} done successfully with Device ID {}.",
-            workspace_id,
-            device_id
+            workspace_id, device_id
         );
 
         self.registration_sender
@@ -216,10 +300,11 @@ impl TokenHandler {
             RegistrationCommand::RefreshRegistrationToken { time } => {
                 if time >= self.last_token_refresh_attempt {
                     let result = self.try_refresh_token().await;
-                    self.last_token_refresh_attempt = Instant::now();
+                    self.last_token_refresh_attempt = self.clock.now_instant();
 
                     if let Err(e) = result {
-                        log::warn!("Unable to refresh registration token: {:?}", e);
+                        self.refresh_failures.failed(&e);
+                        warn!("Unable to refresh registration token: {:?}", e);
 
                         // Ensure that there is enough pause between the attempts
                         tokio::time::sleep(Duration::from_secs(30)).await;
@@ -227,13 +312,10 @@ impl TokenHandler {
                         // Enqueue the command so that it is tried again next time
                         if let Err(e) = self.command_sender.send(
                             RegistrationCommand::RefreshRegistrationToken {
-                                time: Instant::now(),
+                                time: self.clock.now_instant(),
                             },
                         ) {
-                            log::warn!(
-                                "Unable to send refresh registration token command: {:?}",
-                                e
-                            );
+                            warn!("Unable to send refresh registration token command: {:?}", e);
                         }
                     }
                 }
@@ -241,10 +323,11 @@ impl TokenHandler {
             RegistrationCommand::RefreshRegistration { time } => {
                 if time >= self.last_registration_refresh_attempt {
                     let result = self.try_refresh_registration();
-                    self.last_registration_refresh_attempt = Instant::now();
+                    self.last_registration_refresh_attempt = self.clock.now_instant();
 
                     if let Err(e) = result {
-                        log::warn!("Failed registration: {:?}", e);
+                        self.refresh_failures.failed(&e);
+                        warn!("Failed registration: {:?}", e);
 
                         // Ensure that there is enough pause between the attempts
                         tokio::time::sleep(Duration::from_secs(30)).await;
@@ -253,20 +336,69 @@ impl TokenHandler {
                         if let Err(e) =
                             self.command_sender
                                 .send(RegistrationCommand::RefreshRegistration {
-                                    time: Instant::now(),
+                                    time: self.clock.now_instant(),
                                 })
                         {
-                            log::warn!("Unable to send refresh registration command: {:?}", e);
+                            warn!("Unable to send refresh registration command: {:?}", e);
                         }
                     }
                 }
             }
+            RegistrationCommand::SwitchInstance(switch) => {
+                self.switch_instance(*switch).await;
+            }
         }
     }
 
+    // Returns whether the switch succeeded, the requester is notified about the outcome as well
+    async fn switch_instance(&mut self, switch: InstanceSwitch) -> bool {
+        let InstanceSwitch {
+            instance_url,
+            provisioning_token,
+            registration_token,
+            registration_response,
+            done,
+        } = switch;
+
+        info!("Switching to the Platform instance '{instance_url}'");
+
+        let result = async {
+            self.store.save_instance_url(&instance_url).await?;
+            self.store
+                .save_provisioning_token(&provisioning_token)
+                .await?;
+
+            self.instance_url = instance_url;
+            self.tokens.provisioning_token = provisioning_token;
+            self.tokens.registration_token = registration_token;
+
+            // Saves the new Registration Token and publishes the registration so that the next connection uses it
+            self.process_registration_response(registration_response)
+                .await
+        }
+        .await;
+
+        let switched = result.is_ok();
+        if switched {
+            self.refresh_failures.succeeded();
+            let now = self.clock.now_instant();
+            self.last_token_refresh_attempt = now;
+            self.last_registration_refresh_attempt = now;
+        }
+
+        // The requester may have stopped waiting for the outcome
+        _ = done.send(result);
+
+        switched
+    }
+
     fn try_refresh_registration(&mut self) -> Result<()> {
-        log::info!("Refreshing registration to the platform");
-        let registration = drs::register(&self.instance_url, &self.tokens.registration_token)?;
+        info!("Refreshing registration to the platform");
+        let registration = drs::register(
+            &self.instance_url,
+            &self.tokens.registration_token,
+            &self.http_config,
+        )?;
         self.tokens.iothub_sas_token = Some(ConnectionToken {
             valid_until: registration
                 .connection_string_expiration
@@ -276,35 +408,40 @@ impl TokenHandler {
         // Replace does not return Err when no one is listening
         self.registration_sender.send_replace(Some(registration));
 
-        log::info!("Registration refreshed successfully");
+        self.refresh_failures.succeeded();
+        info!("Registration refreshed successfully");
 
         Ok(())
     }
 
     async fn try_refresh_token(&mut self) -> Result<()> {
-        log::info!("Refreshing registration token");
+        info!("Refreshing registration token");
         let refresh = dps::refresh(
             &self.instance_url,
             &self.tokens.provisioning_token,
             &self.tokens.registration_token,
+            &self.http_config,
         )?;
 
         self.tokens.registration_token = RegistrationToken {
             token: refresh.token,
-            expiration: refresh.expiration.map(Self::expect_clockskew),
+            expiration: refresh
+                .expiration
+                .map(|expiration| Self::expect_clockskew(expiration, self.clock.now_utc())),
         };
 
         self.store
             .save_registration_token(&self.tokens.registration_token)
             .await?;
 
-        log::info!("Registration token refreshed successfully");
+        self.refresh_failures.succeeded();
+        info!("Registration token refreshed successfully");
 
         Ok(())
     }
 
-    fn expect_clockskew(expiration: DateTime<Utc>) -> DateTime<Utc> {
-        let expiration_duration = expiration.signed_duration_since(Utc::now());
+    fn expect_clockskew(expiration: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+        let expiration_duration = expiration.signed_duration_since(now);
         let expiration_datetime = expiration - expiration_duration / 2;
 
         let minutes_25 = chrono::Duration::try_minutes(25).expect("Unreachable");
@@ -318,3 +455,78 @@ impl TokenHandler {
         expiration_datetime
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::test::ManualClock;
+
+    struct RefreshFailedSender(mpsc::UnboundedSender<usize>);
+
+    impl TokenRefreshFailedCallback for RefreshFailedSender {
+        fn refresh_failed(&self, consecutive_failures: usize, _error: &anyhow::Error) {
+            _ = self.0.send(consecutive_failures);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registration_token_is_refreshed_before_expiration() {
+        let clock = Arc::new(ManualClock::new());
+        let (failures_sender, mut failures) = mpsc::unbounded_channel();
+        let registration_response = serde_json::from_value(serde_json::json!({
+            "connectionString": "HostName=hub;DeviceId=workspace:device;SharedAccessSignature=sas",
+            "iotHubHostName": "hub",
+            "connectionStringType": "SharedAccessSignature",
+            "connectionStringExpiration": "2100-01-01T00:00:00Z",
+            "tokenRemainingLifetime": "01:00:00",
+        }))
+        .unwrap();
+
+        // Nothing listens on this port, so each refresh of the Registration Token fails and is reported
+        let (mut registration_watch, _) = TokenHandler::init(
+            Uri::from_static("https://127.0.0.1:1"),
+            HttpConfig::default(),
+            ProvisioningToken {
+                token: String::from("provisioning"),
+            },
+            RegistrationToken {
+                token: String::from("registration"),
+                expiration: None,
+            },
+            ConfigurationStore::in_memory().await.unwrap(),
+            Some(registration_response),
+            Some((1, Arc::new(RefreshFailedSender(failures_sender)))),
+            clock.clone(),
+        )
+        .await
+        .unwrap();
+        registration_watch.changed().await.unwrap();
+
+        // The periodic checks of the expiration don't refresh the token while the clock doesn't move
+        tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+        assert!(failures.try_recv().is_err());
+
+        // The token is refreshed at half of its remaining lifetime to account for clock skew
+        clock.advance(Duration::from_secs(30 * 60));
+        assert_eq!(failures.recv().await, Some(1));
+    }
+
+    #[test]
+    fn clockskew_is_expected_in_remaining_lifetime() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let expiration = now + chrono::Duration::try_hours(24).unwrap();
+        assert_eq!(
+            TokenHandler::expect_clockskew(expiration, now),
+            now + chrono::Duration::try_hours(12).unwrap()
+        );
+
+        let expiration = now + chrono::Duration::try_minutes(20).unwrap();
+        assert_eq!(
+            TokenHandler::expect_clockskew(expiration, now),
+            now + chrono::Duration::try_minutes(10).unwrap()
+        );
+    }
+}
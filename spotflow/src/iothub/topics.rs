@@ -6,6 +6,7 @@ pub(super) fn publish_topic(device_id: &str) -> String {
     format!("devices/{device_id}/messages/events/")
 }
 
+#[cfg(feature = "c2d")]
 pub(super) fn c2d_topic(device_id: &str) -> String {
     format!("devices/{device_id}/messages/devicebound/")
 }
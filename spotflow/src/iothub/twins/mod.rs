@@ -1,18 +1,22 @@
 use std::{collections::VecDeque, sync::Arc};
 
-use crate::connection::twins::{DesiredProperties, DesiredPropertiesUpdatedCallback, TwinsClient};
+use crate::connection::twins::{
+    DesiredProperties, DesiredPropertiesGap, DesiredPropertiesGapCallback,
+    DesiredPropertiesTooLarge, DesiredPropertiesTooLargeCallback, DesiredPropertiesUpdatedCallback,
+    TwinsClient,
+};
 use crate::persistence::twins::{
     ReportedPropertiesUpdate, ReportedPropertiesUpdateType, Twin, TwinUpdate, Twins,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use json_patch::merge;
-use log::trace;
 use tokio::sync::{mpsc, watch, Mutex};
 
 use crate::persistence::{sqlite_channel, TwinsStore};
 
 use super::handlers::twins::PropertiesUpdateError;
+use super::json_diff::changed_paths;
 
 use self::update_callback_dispatcher::DesiredPropertiesUpdatedCallbackDispatcher;
 
@@ -45,7 +49,70 @@ impl InitializationWaiter {
     }
 }
 
-#[derive(Debug)]
+// The maximum size of the Desired Properties, the larger ones are refused so that they don't exhaust the memory
+#[derive(Clone)]
+pub(crate) struct DesiredPropertiesLimit {
+    pub max_bytes: usize,
+    pub callback: Option<Arc<dyn DesiredPropertiesTooLargeCallback>>,
+}
+
+impl std::fmt::Debug for DesiredPropertiesLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DesiredPropertiesLimit")
+            .field("max_bytes", &self.max_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DesiredPropertiesLimit {
+    fn check(&self, version: u64, properties: &serde_json::Value) -> Result<()> {
+        let size = serialized_len(properties);
+        if size <= self.max_bytes {
+            return Ok(());
+        }
+
+        warn!(
+            "Refusing Desired Properties of version {version} because their size {size} B exceeds the limit of {} B",
+            self.max_bytes
+        );
+        if let Some(callback) = &self.callback {
+            let callback = callback.clone();
+            let refused = DesiredPropertiesTooLarge {
+                version,
+                size,
+                limit: self.max_bytes,
+            };
+            tokio::task::spawn_blocking(move || callback.too_large(refused));
+        }
+
+        bail!(
+            "Desired Properties of version {version} are too large ({size} B, the limit is {} B)",
+            self.max_bytes
+        );
+    }
+}
+
+// Measures the JSON document without allocating its serialized form
+fn serialized_len(value: &serde_json::Value) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value)
+        .expect("Serializing a JSON value without writing it anywhere cannot fail");
+    counter.0
+}
+
 pub(crate) struct DeviceTwin {
     store: TwinsStore,
     desired: Option<Twin>,
@@ -55,12 +122,34 @@ pub(crate) struct DeviceTwin {
     reported_initialized_tx: watch::Sender<bool>,
     desired_properties_update_callback_dispatcher:
         Option<DesiredPropertiesUpdatedCallbackDispatcher>,
+    desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+    desired_properties_limit: Option<DesiredPropertiesLimit>,
+}
+
+impl std::fmt::Debug for DeviceTwin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceTwin")
+            .field("store", &self.store)
+            .field("desired", &self.desired)
+            .field("reported", &self.reported)
+            .field(
+                "desired_properties_updates",
+                &self.desired_properties_updates,
+            )
+            .field(
+                "desired_properties_update_callback_dispatcher",
+                &self.desired_properties_update_callback_dispatcher,
+            )
+            .finish_non_exhaustive()
+    }
 }
 
 impl DeviceTwin {
     pub(super) async fn init(
         store: TwinsStore,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+        desired_properties_limit: Option<DesiredPropertiesLimit>,
     ) -> DeviceTwin {
         let desired = store.load_desired_properties().await.unwrap_or_default();
         let reported = store.load_reported_properties().await.unwrap_or_default();
@@ -79,6 +168,8 @@ impl DeviceTwin {
             desired_initialized_tx,
             reported_initialized_tx,
             desired_properties_update_callback_dispatcher,
+            desired_properties_gap_callback,
+            desired_properties_limit,
         }
     }
 
@@ -89,16 +180,15 @@ impl DeviceTwin {
     ) -> Result<()> {
         if let Some(ref twin) = self.reported {
             if version < twin.version {
-                log::debug!(
+                debug!(
                     "Ignoring reported properties update to version {} because we are at {}",
-                    version,
-                    twin.version
+                    version, twin.version
                 );
                 return Ok(());
             }
         }
 
-        log::debug!("Setting reported properties to version {version}");
+        debug!("Setting reported properties to version {version}");
         self.reported = Some(Twin {
             version,
             properties,
@@ -122,34 +212,38 @@ impl DeviceTwin {
     ) -> Result<()> {
         if let Some(ref twin) = self.desired {
             if version < twin.version {
-                log::debug!(
+                debug!(
                     "Ignoring desired properties update to version {} because we are at {}",
-                    version,
-                    twin.version
+                    version, twin.version
                 );
                 return Ok(());
             }
         }
 
-        log::debug!("Setting desired properties to version {version}");
-        self.desired = Some(Twin {
+        let mut desired = Twin {
             version,
             properties,
-        });
+        };
+        while let Some(update) = self.desired_properties_updates.pop_front() {
+            desired.update(&update)?;
+        }
+
+        if let Some(limit) = &self.desired_properties_limit {
+            limit.check(desired.version, &desired.properties)?;
+        }
+
+        debug!("Setting desired properties to version {version}");
+        let previous = self.desired.replace(desired).map(|twin| twin.properties);
         let desired = self
             .desired
             .as_mut()
             .expect("Desired properties value has just been assigned but is missing");
 
-        while let Some(update) = self.desired_properties_updates.pop_front() {
-            desired.update(&update)?;
-        }
-
-        log::trace!("Current desired properties:\n{:#?}", desired.properties);
+        trace!("Current desired properties:\n{:#?}", desired.properties);
 
         self.store.save_desired_properties(desired).await?;
 
-        self.notify_desired_properties_updated()?;
+        self.notify_desired_properties_updated(previous.as_ref())?;
 
         Ok(())
     }
@@ -169,23 +263,39 @@ impl DeviceTwin {
                     .map_or(String::from("Missing"), |x| x.to_string()),
             )));
         }
-        log::trace!("Received desired properties update: {:#?}", update);
+        trace!("Received desired properties update: {:#?}", update);
         match &mut self.desired {
             None => {
                 self.desired_properties_updates.push_back(update);
             }
             Some(twin) => {
                 if version == twin.version + 1 {
-                    log::debug!("Applying desired properties patch to version {version}.");
+                    debug!("Applying desired properties patch to version {version}.");
+                    let previous = twin.properties.clone();
                     merge(&mut twin.properties, &update.patch);
+                    if let Some(limit) = &self.desired_properties_limit {
+                        if let Err(e) = limit.check(version, &twin.properties) {
+                            twin.properties = previous;
+                            return Err(e.into());
+                        }
+                    }
                     twin.version = version;
-                    log::trace!("Current desired properties:\n{:#?}", twin.properties);
+                    trace!("Current desired properties:\n{:#?}", twin.properties);
 
                     self.store.save_desired_properties(twin).await?;
 
-                    self.notify_desired_properties_updated()?;
+                    self.notify_desired_properties_updated(Some(&previous))?;
                 } else {
-                    log::info!("Unable to apply Desired Properties patch of version {} because we are at {}.", version, twin.version);
+                    info!("Unable to apply Desired Properties patch of version {} because we are at {}.", version, twin.version);
+                    if let Some(callback) = &self.desired_properties_gap_callback {
+                        let callback = callback.clone();
+                        let gap = DesiredPropertiesGap {
+                            current_version: twin.version,
+                            patch_version: version,
+                        };
+                        // The callback must not delay the recovery, which requests the full Device Twin
+                        tokio::task::spawn_blocking(move || callback.gap_detected(gap));
+                    }
                     return Err(PropertiesUpdateError::PatchVersionMismatch {
                         current_version: twin.version,
                         patch_version: version,
@@ -201,17 +311,17 @@ impl DeviceTwin {
         let update: TwinUpdate = serde_json::from_str(update).context(
             "Unable to deserialize JSON representation of reported properties to update",
         )?;
-        log::trace!("Received reported properties update: {:#?}", update);
+        trace!("Received reported properties update: {:#?}", update);
         match &mut self.reported {
             None => {
                 bail!("Reported properties cannot be updated yet because they are not loaded.");
             }
             Some(twin) => {
                 let version = twin.version + 1;
-                log::debug!("Applying reported properties patch to version {version}");
+                debug!("Applying reported properties patch to version {version}");
                 merge(&mut twin.properties, &update.patch);
                 twin.version = version;
-                log::trace!("Current reported properties:\n{:#?}", twin.properties);
+                trace!("Current reported properties:\n{:#?}", twin.properties);
 
                 self.store.save_reported_properties(twin).await?;
 
@@ -222,7 +332,11 @@ impl DeviceTwin {
         Ok(())
     }
 
-    fn notify_desired_properties_updated(&self) -> Result<()> {
+    // The changed paths are computed against `previous`, all the properties are considered changed if there are no previous ones
+    fn notify_desired_properties_updated(
+        &self,
+        previous: Option<&serde_json::Value>,
+    ) -> Result<()> {
         self.desired_initialized_tx.send_replace(true);
 
         if let Some(dispatcher) = &self.desired_properties_update_callback_dispatcher {
@@ -231,10 +345,18 @@ impl DeviceTwin {
                 .as_ref()
                 .expect("Desired Properties should have been initialized");
 
-            dispatcher.dispatch(DesiredProperties {
-                version: desired.version,
-                values: desired.properties.to_string(),
-            })?;
+            let changed_paths = match previous {
+                Some(previous) => changed_paths(previous, &desired.properties),
+                None => changed_paths(&serde_json::json!({}), &desired.properties),
+            };
+
+            dispatcher.dispatch(
+                DesiredProperties {
+                    version: desired.version,
+                    values: desired.properties.to_string(),
+                },
+                changed_paths,
+            )?;
         }
 
         Ok(())
@@ -252,8 +374,26 @@ impl DeviceTwin {
         &self.reported
     }
 
+    /// Get the Reported Properties as they will be once all the pending updates are sent.
+    pub(super) async fn reported_properties_with_pending_updates(&self) -> Result<Option<Twin>> {
+        let mut reported = match &self.reported {
+            Some(reported) => reported.clone(),
+            None => return Ok(None),
+        };
+
+        let updates = self
+            .store
+            .load_pending_reported_properties_updates()
+            .await?;
+        for update in updates {
+            apply_reported_properties_update(&mut reported, &update);
+        }
+
+        Ok(Some(reported))
+    }
+
     pub(crate) async fn set_twins(&mut self, twins: Twins) -> Result<()> {
-        log::trace!("Received twins:\n{:#?}", twins);
+        trace!("Received twins:\n{:#?}", twins);
 
         self.set_desired_properties(twins.desired.version, twins.desired.properties)
             .await?;
@@ -263,6 +403,10 @@ impl DeviceTwin {
         Ok(())
     }
 
+    pub(crate) fn subscribe_desired_initialized(&self) -> watch::Receiver<bool> {
+        self.desired_initialized_tx.subscribe()
+    }
+
     pub(crate) fn create_initialization_waiter(&self) -> InitializationWaiter {
         InitializationWaiter {
             desired_rx: self.desired_initialized_tx.subscribe(),
@@ -271,6 +415,15 @@ impl DeviceTwin {
     }
 }
 
+// Some of the updates may have already been applied to the local copy but not acknowledged yet.
+// Applying them again is harmless because both replacing and merging the properties are idempotent.
+fn apply_reported_properties_update(twin: &mut Twin, update: &ReportedPropertiesUpdate) {
+    match update.update_type {
+        ReportedPropertiesUpdateType::Full => twin.properties = update.patch.clone(),
+        ReportedPropertiesUpdateType::Patch => merge(&mut twin.properties, &update.patch),
+    }
+}
+
 #[derive(Debug)]
 pub struct IotHubTwinsClient {
     twins: Arc<Mutex<DeviceTwin>>,
@@ -362,14 +515,25 @@ impl TwinsClient for IotHubTwinsClient {
             .map(|t| t.properties.to_string())
     }
 
+    async fn get_reported_properties_with_pending_updates(&self) -> Result<Option<String>> {
+        let reported = self
+            .twins
+            .lock()
+            .await
+            .reported_properties_with_pending_updates()
+            .await?;
+
+        Ok(reported.map(|t| t.properties.to_string()))
+    }
+
     async fn desired_properties_changed(&self) -> Result<DesiredProperties> {
-        log::trace!("Awaiting desired properties change");
+        trace!("Awaiting desired properties change");
         self.desired_properties_changed
             .lock()
             .await
             .changed()
             .await?;
-        log::trace!("Received new desired properties");
+        trace!("Received new desired properties");
 
         let twin_guard = self.twins.lock().await;
 
@@ -389,6 +553,12 @@ impl TwinsClient for IotHubTwinsClient {
         Ok(count > 0)
     }
 
+    async fn subscribe_pending_reported_properties_updates(
+        &self,
+    ) -> Result<watch::Receiver<usize>> {
+        self.reported_properties_updates.subscribe_count().await
+    }
+
     async fn wait_properties_ready(&self) -> Result<()> {
         let guard = self.twins.lock().await;
         let mut waiter = guard.create_initialization_waiter();
@@ -406,8 +576,16 @@ impl IotHubTwinsClient {
         reported_properties_updates: sqlite_channel::Sender<ReportedPropertiesUpdate>,
         desired_properties_changed: watch::Receiver<u64>,
         desired_properties_updated_callback: Option<Box<dyn DesiredPropertiesUpdatedCallback>>,
+        desired_properties_gap_callback: Option<Arc<dyn DesiredPropertiesGapCallback>>,
+        desired_properties_limit: Option<DesiredPropertiesLimit>,
     ) -> Self {
-        let device_twins = DeviceTwin::init(store, desired_properties_updated_callback).await;
+        let device_twins = DeviceTwin::init(
+            store,
+            desired_properties_updated_callback,
+            desired_properties_gap_callback,
+            desired_properties_limit,
+        )
+        .await;
         IotHubTwinsClient {
             twins: Arc::new(Mutex::new(device_twins)),
             get_twins,
@@ -416,10 +594,53 @@ impl IotHubTwinsClient {
         }
     }
 
+    // Creates a client for a new connection that shares the Device Twin with this one
+    pub fn with_channels(
+        &self,
+        get_twins: mpsc::Sender<()>,
+        reported_properties_updates: sqlite_channel::Sender<ReportedPropertiesUpdate>,
+        desired_properties_changed: watch::Receiver<u64>,
+    ) -> Self {
+        IotHubTwinsClient {
+            twins: self.twins.clone(),
+            get_twins,
+            reported_properties_updates,
+            desired_properties_changed: Mutex::new(desired_properties_changed),
+        }
+    }
+
+    // The subscribers of the pending updates count are kept by the client of the next connection
+    pub fn pending_reported_properties_count(&self) -> Arc<watch::Sender<usize>> {
+        self.reported_properties_updates.count_watch()
+    }
+
     pub(crate) async fn set_twins(&self, twins: Twins) -> Result<()> {
         self.twins.lock().await.set_twins(twins).await
     }
 
+    // Requests the whole Device Twin and optionally waits until the response or another Desired Properties update arrives
+    pub(crate) async fn refresh(&self, wait: bool) -> Result<()> {
+        let mut desired_properties_changed = self.desired_properties_changed.lock().await;
+        // Ignore the changes that happened before the request
+        desired_properties_changed.borrow_and_update();
+
+        self.get_twins().await;
+
+        if wait {
+            desired_properties_changed
+                .changed()
+                .await
+                .context("The client was disconnected before the Device Twin was received")?;
+        }
+
+        Ok(())
+    }
+
+    // The Device Twin is shared by all the connections, so the receiver stays valid after reconnecting
+    pub(crate) async fn subscribe_desired_properties_initialized(&self) -> watch::Receiver<bool> {
+        self.twins.lock().await.subscribe_desired_initialized()
+    }
+
     pub(crate) async fn update_desired_properties(
         &self,
         version: u64,
@@ -459,7 +680,11 @@ impl Clone for IotHubTwinsClient {
 }
 #[cfg(test)]
 mod tests {
-    use crate::persistence::twins::{TwinUpdate, Twins};
+    use crate::persistence::twins::{
+        ReportedPropertiesUpdate, ReportedPropertiesUpdateType, Twin, TwinUpdate, Twins,
+    };
+
+    use super::{apply_reported_properties_update, serialized_len, DesiredPropertiesLimit};
 
     #[test]
     fn deserialize_twins() {
@@ -505,4 +730,47 @@ mod tests {
         assert_eq!(twins.desired.properties, result);
         assert_eq!(twins.desired.version, 11);
     }
+
+    #[test]
+    fn apply_pending_reported_properties_updates() {
+        let mut twin = Twin {
+            version: 3,
+            properties: serde_json::json!({"foo": "bar", "lorem": "ipsum"}),
+        };
+
+        let patch = ReportedPropertiesUpdate {
+            id: Some(1),
+            update_type: ReportedPropertiesUpdateType::Patch,
+            patch: serde_json::json!({"foo": null, "next": 42}),
+        };
+        apply_reported_properties_update(&mut twin, &patch);
+        assert_eq!(
+            twin.properties,
+            serde_json::json!({"lorem": "ipsum", "next": 42})
+        );
+
+        let full = ReportedPropertiesUpdate {
+            id: Some(2),
+            update_type: ReportedPropertiesUpdateType::Full,
+            patch: serde_json::json!({"ahoj": "hi"}),
+        };
+        apply_reported_properties_update(&mut twin, &full);
+        assert_eq!(twin.properties, serde_json::json!({"ahoj": "hi"}));
+        assert_eq!(twin.version, 3);
+    }
+
+    #[test]
+    fn desired_properties_limit() {
+        let properties = serde_json::json!({"foo": "bar"});
+        assert_eq!(serialized_len(&properties), properties.to_string().len());
+
+        let limit = DesiredPropertiesLimit {
+            max_bytes: 13,
+            callback: None,
+        };
+        assert!(limit.check(1, &properties).is_ok());
+        assert!(limit
+            .check(2, &serde_json::json!({"foo": "bar", "lorem": "ipsum"}))
+            .is_err());
+    }
 }
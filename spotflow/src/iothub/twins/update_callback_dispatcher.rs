@@ -5,7 +5,7 @@ use crate::connection::twins::{DesiredProperties, DesiredPropertiesUpdatedCallba
 
 #[derive(Debug)]
 pub struct DesiredPropertiesUpdatedCallbackDispatcher {
-    sender: Option<mpsc::Sender<DesiredProperties>>,
+    sender: Option<mpsc::Sender<(DesiredProperties, Vec<String>)>>,
     thread: Option<JoinHandle<()>>,
 }
 
@@ -13,14 +13,16 @@ impl DesiredPropertiesUpdatedCallbackDispatcher {
     pub fn new(callback: Box<dyn DesiredPropertiesUpdatedCallback>) -> Self {
         let (sender, receiver) = mpsc::channel();
 
-        log::debug!("Starting properties updated processing thread.");
+        debug!("Starting properties updated processing thread.");
         // This is thread and not a simple Tokio task because the user-provided callback can potentially block.
         // That's also the reason why we use an asynchronous (unbounded) channel here - to avoid blocking our internal threads by the user code.
         let thread = std::thread::spawn(move || {
-            while let Ok(properties) = receiver.recv() {
+            while let Ok((properties, changed_paths)) = receiver.recv() {
                 let result = catch_unwind(|| {
-                    if let Err(e) = callback.properties_updated(properties) {
-                        log::error!("Properties updated processing callback failed: {}", e);
+                    if let Err(e) =
+                        callback.properties_updated_with_changes(properties, changed_paths)
+                    {
+                        error!("Properties updated processing callback failed: {}", e);
                     }
                 });
 
@@ -33,14 +35,14 @@ impl DesiredPropertiesUpdatedCallbackDispatcher {
                         "Unknown panic with no string representation.".to_string()
                     };
 
-                    log::error!(
+                    error!(
                         "Properties updated processing callback failed with panic: {}",
                         message
                     );
                 }
             }
 
-            log::debug!("Properties updated processing thread is stopping.");
+            debug!("Properties updated processing thread is stopping.");
         });
 
         Self {
@@ -49,11 +51,15 @@ impl DesiredPropertiesUpdatedCallbackDispatcher {
         }
     }
 
-    pub fn dispatch(&self, properties: DesiredProperties) -> Result<()> {
+    pub fn dispatch(
+        &self,
+        properties: DesiredProperties,
+        changed_paths: Vec<String>,
+    ) -> Result<()> {
         self.sender
             .as_ref()
             .expect("Sender unexpectedly empty")
-            .send(properties)
+            .send((properties, changed_paths))
             .context("Failed to send updated properties to the callback")
     }
 }
@@ -68,7 +74,7 @@ impl Drop for DesiredPropertiesUpdatedCallbackDispatcher {
             .expect("Property update thread join handle unexpectedly empty.")
             .join()
             .unwrap_or_else(|_| {
-                log::error!("Failed joining the thread for processing of property updates.");
+                error!("Failed joining the thread for processing of property updates.");
             });
     }
 }
@@ -31,19 +31,39 @@
 
 use anyhow::Result;
 
+// Must be declared first so that its logging macros are available in all the other modules
+#[macro_use]
+mod logging;
+
 mod cloud;
 mod connection;
+mod error;
 mod ingress;
 mod iothub;
 mod persistence;
 
+#[cfg(feature = "c2d")]
 #[doc(hidden)]
-pub use ingress::CloudToDeviceMessage;
+pub use ingress::{AckMode, BufferPolicy, CloudToDeviceMessage};
+
+#[cfg(feature = "packet-observer")]
+pub use ingress::{PacketDirection, RawPacket, RawPacketObserver};
+
+pub use error::ErrorKind;
+pub use logging::LogSink;
 
 pub use ingress::{
-    Compression, DesiredProperties, DesiredPropertiesUpdatedCallback, DeviceClient,
-    DeviceClientBuilder, MessageContext, ProvisioningOperation,
-    ProvisioningOperationDisplayHandler,
+    BatchIdGenerator, BatchSummary, ChannelConfig, ChunkedUpload, Cipher, Compression,
+    ConnectionStatus, DatabaseCreatedCallback, DatabaseMigration, DesiredProperties,
+    DesiredPropertiesGap, DesiredPropertiesGapCallback, DesiredPropertiesTooLarge,
+    DesiredPropertiesTooLargeCallback, DesiredPropertiesUpdatedCallback, DeviceClient,
+    DeviceClientBuilder, DeviceIdAssignedCallback, DeviceIdAssignment, EffectiveConfig,
+    EnqueueReport, LastWillQos, LinkQuality, LinkQualityLevel, MessageContext, MessageIdGenerator,
+    MessageIds, MigrationCallback, PayloadCodec, PendingMessagesLoadedCallback,
+    ProvisioningOperation, ProvisioningOperationDisplayHandler, ProvisioningPhase,
+    ProvisioningProgressCallback, ProvisioningResult, RecoveryPolicy, ReportedPropertiesBuilder,
+    ReportedPropertiesSentCallback, SdkEvent, StartupInfo, TokenRefreshFailedCallback,
+    TransportMode, WorkspaceState, WorkspaceStateCallback,
 };
 
 pub(crate) mod utils;
@@ -0,0 +1,145 @@
+// Routes the diagnostic records of the Device SDK either to the `log` facade or to the configured `LogSink`
+// All the logging in the crate must use the macros defined here instead of the ones from the `log` crate
+
+use std::{
+    fmt,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+/// Receives the diagnostic records emitted by the Device SDK instead of the global logger of the `log` crate,
+/// see [`DeviceClientBuilder::with_log_sink`](crate::DeviceClientBuilder::with_log_sink).
+///
+/// The target of each record names the subsystem that emitted it, so that the records can be filtered by it:
+/// `spotflow::mqtt` for the connection to the Platform, `spotflow::persistence` for the local database file,
+/// `spotflow::provisioning` for Device Provisioning and the renewal of the credentials, and `spotflow::twins`
+/// for the Device Twin. The records of the other parts of the Device SDK have their module paths as targets.
+pub trait LogSink: Send + Sync {
+    /// Check whether the records with the given level should be passed to [`LogSink::log`].
+    /// All the records are passed by default.
+    fn enabled(&self, level: log::Level) -> bool {
+        let _ = level;
+        true
+    }
+
+    /// Handle a diagnostic record emitted by the Device SDK.
+    fn log(&self, record: &log::Record<'_>);
+}
+
+static SINK: RwLock<Option<Arc<dyn LogSink>>> = RwLock::new(None);
+
+// The modules whose records are assigned to the subsystems, the more specific prefixes must go first
+const SUBSYSTEM_TARGETS: &[(&str, &str)] = &[
+    ("spotflow::connection::twins", "spotflow::twins"),
+    ("spotflow::iothub::handlers::twins", "spotflow::twins"),
+    ("spotflow::iothub::twins", "spotflow::twins"),
+    ("spotflow::persistence::twins", "spotflow::twins"),
+    ("spotflow::iothub::token_handler", "spotflow::provisioning"),
+    ("spotflow::iothub", "spotflow::mqtt"),
+    ("spotflow::persistence", "spotflow::persistence"),
+    ("spotflow::cloud", "spotflow::provisioning"),
+    ("spotflow::ingress::builder", "spotflow::provisioning"),
+];
+
+// Finds the subsystem of the module, so that the records can be filtered without knowing the internal modules
+fn target(module_path: &'static str) -> &'static str {
+    SUBSYSTEM_TARGETS
+        .iter()
+        .find(|(prefix, _)| {
+            module_path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .map_or(module_path, |(_, target)| target)
+}
+
+pub(crate) fn set_sink(sink: Arc<dyn LogSink>) {
+    *SINK.write().unwrap_or_else(PoisonError::into_inner) = Some(sink);
+}
+
+pub(crate) fn emit(
+    level: log::Level,
+    module_path: &'static str,
+    file: &'static str,
+    line: u32,
+    args: fmt::Arguments<'_>,
+) {
+    let sink = SINK.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+    let enabled = match &sink {
+        Some(sink) => sink.enabled(level),
+        None => level <= log::max_level(),
+    };
+    if !enabled {
+        return;
+    }
+
+    let record = log::Record::builder()
+        .level(level)
+        .target(target(module_path))
+        .module_path_static(Some(module_path))
+        .file_static(Some(file))
+        .line(Some(line))
+        .args(args)
+        .build();
+
+    match sink {
+        Some(sink) => sink.log(&record),
+        None => log::logger().log(&record),
+    }
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::emit($level, module_path!(), file!(), line!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { log_at!(::log::Level::Trace, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_at!(::log::Level::Debug, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_at!(::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_at!(::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log_at!(::log::Level::Error, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsystem_targets() {
+        assert_eq!(target("spotflow::iothub::eventloop"), "spotflow::mqtt");
+        assert_eq!(target("spotflow::iothub"), "spotflow::mqtt");
+        assert_eq!(
+            target("spotflow::iothub::twins::handler"),
+            "spotflow::twins"
+        );
+        assert_eq!(
+            target("spotflow::iothub::token_handler"),
+            "spotflow::provisioning"
+        );
+        assert_eq!(
+            target("spotflow::persistence::sqlite"),
+            "spotflow::persistence"
+        );
+        assert_eq!(target("spotflow::cloud::dps"), "spotflow::provisioning");
+    }
+
+    #[test]
+    fn other_modules_keep_their_paths() {
+        assert_eq!(target("spotflow::ingress::base"), "spotflow::ingress::base");
+        assert_eq!(target("spotflow::iothubs"), "spotflow::iothubs");
+    }
+}
@@ -29,7 +29,7 @@ impl Storable for CloudToDeviceMessage {
         .fetch_one(&mut *transaction)
         .await?;
 
-        log::debug!("Saved C2D message with ID {}", record.id);
+        debug!("Saved C2D message with ID {}", record.id);
 
         for (k, v) in &self.properties {
             sqlx::query!(
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{CloseOption, Compression, DeviceMessage};
+
+// A single line of the newline-delimited JSON produced by the export of the pending messages
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedMessage {
+    site_id: Option<String>,
+    stream_group: Option<String>,
+    stream: Option<String>,
+    source_device_id: Option<String>,
+    batch_id: Option<String>,
+    message_id: Option<String>,
+    batch_slice_id: Option<String>,
+    chunk_id: Option<String>,
+    close_option: CloseOption,
+    compression: Compression,
+    expiry: Option<DateTime<Utc>>,
+    properties: Option<String>,
+    ordered: bool,
+    content_type: Option<String>,
+    // The content is stored as it is in the database, i.e., already compressed if the compression is enabled
+    content: String,
+}
+
+impl From<&DeviceMessage> for ExportedMessage {
+    fn from(msg: &DeviceMessage) -> Self {
+        ExportedMessage {
+            site_id: msg.site_id.clone(),
+            stream_group: msg.stream_group.clone(),
+            stream: msg.stream.clone(),
+            source_device_id: msg.source_device_id.clone(),
+            batch_id: msg.batch_id.clone(),
+            message_id: msg.message_id.clone(),
+            batch_slice_id: msg.batch_slice_id.clone(),
+            chunk_id: msg.chunk_id.clone(),
+            close_option: msg.close_option,
+            compression: msg.compression,
+            expiry: msg.expiry,
+            properties: msg.properties.clone(),
+            ordered: msg.ordered,
+            content_type: msg.content_type.clone(),
+            content: STANDARD.encode(&msg.content),
+        }
+    }
+}
+
+impl TryFrom<ExportedMessage> for DeviceMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(msg: ExportedMessage) -> Result<Self> {
+        // The properties are parsed only when the message is being sent, so they must be valid before it's stored
+        if let Some(properties) = &msg.properties {
+            serde_json::from_str::<HashMap<String, String>>(properties)
+                .context("The properties of the message are not a JSON object of strings")?;
+        }
+
+        Ok(DeviceMessage {
+            id: None,
+            site_id: msg.site_id,
+            stream_group: msg.stream_group,
+            stream: msg.stream,
+            source_device_id: msg.source_device_id,
+            batch_id: msg.batch_id,
+            message_id: msg.message_id,
+            content: STANDARD
+                .decode(msg.content)
+                .context("The content of the message is not valid base64")?,
+            close_option: msg.close_option,
+            compression: msg.compression,
+            batch_slice_id: msg.batch_slice_id,
+            chunk_id: msg.chunk_id,
+            expiry: msg.expiry,
+            properties: msg.properties,
+            ordered: msg.ordered,
+            content_type: msg.content_type,
+        })
+    }
+}
+
+pub(crate) fn write_message(writer: &mut impl Write, msg: &DeviceMessage) -> Result<()> {
+    serde_json::to_writer(&mut *writer, &ExportedMessage::from(msg))
+        .context("Unable to serialize the message")?;
+    writer
+        .write_all(b"\n")
+        .context("Unable to write the message")?;
+    Ok(())
+}
+
+// Reads the messages one line at a time, empty lines are skipped
+pub(crate) fn read_messages(reader: impl BufRead) -> impl Iterator<Item = Result<DeviceMessage>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(index, line)| {
+            let line = line.context("Unable to read the exported messages")?;
+            let msg: ExportedMessage = serde_json::from_str(&line)
+                .with_context(|| format!("Unable to parse the message on line {}", index + 1))?;
+            DeviceMessage::try_from(msg)
+                .with_context(|| format!("Invalid message on line {}", index + 1))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let msg = DeviceMessage {
+            id: Some(42),
+            site_id: None,
+            stream_group: Some("group".to_owned()),
+            stream: Some("stream".to_owned()),
+            source_device_id: None,
+            batch_id: Some("batch".to_owned()),
+            message_id: Some("message".to_owned()),
+            content: vec![0, 1, 2, 255],
+            close_option: CloseOption::Close,
+            compression: Compression::Gzip,
+            batch_slice_id: None,
+            chunk_id: None,
+            expiry: None,
+            properties: Some(r#"{"key":"value"}"#.to_owned()),
+            ordered: false,
+            content_type: Some("application/json".to_owned()),
+        };
+
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &msg).unwrap();
+        write_message(&mut buffer, &msg).unwrap();
+
+        let messages = read_messages(buffer.as_slice())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+
+        let imported = &messages[0];
+        assert_eq!(imported.id, None);
+        assert_eq!(imported.stream_group.as_deref(), Some("group"));
+        assert_eq!(imported.stream.as_deref(), Some("stream"));
+        assert_eq!(imported.batch_id.as_deref(), Some("batch"));
+        assert_eq!(imported.message_id.as_deref(), Some("message"));
+        assert_eq!(imported.content, msg.content);
+        assert!(matches!(imported.close_option, CloseOption::Close));
+        assert!(matches!(imported.compression, Compression::Gzip));
+        assert_eq!(imported.properties, msg.properties);
+        assert!(!imported.ordered);
+        assert_eq!(imported.content_type, msg.content_type);
+    }
+
+    #[test]
+    fn invalid_properties() {
+        let mut line = serde_json::to_value(ExportedMessage::from(&DeviceMessage {
+            id: None,
+            site_id: None,
+            stream_group: None,
+            stream: Some("stream".to_owned()),
+            source_device_id: None,
+            batch_id: None,
+            message_id: None,
+            content: Vec::new(),
+            close_option: CloseOption::None,
+            compression: Compression::None,
+            batch_slice_id: None,
+            chunk_id: None,
+            expiry: None,
+            properties: None,
+            ordered: true,
+            content_type: None,
+        }))
+        .unwrap();
+        line["properties"] = serde_json::Value::from(r#"{"key":1}"#);
+
+        let result = read_messages(line.to_string().as_bytes()).collect::<Result<Vec<_>>>();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_line() {
+        let result = read_messages("\n{}\n".as_bytes()).collect::<Result<Vec<_>>>();
+        assert!(result.is_err());
+    }
+}
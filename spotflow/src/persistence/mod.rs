@@ -1,21 +1,34 @@
-use std::collections::HashMap;
-use std::{path::Path, str::FromStr};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex as StdMutex, PoisonError};
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::cloud::dps::{ProvisioningToken, RegistrationToken};
-use anyhow::{Context, Result};
+use crate::ingress::{PayloadCodec, PendingMessagesLoadedCallback};
+use crate::utils::metrics;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
 use http::Uri;
+use serde::{Deserialize, Serialize};
 use sqlite::SdkConfiguration;
+#[cfg(feature = "c2d")]
 use sqlite_channel::{Receiver, Sender};
 use tokio::{
     select,
-    sync::{mpsc, watch},
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
 };
 use tokio_util::sync::CancellationToken;
-use twins::Twin;
+use twins::{ReportedPropertiesUpdate, Twin};
 
 use self::sqlite::SqliteStore;
 
+#[cfg(feature = "c2d")]
 pub mod c2d;
+pub(crate) mod export;
 pub mod sqlite;
 pub mod sqlite_channel;
 pub mod twins;
@@ -23,28 +36,108 @@ pub mod twins;
 pub struct Store {
     pub store: SqliteStore,
     pub d2c_producer: Producer,
-    pub d2c_consumer: Consumer,
-    pub d2c_acknowledger: Acknowledger,
     pub configuration_store: ConfigurationStore,
-    pub c2d_producer: Sender<CloudToDeviceMessage>,
+    pub c2d_producer: CloudToDeviceProducer,
+    #[cfg(feature = "c2d")]
     pub c2d_consumer: Receiver<CloudToDeviceMessage>,
     pub twins_store: TwinsStore,
 }
 
+// Transforms the content of the messages before they are stored and after they are loaded, the content is unchanged without a codec
+#[derive(Clone, Default)]
+struct ContentCodec(Option<Arc<dyn PayloadCodec>>);
+
+impl std::fmt::Debug for ContentCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentCodec")
+            .field("custom", &self.0.is_some())
+            .finish()
+    }
+}
+
+impl ContentCodec {
+    fn encode(&self, msg: &mut DeviceMessage) -> Result<()> {
+        if let Some(codec) = &self.0 {
+            msg.content = codec
+                .encode(&msg.content)
+                .context("Unable to encode the content of device to cloud message")?;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, msg: &mut DeviceMessage) -> Result<()> {
+        if let Some(codec) = &self.0 {
+            msg.content = codec
+                .decode(&msg.content)
+                .context("Unable to decode the content of device to cloud message")?;
+        }
+        Ok(())
+    }
+}
+
+// The senders notifying about the outcome of the tracked messages, keyed by their IDs
+type Deliveries = Arc<Mutex<HashMap<i32, oneshot::Sender<Result<()>>>>>;
+
 #[derive(Debug)]
 pub struct Producer {
     inner: SqliteStore,
     sender: watch::Sender<i32>,
+    // Kept so that new consumers can be subscribed to the notifications of new messages
+    receiver: watch::Receiver<i32>,
+    deliveries: Deliveries,
+    // Whether the consumers should stop sending the messages, shared by all of them
+    paused: watch::Sender<bool>,
+    // The ID of the newest message removed by clearing, the consumers skip the messages loaded before they were removed
+    cleared: watch::Sender<i32>,
+    codec: ContentCodec,
 }
 
 #[derive(Debug)]
 pub struct Consumer {
+    inner: SqliteStore,
     receiver: mpsc::Receiver<DeviceMessage>,
+    deliveries: Deliveries,
+    paused: watch::Receiver<bool>,
+    cleared: watch::Receiver<i32>,
+    in_flight: Arc<InFlight>,
+    codec: ContentCodec,
 }
 
 #[derive(Debug)]
 pub struct Acknowledger {
     inner: SqliteStore,
+    deliveries: Deliveries,
+    in_flight: Arc<InFlight>,
+}
+
+// The messages that were published but haven't been acknowledged yet
+// Unordered messages can be acknowledged in any order, so they are matched to the acknowledgments by their packet IDs
+#[derive(Debug, Default)]
+struct InFlight {
+    messages: StdMutex<InFlightMessages>,
+    acknowledged: Notify,
+}
+
+#[derive(Debug, Default)]
+struct InFlightMessages {
+    // The IDs of the published messages whose packet IDs aren't known yet, in the order they were published
+    unassigned: VecDeque<(i32, Instant)>,
+    // The IDs of the messages keyed by their packet IDs, together with the times they were published
+    assigned: HashMap<u16, (i32, Instant)>,
+}
+
+impl InFlightMessages {
+    fn is_empty(&self) -> bool {
+        self.unassigned.is_empty() && self.assigned.is_empty()
+    }
+
+    fn oldest_published(&self) -> Option<Instant> {
+        self.unassigned
+            .iter()
+            .chain(self.assigned.values())
+            .map(|(_, published)| *published)
+            .min()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -54,44 +147,400 @@ pub struct ConfigurationStore {
 }
 
 impl Producer {
-    pub async fn add(&self, mut msg: DeviceMessage) -> Result<()> {
+    pub async fn add(&self, mut msg: DeviceMessage) -> Result<i32> {
+        self.codec.encode(&mut msg)?;
+        self.add_encoded(msg).await
+    }
+
+    // Adds the message whose content was already encoded, for example, an imported one
+    pub async fn add_encoded(&self, mut msg: DeviceMessage) -> Result<i32> {
         let id = self
             .inner
             .store_message(&msg)
             .await
             .context("Unable to store device to cloud message")?;
         msg.id = Some(id);
+        metrics::message_enqueued();
         self.sender
             .send(id)
             .context("Unable to send notification of new message")?;
 
-        Ok(())
+        Ok(id)
+    }
+
+    // Adds the message unless a pending message with the same idempotency key was already added, returns whether it was added
+    pub async fn add_once(&self, mut msg: DeviceMessage, idempotency_key: &str) -> Result<bool> {
+        self.codec.encode(&mut msg)?;
+        let Some(id) = self
+            .inner
+            .store_message_once(&msg, idempotency_key)
+            .await
+            .context("Unable to store device to cloud message")?
+        else {
+            return Ok(false);
+        };
+        msg.id = Some(id);
+        metrics::message_enqueued();
+        self.sender
+            .send(id)
+            .context("Unable to send notification of new message")?;
+
+        Ok(true)
+    }
+
+    // Adds all the messages in a single transaction, either all of them are stored or none
+    pub async fn add_all(&self, mut msgs: Vec<DeviceMessage>) -> Result<Vec<i32>> {
+        for msg in &mut msgs {
+            self.codec.encode(msg)?;
+        }
+        self.add_all_encoded(msgs).await
+    }
+
+    // Adds all the messages whose content was already encoded in a single transaction, for example, the imported ones
+    pub async fn add_all_encoded(&self, msgs: Vec<DeviceMessage>) -> Result<Vec<i32>> {
+        let ids = self
+            .inner
+            .store_messages(&msgs)
+            .await
+            .context("Unable to store device to cloud messages")?;
+        for _ in &ids {
+            metrics::message_enqueued();
+        }
+        if let Some(&last_id) = ids.last() {
+            self.sender
+                .send(last_id)
+                .context("Unable to send notification of new messages")?;
+        }
+
+        Ok(ids)
+    }
+
+    // Adds the message and returns a receiver that is notified when the Platform acknowledges it
+    // The receiver fails if the message is removed without being sent
+    pub async fn add_tracked(&self, msg: DeviceMessage) -> Result<oneshot::Receiver<Result<()>>> {
+        // The lock is held until the sender is registered so that a fast acknowledgment can't be missed
+        let mut deliveries = self.deliveries.lock().await;
+        let id = self.add(msg).await?;
+
+        let (sender, receiver) = oneshot::channel();
+        deliveries.insert(id, sender);
+
+        Ok(receiver)
     }
 
     pub async fn count(&self) -> Result<usize> {
         self.inner.message_count().await
     }
+
+    // The messages are still stored while sending is paused, they are sent once it's resumed
+    pub fn set_sending_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn is_sending_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub async fn oldest_enqueued_at(&self) -> Result<Option<DateTime<Utc>>> {
+        self.inner.oldest_message_enqueued_at().await
+    }
+
+    pub async fn pending_batches(&self) -> Result<Vec<BatchSummary>> {
+        self.inner.pending_batches().await
+    }
+
+    pub async fn batch_progress(&self, batch_id: &str) -> Result<Option<f32>> {
+        self.inner.batch_progress(batch_id).await
+    }
+
+    pub async fn pending_message_parts(&self, batch_id: &str, message_id: &str) -> Result<usize> {
+        self.inner.pending_message_parts(batch_id, message_id).await
+    }
+
+    // Loads the next page of the pending messages, the messages with IDs lower than or equal to `after` are skipped
+    pub async fn list_after(&self, after: i32) -> Result<Vec<DeviceMessage>> {
+        self.inner.list_messages_after(after).await
+    }
+
+    // The consumers may keep sending, the messages are acknowledged by their IDs, so no newer message can be removed
+    pub async fn clear(&self) -> Result<usize> {
+        let (removed, newest) = self.inner.clear_messages().await?;
+        if let Some(newest) = newest {
+            self.cleared.send_replace(newest);
+        }
+        metrics::queue_depth(0);
+        // Dropping the senders notifies the tracked messages that they won't be sent
+        self.deliveries.lock().await.clear();
+        Ok(removed)
+    }
+
+    // Starts loading the persisted messages, beginning with the oldest one, and passing them to the returned consumer
+    // Each connection to the Platform subscribes anew so that the messages that weren't acknowledged are sent again
+    // The callback is notified about the number of the pending messages before the first one is passed to the consumer
+    pub fn subscribe(
+        &self,
+        capacity: usize,
+        pending_messages_loaded_callback: Option<Arc<dyn PendingMessagesLoadedCallback>>,
+        cancellation_token: CancellationToken,
+    ) -> (Consumer, Acknowledger) {
+        let (message_sender, message_receiver) = mpsc::channel(capacity);
+        let mut latest_msg_id_receiver = self.receiver.clone();
+        let sqlite = self.inner.clone();
+
+        tokio::spawn(async move {
+            if let Some(callback) = pending_messages_loaded_callback {
+                match sqlite.message_count().await {
+                    Ok(count) => {
+                        debug!("{count} messages were pending on startup");
+                        // The callback is called on a separate thread so that it can't delay loading the messages
+                        tokio::task::spawn_blocking(move || {
+                            callback.pending_messages_loaded(count);
+                        });
+                    }
+                    Err(e) => warn!("Unable to count the messages pending on startup: {e:?}"),
+                }
+            }
+
+            let mut last_id = -1;
+            loop {
+                let messages = sqlite
+                    .list_messages_after(last_id)
+                    .await
+                    .expect("Unable to load saved device messages");
+
+                if !messages.is_empty() {
+                    trace!(
+                        "At least {} messages were persisted and are ready to be sent",
+                        messages.len()
+                    );
+                    last_id = messages
+                        .last()
+                        .expect("We checked that the vec is not empty")
+                        .id
+                        .expect("ID is not empty after being stored in store");
+
+                    for msg in messages {
+                        select!(
+                            () = cancellation_token.cancelled() => {
+                                // Cancelled
+                                return;
+                            },
+                            sent = message_sender.send(msg) => {
+                                if sent.is_err() {
+                                    // No more receivers
+                                    debug!("There is no one listening for messages to be sent. Finishing sender publisher.");
+                                    return;
+                                }
+                            },
+                        );
+                    }
+                } else if *latest_msg_id_receiver.borrow_and_update() == last_id {
+                    select!(
+                        () = cancellation_token.cancelled() => {
+                            // Cancelled
+                            return;
+                        },
+                        read = latest_msg_id_receiver.changed() => {
+                            if read.is_err() {
+                                // No more updates are coming
+                                return;
+                            }
+                            // else we start running the loop again
+                        },
+                    );
+                }
+            }
+        });
+
+        let in_flight = Arc::new(InFlight::default());
+
+        let consumer = Consumer {
+            inner: self.inner.clone(),
+            receiver: message_receiver,
+            deliveries: self.deliveries.clone(),
+            paused: self.paused.subscribe(),
+            cleared: self.cleared.subscribe(),
+            in_flight: in_flight.clone(),
+            codec: self.codec.clone(),
+        };
+
+        let acknowledger = Acknowledger {
+            inner: self.inner.clone(),
+            deliveries: self.deliveries.clone(),
+            in_flight,
+        };
+
+        (consumer, acknowledger)
+    }
 }
 
 impl Consumer {
     pub async fn get_message(&mut self) -> Option<DeviceMessage> {
-        self.receiver.recv().await
+        loop {
+            let msg = self.receiver.recv().await?;
+            // The message might have been loaded before the pending messages were cleared
+            let cleared = msg.id.is_some_and(|id| id <= *self.cleared.borrow());
+            if !cleared {
+                return Some(msg);
+            }
+        }
+    }
+
+    // Restores the content of the message as it was before it was stored
+    pub fn decode(&self, msg: &mut DeviceMessage) -> Result<()> {
+        self.codec.decode(msg)
+    }
+
+    pub fn is_expired(&self, msg: &DeviceMessage) -> bool {
+        msg.expiry
+            .is_some_and(|expiry| expiry <= self.inner.clock().now_utc())
+    }
+
+    // Waits until sending isn't paused, returns immediately if it isn't
+    pub async fn wait_resumed(&mut self) {
+        while *self.paused.borrow_and_update() {
+            // The producer was dropped, so nothing can resume sending anymore
+            if self.paused.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    // Must be called right before the message is handed over to MQTT so that its packet ID can be assigned in the same order
+    pub fn published(&self, id: i32) {
+        self.in_flight
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .unassigned
+            .push_back((id, Instant::now()));
+    }
+
+    // Waits until all the published messages are acknowledged, ordered messages are published only after that
+    pub async fn wait_all_acknowledged(&self) {
+        loop {
+            if self
+                .in_flight
+                .messages
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_empty()
+            {
+                return;
+            }
+            // An acknowledgment received since the check leaves a permit, so it isn't missed
+            self.in_flight.acknowledged.notified().await;
+        }
+    }
+
+    // Removes a message that will not be sent, so it will not be acknowledged either
+    // The reason completes the error of the tracked message, for example, "expired before it was sent"
+    pub async fn discard(&self, msg: &DeviceMessage, reason: &str) -> Result<()> {
+        let id = msg.id.expect("ID is not empty after being stored in store");
+        self.inner.remove_message(id).await?;
+        metrics::message_discarded();
+
+        if let Some(delivery) = self.deliveries.lock().await.remove(&id) {
+            _ = delivery.send(Err(anyhow!("The message with ID {id} {reason}")));
+        }
+
+        Ok(())
     }
 }
 
 impl Acknowledger {
-    pub async fn remove_oldest(&self) -> Result<()> {
-        self.inner.remove_oldest_message().await
+    // Matches the packet ID to the oldest published message that doesn't have one yet
+    // Retransmissions of already matched packets are ignored
+    pub fn packet_published(&self, pkid: u16) {
+        let mut messages = self
+            .in_flight
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if messages.assigned.contains_key(&pkid) {
+            return;
+        }
+        if let Some(entry) = messages.unassigned.pop_front() {
+            messages.assigned.insert(pkid, entry);
+        }
+    }
+
+    // The time when the oldest message that hasn't been acknowledged yet was published
+    pub fn oldest_unacknowledged(&self) -> Option<Instant> {
+        self.in_flight
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .oldest_published()
+    }
+
+    // Restarts the publish timeouts of all the messages, used when they are going to be retransmitted on a new connection
+    pub fn restart_publish_timeouts(&self) {
+        let now = Instant::now();
+        let mut messages = self
+            .in_flight
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let messages = &mut *messages;
+        for (_, published) in messages
+            .unassigned
+            .iter_mut()
+            .chain(messages.assigned.values_mut())
+        {
+            *published = now;
+        }
+    }
+
+    // Removes the message with the given packet ID and returns how long it took to acknowledge it if there was such a message
+    // Packets other than device to cloud messages are not tracked, so their acknowledgments are ignored
+    pub async fn acknowledge(&self, pkid: u16) -> Result<Option<Duration>> {
+        let id = self
+            .in_flight
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .assigned
+            .remove(&pkid);
+        let Some((id, published)) = id else {
+            return Ok(None);
+        };
+        let latency = published.elapsed();
+        self.in_flight.acknowledged.notify_one();
+
+        self.inner.remove_message(id).await?;
+        metrics::message_sent();
+
+        if let Some(delivery) = self.deliveries.lock().await.remove(&id) {
+            // The receiver might not be waiting anymore
+            _ = delivery.send(Ok(()));
+        }
+
+        Ok(Some(latency))
     }
 }
 
 #[allow(dead_code)] // Not all the load methods are currently used, but we'll keep the interface "round" for now
 impl ConfigurationStore {
+    #[cfg(test)]
+    pub(crate) async fn in_memory() -> Result<Self> {
+        Ok(ConfigurationStore {
+            inner: SqliteStore::in_memory().await?,
+            site_id: None,
+        })
+    }
+
     pub async fn load_instance_url(&self) -> Result<Uri> {
         let url = self.inner.load_instance_url().await?;
         Uri::from_str(&url).context("Unable to parse the Platform instance URL from configuration.")
     }
 
+    pub async fn save_instance_url(&self, instance_url: &Uri) -> Result<()> {
+        self.inner
+            .save_instance_url(&instance_url.to_string())
+            .await
+    }
+
     pub async fn load_provisioning_token(&self) -> Result<ProvisioningToken> {
         self.inner.load_provisioning_token().await
     }
@@ -120,6 +569,10 @@ impl ConfigurationStore {
         self.inner.save_workspace_id(workspace_id).await
     }
 
+    pub async fn load_device_instance_id(&self) -> Result<String> {
+        self.inner.load_device_instance_id().await
+    }
+
     pub async fn load_device_id(&self) -> Result<String> {
         self.inner.load_device_id().await
     }
@@ -154,96 +607,42 @@ impl TwinsStore {
     pub async fn save_reported_properties(&self, twin: &Twin) -> Result<()> {
         self.inner.save_reported_properties(twin).await
     }
-}
-
-pub async fn create(
-    store_path: &Path,
-    config: &SdkConfiguration,
-    cancellation_token: CancellationToken,
-) -> Result<Store> {
-    let sqlite = SqliteStore::init(store_path, config).await?;
 
-    Ok(start(sqlite, config, cancellation_token))
+    pub async fn load_pending_reported_properties_updates(
+        &self,
+    ) -> Result<Vec<ReportedPropertiesUpdate>> {
+        let mut conn = self.inner.read_connection().await;
+        ReportedPropertiesUpdate::load_all(&mut conn).await
+    }
 }
 
-fn start(
-    sqlite: SqliteStore,
-    config: &SdkConfiguration,
-    cancellation_token: CancellationToken,
-) -> Store {
-    let (message_sender, message_receiver) = mpsc::channel(100);
-    let (latest_msg_id_sender, mut latest_msg_id_receiver) = watch::channel(-1);
+pub async fn create(store_path: &Path, config: &SdkConfiguration) -> Result<Store> {
+    let sqlite = SqliteStore::init(store_path, config).await?;
 
-    {
-        let sqlite = sqlite.clone();
+    #[cfg(feature = "metrics")]
+    metrics::queue_depth(sqlite.message_count().await?);
 
-        tokio::spawn(async move {
-            let mut last_id = -1;
-            loop {
-                let messages = sqlite
-                    .list_messages_after(last_id)
-                    .await
-                    .expect("Unable to load saved device messages");
-
-                if !messages.is_empty() {
-                    log::trace!(
-                        "At least {} messages were persisted and are ready to be sent",
-                        messages.len()
-                    );
-                    last_id = messages
-                        .last()
-                        .expect("We checked that the vec is not empty")
-                        .id
-                        .expect("ID is not empty after being stored in store");
+    Ok(start(sqlite, config))
+}
 
-                    for msg in messages {
-                        select!(
-                            () = cancellation_token.cancelled() => {
-                                // Cancelled
-                                return;
-                            },
-                            sent = message_sender.send(msg) => {
-                                if sent.is_err() {
-                                    // No more receivers
-                                    log::debug!("There is no one listening for messages to be sent. Finishing sender publisher.");
-                                    return;
-                                }
-                            },
-                        );
-                    }
-                } else if *latest_msg_id_receiver.borrow_and_update() == last_id {
-                    select!(
-                        () = cancellation_token.cancelled() => {
-                            // Cancelled
-                            return;
-                        },
-                        read = latest_msg_id_receiver.changed() => {
-                            if read.is_err() {
-                                // No more updates are coming
-                                return;
-                            }
-                            // else we start running the loop again
-                        },
-                    );
-                }
-            }
-        });
-    }
+fn start(sqlite: SqliteStore, config: &SdkConfiguration) -> Store {
+    let (latest_msg_id_sender, latest_msg_id_receiver) = watch::channel(-1);
 
     let producer = Producer {
         inner: sqlite.clone(),
         sender: latest_msg_id_sender,
+        receiver: latest_msg_id_receiver,
+        deliveries: Arc::default(),
+        paused: watch::channel(false).0,
+        cleared: watch::channel(-1).0,
+        codec: ContentCodec(config.payload_codec.clone()),
     };
 
-    let consumer = Consumer {
-        receiver: message_receiver,
-    };
-
-    let acknowledger = Acknowledger {
-        inner: sqlite.clone(),
-    };
-
-    let (c2d_producer, c2d_consumer) = sqlite_channel::channel(sqlite.clone());
+    #[cfg(feature = "c2d")]
+    let (c2d_producer, c2d_consumer) =
+        sqlite_channel::channel(sqlite.clone(), config.c2d_buffer_policy.retained());
+    #[cfg(not(feature = "c2d"))]
+    let c2d_producer = CloudToDeviceProducer;
 
     let token_store = ConfigurationStore {
         inner: sqlite.clone(),
@@ -257,21 +656,58 @@ fn start(
     Store {
         store: sqlite,
         d2c_producer: producer,
-        d2c_consumer: consumer,
-        d2c_acknowledger: acknowledger,
         configuration_store: token_store,
         c2d_producer,
+        #[cfg(feature = "c2d")]
         c2d_consumer,
         twins_store,
     }
 }
 
+impl Store {
+    // The tests use the default configuration, such as no payload codec and an unbounded Cloud-to-Device buffer
+    #[cfg(test)]
+    pub(crate) async fn in_memory() -> Result<Store> {
+        let sqlite = SqliteStore::in_memory().await?;
+        let (latest_msg_id_sender, latest_msg_id_receiver) = watch::channel(-1);
+
+        #[cfg(feature = "c2d")]
+        let (c2d_producer, c2d_consumer) = sqlite_channel::channel(sqlite.clone(), None);
+        #[cfg(not(feature = "c2d"))]
+        let c2d_producer = CloudToDeviceProducer;
+
+        Ok(Store {
+            d2c_producer: Producer {
+                inner: sqlite.clone(),
+                sender: latest_msg_id_sender,
+                receiver: latest_msg_id_receiver,
+                deliveries: Arc::default(),
+                paused: watch::channel(false).0,
+                cleared: watch::channel(-1).0,
+                codec: ContentCodec::default(),
+            },
+            configuration_store: ConfigurationStore {
+                inner: sqlite.clone(),
+                site_id: None,
+            },
+            c2d_producer,
+            #[cfg(feature = "c2d")]
+            c2d_consumer,
+            twins_store: TwinsStore {
+                inner: sqlite.clone(),
+            },
+            store: sqlite,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct DeviceMessage {
     pub id: Option<i32>,
     pub site_id: Option<String>,
     pub stream_group: Option<String>,
     pub stream: Option<String>,
+    pub source_device_id: Option<String>,
     pub batch_id: Option<String>,
     pub message_id: Option<String>,
     pub content: Vec<u8>,
@@ -279,9 +715,36 @@ pub struct DeviceMessage {
     pub compression: Compression,
     pub batch_slice_id: Option<String>,
     pub chunk_id: Option<String>,
+    pub expiry: Option<DateTime<Utc>>,
+    // User properties serialized as a JSON object
+    pub properties: Option<String>,
+    // Unordered messages may be sent while the previous messages haven't been acknowledged yet
+    pub ordered: bool,
+    pub content_type: Option<String>,
+}
+
+/// A summary of the [Messages](https://docs.spotflow.io/send-data/#message) of a single
+/// [Batch](https://docs.spotflow.io/send-data/#batch) that haven't been sent to the Platform yet.
+#[derive(Clone, Debug)]
+pub struct BatchSummary {
+    /// The ID of the [Batch](https://docs.spotflow.io/send-data/#batch).
+    pub batch_id: String,
+    /// The number of pending [Messages](https://docs.spotflow.io/send-data/#message) in the Batch.
+    pub message_count: usize,
+    /// The time when the oldest pending [Message](https://docs.spotflow.io/send-data/#message) of the Batch was enqueued.
+    pub oldest_enqueued_at: Option<DateTime<Utc>>,
 }
 
+#[cfg(feature = "c2d")]
+pub type CloudToDeviceProducer = Sender<CloudToDeviceMessage>;
+
+// Without the `c2d` feature, Cloud-to-Device Messages aren't received at all, so there is nothing to store
+#[cfg(not(feature = "c2d"))]
+#[derive(Clone, Debug)]
+pub struct CloudToDeviceProducer;
+
 /// **Warning**: Don't use, the interface for Cloud-to-Device Messages hasn't been finalized yet.
+#[cfg(feature = "c2d")]
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct CloudToDeviceMessage {
@@ -290,6 +753,7 @@ pub struct CloudToDeviceMessage {
     pub properties: HashMap<String, String>,
 }
 
+#[cfg(feature = "c2d")]
 impl CloudToDeviceMessage {
     #[must_use]
     pub fn new(content: Vec<u8>, properties: HashMap<String, String>) -> Self {
@@ -301,7 +765,7 @@ impl CloudToDeviceMessage {
     }
 }
 
-#[derive(Copy, Clone, Debug, sqlx::Type)]
+#[derive(Copy, Clone, Debug, sqlx::Type, Serialize, Deserialize)]
 pub enum CloseOption {
     None,
     Close,
@@ -309,9 +773,67 @@ pub enum CloseOption {
     CloseMessageOnly,
 }
 
-#[derive(Copy, Clone, Debug, sqlx::Type)]
+#[derive(Copy, Clone, Debug, sqlx::Type, Serialize, Deserialize)]
 pub enum Compression {
     None,
     BrotliFastest,
     BrotliSmallestSize,
+    Gzip,
+    // The content was compressed using Brotli with custom parameters before it was stored
+    Brotli,
+    // The content was compressed using gzip before it was stored
+    PrecompressedGzip,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &[u8]) -> DeviceMessage {
+        DeviceMessage {
+            id: None,
+            site_id: None,
+            stream_group: None,
+            stream: Some(String::from("stream")),
+            source_device_id: None,
+            batch_id: None,
+            message_id: None,
+            content: content.to_vec(),
+            close_option: CloseOption::None,
+            compression: Compression::None,
+            batch_slice_id: None,
+            chunk_id: None,
+            expiry: None,
+            properties: None,
+            ordered: true,
+            content_type: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn cleared_messages_are_skipped_by_consumer() {
+        let (sender, receiver) = watch::channel(-1);
+        let producer = Producer {
+            inner: SqliteStore::in_memory().await.unwrap(),
+            sender,
+            receiver,
+            deliveries: Arc::default(),
+            paused: watch::channel(false).0,
+            cleared: watch::channel(-1).0,
+            codec: ContentCodec::default(),
+        };
+
+        producer.add(message(b"cleared")).await.unwrap();
+        let (mut consumer, _acknowledger) = producer.subscribe(10, None, CancellationToken::new());
+
+        // The message is already loaded for sending when the queue is cleared
+        while consumer.receiver.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(producer.clear().await.unwrap(), 1);
+
+        producer.add(message(b"kept")).await.unwrap();
+        let msg = consumer.get_message().await.unwrap();
+        assert_eq!(msg.content, b"kept");
+    }
 }
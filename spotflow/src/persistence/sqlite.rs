@@ -1,18 +1,44 @@
 use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::{DateTime, Utc};
 use http::Uri;
-use log::{debug, warn};
-use sqlx::{Connection, Row, SqliteConnection};
-use std::{fs::File, path::Path, str::FromStr, sync::Arc};
-use tokio::sync::{Mutex, MutexGuard};
+use native_tls::TlsConnector;
+use rumqttc::ClientConfig;
+use sqlx::{sqlite::SqliteConnectOptions, Connection, Row, SqliteConnection};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+#[cfg(feature = "c2d")]
+use crate::ingress::BufferPolicy;
+#[cfg(feature = "packet-observer")]
+use crate::ingress::RawPacketObserver;
+use crate::ingress::{
+    ChannelConfig, Cipher, DatabaseCreatedCallback, DatabaseMigration,
+    DesiredPropertiesTooLargeCallback, MigrationCallback, PayloadCodec,
+    PendingMessagesLoadedCallback, RecoveryPolicy, ReportedPropertiesSentCallback,
+    TokenRefreshFailedCallback, TransportMode,
+};
+use crate::iothub::LastWillMessage;
+use crate::utils::clock::Clock;
 
 use super::{
-    CloseOption, Compression,
+    BatchSummary, CloseOption, Compression,
     {twins::Twin, DeviceMessage},
     {ProvisioningToken, RegistrationToken},
 };
 
-const DB_VERSION: &str = "1.2.0";
+const DB_VERSION: &str = "1.11.0";
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -20,11 +46,86 @@ pub enum Error {
     Sqlite(#[from] sqlx::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("The local database file can't grow because the storage is full")]
+    StorageFull(#[source] sqlx::Error),
+}
+
+// The primary result code of SQLite reporting that the database file or the disk is full
+const SQLITE_FULL: &str = "13";
+
+// Distinguishes the failures caused by the full storage because they can be resolved only by freeing some space
+fn storage_full(error: impl Into<anyhow::Error>) -> anyhow::Error {
+    match error.into().downcast::<sqlx::Error>() {
+        Ok(sqlx::Error::Database(e)) if e.code().as_deref() == Some(SQLITE_FULL) => {
+            Error::StorageFull(sqlx::Error::Database(e)).into()
+        }
+        Ok(e) => e.into(),
+        Err(e) => e,
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct SqliteStore {
     conn: Arc<Mutex<SqliteConnection>>,
+    // Queries that don't modify the database use a separate connection so that they don't have to wait for the writes
+    read_conn: Arc<Mutex<SqliteConnection>>,
+    credential_backup: Option<Arc<CredentialBackup>>,
+    token_cipher: TokenCipher,
+    clock: Arc<dyn Clock>,
+}
+
+// Marks the tokens encrypted by the cipher, the tokens without it are stored in plain text
+const ENCRYPTED_TOKEN_PREFIX: &str = "encrypted:";
+
+// Encrypts the tokens before they are stored and decrypts them after they are loaded, the tokens are unchanged without a cipher
+#[derive(Clone, Default)]
+struct TokenCipher(Option<Arc<dyn Cipher>>);
+
+impl std::fmt::Debug for TokenCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCipher")
+            .field("custom", &self.0.is_some())
+            .finish()
+    }
+}
+
+impl TokenCipher {
+    fn encrypt(&self, token: &str) -> Result<String> {
+        let Some(cipher) = &self.0 else {
+            return Ok(token.to_owned());
+        };
+        let encrypted = cipher
+            .encrypt(token.as_bytes())
+            .context("Unable to encrypt the token")?;
+        Ok(format!(
+            "{ENCRYPTED_TOKEN_PREFIX}{}",
+            STANDARD.encode(encrypted)
+        ))
+    }
+
+    // The tokens stored in plain text before the cipher was provided are returned as they are
+    fn decrypt(&self, stored: String) -> Result<String> {
+        let Some(encoded) = stored.strip_prefix(ENCRYPTED_TOKEN_PREFIX) else {
+            return Ok(stored);
+        };
+        let Some(cipher) = &self.0 else {
+            bail!("The token in the local database file is encrypted, but no cipher was provided to decrypt it");
+        };
+        let encrypted = STANDARD
+            .decode(encoded)
+            .context("The encrypted token is not valid base64")?;
+        let token = cipher
+            .decrypt(&encrypted)
+            .context("Unable to decrypt the token")?;
+        String::from_utf8(token).context("The decrypted token is not valid UTF-8")
+    }
+}
+
+// The configuration including the credentials is mirrored to the backup file so that it survives the loss of the database file
+#[derive(Debug)]
+struct CredentialBackup {
+    database_path: PathBuf,
+    backup_path: PathBuf,
 }
 
 pub struct SdkConfiguration {
@@ -35,6 +136,36 @@ pub struct SdkConfiguration {
     pub workspace_id: String,
     pub device_id: String,
     pub site_id: Option<String>,
+    pub channel_config: ChannelConfig,
+    pub recovery_policy: RecoveryPolicy,
+    pub worker_threads: usize,
+    pub runtime_handle: Option<Handle>,
+    pub tls_connector: Option<TlsConnector>,
+    pub mqtt_tls_config: Option<Arc<ClientConfig>>,
+    pub clean_session: bool,
+    pub transport: TransportMode,
+    pub max_reconnect_attempts: Option<u32>,
+    pub publish_timeout: Duration,
+    pub last_will: Option<LastWillMessage>,
+    pub iothub_api_version: String,
+    pub max_message_bytes: Option<usize>,
+    pub token_refresh_failed_callback: Option<(usize, Arc<dyn TokenRefreshFailedCallback>)>,
+    pub pending_messages_loaded_callback: Option<Arc<dyn PendingMessagesLoadedCallback>>,
+    pub migration_callback: Option<Arc<dyn MigrationCallback>>,
+    pub database_created_callback: Option<Arc<dyn DatabaseCreatedCallback>>,
+    pub payload_codec: Option<Arc<dyn PayloadCodec>>,
+    pub credential_cipher: Option<Arc<dyn Cipher>>,
+    #[cfg(feature = "c2d")]
+    pub c2d_buffer_policy: BufferPolicy,
+    pub reported_properties_sent_callback: Option<Arc<dyn ReportedPropertiesSentCallback>>,
+    pub max_desired_properties_bytes: Option<usize>,
+    pub desired_properties_too_large_callback: Option<Arc<dyn DesiredPropertiesTooLargeCallback>>,
+    pub credential_backup_path: Option<PathBuf>,
+    #[cfg(feature = "packet-observer")]
+    pub raw_packet_observer: Option<Arc<dyn RawPacketObserver>>,
+    pub http_timeout: Duration,
+    pub extra_http_headers: HashMap<String, String>,
+    pub clock: Arc<dyn Clock>,
 }
 
 #[derive(Default)]
@@ -58,7 +189,19 @@ impl SqliteStore {
         self.conn.lock().await
     }
 
-    pub async fn load_available_configuration(path: &Path) -> SdkConfigurationFragment {
+    // All the time-dependent logic reads the current time from this clock so that tests can replace it
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub(crate) async fn read_connection(&self) -> MutexGuard<'_, SqliteConnection> {
+        self.read_conn.lock().await
+    }
+
+    pub async fn load_available_configuration(
+        path: &Path,
+        cipher: Option<Arc<dyn Cipher>>,
+    ) -> SdkConfigurationFragment {
         if !path.exists() {
             debug!(
                 "The local database file on the path '{}' doesn't exist yet.",
@@ -73,7 +216,7 @@ impl SqliteStore {
             path.to_string_lossy()
         );
 
-        match try_load_available_configuration(path).await {
+        match try_load_available_configuration(path, &TokenCipher(cipher)).await {
             Ok(fragment) => fragment,
             Err(e) => {
                 warn!(
@@ -89,19 +232,33 @@ impl SqliteStore {
     // Setup
     // ================================================================================
     pub async fn init(path: &Path, config: &SdkConfiguration) -> Result<SqliteStore> {
+        if config.recovery_policy == RecoveryPolicy::RebuildPreservingCredentials && path.exists() {
+            if let Err(e) = check_integrity(path).await {
+                warn!(
+                    "The local database file on the path '{}' is corrupted and will be recreated, \
+                    the Messages that weren't sent yet are lost. Error details: {e:?}",
+                    path.to_string_lossy(),
+                );
+                discard_corrupted_file(path)?;
+            }
+        }
+
         if !Path::new(path).exists() {
-            log::debug!("Creating a local database file");
+            debug!("Creating a local database file");
             File::create(path)?;
+            if let Some(callback) = &config.database_created_callback {
+                callback.database_created(path);
+            }
         }
         // let mut conn = SqliteConnection::connect(&path.as_os_str().to_string_lossy()).await?;
         let conn = SqliteConnection::connect(&path.as_os_str().to_string_lossy()).await;
         let mut conn = match conn {
             Ok(conn) => {
-                log::debug!("Connection to SQLite established");
+                debug!("Connection to SQLite established");
                 conn
             }
             Err(e) => {
-                log::error!("
+                error!("
                     Unable to connect to SQLite in file `{path:?}`. \
                     Make sure that the current process can read from the file and write to it, and that no other process accesses the file. \
                     Error details: {e:?}");
@@ -109,13 +266,13 @@ impl SqliteStore {
             }
         };
 
-        log::debug!("Getting database version");
+        debug!("Getting database version");
         let record = sqlx::query!(r#"SELECT db_version FROM SdkConfiguration WHERE id = "0""#)
             .fetch_one(&mut conn)
             .await;
 
         if let Ok(record) = record {
-            log::debug!(
+            debug!(
                 "The database contains schema in version {}",
                 record.db_version
             );
@@ -127,10 +284,16 @@ impl SqliteStore {
                     workspace_id: config.workspace_id.as_str(),
                 };
 
-                try_update_version(&mut conn, &record.db_version, &migration_values).await?;
+                try_update_version(
+                    &mut conn,
+                    &record.db_version,
+                    &migration_values,
+                    config.migration_callback.as_deref(),
+                )
+                .await?;
             }
         } else {
-            log::debug!("Importing schema");
+            debug!("Importing schema");
             sqlx::query_file!("./db_init.sql")
                 .execute(&mut conn)
                 .await?;
@@ -140,47 +303,154 @@ impl SqliteStore {
 
         let instance_url = config.instance_url.to_string();
 
-        log::debug!("Saving configuration");
+        // The instance ID is generated only once for each local database file, the existing one is kept
+        let new_device_instance_id = Uuid::new_v4().to_string();
+
+        // The tokens stored in plain text by an earlier run are replaced by the encrypted ones
+        let token_cipher = TokenCipher(config.credential_cipher.clone());
+        let provisioning_token = token_cipher.encrypt(&config.provisioning_token.token)?;
+        let registration_token = token_cipher.encrypt(&config.registration_token.token)?;
+
+        debug!("Saving configuration");
         sqlx::query!(
-            "INSERT OR REPLACE INTO SdkConfiguration (id, db_version, instance_url, provisioning_token, registration_token, rt_expiration, requested_device_id, workspace_id, device_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT OR REPLACE INTO SdkConfiguration (id, db_version, instance_url, provisioning_token, registration_token, rt_expiration, requested_device_id, workspace_id, device_id, device_instance_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, COALESCE((SELECT device_instance_id FROM SdkConfiguration WHERE id = 0), ?))",
             0i64,
             DB_VERSION,
             instance_url,
-            config.provisioning_token.token,
-            config.registration_token.token,
+            provisioning_token,
+            registration_token,
             config.registration_token.expiration,
             config.requested_device_id,
             config.workspace_id,
             config.device_id,
+            new_device_instance_id,
         ).execute(&mut conn)
         .await?;
-        log::debug!("Configuration saved");
+        debug!("Configuration saved");
 
-        Ok(SqliteStore {
+        // The read-only connection must be opened only after the schema is created
+        let read_options = SqliteConnectOptions::new().filename(path).read_only(true);
+        let read_conn = SqliteConnection::connect_with(&read_options)
+            .await
+            .context("Unable to open a read-only connection to the local database file")?;
+
+        let store = SqliteStore {
             conn: Arc::new(Mutex::new(conn)),
+            read_conn: Arc::new(Mutex::new(read_conn)),
+            credential_backup: config.credential_backup_path.as_ref().map(|backup_path| {
+                Arc::new(CredentialBackup {
+                    database_path: path.to_path_buf(),
+                    backup_path: backup_path.clone(),
+                })
+            }),
+            token_cipher,
+            clock: config.clock.clone(),
+        };
+
+        store.back_up_credentials().await;
+
+        Ok(store)
+    }
+
+    // The schema is created but the configuration is empty, the tests fill only the parts they need
+    #[cfg(test)]
+    pub(crate) async fn in_memory() -> Result<SqliteStore> {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await?;
+        sqlx::query_file!("./db_init.sql")
+            .execute(&mut conn)
+            .await?;
+
+        // Each connection to a database in memory would open a separate one
+        let conn = Arc::new(Mutex::new(conn));
+        Ok(SqliteStore {
+            conn: conn.clone(),
+            read_conn: conn,
+            credential_backup: None,
+            token_cipher: TokenCipher::default(),
+            clock: crate::utils::clock::system_clock(),
         })
     }
 
+    // Called while holding the lock of the writing connection so that the backup can't miss a concurrent change
+    // The failures are only logged, the credentials in the database file are still valid
+    async fn back_up_credentials(&self) {
+        let Some(backup) = &self.credential_backup else {
+            return;
+        };
+
+        if let Err(e) = back_up_configuration(&backup.database_path, &backup.backup_path).await {
+            warn!(
+                "Unable to back up the credentials to the file on the path '{}': {e:?}",
+                backup.backup_path.to_string_lossy(),
+            );
+        }
+    }
+
     // Device to Cloud Messages
     // ================================================================================
     pub async fn store_message(&self, msg: &DeviceMessage) -> Result<i32> {
+        let enqueued_at = self.clock.now_utc();
         let mut conn = self.conn.lock().await;
-        let record = sqlx::query!(
-            r#"INSERT INTO Messages (site_id, stream_group, stream, batch_id, message_id, content, close_option, compression, batch_slice_id, chunk_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
-            SELECT last_insert_rowid() as id"#,
-            msg.site_id,
-            msg.stream_group,
-            msg.stream,
-            msg.batch_id,
-            msg.message_id,
-            msg.content,
-            msg.close_option as _,
-            msg.compression as _,
-            msg.batch_slice_id,
-            msg.chunk_id,
-        ).fetch_one(&mut *conn).await?;
+        insert_message(&mut conn, msg, enqueued_at)
+            .await
+            .map_err(storage_full)
+    }
 
-        Ok(record.id)
+    // Stores all the messages in a single transaction and returns their IDs, either all of them are stored or none
+    pub async fn store_messages(&self, msgs: &[DeviceMessage]) -> Result<Vec<i32>> {
+        let enqueued_at = self.clock.now_utc();
+        let mut conn = self.conn.lock().await;
+        let mut transaction = conn.begin().await?;
+
+        let mut ids = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            ids.push(
+                insert_message(&mut transaction, msg, enqueued_at)
+                    .await
+                    .map_err(storage_full)?,
+            );
+        }
+
+        transaction.commit().await.map_err(storage_full)?;
+
+        Ok(ids)
+    }
+
+    // Stores the message only if no pending message was stored with the same idempotency key, returns its ID if it was stored
+    pub async fn store_message_once(
+        &self,
+        msg: &DeviceMessage,
+        idempotency_key: &str,
+    ) -> Result<Option<i32>> {
+        let enqueued_at = self.clock.now_utc();
+        let mut conn = self.conn.lock().await;
+        let mut transaction = conn.begin().await?;
+
+        let duplicate = sqlx::query!(
+            "SELECT id FROM Messages WHERE idempotency_key = ?",
+            idempotency_key,
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if duplicate.is_some() {
+            return Ok(None);
+        }
+
+        let id = insert_message(&mut transaction, msg, enqueued_at)
+            .await
+            .map_err(storage_full)?;
+        sqlx::query!(
+            "UPDATE Messages SET idempotency_key = ? WHERE id = ?",
+            idempotency_key,
+            id,
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(storage_full)?;
+
+        transaction.commit().await.map_err(storage_full)?;
+
+        Ok(Some(id))
     }
 
     pub(crate) async fn list_messages_after(&self, after: i32) -> Result<Vec<DeviceMessage>> {
@@ -188,12 +458,12 @@ impl SqliteStore {
 
         sqlx::query_as!(
             DeviceMessage,
-            r#"SELECT id AS "id?: i32", site_id, stream_group, stream, batch_id, message_id, content, close_option AS "close_option!: CloseOption", compression AS "compression!: Compression", batch_slice_id, chunk_id FROM Messages WHERE id > ? ORDER BY id LIMIT 100"#, after,
+            r#"SELECT id AS "id?: i32", site_id, stream_group, stream, batch_id, message_id, content, close_option AS "close_option!: CloseOption", compression AS "compression!: Compression", batch_slice_id, chunk_id, expiry AS "expiry: DateTime<Utc>", properties, source_device_id, ordered AS "ordered: bool", content_type FROM Messages WHERE id > ? ORDER BY id LIMIT 100"#, after,
         ).fetch_all(&mut *conn).await.map_err(anyhow::Error::from)
     }
 
     pub async fn message_count(&self) -> Result<usize> {
-        let mut conn = self.conn.lock().await;
+        let mut conn = self.read_conn.lock().await;
         let res = sqlx::query!("SELECT COUNT(id) as cnt FROM Messages")
             .fetch_one(&mut *conn)
             .await?;
@@ -202,17 +472,103 @@ impl SqliteStore {
         Ok(res.cnt.try_into().unwrap_or_default())
     }
 
-    pub async fn remove_oldest_message(&self) -> Result<()> {
-        let mut conn = self.conn.lock().await;
-        sqlx::query!(
-            "DELETE FROM Messages WHERE id = (SELECT id FROM Messages ORDER BY id LIMIT 1)"
+    pub async fn oldest_message_enqueued_at(&self) -> Result<Option<DateTime<Utc>>> {
+        let mut conn = self.read_conn.lock().await;
+        let record = sqlx::query!(
+            r#"SELECT enqueued_at AS "enqueued_at: DateTime<Utc>" FROM Messages ORDER BY id LIMIT 1"#
         )
-        .execute(&mut *conn)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        Ok(record.and_then(|record| record.enqueued_at))
+    }
+
+    pub async fn pending_batches(&self) -> Result<Vec<BatchSummary>> {
+        let mut conn = self.read_conn.lock().await;
+        let records = sqlx::query!(
+            r#"SELECT batch_id AS "batch_id!", COUNT(id) AS "message_count!: i64", MIN(enqueued_at) AS "oldest_enqueued_at: DateTime<Utc>" FROM Messages WHERE batch_id IS NOT NULL GROUP BY batch_id ORDER BY MIN(id)"#
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(records
+            .into_iter()
+            .map(|record| BatchSummary {
+                batch_id: record.batch_id,
+                // This is safe because the result cannot be negative.
+                message_count: record.message_count.try_into().unwrap_or_default(),
+                oldest_enqueued_at: record.oldest_enqueued_at,
+            })
+            .collect())
+    }
+
+    // Returns `None` if the Batch is unknown or its completion hasn't been enqueued yet
+    #[allow(clippy::cast_precision_loss)]
+    pub async fn batch_progress(&self, batch_id: &str) -> Result<Option<f32>> {
+        let mut conn = self.read_conn.lock().await;
+        let record = sqlx::query!(
+            r#"SELECT enqueued AS "enqueued!: i64", completed AS "completed!: bool", (SELECT COUNT(id) FROM Messages WHERE batch_id = ?) AS "pending!: i64" FROM Batches WHERE batch_id = ?"#,
+            batch_id,
+            batch_id,
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        Ok(record
+            .filter(|record| record.completed && record.enqueued > 0)
+            .map(|record| {
+                // The pending Messages may outnumber the counted ones only if the Batch ID was used again
+                let sent = (record.enqueued - record.pending).max(0);
+                sent as f32 / record.enqueued as f32
+            }))
+    }
+
+    // Counts the pending chunks of a Message together with its completion
+    pub async fn pending_message_parts(&self, batch_id: &str, message_id: &str) -> Result<usize> {
+        let mut conn = self.read_conn.lock().await;
+        let res = sqlx::query!(
+            "SELECT COUNT(id) as cnt FROM Messages WHERE batch_id = ? AND message_id = ?",
+            batch_id,
+            message_id,
+        )
+        .fetch_one(&mut *conn)
         .await?;
 
+        // This is safe because the result cannot be negative.
+        Ok(res.cnt.try_into().unwrap_or_default())
+    }
+
+    pub async fn remove_message(&self, id: i32) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        sqlx::query!("DELETE FROM Messages WHERE id = ?", id)
+            .execute(&mut *conn)
+            .await?;
+
         Ok(())
     }
 
+    // Returns the number of the removed messages and the ID of the newest one, if any
+    pub async fn clear_messages(&self) -> Result<(usize, Option<i32>)> {
+        let mut conn = self.conn.lock().await;
+        let newest = sqlx::query!(r#"SELECT MAX(id) AS "id: i32" FROM Messages"#)
+            .fetch_one(&mut *conn)
+            .await?
+            .id;
+        let Some(newest) = newest else {
+            return Ok((0, None));
+        };
+
+        let result = sqlx::query!("DELETE FROM Messages WHERE id <= ?", newest)
+            .execute(&mut *conn)
+            .await?;
+
+        // This is safe because the number of removed rows cannot exceed the number of stored messages.
+        Ok((
+            result.rows_affected().try_into().unwrap_or_default(),
+            Some(newest),
+        ))
+    }
+
     // Twins
     // ================================================================================
     pub async fn load_desired_properties(&self) -> Result<Option<Twin>> {
@@ -287,11 +643,13 @@ impl SqliteStore {
         .await
         .context("Unable to save Workspace ID to configuration")?;
 
+        self.back_up_credentials().await;
+
         Ok(())
     }
 
     pub async fn load_workspace_id(&self) -> Result<String> {
-        let mut conn = self.conn.lock().await;
+        let mut conn = self.read_conn.lock().await;
         Ok(
             sqlx::query!(r#"SELECT workspace_id FROM SdkConfiguration WHERE id = "0""#,)
                 .fetch_one(&mut *conn)
@@ -311,11 +669,13 @@ impl SqliteStore {
         .await
         .context("Unable to save Device ID to configuration")?;
 
+        self.back_up_credentials().await;
+
         Ok(())
     }
 
     pub async fn load_device_id(&self) -> Result<String> {
-        let mut conn = self.conn.lock().await;
+        let mut conn = self.read_conn.lock().await;
         Ok(
             sqlx::query!(r#"SELECT device_id FROM SdkConfiguration WHERE id = "0""#,)
                 .fetch_one(&mut *conn)
@@ -325,55 +685,80 @@ impl SqliteStore {
         )
     }
 
+    pub async fn load_device_instance_id(&self) -> Result<String> {
+        let mut conn = self.read_conn.lock().await;
+        sqlx::query!(r#"SELECT device_instance_id FROM SdkConfiguration WHERE id = "0""#,)
+            .fetch_one(&mut *conn)
+            .await
+            .context("Unable to load device instance ID from configuration")?
+            .device_instance_id
+            .context("The device instance ID is missing in the configuration")
+    }
+
     pub async fn load_provisioning_token(&self) -> Result<ProvisioningToken> {
         let mut conn = self.conn.lock().await;
-        sqlx::query_as!(
+        let stored = sqlx::query_as!(
             ProvisioningToken,
             r#"SELECT provisioning_token AS token FROM SdkConfiguration WHERE id = "0""#,
         )
         .fetch_one(&mut *conn)
         .await
-        .context("Unable to load provisioning token from configuration")
+        .context("Unable to load provisioning token from configuration")?;
+
+        Ok(ProvisioningToken {
+            token: self.token_cipher.decrypt(stored.token)?,
+        })
     }
 
     pub async fn save_provisioning_token(&self, token: &ProvisioningToken) -> Result<()> {
-        log::debug!("Saving provisioning token");
+        debug!("Saving provisioning token");
+        let stored = self.token_cipher.encrypt(&token.token)?;
         let mut conn = self.conn.lock().await;
         sqlx::query!(
             r#"UPDATE SdkConfiguration SET provisioning_token = ? WHERE id = "0""#,
-            token.token,
+            stored,
         )
         .execute(&mut *conn)
         .await?;
 
+        self.back_up_credentials().await;
+
         Ok(())
     }
 
     pub async fn load_registration_token(&self) -> Result<RegistrationToken> {
         let mut conn = self.conn.lock().await;
-        sqlx::query_as!(
+        let stored = sqlx::query_as!(
             RegistrationToken,
             r#"SELECT registration_token AS token, rt_expiration AS "expiration: DateTime<Utc>" FROM SdkConfiguration WHERE id = "0""#,
         )
         .fetch_one(&mut *conn)
         .await
-        .context("Unable to load registration token from configuration")
+        .context("Unable to load registration token from configuration")?;
+
+        Ok(RegistrationToken {
+            token: self.token_cipher.decrypt(stored.token)?,
+            expiration: stored.expiration,
+        })
     }
 
     pub async fn save_registration_token(&self, token: &RegistrationToken) -> Result<()> {
-        log::debug!(
+        debug!(
             "Saving registration token with expiration {:?}",
             token.expiration
         );
+        let stored = self.token_cipher.encrypt(&token.token)?;
         let mut conn = self.conn.lock().await;
         sqlx::query!(
             r#"UPDATE SdkConfiguration SET registration_token = ?, rt_expiration = ? WHERE id = "0""#,
-            token.token,
+            stored,
             token.expiration,
         )
         .execute(&mut *conn)
         .await?;
 
+        self.back_up_credentials().await;
+
         Ok(())
     }
 
@@ -385,9 +770,158 @@ impl SqliteStore {
 
         Ok(record.instance_url)
     }
+
+    pub(crate) async fn save_instance_url(&self, instance_url: &str) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        sqlx::query!(
+            r#"UPDATE SdkConfiguration SET instance_url = ? WHERE id = "0""#,
+            instance_url,
+        )
+        .execute(&mut *conn)
+        .await
+        .context("Unable to save the Platform instance URL to configuration")?;
+
+        self.back_up_credentials().await;
+
+        Ok(())
+    }
+}
+
+// Shared by storing a single message and storing multiple messages in a transaction
+async fn insert_message(
+    conn: &mut SqliteConnection,
+    msg: &DeviceMessage,
+    enqueued_at: DateTime<Utc>,
+) -> Result<i32> {
+    let record = sqlx::query!(
+        r#"INSERT INTO Messages (site_id, stream_group, stream, batch_id, message_id, content, close_option, compression, batch_slice_id, chunk_id, expiry, properties, enqueued_at, source_device_id, ordered, content_type) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+            SELECT last_insert_rowid() as id"#,
+        msg.site_id,
+        msg.stream_group,
+        msg.stream,
+        msg.batch_id,
+        msg.message_id,
+        msg.content,
+        msg.close_option as _,
+        msg.compression as _,
+        msg.batch_slice_id,
+        msg.chunk_id,
+        msg.expiry,
+        msg.properties,
+        enqueued_at,
+        msg.source_device_id,
+        msg.ordered,
+        msg.content_type,
+    ).fetch_one(&mut *conn).await?;
+
+    // The Messages of a Batch are counted so that its progress is known after some of them are sent
+    if let Some(batch_id) = &msg.batch_id {
+        let completed = matches!(
+            msg.close_option,
+            CloseOption::Close | CloseOption::CloseOnly
+        );
+        // A completed Batch whose ID is used again is counted from the beginning
+        sqlx::query!(
+            "INSERT INTO Batches (batch_id, enqueued, completed) VALUES (?, 1, ?) ON CONFLICT(batch_id) DO UPDATE SET enqueued = CASE WHEN completed THEN 1 ELSE enqueued + 1 END, completed = excluded.completed",
+            batch_id,
+            completed,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        if completed {
+            // Only the last finished Batches are kept so that the table doesn't grow indefinitely
+            sqlx::query!(
+                "DELETE FROM Batches WHERE completed AND batch_id != ? AND NOT EXISTS (SELECT 1 FROM Messages WHERE Messages.batch_id = Batches.batch_id)",
+                batch_id,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    Ok(record.id)
+}
+
+// Copies the configuration row to the backup file, which contains only the configuration table
+async fn back_up_configuration(database_path: &Path, backup_path: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new()
+        .filename(backup_path)
+        .create_if_missing(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+
+    sqlx::query("ATTACH DATABASE ? AS source")
+        .bind(database_path.to_string_lossy())
+        .execute(&mut conn)
+        .await?;
+
+    sqlx::query(
+        r"CREATE TABLE IF NOT EXISTS main.SdkConfiguration (
+            id                  INTEGER PRIMARY KEY,
+            db_version          TEXT NOT NULL,
+            instance_url        TEXT NOT NULL,
+            provisioning_token  TEXT NOT NULL,
+            registration_token  TEXT NOT NULL,
+            rt_expiration       TEXT, -- DATETIME
+            requested_device_id TEXT,
+            workspace_id        TEXT NOT NULL,
+            device_id           TEXT NOT NULL,
+            device_instance_id  TEXT
+        ) STRICT",
+    )
+    .execute(&mut conn)
+    .await?;
+
+    sqlx::query(
+        r"INSERT OR REPLACE INTO main.SdkConfiguration (id, db_version, instance_url, provisioning_token, registration_token, rt_expiration, requested_device_id, workspace_id, device_id, device_instance_id)
+        SELECT id, db_version, instance_url, provisioning_token, registration_token, rt_expiration, requested_device_id, workspace_id, device_id, device_instance_id FROM source.SdkConfiguration",
+    )
+    .execute(&mut conn)
+    .await?;
+
+    conn.close().await?;
+
+    Ok(())
+}
+
+async fn check_integrity(path: &Path) -> Result<()> {
+    let mut conn = SqliteConnection::connect(&path.as_os_str().to_string_lossy()).await?;
+
+    let result: String = sqlx::query("PRAGMA integrity_check")
+        .fetch_one(&mut conn)
+        .await?
+        .try_get(0)?;
+
+    conn.close().await?;
+
+    if result != "ok" {
+        bail!("The integrity check failed: {result}");
+    }
+
+    Ok(())
 }
 
-async fn try_load_available_configuration(path: &Path) -> Result<SdkConfigurationFragment> {
+// The corrupted file is kept next to the new one so that it can be inspected later.
+// The credentials were already salvaged by `SqliteStore::load_available_configuration` and they are saved
+// to the new file from the provided configuration.
+fn discard_corrupted_file(path: &Path) -> Result<()> {
+    let mut corrupted_path = path.as_os_str().to_owned();
+    corrupted_path.push(".corrupted");
+
+    fs::rename(path, &corrupted_path).with_context(|| {
+        format!(
+            "Unable to move the corrupted local database file to '{}'",
+            corrupted_path.to_string_lossy()
+        )
+    })?;
+
+    Ok(())
+}
+
+async fn try_load_available_configuration(
+    path: &Path,
+    token_cipher: &TokenCipher,
+) -> Result<SdkConfigurationFragment> {
     let mut conn = SqliteConnection::connect(&path.as_os_str().to_string_lossy()).await?;
 
     let row = load_configuration_row(&mut conn).await?;
@@ -406,7 +940,7 @@ async fn try_load_available_configuration(path: &Path) -> Result<SdkConfiguratio
     };
 
     if let Some(instance_url) = &instance_url {
-        log::debug!(
+        debug!(
             "Loaded existing instance URL '{}' from the local database file.",
             instance_url
         );
@@ -414,8 +948,9 @@ async fn try_load_available_configuration(path: &Path) -> Result<SdkConfiguratio
 
     let provisioning_token = match row.try_get::<String, _>("provisioning_token") {
         Ok(token) => {
-            log::debug!("Loaded existing provisioning token from the local database file.");
+            debug!("Loaded existing provisioning token from the local database file.");
 
+            let token = token_cipher.decrypt(token)?;
             Some(ProvisioningToken { token })
         }
         Err(_) => None,
@@ -423,8 +958,9 @@ async fn try_load_available_configuration(path: &Path) -> Result<SdkConfiguratio
 
     let registration_token = match row.try_get::<String, _>("registration_token") {
         Ok(token) => {
-            log::debug!("Loaded existing registration token from the local database file.");
+            debug!("Loaded existing registration token from the local database file.");
 
+            let token = token_cipher.decrypt(token)?;
             let expiration: Option<DateTime<Utc>> = row.try_get("rt_expiration").ok();
             Some(RegistrationToken { token, expiration })
         }
@@ -434,7 +970,7 @@ async fn try_load_available_configuration(path: &Path) -> Result<SdkConfiguratio
     let requested_device_id = row.try_get::<String, _>("requested_device_id").ok();
 
     if let Some(requested_device_id) = &requested_device_id {
-        log::debug!(
+        debug!(
             "Loaded existing last requested device ID '{}' from the local database file.",
             requested_device_id
         );
@@ -463,13 +999,13 @@ async fn try_load_available_configuration(path: &Path) -> Result<SdkConfiguratio
     };
 
     if let Some(workspace_id) = &workspace_id {
-        log::debug!(
+        debug!(
             "Loaded existing Workspace ID '{}' from the local database file.",
             workspace_id
         );
     }
     if let Some(device_id) = &device_id {
-        log::debug!(
+        debug!(
             "Loaded existing Device ID '{}' from the local database file.",
             device_id
         );
@@ -489,6 +1025,7 @@ async fn try_update_version(
     conn: &mut SqliteConnection,
     db_version: &str,
     values: &MigrationRequiredValues<'_>,
+    migration_callback: Option<&dyn MigrationCallback>,
 ) -> Result<()> {
     if db_version != DB_VERSION {
         let mut current_db_version = db_version;
@@ -497,18 +1034,84 @@ async fn try_update_version(
         if current_db_version == "0.1.3" {
             known_version = true;
             update_version_to_1_0_1(conn).await?;
+            migrated(migration_callback, current_db_version, "1.0.1");
             current_db_version = "1.0.1";
         }
 
         if current_db_version == "1.0.1" {
             known_version = true;
             update_version_to_1_1_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.1.0");
             current_db_version = "1.1.0";
         }
 
         if current_db_version == "1.1.0" {
             known_version = true;
             update_version_to_1_2_0(conn, values).await?;
+            migrated(migration_callback, current_db_version, "1.2.0");
+            current_db_version = "1.2.0";
+        }
+
+        if current_db_version == "1.2.0" {
+            known_version = true;
+            update_version_to_1_3_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.3.0");
+            current_db_version = "1.3.0";
+        }
+
+        if current_db_version == "1.3.0" {
+            known_version = true;
+            update_version_to_1_4_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.4.0");
+            current_db_version = "1.4.0";
+        }
+
+        if current_db_version == "1.4.0" {
+            known_version = true;
+            update_version_to_1_5_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.5.0");
+            current_db_version = "1.5.0";
+        }
+
+        if current_db_version == "1.5.0" {
+            known_version = true;
+            update_version_to_1_6_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.6.0");
+            current_db_version = "1.6.0";
+        }
+
+        if current_db_version == "1.6.0" {
+            known_version = true;
+            update_version_to_1_7_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.7.0");
+            current_db_version = "1.7.0";
+        }
+
+        if current_db_version == "1.7.0" {
+            known_version = true;
+            update_version_to_1_8_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.8.0");
+            current_db_version = "1.8.0";
+        }
+
+        if current_db_version == "1.8.0" {
+            known_version = true;
+            update_version_to_1_9_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.9.0");
+            current_db_version = "1.9.0";
+        }
+
+        if current_db_version == "1.9.0" {
+            known_version = true;
+            update_version_to_1_10_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.10.0");
+            current_db_version = "1.10.0";
+        }
+
+        if current_db_version == "1.10.0" {
+            known_version = true;
+            update_version_to_1_11_0(conn).await?;
+            migrated(migration_callback, current_db_version, "1.11.0");
         }
 
         if !known_version {
@@ -522,8 +1125,19 @@ async fn try_update_version(
     Ok(())
 }
 
+// Reports a successful step of the schema migration
+fn migrated(migration_callback: Option<&dyn MigrationCallback>, from: &str, to: &str) {
+    info!("The local database file was migrated from version {from} to {to}");
+    if let Some(callback) = migration_callback {
+        callback.migrated(&DatabaseMigration {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+}
+
 async fn update_version_to_1_0_1(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
-    log::debug!("Updating database schema from version 0.1.3 to 1.0.1");
+    debug!("Updating database schema from version 0.1.3 to 1.0.1");
 
     sqlx::query(
         r#"BEGIN TRANSACTION;
@@ -534,7 +1148,7 @@ async fn update_version_to_1_0_1(conn: &mut SqliteConnection) -> Result<(), anyh
     .execute(conn)
     .await?;
 
-    log::debug!("Database schema updated to version 1.0.1");
+    debug!("Database schema updated to version 1.0.1");
     Ok(())
 }
 
@@ -551,7 +1165,7 @@ async fn update_version_to_1_1_0(conn: &mut SqliteConnection) -> Result<(), anyh
         Ok(res == 2)
     }
 
-    log::debug!("Updating database schema from version 1.0.1 to 1.1.0");
+    debug!("Updating database schema from version 1.0.1 to 1.1.0");
 
     // There was an error in the code causing schema of version 1.1.0 to be marked 1.0.1, so we need to check if the
     // columns don't already exist
@@ -569,7 +1183,7 @@ async fn update_version_to_1_1_0(conn: &mut SqliteConnection) -> Result<(), anyh
 
     query.execute(conn).await?;
 
-    log::debug!("Database schema updated to version 1.1.0");
+    debug!("Database schema updated to version 1.1.0");
     Ok(())
 }
 
@@ -577,7 +1191,7 @@ async fn update_version_to_1_2_0(
     conn: &mut SqliteConnection,
     values: &MigrationRequiredValues<'_>,
 ) -> Result<(), anyhow::Error> {
-    log::debug!("Updating database schema from version 1.1.0 to 1.2.0");
+    debug!("Updating database schema from version 1.1.0 to 1.2.0");
 
     let configuration_row = load_configuration_row(conn).await?;
 
@@ -616,7 +1230,160 @@ async fn update_version_to_1_2_0(
     .execute(conn)
     .await?;
 
-    log::debug!("Database schema updated to version 1.2.0");
+    debug!("Database schema updated to version 1.2.0");
+    Ok(())
+}
+
+async fn update_version_to_1_3_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.2.0 to 1.3.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD expiry TEXT;
+        UPDATE SdkConfiguration SET db_version = '1.3.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.3.0");
+    Ok(())
+}
+
+async fn update_version_to_1_4_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.3.0 to 1.4.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD properties TEXT;
+        UPDATE SdkConfiguration SET db_version = '1.4.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.4.0");
+    Ok(())
+}
+
+async fn update_version_to_1_5_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.4.0 to 1.5.0");
+
+    // The time when the existing messages were enqueued is unknown, use the time of the update as the closest estimate
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD enqueued_at TEXT;
+        UPDATE Messages SET enqueued_at = datetime('now');
+        UPDATE SdkConfiguration SET db_version = '1.5.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.5.0");
+    Ok(())
+}
+
+async fn update_version_to_1_6_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.5.0 to 1.6.0");
+
+    // The value itself is generated when the configuration is saved
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE SdkConfiguration ADD device_instance_id TEXT;
+        UPDATE SdkConfiguration SET db_version = '1.6.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.6.0");
+    Ok(())
+}
+
+async fn update_version_to_1_7_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.6.0 to 1.7.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD source_device_id TEXT;
+        UPDATE SdkConfiguration SET db_version = '1.7.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.7.0");
+    Ok(())
+}
+
+async fn update_version_to_1_8_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.7.0 to 1.8.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD ordered INTEGER NOT NULL DEFAULT 1;
+        UPDATE SdkConfiguration SET db_version = '1.8.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.8.0");
+    Ok(())
+}
+
+async fn update_version_to_1_9_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.8.0 to 1.9.0");
+
+    // The progress of the Batches enqueued before the update is unknown
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        CREATE TABLE Batches (
+            batch_id            TEXT PRIMARY KEY,
+            enqueued            INTEGER NOT NULL,
+            completed           INTEGER NOT NULL DEFAULT 0 -- BOOLEAN
+        ) STRICT;
+        UPDATE SdkConfiguration SET db_version = '1.9.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.9.0");
+    Ok(())
+}
+
+async fn update_version_to_1_10_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.9.0 to 1.10.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD idempotency_key TEXT;
+        CREATE UNIQUE INDEX MessagesIdempotencyKey ON Messages (idempotency_key);
+        UPDATE SdkConfiguration SET db_version = '1.10.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.10.0");
+    Ok(())
+}
+
+async fn update_version_to_1_11_0(conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    debug!("Updating database schema from version 1.10.0 to 1.11.0");
+
+    sqlx::query(
+        r#"BEGIN TRANSACTION;
+        ALTER TABLE Messages ADD content_type TEXT;
+        UPDATE SdkConfiguration SET db_version = '1.11.0' WHERE id = "0";
+        COMMIT"#,
+    )
+    .execute(conn)
+    .await?;
+
+    debug!("Database schema updated to version 1.11.0");
     Ok(())
 }
 
@@ -661,3 +1428,82 @@ fn convert_dps_url_to_instance_url(dps_url: &str) -> Result<String, anyhow::Erro
 
     Ok(instance_uri)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    struct XorCipher;
+
+    impl Cipher for XorCipher {
+        fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+            Ok(plaintext.iter().map(|b| b ^ 0x5a).collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+            self.encrypt(ciphertext)
+        }
+    }
+
+    #[test]
+    fn token_encryption() {
+        let cipher = TokenCipher(Some(Arc::new(XorCipher)));
+
+        let stored = cipher.encrypt("token").unwrap();
+        assert!(stored.starts_with(ENCRYPTED_TOKEN_PREFIX));
+        assert!(!stored.contains("token"));
+        assert_eq!(cipher.decrypt(stored.clone()).unwrap(), "token");
+
+        // Tokens stored before the cipher was provided are still readable
+        assert_eq!(cipher.decrypt(String::from("legacy")).unwrap(), "legacy");
+
+        // Encrypted tokens can't be read without the cipher
+        assert!(TokenCipher::default().decrypt(stored).is_err());
+    }
+
+    #[test]
+    fn full_storage() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let mut conn = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+            sqlx::query_file!("./db_init.sql")
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            // The database can't grow beyond its current size
+            sqlx::query("PRAGMA max_page_count = 1")
+                .execute(&mut conn)
+                .await
+                .unwrap();
+
+            let msg = DeviceMessage {
+                id: None,
+                site_id: None,
+                stream_group: None,
+                stream: Some(String::from("stream")),
+                source_device_id: None,
+                batch_id: None,
+                message_id: None,
+                content: vec![0; 1024 * 1024],
+                close_option: CloseOption::None,
+                compression: Compression::None,
+                batch_slice_id: None,
+                chunk_id: None,
+                expiry: None,
+                properties: None,
+                ordered: true,
+                content_type: None,
+            };
+
+            let error = insert_message(&mut conn, &msg, Utc::now())
+                .await
+                .map_err(storage_full)
+                .unwrap_err();
+            assert_eq!(ErrorKind::of(&error), ErrorKind::StorageFull);
+        });
+    }
+}
@@ -33,35 +33,79 @@ pub trait Storable: Sized {
     async fn count(conn: &mut SqliteConnection) -> Result<usize>;
 }
 
-pub fn channel<T: Storable>(store: SqliteStore) -> (Sender<T>, Receiver<T>) {
+// If `retained` is set, only that many latest objects are kept, the older ones are removed when a new one is sent
+pub fn channel<T: Storable>(
+    store: SqliteStore,
+    retained: Option<usize>,
+) -> (Sender<T>, Receiver<T>) {
+    channel_with_count(store, retained, Arc::new(watch::channel(0).0))
+}
+
+// The count of stored objects can be shared by the channels storing the same objects, for example, across reconnections
+pub fn channel_with_count<T: Storable>(
+    store: SqliteStore,
+    retained: Option<usize>,
+    count: Arc<watch::Sender<usize>>,
+) -> (Sender<T>, Receiver<T>) {
     let (watch_tx, watch_rx) = watch::channel(None);
     (
         Sender {
             store: store.clone(),
             last_saved: Arc::new(Mutex::new(watch_tx)),
+            retained,
+            count: count.clone(),
             phantom: PhantomData,
         },
         Receiver {
             store,
             last_saved: watch_rx,
             last_received: None,
+            count,
             phantom: PhantomData,
         },
     )
 }
 
-#[derive(Debug, Clone)]
+// Notifies the subscribers of the count only when it changes
+async fn update_count<T: Storable>(
+    conn: &mut SqliteConnection,
+    count: &watch::Sender<usize>,
+) -> Result<()> {
+    let current = T::count(conn).await?;
+    if *count.borrow() != current {
+        count.send_replace(current);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
 pub struct Sender<T> {
     store: SqliteStore,
     last_saved: Arc<Mutex<watch::Sender<Option<i32>>>>,
+    retained: Option<usize>,
+    count: Arc<watch::Sender<usize>>,
     phantom: PhantomData<T>,
 }
 
+// Derived `Clone` would require `T: Clone` even though no `T` is held
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            last_saved: self.last_saved.clone(),
+            retained: self.retained,
+            count: self.count.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Receiver<T> {
     store: SqliteStore,
     last_saved: watch::Receiver<Option<i32>>,
     last_received: Option<i32>,
+    count: Arc<watch::Sender<usize>>,
     phantom: PhantomData<T>,
 }
 
@@ -70,6 +114,15 @@ impl<T: Storable + Sync> Sender<T> {
         let mut conn = self.store.connection().await;
         let id = obj.store(&mut conn).await?;
 
+        if let Some(retained) = self.retained {
+            let removed = Self::remove_oldest(&mut conn, retained).await?;
+            if removed > 0 {
+                debug!("Removed {removed} oldest objects to keep only the latest {retained}");
+            }
+        }
+
+        update_count::<T>(&mut conn, &self.count).await?;
+
         {
             let last_saved = self.last_saved.lock().await;
             let last_id = last_saved.send_replace(Some(id));
@@ -85,9 +138,36 @@ impl<T: Storable + Sync> Sender<T> {
     }
 
     pub async fn count(&self) -> Result<usize> {
-        let mut conn = self.store.connection().await;
+        let mut conn = self.store.read_connection().await;
         T::count(&mut conn).await
     }
+
+    // The receiver starts with the current count, which then changes whenever objects are stored or acknowledged
+    pub async fn subscribe_count(&self) -> Result<watch::Receiver<usize>> {
+        let mut conn = self.store.read_connection().await;
+        update_count::<T>(&mut conn, &self.count).await?;
+        Ok(self.count.subscribe())
+    }
+
+    pub fn count_watch(&self) -> Arc<watch::Sender<usize>> {
+        self.count.clone()
+    }
+
+    // Removes the objects with the lowest IDs so that at most `retained` objects are left
+    async fn remove_oldest(conn: &mut SqliteConnection, retained: usize) -> Result<usize> {
+        let excess = T::count(conn).await?.saturating_sub(retained);
+        let mut removed = 0;
+
+        while removed < excess {
+            let Some(obj) = T::load(conn, i32::MIN).await? else {
+                break;
+            };
+            T::remove(conn, obj.id()).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
 }
 
 impl<T: Storable + Send + Sync> Receiver<T> {
@@ -109,6 +189,22 @@ impl<T: Storable + Send + Sync> Receiver<T> {
         Ok(obj)
     }
 
+    // Returns the next object only if it has already been stored, never waits for new ones
+    pub async fn try_recv(&mut self) -> Result<Option<T>> {
+        if *self.last_saved.borrow_and_update() <= self.last_received {
+            return Ok(None);
+        }
+
+        let mut conn = self.store.connection().await;
+
+        let obj = T::load(&mut conn, self.last_received.unwrap_or(i32::MIN)).await?;
+        if let Some(obj) = &obj {
+            self.last_received = Some(obj.id());
+        }
+
+        Ok(obj)
+    }
+
     async fn wait_new(&mut self, cancellation: &Option<CancellationToken>) -> Result<i32> {
         let mut last_inserted = *self.last_saved.borrow_and_update();
 
@@ -135,11 +231,41 @@ impl<T: Storable + Send + Sync> Receiver<T> {
 
     pub async fn ack(&self, obj: &T) -> Result<()> {
         let mut conn = self.store.connection().await;
-        T::remove(&mut conn, obj.id()).await
+        T::remove(&mut conn, obj.id()).await?;
+        update_count::<T>(&mut conn, &self.count).await
+    }
+
+    // Makes the object available to be received again without removing it from the database
+    // All the other unacknowledged objects with higher IDs will be delivered again too
+    pub fn release(&mut self, obj: &T) {
+        let before = obj.id().saturating_sub(1);
+        self.last_received = Some(self.last_received.map_or(before, |last| last.min(before)));
     }
 
     pub async fn count(&self) -> Result<usize> {
-        let mut conn = self.store.connection().await;
+        let mut conn = self.store.read_connection().await;
         T::count(&mut conn).await
     }
+
+    // Acknowledges all the objects that haven't been received yet and match the predicate, the others stay available to be received
+    pub async fn remove_matching<F>(&mut self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut conn = self.store.connection().await;
+        let mut last_loaded = self.last_received.unwrap_or(i32::MIN);
+        let mut removed = 0;
+
+        while let Some(obj) = T::load(&mut conn, last_loaded).await? {
+            last_loaded = obj.id();
+            if predicate(&obj) {
+                T::remove(&mut conn, obj.id()).await?;
+                removed += 1;
+            }
+        }
+
+        update_count::<T>(&mut conn, &self.count).await?;
+
+        Ok(removed)
+    }
 }
@@ -24,19 +24,18 @@ impl Twin {
     pub fn update(&mut self, update: &TwinUpdate) -> Result<()> {
         match update.version {
             None => {
-                log::debug!("Applying twin patch to automatically incremented version");
+                debug!("Applying twin patch to automatically incremented version");
                 merge(&mut self.properties, &update.patch);
                 self.version += 1;
             }
             Some(new_version) => {
                 if new_version <= self.version {
-                    log::debug!(
+                    debug!(
                         "Ignoring twin patch to version {} because we are already at {}",
-                        new_version,
-                        self.version
+                        new_version, self.version
                     );
                 } else if new_version == self.version + 1 {
-                    log::debug!("Applying twin patch to version {new_version}");
+                    debug!("Applying twin patch to version {new_version}");
                     merge(&mut self.properties, &update.patch);
                     self.version = new_version;
                 } else {
@@ -77,6 +76,36 @@ struct ReportedPropertiesUpdateDb {
     patch: String,
 }
 
+impl ReportedPropertiesUpdate {
+    /// Load all the updates that haven't been acknowledged yet, ordered from the oldest one.
+    pub async fn load_all(conn: &mut SqliteConnection) -> Result<Vec<Self>> {
+        let res = sqlx::query_as!(
+            ReportedPropertiesUpdateDb,
+            r#"SELECT id AS "id?: i32", patch, update_type AS "update_type: ReportedPropertiesUpdateType" FROM ReportedPropertiesUpdates ORDER BY id"#,
+        )
+        .fetch_all(conn)
+        .await
+        .context("Unable to load reported properties updates")?;
+
+        res.into_iter()
+            .map(ReportedPropertiesUpdate::from_db)
+            .collect()
+    }
+
+    fn from_db(update: ReportedPropertiesUpdateDb) -> Result<Self> {
+        let patch = serde_json::from_str(&update.patch).context(format!(
+            "Malformed reported properties update with ID {:?}",
+            update.id
+        ))?;
+
+        Ok(ReportedPropertiesUpdate {
+            id: update.id,
+            update_type: update.update_type,
+            patch,
+        })
+    }
+}
+
 #[async_trait]
 impl Storable for ReportedPropertiesUpdate {
     fn id(&self) -> i32 {
@@ -110,22 +139,7 @@ impl Storable for ReportedPropertiesUpdate {
         .await
         .context("Unable to load twin")?;
 
-        let update = match res {
-            None => None,
-            Some(update) => {
-                let patch = serde_json::from_str(&update.patch).context(format!(
-                    "Malformed reported properties update with ID {:?}",
-                    update.id
-                ))?;
-                Some(ReportedPropertiesUpdate {
-                    id: update.id,
-                    update_type: update.update_type,
-                    patch,
-                })
-            }
-        };
-
-        Ok(update)
+        res.map(ReportedPropertiesUpdate::from_db).transpose()
     }
 
     async fn remove(conn: &mut SqliteConnection, id: i32) -> Result<()> {
@@ -0,0 +1,70 @@
+use std::{fmt::Debug, sync::Arc, time::Instant};
+
+use chrono::{DateTime, Utc};
+
+/// The source of the current time for the logic that depends on it, such as the expiration of tokens.
+/// It can be replaced in tests to make the time-dependent behavior deterministic.
+pub(crate) trait Clock: Debug + Send + Sync {
+    fn now_utc(&self) -> DateTime<Utc>;
+    fn now_instant(&self) -> Instant;
+}
+
+#[derive(Debug)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub(crate) fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+
+    // Moves only when the test advances it, so that the expiration can be tested without waiting
+    #[derive(Debug)]
+    pub(crate) struct ManualClock {
+        start_utc: DateTime<Utc>,
+        start_instant: Instant,
+        elapsed: Mutex<Duration>,
+    }
+
+    impl ManualClock {
+        pub(crate) fn new() -> Self {
+            ManualClock {
+                start_utc: Utc::now(),
+                start_instant: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+            }
+        }
+
+        pub(crate) fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now_utc(&self) -> DateTime<Utc> {
+            self.start_utc + chrono::Duration::from_std(self.elapsed()).unwrap()
+        }
+
+        fn now_instant(&self) -> Instant {
+            self.start_instant + self.elapsed()
+        }
+    }
+}
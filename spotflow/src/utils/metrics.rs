@@ -0,0 +1,55 @@
+// Records the internals of the Device SDK through the `metrics` crate facade
+// Without the `metrics` feature, the functions are empty and the calls compile away
+// The totals are counted regardless of the feature so that they can be exposed by `DeviceClient::metrics_text`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Shared by all the clients in the process, the same as the metrics recorded through the facade
+static MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn queue_depth(depth: usize) {
+    #[cfg(feature = "metrics")]
+    #[allow(clippy::cast_precision_loss)]
+    // The depth of the queue never gets anywhere near 2^52
+    metrics::gauge!("spotflow.queue.depth").set(depth as f64);
+}
+
+pub(crate) fn message_enqueued() {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("spotflow.messages.enqueued").increment(1);
+        metrics::gauge!("spotflow.queue.depth").increment(1.0);
+    }
+}
+
+pub(crate) fn message_sent() {
+    MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("spotflow.messages.sent").increment(1);
+        metrics::gauge!("spotflow.queue.depth").decrement(1.0);
+    }
+}
+
+pub(crate) fn message_discarded() {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!("spotflow.queue.depth").decrement(1.0);
+}
+
+pub(crate) fn reconnected() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!("spotflow.reconnects").increment(1);
+}
+
+pub(crate) fn messages_sent_total() -> u64 {
+    MESSAGES_SENT.load(Ordering::Relaxed)
+}
+
+pub(crate) fn reconnects_total() -> u64 {
+    RECONNECTS.load(Ordering::Relaxed)
+}
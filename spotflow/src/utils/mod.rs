@@ -1 +1,4 @@
+pub(crate) mod clock;
+pub(crate) mod metrics;
+pub(crate) mod runtime;
 pub(crate) mod thread;
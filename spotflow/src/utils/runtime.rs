@@ -0,0 +1,37 @@
+use std::future::Future;
+
+use tokio::{
+    runtime::{Handle, Runtime},
+    task::JoinHandle,
+};
+
+// The runtime is either created for a single client or shared by several clients and the application
+#[derive(Debug)]
+pub(crate) enum ClientRuntime {
+    Owned(Runtime),
+    Shared(Handle),
+}
+
+impl ClientRuntime {
+    pub(crate) fn handle(&self) -> &Handle {
+        match self {
+            ClientRuntime::Owned(runtime) => runtime.handle(),
+            ClientRuntime::Shared(handle) => handle,
+        }
+    }
+
+    pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+        match self {
+            ClientRuntime::Owned(runtime) => runtime.block_on(future),
+            ClientRuntime::Shared(handle) => handle.block_on(future),
+        }
+    }
+
+    pub(crate) fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle().spawn(future)
+    }
+}
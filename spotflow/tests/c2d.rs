@@ -1,3 +1,5 @@
+#![cfg(feature = "c2d")]
+
 use std::{
     path::Path,
     thread,
@@ -0,0 +1,72 @@
+use std::{path::Path, time::Duration};
+
+use spotflow::{DeviceClientBuilder, MessageContext};
+
+use log::*;
+use uuid::Uuid;
+
+#[path = "../examples/common/mod.rs"]
+mod common;
+
+#[test]
+fn shared_runtime() {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("sqlx=warn,ureq=warn,rumqtt=warn,info"),
+    )
+    .init();
+
+    let env_ctx = common::EnvironmentContext::try_load()
+        .expect("Unable to load settings from environment variables.");
+
+    let platform_caller = common::PlatformCaller::try_new(&env_ctx)
+        .expect("This test needs to call the Platform automatically and it's unable to do so.");
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .expect("Unable to build the shared runtime");
+
+    let message_context =
+        MessageContext::new(Some("device-sdk".to_owned()), Some("rust".to_owned()));
+
+    // Each client represents a different Device, so each one needs its own local database file
+    let clients = ["first", "second"]
+        .into_iter()
+        .map(|name| {
+            let device_id = format!("shared_runtime_test_{name}_{}", Uuid::new_v4());
+            let path = format!("./test_shared_runtime_{name}.db");
+            common::clear_db(Path::new(&path));
+
+            info!("Creating Device Client with device ID {device_id}");
+
+            DeviceClientBuilder::new(Some(device_id), env_ctx.provisioning_token.clone(), path)
+                .with_instance(env_ctx.instance_url.to_string())
+                .with_runtime_handle(runtime.handle().clone())
+                .with_display_provisioning_operation_callback(Box::new(
+                    common::ProvisioningOperationApprovalHandler::new(Some(
+                        platform_caller.clone(),
+                    )),
+                ))
+                .build()
+                .expect("Unable to build ingress connection")
+        })
+        .collect::<Vec<_>>();
+
+    for client in &clients {
+        client
+            .enqueue_message(&message_context, None, None, vec![b'a'; 1000])
+            .expect("Unable to send message");
+    }
+
+    for client in &clients {
+        let sent = client
+            .wait_enqueued_messages_sent_timeout(Duration::from_secs(30))
+            .expect("Unable to wait for the messages to be sent");
+        assert!(sent, "Sending data took too long");
+    }
+
+    info!("Dropping the clients before the shared runtime");
+    drop(clients);
+    drop(runtime);
+}